@@ -0,0 +1,513 @@
+//! Content-addressed backend
+//!
+//! CAS backend is a target storage on local file system, which stores each
+//! object once by the SHA-256 of its content, like git-annex. Content of
+//! objects is at `objects/ab/abcdef...`, and each key is a relative symlink
+//! at `paths/<key>` to its content, so that `paths` may be served as a plain
+//! mirror tree. Identical objects under different keys (e.g. the same
+//! package in several channels) take space only once.
+//!
+//! CAS backend snapshots are reconstructed from `paths`, and contain size of
+//! content and modification time of links. Checksum is not part of snapshot,
+//! as it wouldn't match checksum of another method given by source, but
+//! `sha256` checksum of objects is taken from their links without reading.
+//!
+//! Content of an object is removed once no key links to it. References are
+//! counted from the snapshot of target, so content is never removed in a
+//! transfer without target snapshot. Content type and response headers of
+//! source are not stored. Raw objects (e.g. status file) are plain files in
+//! `paths`.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::checksum::{checksum_reader, Checksum};
+use crate::common::{Mission, SnapshotConfig, SnapshotPath, TransferPath, TransferStream};
+use crate::error::{Error, Result};
+use crate::metadata::SnapshotMeta;
+use crate::stream_pipe::ByteStream;
+use crate::traits::{Key, Metadata, SnapshotStorage, TargetStorage};
+
+use async_trait::async_trait;
+use filetime::FileTime;
+use futures_util::StreamExt;
+use slog::info;
+use tokio::io::AsyncWriteExt;
+use walkdir::WalkDir;
+
+/// Checksum method by which content is addressed
+const CAS_CHECKSUM: &str = "sha256";
+
+#[derive(Debug)]
+pub struct CasBackend {
+    pub base_path: String,
+    /// Number of keys linking to each content, known once snapshot is taken
+    refs: Mutex<Option<HashMap<String, usize>>>,
+}
+
+impl CasBackend {
+    pub fn new(base_path: String) -> Self {
+        Self {
+            base_path,
+            refs: Mutex::new(None),
+        }
+    }
+
+    fn link_path(&self, key: &str) -> PathBuf {
+        format!("{}/paths/{}", self.base_path, key).into()
+    }
+
+    fn object_path(&self, hash: &str) -> PathBuf {
+        format!("{}/objects/{}/{}", self.base_path, &hash[..2], hash).into()
+    }
+
+    /// Hash of content linked by key, or `None` if key is absent or not a
+    /// link.
+    async fn linked_hash(&self, key: &str) -> Result<Option<String>> {
+        match tokio::fs::read_link(self.link_path(key)).await {
+            Ok(target) => Ok(target
+                .file_name()
+                .and_then(|name| name.to_str())
+                .map(ToString::to_string)),
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    std::io::ErrorKind::NotFound | std::io::ErrorKind::InvalidInput
+                ) =>
+            {
+                Ok(None)
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Add a reference to content. Returns true if content is to be written.
+    async fn add_ref(&self, hash: &str) -> bool {
+        if let Some(refs) = &mut *self.refs.lock().unwrap() {
+            *refs.entry(hash.to_string()).or_default() += 1;
+        }
+        // checked after reference is added, so that content is not removed
+        // by a concurrent deletion once it's found
+        tokio::fs::metadata(self.object_path(hash)).await.is_err()
+    }
+
+    /// Drop a reference to content, and remove content no longer linked.
+    fn drop_ref(&self, hash: &str) -> Result<()> {
+        if let Some(refs) = &mut *self.refs.lock().unwrap() {
+            let count = refs.entry(hash.to_string()).or_default();
+            *count = count.saturating_sub(1);
+            if *count == 0 {
+                refs.remove(hash);
+                match std::fs::remove_file(self.object_path(hash)) {
+                    Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                        return Err(err.into())
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Move file of content into place, unless the same content is already
+    /// stored.
+    async fn store_file(&self, path: &Path, hash: &str, copy: bool) -> Result<()> {
+        let object = self.object_path(hash);
+        if !self.add_ref(hash).await {
+            if !copy {
+                tokio::fs::remove_file(path).await?;
+            }
+            return Ok(());
+        }
+        tokio::fs::create_dir_all(object.parent().unwrap()).await?;
+        let temp: PathBuf = format!("{}.tmp", object.display()).into();
+        if copy {
+            let _ = tokio::fs::remove_file(&temp).await;
+            if tokio::fs::hard_link(path, &temp).await.is_err() {
+                tokio::fs::copy(path, &temp).await?;
+            }
+        } else if tokio::fs::rename(path, &object).await.is_ok() {
+            return Ok(());
+        } else {
+            // buffer may be on another file system
+            tokio::fs::copy(path, &temp).await?;
+            tokio::fs::remove_file(path).await?;
+        }
+        tokio::fs::rename(&temp, &object).await?;
+        Ok(())
+    }
+
+    /// Point key to content, replacing its previous link atomically.
+    async fn link(&self, key: &str, hash: &str, modified_at: Option<u64>) -> Result<()> {
+        let link = self.link_path(key);
+        tokio::fs::create_dir_all(link.parent().unwrap()).await?;
+        // relative to directory of link, e.g. `../../objects/ab/abcdef...`
+        // for `paths/a/b`
+        let depth = key.matches('/').count() + 1;
+        let target = format!("{}objects/{}/{}", "../".repeat(depth), &hash[..2], hash);
+        let temp: PathBuf = format!("{}.tmp", link.display()).into();
+        let _ = tokio::fs::remove_file(&temp).await;
+        tokio::fs::symlink(&target, &temp).await?;
+        if let Some(modified_at) = modified_at {
+            let mtime = FileTime::from_unix_time(modified_at as i64, 0);
+            filetime::set_symlink_file_times(&temp, mtime, mtime)?;
+        }
+        let previous = self.linked_hash(key).await?;
+        tokio::fs::rename(&temp, &link).await?;
+        match previous {
+            Some(previous) if previous != hash => self.drop_ref(&previous),
+            // link is replaced by the same content
+            Some(_) => self.drop_ref(hash),
+            None => Ok(()),
+        }
+    }
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotMeta> for CasBackend {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        _config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotMeta>> {
+        let logger = mission.logger;
+        let progress = mission.progress;
+
+        info!(logger, "scanning content-addressed storage...");
+
+        let paths_base = PathBuf::from(format!("{}/paths", self.base_path));
+        std::fs::create_dir_all(&paths_base)?;
+        let (snapshot, refs) = tokio::task::spawn_blocking(move || {
+            let mut snapshot = vec![];
+            let mut refs: HashMap<String, usize> = HashMap::new();
+            let paths_base = paths_base.canonicalize()?;
+            for entry in WalkDir::new(&paths_base) {
+                let entry = entry.map_err(|err| {
+                    Error::StorageError(format!("error while scanning file: {:?}", err))
+                })?;
+                let file_type = entry.file_type();
+                if !file_type.is_file() && !file_type.is_symlink() {
+                    continue;
+                }
+                let key = entry.path().strip_prefix(&paths_base).unwrap();
+                let key = key.to_str().unwrap().to_string();
+                let link_metadata = entry.metadata().map_err(|err| {
+                    Error::StorageError(format!("cas backend fails to get metadata {:?}", err))
+                })?;
+                let mtime = FileTime::from_last_modification_time(&link_metadata);
+                let size = if file_type.is_symlink() {
+                    let hash = std::fs::read_link(entry.path())?
+                        .file_name()
+                        .and_then(|name| name.to_str())
+                        .map(ToString::to_string);
+                    if let Some(hash) = hash {
+                        *refs.entry(hash).or_default() += 1;
+                    }
+                    // content of a dangling link is missing, and is written
+                    // again
+                    match std::fs::metadata(entry.path()) {
+                        Ok(metadata) => metadata.len(),
+                        Err(_) => continue,
+                    }
+                } else {
+                    link_metadata.len()
+                };
+
+                progress.set_message(&key);
+                snapshot.push(SnapshotMeta {
+                    key,
+                    size: Some(size),
+                    last_modified: Some(mtime.unix_seconds() as u64),
+                    ..Default::default()
+                });
+            }
+            Ok::<_, Error>((snapshot, refs))
+        })
+        .await
+        .map_err(|err| Error::ProcessError(format!("error while scanning: {:?}", err)))??;
+
+        *self.refs.lock().unwrap() = Some(refs);
+        Ok(snapshot)
+    }
+
+    fn info(&self) -> String {
+        format!("cas (meta), {:?}", self)
+    }
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotPath> for CasBackend {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotPath>> {
+        Ok(
+            <Self as SnapshotStorage<SnapshotMeta>>::snapshot(self, mission, config)
+                .await?
+                .into_iter()
+                .map(|x| SnapshotPath::new(x.key))
+                .collect(),
+        )
+    }
+
+    fn info(&self) -> String {
+        format!("cas (path), {:?}", self)
+    }
+}
+
+#[async_trait]
+impl<Snapshot: Key + Metadata> TargetStorage<Snapshot, ByteStream> for CasBackend {
+    async fn put_object(
+        &self,
+        snapshot: &Snapshot,
+        byte_stream: ByteStream,
+        _mission: &Mission,
+    ) -> Result<()> {
+        let modified_at = snapshot.last_modified().unwrap_or(byte_stream.modified_at);
+        let path = byte_stream.object.use_file();
+        let hash = checksum_reader(CAS_CHECKSUM, tokio::fs::File::open(&path).await?)
+            .await?
+            .unwrap();
+        self.store_file(&path, &hash, false).await?;
+        self.link(snapshot.key(), &hash, Some(modified_at)).await
+    }
+
+    async fn delete_object(&self, snapshot: &Snapshot, _mission: &Mission) -> Result<()> {
+        let hash = self.linked_hash(snapshot.key()).await?;
+        let link = self.link_path(snapshot.key());
+        tokio::fs::remove_file(&link).await?;
+        if let Some(hash) = hash {
+            self.drop_ref(&hash)?;
+        }
+        // prune parent directories left empty
+        let paths_base = PathBuf::from(format!("{}/paths", self.base_path));
+        let mut parent = link.parent();
+        while let Some(dir) = parent.filter(|dir| *dir != paths_base) {
+            if tokio::fs::remove_dir(dir).await.is_err() {
+                break;
+            }
+            parent = dir.parent();
+        }
+        Ok(())
+    }
+
+    async fn free_space(&self) -> Result<Option<u64>> {
+        Ok(Some(fs2::available_space(&self.base_path)?))
+    }
+
+    fn can_checksum(&self) -> bool {
+        true
+    }
+
+    async fn checksum_object(&self, snapshot: &Snapshot, method: &str) -> Result<Option<String>> {
+        if method == CAS_CHECKSUM {
+            if let Some(hash) = self.linked_hash(snapshot.key()).await? {
+                return Ok(Some(hash));
+            }
+        }
+        match tokio::fs::File::open(self.link_path(snapshot.key())).await {
+            Ok(file) => checksum_reader(method, file).await,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn get_raw_object(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        match tokio::fs::read(self.link_path(key)).await {
+            Ok(content) => Ok(Some(content)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn put_raw_object(&self, key: &str, content: Vec<u8>) -> Result<()> {
+        let target = self.link_path(key);
+        let temp: PathBuf = format!("{}.tmp", target.display()).into();
+        tokio::fs::create_dir_all(target.parent().unwrap()).await?;
+        tokio::fs::write(&temp, content).await?;
+        tokio::fs::rename(&temp, &target).await?;
+        Ok(())
+    }
+
+    async fn delete_raw_object(&self, key: &str) -> Result<()> {
+        tokio::fs::remove_file(self.link_path(key)).await?;
+        Ok(())
+    }
+}
+
+/// Streamed objects are hashed while written to a temporary file, which is
+/// renamed into place once the stream ends without error.
+#[async_trait]
+impl<Snapshot: Key + Metadata> TargetStorage<Snapshot, TransferStream> for CasBackend {
+    async fn put_object(
+        &self,
+        snapshot: &Snapshot,
+        item: TransferStream,
+        _mission: &Mission,
+    ) -> Result<()> {
+        let modified_at = snapshot.last_modified().unwrap_or(item.modified_at);
+        let temp_dir = PathBuf::from(format!("{}/objects/tmp", self.base_path));
+        tokio::fs::create_dir_all(&temp_dir).await?;
+        let temp = temp_dir.join(format!("{:016x}", rand::random::<u64>()));
+        let mut stream = item.stream;
+        let result = async {
+            let mut checksum = Checksum::new(CAS_CHECKSUM).unwrap();
+            let mut file = tokio::io::BufWriter::new(tokio::fs::File::create(&temp).await?);
+            while let Some(chunk) = stream.next().await {
+                let chunk = chunk?;
+                checksum.update(&chunk);
+                file.write_all(&chunk).await?;
+            }
+            file.flush().await?;
+            Ok::<_, Error>(checksum.finish())
+        };
+        let hash = match result.await {
+            Ok(hash) => hash,
+            Err(err) => {
+                let _ = tokio::fs::remove_file(&temp).await;
+                return Err(err);
+            }
+        };
+        self.store_file(&temp, &hash, false).await?;
+        self.link(snapshot.key(), &hash, Some(modified_at)).await
+    }
+
+    async fn delete_object(&self, snapshot: &Snapshot, mission: &Mission) -> Result<()> {
+        <Self as TargetStorage<Snapshot, ByteStream>>::delete_object(self, snapshot, mission).await
+    }
+
+    async fn free_space(&self) -> Result<Option<u64>> {
+        <Self as TargetStorage<Snapshot, ByteStream>>::free_space(self).await
+    }
+
+    fn can_checksum(&self) -> bool {
+        true
+    }
+
+    async fn checksum_object(&self, snapshot: &Snapshot, method: &str) -> Result<Option<String>> {
+        <Self as TargetStorage<Snapshot, ByteStream>>::checksum_object(self, snapshot, method).await
+    }
+
+    async fn get_raw_object(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        <Self as TargetStorage<Snapshot, ByteStream>>::get_raw_object(self, key).await
+    }
+
+    async fn put_raw_object(&self, key: &str, content: Vec<u8>) -> Result<()> {
+        <Self as TargetStorage<Snapshot, ByteStream>>::put_raw_object(self, key, content).await
+    }
+
+    async fn delete_raw_object(&self, key: &str) -> Result<()> {
+        <Self as TargetStorage<Snapshot, ByteStream>>::delete_raw_object(self, key).await
+    }
+}
+
+/// Objects already on local disk are hard linked into place as content, or
+/// copied if they are on another file system.
+#[async_trait]
+impl<Snapshot: Key + Metadata> TargetStorage<Snapshot, TransferPath> for CasBackend {
+    async fn put_object(
+        &self,
+        snapshot: &Snapshot,
+        item: TransferPath,
+        _mission: &Mission,
+    ) -> Result<()> {
+        let hash = checksum_reader(CAS_CHECKSUM, tokio::fs::File::open(&item.0).await?)
+            .await?
+            .unwrap();
+        self.store_file(&item.0, &hash, true).await?;
+        self.link(snapshot.key(), &hash, snapshot.last_modified())
+            .await
+    }
+
+    async fn delete_object(&self, snapshot: &Snapshot, mission: &Mission) -> Result<()> {
+        <Self as TargetStorage<Snapshot, ByteStream>>::delete_object(self, snapshot, mission).await
+    }
+
+    async fn free_space(&self) -> Result<Option<u64>> {
+        <Self as TargetStorage<Snapshot, ByteStream>>::free_space(self).await
+    }
+
+    fn can_checksum(&self) -> bool {
+        true
+    }
+
+    async fn checksum_object(&self, snapshot: &Snapshot, method: &str) -> Result<Option<String>> {
+        <Self as TargetStorage<Snapshot, ByteStream>>::checksum_object(self, snapshot, method).await
+    }
+
+    async fn get_raw_object(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        <Self as TargetStorage<Snapshot, ByteStream>>::get_raw_object(self, key).await
+    }
+
+    async fn put_raw_object(&self, key: &str, content: Vec<u8>) -> Result<()> {
+        <Self as TargetStorage<Snapshot, ByteStream>>::put_raw_object(self, key, content).await
+    }
+
+    async fn delete_raw_object(&self, key: &str) -> Result<()> {
+        <Self as TargetStorage<Snapshot, ByteStream>>::delete_raw_object(self, key).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_dedup() {
+        let dir = std::env::temp_dir().join(format!("cas-{}", std::process::id()));
+        let source = dir.join("source");
+        std::fs::create_dir_all(&source).unwrap();
+        std::fs::write(source.join("a"), b"same").unwrap();
+        std::fs::write(source.join("b"), b"other").unwrap();
+        let mut target = CasBackend::new(dir.join("cas").to_str().unwrap().to_string());
+        let mission = Mission::for_test();
+        let put = |key: &str, file: &str| {
+            let snapshot = SnapshotMeta {
+                key: key.to_string(),
+                last_modified: Some(1_600_000_000),
+                ..Default::default()
+            };
+            let item = TransferPath::new(source.join(file));
+            let target = &target;
+            let mission = &mission;
+            async move { target.put_object(&snapshot, item, mission).await.unwrap() }
+        };
+        put("main/x.tar", "a").await;
+        put("extra/y.tar", "a").await;
+        put("z.tar", "b").await;
+        let objects = WalkDir::new(dir.join("cas/objects"))
+            .into_iter()
+            .filter(|entry| entry.as_ref().unwrap().file_type().is_file())
+            .count();
+        assert_eq!(objects, 2);
+
+        let config = SnapshotConfig::for_test();
+        let mut snapshot: Vec<SnapshotMeta> =
+            target.snapshot(Mission::for_test(), &config).await.unwrap();
+        snapshot.sort_by(|a, b| a.key.cmp(&b.key));
+        let keys: Vec<_> = snapshot.iter().map(|x| x.key.as_str()).collect();
+        assert_eq!(keys, vec!["extra/y.tar", "main/x.tar", "z.tar"]);
+        assert_eq!(snapshot[0].size, Some(4));
+        assert_eq!(snapshot[0].last_modified, Some(1_600_000_000));
+        assert_eq!(
+            std::fs::read(dir.join("cas/paths/main/x.tar")).unwrap(),
+            b"same"
+        );
+
+        // content is kept until its last link is deleted
+        let mission = Mission::for_test();
+        let hash = target.linked_hash("main/x.tar").await.unwrap().unwrap();
+        TargetStorage::<_, TransferPath>::delete_object(&target, &snapshot[1], &mission)
+            .await
+            .unwrap();
+        assert!(target.object_path(&hash).exists());
+        TargetStorage::<_, TransferPath>::delete_object(&target, &snapshot[0], &mission)
+            .await
+            .unwrap();
+        assert!(!target.object_path(&hash).exists());
+        assert!(!dir.join("cas/paths/main").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,83 @@
+//! Checksum
+//!
+//! Checksums are computed while an object is being downloaded, and compared
+//! with the checksum recorded in snapshot, if any. With `--verify-checksum`,
+//! objects are also read back from target after being written.
+
+use crate::error::Result;
+
+use md5::Md5;
+use sha1::Sha1;
+use sha2::{Digest, Sha224, Sha256, Sha384, Sha512};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Supported checksum methods, by their names in snapshot.
+pub const CHECKSUM_METHODS: &[&str] = &["md5", "sha1", "sha224", "sha256", "sha384", "sha512"];
+
+pub enum Checksum {
+    Md5(Md5),
+    Sha1(Sha1),
+    Sha224(Sha224),
+    Sha256(Sha256),
+    Sha384(Sha384),
+    Sha512(Sha512),
+}
+
+impl Checksum {
+    /// Create a hasher for checksum method, or `None` if it's not supported.
+    pub fn new(method: &str) -> Option<Self> {
+        match method {
+            "md5" => Some(Self::Md5(Md5::new())),
+            "sha1" => Some(Self::Sha1(Sha1::new())),
+            "sha224" => Some(Self::Sha224(Sha224::new())),
+            "sha256" => Some(Self::Sha256(Sha256::new())),
+            "sha384" => Some(Self::Sha384(Sha384::new())),
+            "sha512" => Some(Self::Sha512(Sha512::new())),
+            _ => None,
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Md5(hasher) => hasher.update(data),
+            Self::Sha1(hasher) => hasher.update(data),
+            Self::Sha224(hasher) => hasher.update(data),
+            Self::Sha256(hasher) => hasher.update(data),
+            Self::Sha384(hasher) => hasher.update(data),
+            Self::Sha512(hasher) => hasher.update(data),
+        }
+    }
+
+    /// Lowercase hex digest.
+    pub fn finish(self) -> String {
+        match self {
+            Self::Md5(hasher) => format!("{:x}", hasher.finalize()),
+            Self::Sha1(hasher) => format!("{:x}", hasher.finalize()),
+            Self::Sha224(hasher) => format!("{:x}", hasher.finalize()),
+            Self::Sha256(hasher) => format!("{:x}", hasher.finalize()),
+            Self::Sha384(hasher) => format!("{:x}", hasher.finalize()),
+            Self::Sha512(hasher) => format!("{:x}", hasher.finalize()),
+        }
+    }
+}
+
+/// Lowercase hex digest of all content of `reader`, or `None` if checksum
+/// method is not supported.
+pub async fn checksum_reader(
+    method: &str,
+    mut reader: impl AsyncRead + Unpin,
+) -> Result<Option<String>> {
+    let mut checksum = match Checksum::new(method) {
+        Some(checksum) => checksum,
+        None => return Ok(None),
+    };
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let len = reader.read(&mut buffer).await?;
+        if len == 0 {
+            break;
+        }
+        checksum.update(&buffer[..len]);
+    }
+    Ok(Some(checksum.finish()))
+}
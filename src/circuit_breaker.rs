@@ -0,0 +1,193 @@
+//! Circuit breaker
+//!
+//! When source goes down, retrying every object at full concurrency only
+//! hammers the failing server. Circuit breaker tracks results of recent
+//! requests to source. If failure rate in the window exceeds threshold, the
+//! breaker opens and no request is issued for a cooldown. Then a few probe
+//! requests are let through, and full concurrency is resumed only if all of
+//! them succeed. After tripping too many times in a row, the breaker gives
+//! up, and the transfer fails quickly as source unavailable.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+#[derive(Debug, Clone)]
+pub struct CircuitBreakerConfig {
+    /// Number of recent requests to compute failure rate
+    pub window: usize,
+    /// Failure rate in window which opens the breaker
+    pub threshold: f64,
+    pub cooldown: Duration,
+    /// Number of probe requests after cooldown
+    pub probes: usize,
+    /// Give up after tripping this many times in a row
+    pub max_trips: usize,
+}
+
+#[derive(Debug)]
+enum State {
+    Closed { results: VecDeque<bool> },
+    Open { until: Instant },
+    HalfOpen { in_flight: usize, succeeded: usize },
+}
+
+#[derive(Debug)]
+struct Inner {
+    state: State,
+    trips: usize,
+}
+
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    config: CircuitBreakerConfig,
+    inner: Mutex<Inner>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: CircuitBreakerConfig) -> Self {
+        Self {
+            config,
+            inner: Mutex::new(Inner {
+                state: State::Closed {
+                    results: VecDeque::new(),
+                },
+                trips: 0,
+            }),
+        }
+    }
+
+    /// Wait until a request may be issued. Returns false if breaker has
+    /// given up, in which case no request should be issued.
+    pub async fn wait(&self) -> bool {
+        loop {
+            let sleep_until = {
+                let mut inner = self.inner.lock().unwrap();
+                if inner.trips >= self.config.max_trips {
+                    return false;
+                }
+                let now = Instant::now();
+                match &mut inner.state {
+                    State::Closed { .. } => return true,
+                    State::Open { until } if now >= *until => {
+                        inner.state = State::HalfOpen {
+                            in_flight: 1,
+                            succeeded: 0,
+                        };
+                        return true;
+                    }
+                    State::Open { until } => *until,
+                    State::HalfOpen {
+                        in_flight,
+                        succeeded,
+                    } => {
+                        if *in_flight + *succeeded < self.config.probes {
+                            *in_flight += 1;
+                            return true;
+                        }
+                        now + Duration::from_millis(100)
+                    }
+                }
+            };
+            tokio::time::sleep_until(sleep_until).await;
+        }
+    }
+
+    /// Record result of a request issued after `wait`. Returns true if
+    /// breaker is opened by this result.
+    pub fn record(&self, success: bool) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        let open = match &mut inner.state {
+            State::Closed { results } => {
+                results.push_back(success);
+                if results.len() > self.config.window {
+                    results.pop_front();
+                }
+                let failures = results.iter().filter(|success| !**success).count();
+                results.len() >= self.config.window
+                    && failures as f64 >= self.config.threshold * results.len() as f64
+            }
+            State::HalfOpen {
+                in_flight,
+                succeeded,
+            } => {
+                *in_flight = in_flight.saturating_sub(1);
+                if success {
+                    *succeeded += 1;
+                    if *succeeded >= self.config.probes {
+                        inner.state = State::Closed {
+                            results: VecDeque::new(),
+                        };
+                        inner.trips = 0;
+                    }
+                    false
+                } else {
+                    true
+                }
+            }
+            // requests issued before opening
+            State::Open { .. } => false,
+        };
+        if open {
+            inner.state = State::Open {
+                until: Instant::now() + self.config.cooldown,
+            };
+            inner.trips += 1;
+        }
+        open
+    }
+
+    /// Whether breaker has given up.
+    pub fn given_up(&self) -> bool {
+        self.inner.lock().unwrap().trips >= self.config.max_trips
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker(max_trips: usize) -> CircuitBreaker {
+        CircuitBreaker::new(CircuitBreakerConfig {
+            window: 4,
+            threshold: 0.5,
+            cooldown: Duration::from_millis(10),
+            probes: 2,
+            max_trips,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_open_and_recover() {
+        let breaker = breaker(3);
+        assert!(breaker.wait().await);
+        assert!(!breaker.record(true));
+        assert!(!breaker.record(false));
+        assert!(!breaker.record(true));
+        assert!(breaker.record(false));
+
+        // half open after cooldown, let probes through
+        assert!(breaker.wait().await);
+        assert!(breaker.wait().await);
+        assert!(!breaker.record(true));
+        assert!(!breaker.record(true));
+        assert!(matches!(
+            breaker.inner.lock().unwrap().state,
+            State::Closed { .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_give_up() {
+        let breaker = breaker(2);
+        for _ in 0..4 {
+            breaker.record(false);
+        }
+        assert!(breaker.wait().await);
+        assert!(breaker.record(false));
+        assert!(breaker.given_up());
+        assert!(!breaker.wait().await);
+    }
+}
@@ -1,20 +1,52 @@
+use std::sync::atomic::AtomicU64;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use futures_util::stream::BoxStream;
 use indicatif::ProgressBar;
 use reqwest::Client;
+use serde::{Deserialize, Serialize};
 use slog::Logger;
 
+use crate::conditional::Validators;
+use crate::rate_limit::RateLimit;
+
 #[derive(Clone)]
 pub struct Mission {
     pub progress: ProgressBar,
     pub client: Client,
     pub logger: Logger,
+    pub rate_limit: Option<RateLimit>,
+    /// Bytes per second downloaded, shared by all concurrent transfers
+    pub bandwidth_limit: Option<RateLimit>,
+    /// Counter of bytes downloaded, shared by all concurrent transfers
+    pub bytes_received: Option<Arc<AtomicU64>>,
+    /// Validators of objects for conditional requests, see `conditional`
+    pub validators: Option<std::sync::Arc<Validators>>,
+}
+
+impl Mission {
+    /// Wait for rate limit of requests, if any.
+    pub async fn throttle(&self) {
+        crate::rate_limit::throttle(&self.rate_limit).await;
+    }
 }
 
-#[derive(Debug, Copy, Clone)]
+/// Configuration of snapshot phase. Concurrency of transfer phase is
+/// configured separately in `SimpleDiffTransferConfig`.
+#[derive(Debug, Clone)]
 pub struct SnapshotConfig {
+    /// Concurrent requests a storage may issue while taking snapshot
     pub concurrent_resolve: usize,
+    /// Directory to cache source snapshot in, see `snapshot_cache`
+    pub snapshot_cache_dir: Option<std::path::PathBuf>,
+    /// Cached source snapshot younger than this is reused
+    pub snapshot_ttl: std::time::Duration,
+    /// Objects taken by sources in debug mode, instead of their own default
+    pub debug_limit: Option<usize>,
 }
 
-#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct SnapshotPath(pub String, pub bool);
 
 impl SnapshotPath {
@@ -27,5 +59,91 @@ impl SnapshotPath {
     }
 }
 
+/// URL of an object, and fallback URLs to try in order if it fails. The
+/// object is to be fetched, usually by `ByteStreamPipe`.
 #[derive(Debug)]
-pub struct TransferURL(pub String);
+pub struct TransferURL(pub String, pub Vec<String>);
+
+impl TransferURL {
+    pub fn new(url: String) -> Self {
+        Self(url, vec![])
+    }
+
+    pub fn with_fallbacks(url: String, fallbacks: Vec<String>) -> Self {
+        Self(url, fallbacks)
+    }
+
+    pub fn candidates(&self) -> impl Iterator<Item = &String> {
+        std::iter::once(&self.0).chain(self.1.iter())
+    }
+}
+
+/// Path of an object already present on local disk, e.g. a local rsync
+/// tree. Target may link or copy it into place without fetching. The file
+/// belongs to source, and must not be moved or modified, unless it is a
+/// temporary one (e.g. index page generated by pipe), which is removed on
+/// dropping.
+#[derive(Debug)]
+pub struct TransferPath(pub std::path::PathBuf, pub bool);
+
+impl TransferPath {
+    pub fn new(path: std::path::PathBuf) -> Self {
+        Self(path, false)
+    }
+
+    pub fn temporary(path: std::path::PathBuf) -> Self {
+        Self(path, true)
+    }
+}
+
+impl Drop for TransferPath {
+    fn drop(&mut self) {
+        if self.1 {
+            if let Err(err) = std::fs::remove_file(&self.0) {
+                eprintln!("failed to remove temporary file: {:?} {:?}", err, self.0);
+            }
+        }
+    }
+}
+
+/// Body of an object streamed from source, usually by `TransferStreamPipe`.
+/// Target consumes it chunk by chunk, so that large objects are never
+/// buffered as a whole. Length is unknown if source didn't send it.
+pub struct TransferStream {
+    pub stream: BoxStream<'static, std::io::Result<Bytes>>,
+    pub length: Option<u64>,
+    pub modified_at: u64,
+    pub content_type: Option<String>,
+    /// Response headers of source object which targets may store. See
+    /// `STORED_HEADERS`.
+    pub headers: Vec<(String, String)>,
+}
+
+#[cfg(test)]
+impl SnapshotConfig {
+    /// Config resolving one request at a time without cache, for tests.
+    pub fn for_test() -> Self {
+        Self {
+            concurrent_resolve: 1,
+            snapshot_cache_dir: None,
+            snapshot_ttl: std::time::Duration::from_secs(0),
+            debug_limit: None,
+        }
+    }
+}
+
+#[cfg(test)]
+impl Mission {
+    /// Mission with a hidden progress bar and a discarding logger, for tests.
+    pub fn for_test() -> Self {
+        Self {
+            progress: ProgressBar::hidden(),
+            client: Client::new(),
+            logger: Logger::root(slog::Discard, slog::o!()),
+            rate_limit: None,
+            bandwidth_limit: None,
+            bytes_received: None,
+            validators: None,
+        }
+    }
+}
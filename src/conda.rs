@@ -1,7 +1,9 @@
 //! Conda source
 //!
 //! Conda is a source storage that scans an conda repository.
-//! This source yields a snapshot with size and checksum metadata.
+//! This source yields a snapshot with size and checksum metadata, taken
+//! from `repodata.json` of each subdir. Checksum is sha256, or md5 if a
+//! package has no sha256.
 //! To ensure consistency, repository data is always transferred
 //! at the end. This is done by setting priority in snapshot metadata.
 
@@ -9,6 +11,7 @@ use crate::common::{Mission, SnapshotConfig, TransferURL};
 use crate::error::{Error, Result};
 use crate::metadata::SnapshotMeta;
 use crate::traits::{SnapshotStorage, SourceStorage};
+use crate::utils::check_url;
 
 use async_trait::async_trait;
 use futures_util::{stream, StreamExt, TryStreamExt};
@@ -39,13 +42,22 @@ fn parse_index(repo: &str, data: &[u8]) -> Result<Vec<SnapshotMeta>> {
     let v: JsonValue = serde_json::from_slice(data)?;
     let mut result = vec![];
 
-    let package_mapper = |(key, value): (&String, &JsonValue)| SnapshotMeta {
-        key: format!("{}/{}", repo, key),
-        size: value.get("size").map(|x| x.as_u64().unwrap()),
-        last_modified: None,
-        checksum_method: value.get("sha256").map(|_| "sha256".to_string()),
-        checksum: value.get("sha256").map(|x| x.as_str().unwrap().to_owned()),
-        ..Default::default()
+    let package_mapper = |(key, value): (&String, &JsonValue)| {
+        // prefer sha256, while some old packages only have md5
+        let checksum = ["sha256", "md5"].iter().find_map(|method| {
+            value
+                .get(*method)
+                .and_then(JsonValue::as_str)
+                .map(|checksum| (method.to_string(), checksum.to_lowercase()))
+        });
+        SnapshotMeta {
+            key: format!("{}/{}", repo, key),
+            size: value.get("size").and_then(JsonValue::as_u64),
+            last_modified: None,
+            checksum_method: checksum.as_ref().map(|(method, _)| method.clone()),
+            checksum: checksum.map(|(_, checksum)| checksum),
+            ..Default::default()
+        }
     };
 
     if let Some(JsonValue::Object(map)) = v.get("packages") {
@@ -77,11 +89,12 @@ impl SnapshotStorage<SnapshotMeta> for Conda {
     async fn snapshot(
         &mut self,
         mission: Mission,
-        _config: &SnapshotConfig,
+        config: &SnapshotConfig,
     ) -> Result<Vec<SnapshotMeta>> {
         let logger = mission.logger;
         let progress = mission.progress;
         let client = mission.client;
+        let rate_limit = mission.rate_limit;
 
         let fetch = |repo: String| {
             info!(logger, "fetching {}", repo);
@@ -90,13 +103,15 @@ impl SnapshotStorage<SnapshotMeta> for Conda {
             let client = client.clone();
             let logger = logger.clone();
             let repo_ = repo.clone();
+            let rate_limit = rate_limit.clone();
 
             let future = async move {
                 let mut snapshot = vec![];
                 let repodata = format!("{}/{}/repodata.json", base, repo);
+                crate::rate_limit::throttle(&rate_limit).await;
                 let index_data = client.get(&repodata).send().await?.bytes().await?;
                 let packages = parse_index(&repo, &index_data)?;
-                snapshot.extend(packages.into_iter());
+                snapshot.extend(packages);
                 progress.set_message(&repo);
                 snapshot.append(&mut vec![
                     SnapshotMeta::force(format!("{}/repodata.json", repo)),
@@ -117,7 +132,7 @@ impl SnapshotStorage<SnapshotMeta> for Conda {
 
         let snapshots = stream::iter(self.repos.repos.clone())
             .map(fetch)
-            .buffer_unordered(4)
+            .buffer_unordered(config.concurrent_resolve)
             .try_collect::<Vec<_>>()
             .await?
             .into_iter()
@@ -127,6 +142,10 @@ impl SnapshotStorage<SnapshotMeta> for Conda {
         Ok(snapshots)
     }
 
+    async fn check(&mut self, mission: Mission, _config: &SnapshotConfig) -> Result<String> {
+        check_url(&mission, &self.repos.base).await
+    }
+
     fn info(&self) -> String {
         format!("conda, {:?}", self.config)
     }
@@ -135,6 +154,58 @@ impl SnapshotStorage<SnapshotMeta> for Conda {
 #[async_trait]
 impl SourceStorage<SnapshotMeta, TransferURL> for Conda {
     async fn get_object(&self, snapshot: &SnapshotMeta, _mission: &Mission) -> Result<TransferURL> {
-        Ok(TransferURL(format!("{}/{}", self.repos.base, snapshot.key)))
+        Ok(TransferURL::new(format!(
+            "{}/{}",
+            self.repos.base, snapshot.key
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_index() {
+        let data = br#"{
+            "packages": {
+                "a-1.0-0.tar.bz2": { "size": 10, "md5": "AA", "sha256": "BB" },
+                "b-1.0-0.tar.bz2": { "size": 20, "md5": "cc" }
+            },
+            "packages.conda": {
+                "c-1.0-0.conda": { "sha256": "dd" }
+            }
+        }"#;
+        let mut snapshot = parse_index("linux-64", data).unwrap();
+        snapshot.sort_by(|a, b| a.key.cmp(&b.key));
+        let checksums: Vec<_> = snapshot
+            .iter()
+            .map(|x| {
+                (
+                    x.key.as_str(),
+                    x.size,
+                    x.checksum_method.as_deref(),
+                    x.checksum.as_deref(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            checksums,
+            vec![
+                (
+                    "linux-64/a-1.0-0.tar.bz2",
+                    Some(10),
+                    Some("sha256"),
+                    Some("bb")
+                ),
+                (
+                    "linux-64/b-1.0-0.tar.bz2",
+                    Some(20),
+                    Some("md5"),
+                    Some("cc")
+                ),
+                ("linux-64/c-1.0-0.conda", None, Some("sha256"), Some("dd")),
+            ]
+        );
     }
 }
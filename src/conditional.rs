@@ -0,0 +1,160 @@
+//! Conditional requests
+//!
+//! Some objects are transferred on every run though they rarely change,
+//! e.g. forced indexes. If conditional requests are enabled, `ETag` and
+//! `Last-Modified` of objects are saved in snapshot cache directory once
+//! they are written to target, and sent as `If-None-Match` and
+//! `If-Modified-Since` by the next run. An object answered with `304 Not
+//! Modified` is skipped without being downloaded.
+//!
+//! Validators are only kept for forced objects found on target, so that an
+//! object removed from target, or updated because its snapshot differs from
+//! target, is always downloaded again. Without target snapshot, e.g. with
+//! `--force-all`, target is assumed to still hold objects written by
+//! previous runs.
+//!
+//! Validators are keyed by source key, and only sent by URL-based pipes.
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use reqwest::header::{self, HeaderMap};
+use reqwest::RequestBuilder;
+use serde::{Deserialize, Serialize};
+
+use crate::error::Result;
+use crate::snapshot_cache;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Validator {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+/// Validators of objects on target, and of objects being transferred which
+/// are saved once they are written.
+#[derive(Debug, Default)]
+pub struct Validators {
+    saved: Mutex<HashMap<String, Validator>>,
+    pending: Mutex<HashMap<String, Validator>>,
+}
+
+impl Validators {
+    /// Path of validators file for source with `info`, next to its cached
+    /// snapshot.
+    pub fn path(dir: &Path, info: &str) -> PathBuf {
+        snapshot_cache::cache_path(dir, &format!("validators <{}>", info))
+    }
+
+    /// Load validators saved by a previous run. A file which can't be read
+    /// is ignored, and all objects are downloaded.
+    pub fn load(path: &Path) -> Self {
+        let saved = std::fs::File::open(path)
+            .ok()
+            .and_then(|file| serde_json::from_reader(std::io::BufReader::new(file)).ok())
+            .unwrap_or_default();
+        Self {
+            saved: Mutex::new(saved),
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Save validators, replacing the previous file atomically.
+    pub fn store(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let temp = path.with_extension("json.tmp");
+        let mut writer = std::io::BufWriter::new(std::fs::File::create(&temp)?);
+        serde_json::to_writer(&mut writer, &*self.saved.lock().unwrap())?;
+        writer.flush()?;
+        std::fs::rename(&temp, path)?;
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.saved.lock().unwrap().len()
+    }
+
+    /// Only keep validators of keys for which `f` returns true.
+    pub fn retain(&self, mut f: impl FnMut(&str) -> bool) {
+        self.saved.lock().unwrap().retain(|key, _| f(key));
+    }
+
+    /// Add conditional headers of object to request, if any.
+    pub fn apply(&self, key: &str, mut request: RequestBuilder) -> RequestBuilder {
+        if let Some(validator) = self.saved.lock().unwrap().get(key) {
+            if let Some(etag) = &validator.etag {
+                request = request.header(header::IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = &validator.last_modified {
+                request = request.header(header::IF_MODIFIED_SINCE, last_modified);
+            }
+        }
+        request
+    }
+
+    /// Record validators in response headers of object, until it is written
+    /// to target.
+    pub fn record(&self, key: &str, headers: &HeaderMap) {
+        let value = |name| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(ToString::to_string)
+        };
+        let validator = Validator {
+            etag: value(header::ETAG),
+            last_modified: value(header::LAST_MODIFIED),
+        };
+        let mut pending = self.pending.lock().unwrap();
+        if validator == Validator::default() {
+            pending.remove(key);
+        } else {
+            pending.insert(key.to_string(), validator);
+        }
+    }
+
+    /// Save validators recorded for object, after it is written to target.
+    pub fn commit(&self, key: &str) {
+        let validator = self.pending.lock().unwrap().remove(key);
+        let mut saved = self.saved.lock().unwrap();
+        match validator {
+            Some(validator) => saved.insert(key.to_string(), validator),
+            None => saved.remove(key),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    #[test]
+    fn test_record_commit() {
+        let dir = std::env::temp_dir().join(format!("validators-{}", std::process::id()));
+        let path = Validators::path(&dir, "pypi");
+        let validators = Validators::default();
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ETAG, HeaderValue::from_static("\"abc\""));
+        validators.record("a", &headers);
+        validators.record("b", &headers);
+        validators.commit("a");
+        validators.store(&path).unwrap();
+
+        let loaded = Validators::load(&path);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(loaded.len(), 1);
+        let request = loaded.apply("a", reqwest::Client::new().get("http://localhost/a"));
+        let request = request.build().unwrap();
+        assert_eq!(request.headers()[header::IF_NONE_MATCH], "\"abc\"");
+        assert!(request.headers().get(header::IF_MODIFIED_SINCE).is_none());
+
+        loaded.retain(|key| key != "a");
+        assert_eq!(loaded.len(), 0);
+        assert_eq!(Validators::load(&path).len(), 0);
+    }
+}
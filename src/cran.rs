@@ -0,0 +1,213 @@
+//! CRAN Source
+//!
+//! CRAN source parses `PACKAGES` of source packages in `src/contrib`, and
+//! of configured binary trees in `bin`, e.g. `windows/contrib/4.3`. Each
+//! package is recorded with its md5. `PACKAGES` themselves are always
+//! transferred at the end.
+//!
+//! If `--archive` is set, old versions of source packages are also listed
+//! by crawling directory listing of `src/contrib/Archive`, see `http_listing`.
+
+use crate::common::{Mission, SnapshotConfig, TransferURL};
+use crate::error::{Error, Result};
+use crate::http_listing::parse_links;
+use crate::metadata::SnapshotMeta;
+use crate::rate_limit::throttle;
+use crate::traits::{SnapshotStorage, SourceStorage};
+use crate::utils::{check_url, parse_control_fields};
+
+use async_trait::async_trait;
+use futures_util::{stream, StreamExt, TryStreamExt};
+use slog::{info, warn};
+use structopt::StructOpt;
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct Cran {
+    #[structopt(long, default_value = "https://cloud.r-project.org")]
+    pub base: String,
+    #[structopt(
+        long,
+        number_of_values = 1,
+        help = "Binary tree under bin to mirror, e.g. windows/contrib/4.3 or macosx/big-sur-arm64/contrib/4.3, may be specified multiple times"
+    )]
+    pub binary: Vec<String>,
+    #[structopt(long, help = "Include archived versions of source packages")]
+    pub archive: bool,
+    #[structopt(long)]
+    pub debug: bool,
+}
+
+/// Number of packages taken from each `PACKAGES` and archive in debug mode
+const DEBUG_PACKAGES: usize = 100;
+
+/// Index files in each package directory
+const PACKAGES_INDEX: &[&str] = &["PACKAGES", "PACKAGES.gz", "PACKAGES.rds"];
+
+/// Parse packages in `PACKAGES` of `dir`, whose files have `extension`.
+fn parse_packages(dir: &str, extension: &str, content: &str) -> Vec<SnapshotMeta> {
+    content
+        .split("\n\n")
+        .filter_map(|paragraph| {
+            let fields = parse_control_fields(paragraph);
+            let field = |name: &str| {
+                fields
+                    .iter()
+                    .find(|(field, _)| *field == name)
+                    .map(|(_, value)| value.as_str())
+            };
+            let key = format!(
+                "{}/{}_{}.{}",
+                dir,
+                field("Package")?,
+                field("Version")?,
+                extension
+            );
+            let md5 = field("MD5sum").map(str::to_lowercase);
+            Some(SnapshotMeta {
+                key,
+                checksum_method: md5.as_ref().map(|_| "md5".to_string()),
+                checksum: md5,
+                ..Default::default()
+            })
+        })
+        .collect()
+}
+
+impl Cran {
+    async fn fetch(&self, mission: &Mission, path: &str) -> Result<String> {
+        throttle(&mission.rate_limit).await;
+        let resp = mission
+            .client
+            .get(format!("{}/{}", self.base, path))
+            .send()
+            .await?;
+        if !resp.status().is_success() {
+            return Err(Error::HTTPError(resp.status()));
+        }
+        Ok(resp.text().await?)
+    }
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotMeta> for Cran {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotMeta>> {
+        let logger = mission.logger.clone();
+        let progress = mission.progress.clone();
+
+        let mut dirs = vec![("src/contrib".to_string(), "tar.gz")];
+        for binary in &self.binary {
+            let extension = if binary.starts_with("windows/") {
+                "zip"
+            } else {
+                "tgz"
+            };
+            dirs.push((format!("bin/{}", binary.trim_matches('/')), extension));
+        }
+
+        let mut snapshot = vec![];
+        for (dir, extension) in dirs {
+            info!(logger, "fetching {}/PACKAGES", dir);
+            progress.set_message(&dir);
+            let content = self.fetch(&mission, &format!("{}/PACKAGES", dir)).await?;
+            let mut packages = parse_packages(&dir, extension, &content);
+            if self.debug {
+                packages.truncate(DEBUG_PACKAGES);
+            }
+            snapshot.extend(packages);
+            for index in PACKAGES_INDEX {
+                snapshot.push(SnapshotMeta::force(format!("{}/{}", dir, index)));
+            }
+        }
+
+        if self.archive {
+            info!(logger, "fetching archive...");
+            let archive = "src/contrib/Archive";
+            let mut packages: Vec<_> = parse_links(&self.fetch(&mission, archive).await?)
+                .into_iter()
+                .filter(|link| link.ends_with('/'))
+                .collect();
+            if self.debug {
+                packages.truncate(DEBUG_PACKAGES);
+            }
+            progress.inc_length(packages.len() as u64);
+            let this = &*self;
+            let mission = &mission;
+            let archived = stream::iter(packages.into_iter().map(|package| {
+                let progress = progress.clone();
+                let logger = logger.clone();
+                async move {
+                    let dir = format!("{}/{}", archive, package.trim_end_matches('/'));
+                    progress.set_message(&dir);
+                    let result = this.fetch(mission, &format!("{}/", dir)).await;
+                    progress.inc(1);
+                    match result {
+                        Ok(content) => Ok(parse_links(&content)
+                            .into_iter()
+                            .filter(|link| link.ends_with(".tar.gz"))
+                            .map(|link| SnapshotMeta::new(format!("{}/{}", dir, link)))
+                            .collect::<Vec<_>>()),
+                        Err(err) => {
+                            warn!(logger, "failed to fetch {}: {:?}", dir, err);
+                            Err(err)
+                        }
+                    }
+                }
+            }))
+            .buffer_unordered(config.concurrent_resolve)
+            .try_collect::<Vec<_>>()
+            .await?;
+            snapshot.extend(archived.into_iter().flatten());
+        }
+
+        progress.finish_with_message("done");
+
+        Ok(snapshot)
+    }
+
+    async fn check(&mut self, mission: Mission, _config: &SnapshotConfig) -> Result<String> {
+        check_url(&mission, &self.base).await
+    }
+
+    fn info(&self) -> String {
+        format!("cran, {:?}", self)
+    }
+}
+
+#[async_trait]
+impl SourceStorage<SnapshotMeta, TransferURL> for Cran {
+    async fn get_object(&self, snapshot: &SnapshotMeta, _mission: &Mission) -> Result<TransferURL> {
+        Ok(TransferURL::new(format!("{}/{}", self.base, snapshot.key)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_packages() {
+        let packages = parse_packages(
+            "bin/windows/contrib/4.3",
+            "zip",
+            "Package: A3\n\
+             Version: 1.0.0\n\
+             Depends: R (>= 2.15.0), xtable,\n        pbapply\n\
+             MD5sum: 027EBDD8AFFCE8F0AFFF80A3A2F2A0E2\n\
+             \n\
+             Package: abc\n\
+             Version: 2.2.1\n",
+        );
+        assert_eq!(packages.len(), 2);
+        assert_eq!(packages[0].key, "bin/windows/contrib/4.3/A3_1.0.0.zip");
+        assert_eq!(
+            packages[0].checksum.as_deref(),
+            Some("027ebdd8affce8f0afff80a3a2f2a0e2")
+        );
+        assert_eq!(packages[1].key, "bin/windows/contrib/4.3/abc_2.2.1.zip");
+        assert_eq!(packages[1].checksum, None);
+    }
+}
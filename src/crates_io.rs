@@ -2,10 +2,15 @@
 //!
 //! Crates.io source first download current crates.io-index zip from GitHub,
 //! and then extract downloadable crates from crates.io-index in memory.
+//! Every version of every crate is mirrored, including yanked ones, as
+//! lock files may still pin them. In debug mode, only the first few crate
+//! files are taken. See also `crates_io_index` to mirror the index itself.
 
 use crate::common::{Mission, SnapshotConfig, SnapshotPath, TransferURL};
 use crate::error::Result;
+use crate::rate_limit::throttle;
 use crate::traits::{SnapshotStorage, SourceStorage};
+use crate::utils::check_url;
 
 use async_trait::async_trait;
 use serde::Deserialize;
@@ -13,11 +18,13 @@ use slog::info;
 use std::io::Read;
 use structopt::StructOpt;
 
+/// Number of crate files to take in debug mode
+const DEBUG_CRATES: usize = 10000;
+
 #[derive(Deserialize, Debug)]
 pub struct CratesIoPackage {
     name: String,
     vers: String,
-    cksum: String,
 }
 
 #[derive(Debug, Clone, StructOpt)]
@@ -43,9 +50,11 @@ impl SnapshotStorage<SnapshotPath> for CratesIo {
         let logger = mission.logger;
         let progress = mission.progress;
         let client = mission.client;
+        let rate_limit = mission.rate_limit;
 
         info!(logger, "fetching crates.io-index zip...");
         progress.set_message("fetching crates.io-index zip...");
+        throttle(&rate_limit).await;
         let data = client.get(&self.zip_master).send().await?.bytes().await?;
         let mut data = std::io::Cursor::new(data);
         let mut buf = vec![];
@@ -79,10 +88,10 @@ impl SnapshotStorage<SnapshotPath> for CratesIo {
                 Ok(None) => break,
                 Err(e) => return Err(e.into()),
             }
-            if self.debug && idx >= 10000 {
+            if self.debug && idx >= DEBUG_CRATES {
                 break;
             }
-            tokio::task::yield_now().await;
+            let _ = tokio::task::yield_now().await;
         }
 
         progress.finish_with_message("done");
@@ -90,6 +99,10 @@ impl SnapshotStorage<SnapshotPath> for CratesIo {
         Ok(crate::utils::snapshot_string_to_path(snapshot))
     }
 
+    async fn check(&mut self, mission: Mission, _config: &SnapshotConfig) -> Result<String> {
+        check_url(&mission, &self.zip_master).await
+    }
+
     fn info(&self) -> String {
         format!("crates.io, {:?}", self)
     }
@@ -98,6 +111,32 @@ impl SnapshotStorage<SnapshotPath> for CratesIo {
 #[async_trait]
 impl SourceStorage<SnapshotPath, TransferURL> for CratesIo {
     async fn get_object(&self, snapshot: &SnapshotPath, _mission: &Mission) -> Result<TransferURL> {
-        Ok(TransferURL(format!("{}/{}", self.crates_base, snapshot.0)))
+        Ok(TransferURL::new(format!(
+            "{}/{}",
+            self.crates_base, snapshot.0
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_plain_http_base() {
+        let source = CratesIo {
+            zip_master: "http://mirror.example.com/crates.io-index/master.zip".to_string(),
+            crates_base: "http://mirror.example.com/crates".to_string(),
+            debug: false,
+        };
+        let snapshot = SnapshotPath::new("foo/foo-0.1.0.crate".to_string());
+        let url = source
+            .get_object(&snapshot, &Mission::for_test())
+            .await
+            .unwrap();
+        assert_eq!(
+            url.0,
+            "http://mirror.example.com/crates/foo/foo-0.1.0.crate"
+        );
     }
 }
@@ -0,0 +1,142 @@
+//! crates.io index source
+//!
+//! CratesIoIndex mirrors only the crates.io index, in the layout of the
+//! sparse HTTP index, so that cargo may resolve dependencies against the
+//! mirror while crate files come from elsewhere. It's much cheaper than
+//! the full crates.io source, and may be run more frequently.
+//!
+//! Paths of index files are taken from crates.io-index zip, and files are
+//! downloaded from sparse index. Size of each file in zip is recorded in
+//! snapshot, so that index files updated on source are re-transferred.
+//!
+//! CratesIoIndex supports meta snapshot, and TransferURL source object.
+
+use crate::common::{Mission, SnapshotConfig, TransferURL};
+use crate::error::Result;
+use crate::metadata::SnapshotMeta;
+use crate::rate_limit::throttle;
+use crate::traits::{SnapshotStorage, SourceStorage};
+use crate::utils::check_url;
+
+use async_trait::async_trait;
+use slog::info;
+use structopt::StructOpt;
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct CratesIoIndex {
+    #[structopt(
+        long,
+        default_value = "https://github.com/rust-lang/crates.io-index/archive/master.zip"
+    )]
+    pub zip_master: String,
+    #[structopt(long, default_value = "https://index.crates.io")]
+    pub sparse_base: String,
+    #[structopt(long)]
+    pub debug: bool,
+}
+
+/// Key of a file in crates.io-index zip, or `None` if it isn't part of index.
+/// The top-level directory of zip is stripped, and hidden files and files
+/// at top level other than `config.json` are skipped.
+fn index_key(name: &str) -> Option<&str> {
+    let (_, key) = name.split_once('/')?;
+    if key.is_empty() || key.ends_with('/') || key.split('/').any(|part| part.starts_with('.')) {
+        return None;
+    }
+    if !key.contains('/') && key != "config.json" {
+        return None;
+    }
+    Some(key)
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotMeta> for CratesIoIndex {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        _config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotMeta>> {
+        let logger = mission.logger;
+        let progress = mission.progress;
+        let client = mission.client;
+        let rate_limit = mission.rate_limit;
+
+        info!(logger, "fetching crates.io-index zip...");
+        progress.set_message("fetching crates.io-index zip...");
+        throttle(&rate_limit).await;
+        let data = client.get(&self.zip_master).send().await?.bytes().await?;
+        let mut data = std::io::Cursor::new(data);
+        let mut snapshot = vec![];
+        info!(logger, "parsing...");
+
+        loop {
+            match zip::read::read_zipfile_from_stream(&mut data) {
+                Ok(Some(mut file)) => {
+                    if let Some(key) = index_key(file.name()) {
+                        progress.set_message(key);
+                        progress.inc(1);
+                        snapshot.push(SnapshotMeta {
+                            key: key.to_string(),
+                            size: Some(file.size()),
+                            ..Default::default()
+                        });
+                    }
+                    std::io::copy(&mut file, &mut std::io::sink())?;
+                }
+                Ok(None) => break,
+                Err(e) => return Err(e.into()),
+            }
+            if self.debug && snapshot.len() >= 10000 {
+                break;
+            }
+            let _ = tokio::task::yield_now().await;
+        }
+
+        progress.finish_with_message("done");
+
+        Ok(snapshot)
+    }
+
+    async fn check(&mut self, mission: Mission, _config: &SnapshotConfig) -> Result<String> {
+        check_url(&mission, &self.zip_master).await
+    }
+
+    fn info(&self) -> String {
+        format!("crates.io index, {:?}", self)
+    }
+}
+
+#[async_trait]
+impl SourceStorage<SnapshotMeta, TransferURL> for CratesIoIndex {
+    async fn get_object(&self, snapshot: &SnapshotMeta, _mission: &Mission) -> Result<TransferURL> {
+        Ok(TransferURL::new(format!(
+            "{}/{}",
+            self.sparse_base, snapshot.key
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_index_key() {
+        assert_eq!(
+            index_key("crates.io-index-master/se/rd/serde"),
+            Some("se/rd/serde")
+        );
+        assert_eq!(index_key("crates.io-index-master/1/a"), Some("1/a"));
+        assert_eq!(
+            index_key("crates.io-index-master/config.json"),
+            Some("config.json")
+        );
+        assert_eq!(index_key("crates.io-index-master/README.md"), None);
+        assert_eq!(
+            index_key("crates.io-index-master/.github/workflows/ci.yml"),
+            None
+        );
+        assert_eq!(index_key("crates.io-index-master/se/"), None);
+        assert_eq!(index_key("crates.io-index-master/"), None);
+    }
+}
@@ -1,7 +1,9 @@
 use crate::common::{Mission, SnapshotConfig, TransferURL};
 use crate::error::{Error, Result};
 use crate::metadata::SnapshotMeta;
+use crate::rate_limit::throttle;
 use crate::traits::{SnapshotStorage, SourceStorage};
+use crate::utils::check_url;
 
 use async_trait::async_trait;
 use futures_util::{stream, StreamExt, TryStreamExt};
@@ -27,6 +29,7 @@ impl SnapshotStorage<SnapshotMeta> for Dart {
         let logger = mission.logger;
         let progress = mission.progress;
         let client = mission.client;
+        let rate_limit = mission.rate_limit;
 
         let api_base = format!("{}/api/packages", self.base);
 
@@ -36,6 +39,7 @@ impl SnapshotStorage<SnapshotMeta> for Dart {
         let mut page: usize = 1;
 
         loop {
+            throttle(&rate_limit).await;
             let data = client.get(&next_url).send().await?.text().await?;
             let data: Value = serde_json::from_str(&data).unwrap();
             let data = data.as_object().unwrap();
@@ -76,11 +80,13 @@ impl SnapshotStorage<SnapshotMeta> for Dart {
                 let base = format!("{}/", self.base);
                 let progress = progress.clone();
                 let logger = logger.clone();
+                let rate_limit = rate_limit.clone();
 
                 let func = async move {
                     progress.set_message(&name);
+                    throttle(&rate_limit).await;
                     let package = client
-                        .get(&format!("{}/api/packages/{}", base, name))
+                        .get(format!("{}/api/packages/{}", base, name))
                         .send()
                         .await?
                         .text()
@@ -128,6 +134,10 @@ impl SnapshotStorage<SnapshotMeta> for Dart {
         Ok(snapshot)
     }
 
+    async fn check(&mut self, mission: Mission, _config: &SnapshotConfig) -> Result<String> {
+        check_url(&mission, &self.base).await
+    }
+
     fn info(&self) -> String {
         format!("dart, {:?}", self)
     }
@@ -136,6 +146,6 @@ impl SnapshotStorage<SnapshotMeta> for Dart {
 #[async_trait]
 impl SourceStorage<SnapshotMeta, TransferURL> for Dart {
     async fn get_object(&self, snapshot: &SnapshotMeta, _mission: &Mission) -> Result<TransferURL> {
-        Ok(TransferURL(format!("{}/{}", self.base, snapshot.key)))
+        Ok(TransferURL::new(format!("{}/{}", self.base, snapshot.key)))
     }
 }
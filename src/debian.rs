@@ -0,0 +1,334 @@
+//! Debian source
+//!
+//! Debian source scans an apt repository. For each suite, it fetches
+//! `Release`, and takes `Packages.gz` of selected components and
+//! architectures, which are all of them listed in `Release` by default.
+//! Every `.deb` in `Packages` is mirrored, with size and sha256 in snapshot.
+//!
+//! A package in pool may be listed by several suites, and is transferred
+//! only once. Indexes listed in `Release` are always transferred at the end,
+//! after the packages they refer to, and `Release` itself after all indexes.
+
+use crate::common::{Mission, SnapshotConfig, TransferURL};
+use crate::error::{Error, Result};
+use crate::metadata::{SnapshotMeta, SnapshotMetaFlag};
+use crate::rate_limit::throttle;
+use crate::traits::{SnapshotStorage, SourceStorage};
+use crate::utils::{check_url, parse_control_fields};
+
+use async_trait::async_trait;
+use futures_util::{stream, StreamExt, TryStreamExt};
+use slog::{info, warn};
+use std::io::Read;
+use structopt::StructOpt;
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct DebianRepo {
+    #[structopt(long, help = "Base of repository, e.g. https://deb.debian.org/debian")]
+    pub base: String,
+    #[structopt(
+        long,
+        number_of_values = 1,
+        required = true,
+        help = "Suite to mirror, e.g. bullseye, may be specified multiple times"
+    )]
+    pub suite: Vec<String>,
+    #[structopt(
+        long,
+        number_of_values = 1,
+        help = "Component to mirror, may be specified multiple times. All components in Release by default"
+    )]
+    pub component: Vec<String>,
+    #[structopt(
+        long,
+        number_of_values = 1,
+        help = "Architecture to mirror, may be specified multiple times. All architectures in Release by default"
+    )]
+    pub arch: Vec<String>,
+    #[structopt(long)]
+    pub debug: bool,
+}
+
+/// Number of packages taken from each `Packages` in debug mode
+const DEBUG_PACKAGES: usize = 100;
+
+/// Fields of `Release` used for mirroring
+#[derive(Debug, Default)]
+struct Release {
+    components: Vec<String>,
+    architectures: Vec<String>,
+    /// Index files listed in `SHA256`, as path, size and checksum
+    files: Vec<(String, u64, String)>,
+}
+
+fn parse_release(content: &str) -> Release {
+    let mut release = Release::default();
+    for (name, value) in parse_control_fields(content) {
+        match name {
+            "Components" => {
+                release.components = value.split_whitespace().map(str::to_string).collect()
+            }
+            "Architectures" => {
+                release.architectures = value.split_whitespace().map(str::to_string).collect()
+            }
+            "SHA256" => {
+                release.files = value
+                    .lines()
+                    .filter_map(|line| {
+                        let mut parts = line.split_whitespace();
+                        let checksum = parts.next()?.to_lowercase();
+                        let size = parts.next()?.parse().ok()?;
+                        let path = parts.next()?.to_string();
+                        Some((path, size, checksum))
+                    })
+                    .collect()
+            }
+            _ => {}
+        }
+    }
+    release
+}
+
+/// Parse packages in `Packages`. Packages without `Filename` are skipped.
+fn parse_packages(content: &str) -> Vec<SnapshotMeta> {
+    content
+        .split("\n\n")
+        .filter_map(|paragraph| {
+            let mut package = SnapshotMeta::default();
+            for (name, value) in parse_control_fields(paragraph) {
+                match name {
+                    "Filename" => package.key = value,
+                    "Size" => package.size = value.parse().ok(),
+                    "SHA256" => {
+                        package.checksum_method = Some("sha256".to_string());
+                        package.checksum = Some(value.to_lowercase());
+                    }
+                    _ => {}
+                }
+            }
+            if package.key.is_empty() {
+                None
+            } else {
+                Some(package)
+            }
+        })
+        .collect()
+}
+
+/// Whether an index file in `Release` belongs to selected components and
+/// architectures. Translations are taken for all selected components.
+fn is_selected_index(path: &str, components: &[String], architectures: &[String]) -> bool {
+    components.iter().any(|component| {
+        path.strip_prefix(component.as_str())
+            .and_then(|path| path.strip_prefix('/'))
+            .is_some_and(|path| {
+                path.starts_with("i18n/")
+                    || architectures
+                        .iter()
+                        .any(|arch| path.starts_with(&format!("binary-{}/", arch)))
+            })
+    })
+}
+
+impl DebianRepo {
+    async fn fetch(&self, client: &reqwest::Client, path: &str) -> Result<bytes::Bytes> {
+        let resp = client.get(format!("{}/{}", self.base, path)).send().await?;
+        if !resp.status().is_success() {
+            return Err(Error::HTTPError(resp.status()));
+        }
+        Ok(resp.bytes().await?)
+    }
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotMeta> for DebianRepo {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotMeta>> {
+        let logger = mission.logger;
+        let progress = mission.progress;
+        let client = mission.client;
+        let rate_limit = mission.rate_limit;
+
+        let mut snapshot = vec![];
+        let mut packages_index = vec![];
+
+        for suite in &self.suite {
+            info!(logger, "fetching Release of {}", suite);
+            progress.set_message(suite);
+            let dist = format!("dists/{}", suite);
+            throttle(&rate_limit).await;
+            let release = self.fetch(&client, &format!("{}/Release", dist)).await?;
+            let release = parse_release(&String::from_utf8_lossy(&release));
+
+            let components = if self.component.is_empty() {
+                release.components.clone()
+            } else {
+                self.component.clone()
+            };
+            let architectures = if self.arch.is_empty() {
+                release.architectures.clone()
+            } else {
+                self.arch.clone()
+            };
+
+            for component in &components {
+                for arch in &architectures {
+                    let path = format!("{}/binary-{}/Packages.gz", component, arch);
+                    if release.files.iter().any(|(file, _, _)| *file == path) {
+                        packages_index.push(format!("{}/{}", dist, path));
+                    } else {
+                        warn!(logger, "{} not found in Release of {}", path, suite);
+                    }
+                }
+            }
+
+            let index_flags = SnapshotMetaFlag {
+                force: true,
+                force_last: true,
+                force_final: false,
+            };
+            snapshot.extend(
+                release
+                    .files
+                    .into_iter()
+                    .filter(|(path, _, _)| is_selected_index(path, &components, &architectures))
+                    .map(|(path, size, checksum)| SnapshotMeta {
+                        key: format!("{}/{}", dist, path),
+                        size: Some(size),
+                        checksum_method: Some("sha256".to_string()),
+                        checksum: Some(checksum),
+                        flags: index_flags.clone(),
+                        ..Default::default()
+                    }),
+            );
+            for file in ["Release", "Release.gpg", "InRelease"] {
+                let mut meta = SnapshotMeta::force(format!("{}/{}", dist, file));
+                meta.flags.force_final = true;
+                snapshot.push(meta);
+            }
+        }
+
+        info!(logger, "fetching {} Packages...", packages_index.len());
+        progress.inc_length(packages_index.len() as u64);
+
+        let debug = self.debug;
+        let this = &*self;
+        let packages = stream::iter(packages_index.into_iter().map(|path| {
+            let client = client.clone();
+            let progress = progress.clone();
+            let logger = logger.clone();
+            let rate_limit = rate_limit.clone();
+            async move {
+                progress.set_message(&path);
+                throttle(&rate_limit).await;
+                let result = async {
+                    let data = this.fetch(&client, &path).await?;
+                    let mut content = String::new();
+                    flate2::read::GzDecoder::new(&data[..]).read_to_string(&mut content)?;
+                    let mut packages = parse_packages(&content);
+                    if debug {
+                        packages.truncate(DEBUG_PACKAGES);
+                    }
+                    Ok::<_, Error>(packages)
+                }
+                .await;
+                progress.inc(1);
+                if let Err(err) = &result {
+                    warn!(logger, "failed to fetch {}: {:?}", path, err);
+                }
+                result
+            }
+        }))
+        .buffer_unordered(config.concurrent_resolve)
+        .try_collect::<Vec<_>>()
+        .await?;
+
+        snapshot.extend(packages.into_iter().flatten());
+
+        progress.finish_with_message("done");
+
+        Ok(snapshot)
+    }
+
+    async fn check(&mut self, mission: Mission, _config: &SnapshotConfig) -> Result<String> {
+        check_url(&mission, &self.base).await
+    }
+
+    fn info(&self) -> String {
+        format!("debian, {:?}", self)
+    }
+}
+
+#[async_trait]
+impl SourceStorage<SnapshotMeta, TransferURL> for DebianRepo {
+    async fn get_object(&self, snapshot: &SnapshotMeta, _mission: &Mission) -> Result<TransferURL> {
+        Ok(TransferURL::new(format!("{}/{}", self.base, snapshot.key)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_release() {
+        let release = parse_release(
+            "Origin: Debian\n\
+             Suite: stable\n\
+             Architectures: all amd64 arm64\n\
+             Components: main contrib\n\
+             SHA256:\n \
+             AB01 1234 main/binary-amd64/Packages.gz\n \
+             cd02 5678 main/binary-i386/Packages.gz\n \
+             ef03 90 main/i18n/Translation-en.bz2\n \
+             0004 12 contrib/binary-amd64/Release\n",
+        );
+        assert_eq!(release.components, vec!["main", "contrib"]);
+        assert_eq!(release.architectures, vec!["all", "amd64", "arm64"]);
+        assert_eq!(release.files.len(), 4);
+        assert_eq!(
+            release.files[0],
+            (
+                "main/binary-amd64/Packages.gz".to_string(),
+                1234,
+                "ab01".to_string()
+            )
+        );
+        let selected: Vec<_> = release
+            .files
+            .iter()
+            .map(|(path, _, _)| path.as_str())
+            .filter(|path| is_selected_index(path, &["main".to_string()], &["amd64".to_string()]))
+            .collect();
+        assert_eq!(
+            selected,
+            vec![
+                "main/binary-amd64/Packages.gz",
+                "main/i18n/Translation-en.bz2"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_packages() {
+        let packages = parse_packages(
+            "Package: hello\n\
+             Version: 2.10-2\n\
+             Filename: pool/main/h/hello/hello_2.10-2_amd64.deb\n\
+             Size: 56132\n\
+             SHA256: ABCD\n\
+             Description: example package\n \
+             based on GNU hello\n\
+             \n\
+             Package: broken\n\
+             Version: 1.0\n",
+        );
+        assert_eq!(packages.len(), 1);
+        assert_eq!(packages[0].key, "pool/main/h/hello/hello_2.10-2_amd64.deb");
+        assert_eq!(packages[0].size, Some(56132));
+        assert_eq!(packages[0].checksum.as_deref(), Some("abcd"));
+    }
+}
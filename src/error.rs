@@ -30,10 +30,68 @@ pub enum Error {
     JsonDecodeError(#[from] serde_json::Error),
     #[error("Yaml Decode Error {0}")]
     YamlDecodeError(#[from] serde_yaml::Error),
+    #[error("Truncated Download {0}/{1} bytes")]
+    TruncatedDownload(u64, u64),
+    #[error("Checksum Mismatch {0}/{1}")]
+    ChecksumMismatch(String, String),
+    #[error("Interrupted")]
+    Interrupted,
+    #[error("Not Modified")]
+    NotModified,
     #[error("Datetime Parse Error {0}")]
     DatetimeParseError(#[from] chrono::ParseError),
 }
 
+impl Error {
+    /// Whether the object is permanently unavailable on source, e.g. removed
+    /// or blocked by upstream. Such errors should not be retried.
+    pub fn is_permanent(&self) -> bool {
+        matches!(
+            self,
+            Error::HTTPError(status)
+                if *status == reqwest::StatusCode::FORBIDDEN
+                    || *status == reqwest::StatusCode::NOT_FOUND
+                    || *status == reqwest::StatusCode::GONE
+        )
+    }
+
+    /// Whether the object is gone from source (404 or 410). Unlike
+    /// `is_permanent`, 403 is not included, as a wave of them from a WAF or
+    /// rate limit of upstream would otherwise drop objects from snapshot,
+    /// and delete them from target. Indexes fetched while taking snapshot
+    /// should only be skipped on these.
+    pub fn is_gone(&self) -> bool {
+        matches!(
+            self,
+            Error::HTTPError(status)
+                if *status == reqwest::StatusCode::NOT_FOUND
+                    || *status == reqwest::StatusCode::GONE
+        )
+    }
+
+    /// Whether the error is likely transient, e.g. dropped connection or
+    /// overloaded upstream, so that the request may be retried.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::TruncatedDownload(_, _)
+            | Error::ChecksumMismatch(_, _)
+            | Error::TimeoutError(_) => true,
+            Error::Reqwest(err) => {
+                err.is_timeout() || err.is_connect() || err.is_body() || err.is_request()
+            }
+            Error::HTTPError(status) => {
+                status.is_server_error() || *status == reqwest::StatusCode::TOO_MANY_REQUESTS
+            }
+            // errors of streamed body, e.g. truncation, surface as IO errors
+            Error::IoError(err) => err
+                .get_ref()
+                .and_then(|err| err.downcast_ref::<Error>())
+                .is_some_and(Error::is_retryable),
+            _ => false,
+        }
+    }
+}
+
 impl<T: std::fmt::Debug> From<rusoto_core::RusotoError<T>> for Error {
     fn from(error: rusoto_core::RusotoError<T>) -> Self {
         Error::RusotoError(format!("Rusoto Error: {:?}", error))
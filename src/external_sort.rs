@@ -0,0 +1,234 @@
+//! External sort
+//!
+//! Snapshots larger than a threshold are sorted in runs. Each run is sorted
+//! in memory and written to a temporary file as JSON lines, and runs are
+//! merged back with a k-way merge. Objects of a run are freed once it is
+//! written, so the unsorted snapshot, the sort buffer and the sorted
+//! snapshot are never in memory at the same time. Duplicated items are
+//! dropped while merging, and the first one in listing order is kept, as
+//! in stable sort.
+
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::error::Result;
+
+/// Temporary files of sorted runs, removed once merged or on error.
+struct Runs(Vec<PathBuf>);
+
+impl Drop for Runs {
+    fn drop(&mut self) {
+        for path in &self.0 {
+            std::fs::remove_file(path).ok();
+        }
+    }
+}
+
+/// Head of a run in merge. Heads are ordered by item, and then by run, so
+/// that of equal items, the one listed first is popped first.
+struct Head<'a, T, F> {
+    item: T,
+    run: usize,
+    cmp: &'a F,
+}
+
+impl<T, F: Fn(&T, &T) -> Ordering> Ord for Head<'_, T, F> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // `BinaryHeap` is a max-heap
+        (self.cmp)(&other.item, &self.item).then_with(|| other.run.cmp(&self.run))
+    }
+}
+
+impl<T, F: Fn(&T, &T) -> Ordering> PartialOrd for Head<'_, T, F> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T, F: Fn(&T, &T) -> Ordering> PartialEq for Head<'_, T, F> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl<T, F: Fn(&T, &T) -> Ordering> Eq for Head<'_, T, F> {}
+
+/// Sort `items` by `cmp` in runs of `run_len` items written to `dir`, and
+/// drop all but the first of equal items.
+pub fn sort_dedup<T, F>(items: Vec<T>, run_len: usize, dir: &Path, cmp: F) -> Result<Vec<T>>
+where
+    T: Serialize + DeserializeOwned,
+    F: Fn(&T, &T) -> Ordering,
+{
+    std::fs::create_dir_all(dir)?;
+    let mut runs = Runs(vec![]);
+    let mut total = 0;
+    let mut items = items.into_iter();
+    loop {
+        let mut run: Vec<T> = items.by_ref().take(run_len.max(1)).collect();
+        if run.is_empty() {
+            break;
+        }
+        run.sort_by(&cmp);
+        run.dedup_by(|a, b| cmp(a, b).is_eq());
+        total += run.len();
+
+        let path = dir.join(format!(
+            "mirror-clone-sort-{}-{}.jsonl",
+            std::process::id(),
+            runs.0.len()
+        ));
+        runs.0.push(path.clone());
+        let mut writer = BufWriter::new(std::fs::File::create(&path)?);
+        for item in &run {
+            serde_json::to_writer(&mut writer, item)?;
+            writer.write_all(b"\n")?;
+        }
+        writer.flush()?;
+    }
+    drop(items);
+
+    let mut readers = runs
+        .0
+        .iter()
+        .map(|path| {
+            let file = BufReader::new(std::fs::File::open(path)?);
+            Ok(serde_json::Deserializer::from_reader(file).into_iter::<T>())
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let mut heap = BinaryHeap::with_capacity(readers.len());
+    for (run, reader) in readers.iter_mut().enumerate() {
+        if let Some(item) = reader.next() {
+            heap.push(Head {
+                item: item?,
+                run,
+                cmp: &cmp,
+            });
+        }
+    }
+
+    let mut sorted: Vec<T> = Vec::with_capacity(total);
+    while let Some(Head { item, run, .. }) = heap.pop() {
+        if let Some(next) = readers[run].next() {
+            heap.push(Head {
+                item: next?,
+                run,
+                cmp: &cmp,
+            });
+        }
+        match sorted.last() {
+            Some(last) if cmp(last, &item).is_eq() => {}
+            _ => sorted.push(item),
+        }
+    }
+    Ok(sorted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sort_dedup() {
+        let dir = std::env::temp_dir().join(format!("mirror-clone-sort-{}", std::process::id()));
+        let items: Vec<(String, usize)> = ["c", "a", "d", "a", "b", "c", "e", "b", "a"]
+            .iter()
+            .enumerate()
+            .map(|(idx, key)| (key.to_string(), idx))
+            .collect();
+
+        for run_len in 1..=items.len() {
+            let sorted = sort_dedup(items.clone(), run_len, &dir, |a, b| a.0.cmp(&b.0)).unwrap();
+            // first of duplicated keys in listing is kept
+            assert_eq!(
+                sorted,
+                vec![
+                    ("a".to_string(), 1),
+                    ("b".to_string(), 4),
+                    ("c".to_string(), 0),
+                    ("d".to_string(), 2),
+                    ("e".to_string(), 6)
+                ]
+            );
+        }
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+        std::fs::remove_dir(&dir).unwrap();
+
+        let empty: Vec<(String, usize)> = vec![];
+        assert!(sort_dedup(empty, 2, &dir, |a, b| a.0.cmp(&b.0))
+            .unwrap()
+            .is_empty());
+        std::fs::remove_dir(&dir).unwrap();
+    }
+
+    fn vm_hwm_kb() -> u64 {
+        std::fs::read_to_string("/proc/self/status")
+            .unwrap()
+            .lines()
+            .find_map(|line| line.strip_prefix("VmHWM:"))
+            .and_then(|value| value.trim().trim_end_matches("kB").trim().parse().ok())
+            .unwrap()
+    }
+
+    /// Peak memory of sorting 2M objects with checksum in memory, or in runs
+    /// of `run_len` objects, in kB above that of the unsorted snapshot.
+    fn sort_peak_memory(run_len: Option<usize>) -> u64 {
+        use crate::metadata::SnapshotMeta;
+
+        const OBJECTS: usize = 2_000_000;
+
+        let snapshot: Vec<SnapshotMeta> = (0..OBJECTS)
+            .map(|idx| {
+                // keys are listed out of order
+                let package = idx.wrapping_mul(7919) % OBJECTS;
+                SnapshotMeta {
+                    key: format!("packages/{:02x}/pkg{}-1.0.tar.gz", package % 256, package),
+                    size: Some(idx as u64),
+                    checksum_method: Some(String::from("sha256")),
+                    checksum: Some(format!("{:064x}", idx)),
+                    ..Default::default()
+                }
+            })
+            .collect();
+        std::fs::write("/proc/self/clear_refs", "5").unwrap();
+        let before = vm_hwm_kb();
+        let cmp = |a: &SnapshotMeta, b: &SnapshotMeta| a.key.cmp(&b.key);
+        let sorted = match run_len {
+            Some(run_len) => {
+                let dir = std::env::temp_dir()
+                    .join(format!("mirror-clone-sort-peak-{}", std::process::id()));
+                let sorted = sort_dedup(snapshot, run_len, &dir, cmp).unwrap();
+                std::fs::remove_dir(&dir).unwrap();
+                sorted
+            }
+            None => {
+                let mut snapshot = snapshot;
+                snapshot.sort_by(cmp);
+                snapshot.dedup_by(|a, b| a.key == b.key);
+                snapshot
+            }
+        };
+        let peak = vm_hwm_kb();
+        assert_eq!(sorted.len(), OBJECTS);
+        peak - before
+    }
+
+    /// Compare peak memory of in memory and external sort. Run with `cargo
+    /// test --release test_sort_peak_memory -- --ignored --nocapture
+    /// --test-threads 1`. Linux only, as it reads VmHWM of /proc.
+    #[test]
+    #[ignore]
+    fn test_sort_peak_memory() {
+        let in_memory = sort_peak_memory(None);
+        let external = sort_peak_memory(Some(100_000));
+        println!(
+            "peak above unsorted snapshot: {} kB in memory, {} kB in runs of 100000",
+            in_memory, external
+        );
+    }
+}
@@ -4,9 +4,26 @@
 //! local file system, and transferring data to a local folder.
 //!
 //! File backend snapshots contains metadata (size + last modified).
-//! It only accepts ByteStream.
+//! Modification time of written files is taken from snapshot if present
+//! (e.g. from rsync listing), or from the source object otherwise.
+//! It only accepts ByteStream. File backend reports free space of the
+//! underlying file system, so that transfer may stop before the disk is full.
+//!
+//! If `store_headers` is set, content type and stored response headers of
+//! each object are written to a JSON sidecar at `.mirror-clone-meta/<key>.json`.
+//! The metadata prefix is never part of snapshot, so source keys of every name
+//! are mirrored. Sidecars are removed along with objects.
+//!
+//! Objects are moved from buffer into place, or copied to a temporary file
+//! and renamed if buffer is on another file system, so that readers never
+//! see partial files. Directories left empty by deletion are removed.
+//! Objects already on local disk (`TransferPath`) are hard linked, and
+//! streamed objects (`TransferStream`) are written to a temporary file.
+
+use std::collections::BTreeMap;
 
-use crate::common::{Mission, SnapshotConfig, SnapshotPath};
+use crate::checksum::checksum_reader;
+use crate::common::{Mission, SnapshotConfig, SnapshotPath, TransferPath, TransferStream};
 use crate::error::{Error, Result};
 use crate::metadata::SnapshotMeta;
 use crate::stream_pipe::ByteStream;
@@ -14,19 +31,64 @@ use crate::traits::{Key, Metadata, SnapshotStorage, TargetStorage};
 
 use async_trait::async_trait;
 use filetime::FileTime;
+use futures_util::StreamExt;
 use slog::info;
 use structopt::StructOpt;
+use tokio::io::AsyncWriteExt;
 use walkdir::WalkDir;
 
 #[derive(StructOpt, Debug)]
 pub struct FileBackend {
     #[structopt(long)]
     pub base_path: String,
+    #[structopt(long)]
+    pub store_headers: bool,
 }
 
 impl FileBackend {
     pub fn new(base_path: String) -> Self {
-        Self { base_path }
+        Self {
+            base_path,
+            store_headers: false,
+        }
+    }
+
+    /// Write content type and stored headers of object to its sidecar, if
+    /// headers are to be stored.
+    async fn write_headers(
+        &self,
+        key: &str,
+        headers: Vec<(String, String)>,
+        content_type: Option<String>,
+    ) -> Result<()> {
+        if self.store_headers {
+            let mut headers: BTreeMap<String, String> = headers.into_iter().collect();
+            if let Some(content_type) = content_type {
+                headers.insert("content-type".to_string(), content_type);
+            }
+            let sidecar: std::path::PathBuf = self.sidecar_path(key).into();
+            tokio::fs::create_dir_all(sidecar.parent().unwrap()).await?;
+            tokio::fs::write(sidecar, serde_json::to_vec(&headers)?).await?;
+        }
+        Ok(())
+    }
+
+    /// Path of header sidecar of object
+    fn sidecar_path(&self, key: &str) -> String {
+        format!("{}/{}/{}.json", self.base_path, META_PREFIX, key)
+    }
+}
+
+/// Prefix under which header sidecars are stored
+const META_PREFIX: &str = ".mirror-clone-meta";
+
+/// Remove directories from `dir` up to `root` (exclusive) that are left empty
+async fn prune_empty_dirs(root: &std::path::Path, mut dir: Option<&std::path::Path>) {
+    while let Some(current) = dir.filter(|dir| *dir != root) {
+        if tokio::fs::remove_dir(current).await.is_err() {
+            break;
+        }
+        dir = current.parent();
     }
 }
 
@@ -46,7 +108,11 @@ impl SnapshotStorage<SnapshotMeta> for FileBackend {
         tokio::task::spawn_blocking(move || {
             let mut snapshot = vec![];
             let base_path = std::path::PathBuf::from(base_path).canonicalize().unwrap();
-            for entry in WalkDir::new(&base_path) {
+            let meta_path = base_path.join(META_PREFIX);
+            for entry in WalkDir::new(&base_path)
+                .into_iter()
+                .filter_entry(|entry| entry.path() != meta_path)
+            {
                 let entry = entry.map_err(|err| {
                     Error::StorageError(format!("error while scanning file: {:?}", err))
                 })?;
@@ -88,24 +154,203 @@ impl<Snapshot: Key + Metadata> TargetStorage<Snapshot, ByteStream> for FileBacke
         byte_stream: ByteStream,
         _mission: &Mission,
     ) -> Result<()> {
+        let modified_at = snapshot.last_modified().unwrap_or(byte_stream.modified_at);
         let path = byte_stream.object.use_file();
         let target: std::path::PathBuf = format!("{}/{}", self.base_path, snapshot.key()).into();
         let parent = target.parent().unwrap();
         tokio::fs::create_dir_all(parent).await?;
-        tokio::fs::rename(&path, &target).await?;
-        if let Some(last_modified) = snapshot.last_modified() {
-            filetime::set_file_mtime(&target, FileTime::from_unix_time(last_modified as i64, 0))?;
+        if tokio::fs::rename(&path, &target).await.is_err() {
+            // buffer may be on another file system, copy it next to target
+            // first, so that target is never partially written
+            let temp: std::path::PathBuf = format!("{}.tmp", target.display()).into();
+            tokio::fs::copy(&path, &temp).await?;
+            tokio::fs::remove_file(&path).await?;
+            tokio::fs::rename(&temp, &target).await?;
         }
-        Ok(())
+        filetime::set_file_mtime(&target, FileTime::from_unix_time(modified_at as i64, 0))?;
+        self.write_headers(
+            snapshot.key(),
+            byte_stream.headers,
+            byte_stream.content_type,
+        )
+        .await
     }
 
     async fn delete_object(&self, snapshot: &Snapshot, _mission: &Mission) -> Result<()> {
         let target = format!("{}/{}", self.base_path, snapshot.key());
+        tokio::fs::remove_file(&target).await?;
+        // prune parent directories left empty
+        let base_path = std::path::Path::new(&self.base_path);
+        prune_empty_dirs(base_path, std::path::Path::new(&target).parent()).await;
+        if self.store_headers {
+            let sidecar = self.sidecar_path(snapshot.key());
+            match tokio::fs::remove_file(&sidecar).await {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+                _ => {}
+            }
+            prune_empty_dirs(base_path, std::path::Path::new(&sidecar).parent()).await;
+        }
+        Ok(())
+    }
+
+    async fn free_space(&self) -> Result<Option<u64>> {
+        Ok(Some(fs2::available_space(&self.base_path)?))
+    }
+
+    fn can_checksum(&self) -> bool {
+        true
+    }
+
+    async fn checksum_object(&self, snapshot: &Snapshot, method: &str) -> Result<Option<String>> {
+        let target = format!("{}/{}", self.base_path, snapshot.key());
+        match tokio::fs::File::open(target).await {
+            Ok(file) => checksum_reader(method, file).await,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn get_raw_object(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let target = format!("{}/{}", self.base_path, key);
+        match tokio::fs::read(target).await {
+            Ok(content) => Ok(Some(content)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn put_raw_object(&self, key: &str, content: Vec<u8>) -> Result<()> {
+        let target: std::path::PathBuf = format!("{}/{}", self.base_path, key).into();
+        let temp: std::path::PathBuf = format!("{}/{}.tmp", self.base_path, key).into();
+        tokio::fs::create_dir_all(target.parent().unwrap()).await?;
+        tokio::fs::write(&temp, content).await?;
+        tokio::fs::rename(&temp, &target).await?;
+        Ok(())
+    }
+
+    async fn delete_raw_object(&self, key: &str) -> Result<()> {
+        let target = format!("{}/{}", self.base_path, key);
         tokio::fs::remove_file(target).await?;
         Ok(())
     }
 }
 
+/// Streamed objects are written to a temporary file next to target, which
+/// is renamed into place once the stream ends without error.
+#[async_trait]
+impl<Snapshot: Key + Metadata> TargetStorage<Snapshot, TransferStream> for FileBackend {
+    async fn put_object(
+        &self,
+        snapshot: &Snapshot,
+        item: TransferStream,
+        _mission: &Mission,
+    ) -> Result<()> {
+        let modified_at = snapshot.last_modified().unwrap_or(item.modified_at);
+        let target: std::path::PathBuf = format!("{}/{}", self.base_path, snapshot.key()).into();
+        let temp: std::path::PathBuf = format!("{}.tmp", target.display()).into();
+        tokio::fs::create_dir_all(target.parent().unwrap()).await?;
+        let mut stream = item.stream;
+        let result = async {
+            let mut file = tokio::io::BufWriter::new(tokio::fs::File::create(&temp).await?);
+            while let Some(chunk) = stream.next().await {
+                file.write_all(&chunk?).await?;
+            }
+            file.flush().await?;
+            Ok::<_, Error>(())
+        };
+        if let Err(err) = result.await {
+            let _ = tokio::fs::remove_file(&temp).await;
+            return Err(err);
+        }
+        tokio::fs::rename(&temp, &target).await?;
+        filetime::set_file_mtime(&target, FileTime::from_unix_time(modified_at as i64, 0))?;
+        self.write_headers(snapshot.key(), item.headers, item.content_type)
+            .await
+    }
+
+    async fn delete_object(&self, snapshot: &Snapshot, mission: &Mission) -> Result<()> {
+        <Self as TargetStorage<Snapshot, ByteStream>>::delete_object(self, snapshot, mission).await
+    }
+
+    async fn free_space(&self) -> Result<Option<u64>> {
+        <Self as TargetStorage<Snapshot, ByteStream>>::free_space(self).await
+    }
+
+    fn can_checksum(&self) -> bool {
+        true
+    }
+
+    async fn checksum_object(&self, snapshot: &Snapshot, method: &str) -> Result<Option<String>> {
+        <Self as TargetStorage<Snapshot, ByteStream>>::checksum_object(self, snapshot, method).await
+    }
+
+    async fn get_raw_object(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        <Self as TargetStorage<Snapshot, ByteStream>>::get_raw_object(self, key).await
+    }
+
+    async fn put_raw_object(&self, key: &str, content: Vec<u8>) -> Result<()> {
+        <Self as TargetStorage<Snapshot, ByteStream>>::put_raw_object(self, key, content).await
+    }
+
+    async fn delete_raw_object(&self, key: &str) -> Result<()> {
+        <Self as TargetStorage<Snapshot, ByteStream>>::delete_raw_object(self, key).await
+    }
+}
+
+/// Objects already on local disk are hard linked into place, or copied if
+/// they are on another file system. Other operations are the same as those
+/// of fetched objects.
+#[async_trait]
+impl<Snapshot: Key + Metadata> TargetStorage<Snapshot, TransferPath> for FileBackend {
+    async fn put_object(
+        &self,
+        snapshot: &Snapshot,
+        item: TransferPath,
+        _mission: &Mission,
+    ) -> Result<()> {
+        let target: std::path::PathBuf = format!("{}/{}", self.base_path, snapshot.key()).into();
+        let temp: std::path::PathBuf = format!("{}.tmp", target.display()).into();
+        tokio::fs::create_dir_all(target.parent().unwrap()).await?;
+        let _ = tokio::fs::remove_file(&temp).await;
+        if tokio::fs::hard_link(&item.0, &temp).await.is_err() {
+            tokio::fs::copy(&item.0, &temp).await?;
+            if let Some(modified_at) = snapshot.last_modified() {
+                filetime::set_file_mtime(&temp, FileTime::from_unix_time(modified_at as i64, 0))?;
+            }
+        }
+        tokio::fs::rename(&temp, &target).await?;
+        Ok(())
+    }
+
+    async fn delete_object(&self, snapshot: &Snapshot, mission: &Mission) -> Result<()> {
+        <Self as TargetStorage<Snapshot, ByteStream>>::delete_object(self, snapshot, mission).await
+    }
+
+    async fn free_space(&self) -> Result<Option<u64>> {
+        <Self as TargetStorage<Snapshot, ByteStream>>::free_space(self).await
+    }
+
+    fn can_checksum(&self) -> bool {
+        true
+    }
+
+    async fn checksum_object(&self, snapshot: &Snapshot, method: &str) -> Result<Option<String>> {
+        <Self as TargetStorage<Snapshot, ByteStream>>::checksum_object(self, snapshot, method).await
+    }
+
+    async fn get_raw_object(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        <Self as TargetStorage<Snapshot, ByteStream>>::get_raw_object(self, key).await
+    }
+
+    async fn put_raw_object(&self, key: &str, content: Vec<u8>) -> Result<()> {
+        <Self as TargetStorage<Snapshot, ByteStream>>::put_raw_object(self, key, content).await
+    }
+
+    async fn delete_raw_object(&self, key: &str) -> Result<()> {
+        <Self as TargetStorage<Snapshot, ByteStream>>::delete_raw_object(self, key).await
+    }
+}
+
 #[async_trait]
 impl SnapshotStorage<SnapshotPath> for FileBackend {
     async fn snapshot(
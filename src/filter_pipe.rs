@@ -1,64 +1,163 @@
-//! FilterPipe excludes source items by regex pattern.
+//! PathFilter selects paths of a transfer by include and exclude globs.
 
-use async_trait::async_trait;
 use regex::RegexSet;
 
-use crate::common::{Mission, SnapshotConfig};
-use crate::error::Result;
-use crate::traits::{Key, SnapshotStorage, SourceStorage};
+use crate::error::{Error, Result};
 
-pub struct FilterPipe<Source> {
-    pub source: Source,
-    pub exclude_patterns: RegexSet,
-}
-
-impl<Source> FilterPipe<Source> {
-    pub fn new(source: Source, exclude_patterns: RegexSet) -> Self {
-        FilterPipe {
-            source,
-            exclude_patterns,
+/// Translate a glob into an anchored regex, with the syntax of `globset`.
+/// `**` matches across `/`, while `*` and `?` match within a path segment,
+/// `[...]` is a character class which may be negated with `!`, `{a,b}`
+/// matches any of its alternatives, and `\` escapes the next character. A
+/// glob without `/` matches file name only.
+fn glob_to_regex(glob: &str) -> Result<String> {
+    let invalid =
+        |reason: &str| Error::ConfigureError(format!("invalid glob {}: {}", glob, reason));
+    let mut regex = String::from(if glob.contains('/') { "^" } else { "(^|/)" });
+    let mut alternatives = 0;
+    let mut chars = glob.chars().peekable();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    regex.push_str("(.*/)?");
+                } else {
+                    regex.push_str(".*");
+                }
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            '[' => {
+                regex.push('[');
+                if chars.peek() == Some(&'!') {
+                    chars.next();
+                    regex.push('^');
+                }
+                // `]` right after `[` or `[!` is a literal
+                let mut first = true;
+                loop {
+                    match chars.next() {
+                        None => return Err(invalid("unclosed character class")),
+                        Some(']') if !first => break,
+                        Some(ch) => {
+                            if ch == '\\' || ch == '[' || ch == ']' {
+                                regex.push('\\');
+                            }
+                            regex.push(ch);
+                        }
+                    }
+                    first = false;
+                }
+                regex.push(']');
+            }
+            '{' => {
+                if alternatives != 0 {
+                    return Err(invalid("nested alternatives"));
+                }
+                alternatives += 1;
+                regex.push_str("(?:");
+            }
+            ',' if alternatives != 0 => regex.push('|'),
+            '}' if alternatives != 0 => {
+                alternatives -= 1;
+                regex.push(')');
+            }
+            '\\' => match chars.next() {
+                Some(ch) => regex.push_str(&regex::escape(&ch.to_string())),
+                None => return Err(invalid("dangling escape")),
+            },
+            ch => regex.push_str(&regex::escape(&ch.to_string())),
         }
     }
+    if alternatives != 0 {
+        return Err(invalid("unclosed alternatives"));
+    }
+    regex.push('$');
+    Ok(regex)
+}
+
+/// Include and exclude globs of a transfer. A path is selected if it matches
+/// any include glob, or there is none, and matches no exclude glob.
+pub struct PathFilter {
+    include: Option<RegexSet>,
+    exclude: RegexSet,
 }
 
-#[async_trait]
-impl<Snapshot, Source> SnapshotStorage<Snapshot> for FilterPipe<Source>
-where
-    Snapshot: Key + Send + 'static,
-    Source: SnapshotStorage<Snapshot> + Send,
-{
-    async fn snapshot(
-        &mut self,
-        mission: Mission,
-        config: &SnapshotConfig,
-    ) -> Result<Vec<Snapshot>> {
-        self.source
-            .snapshot(mission, config)
-            .await
-            .map(|snapshots| {
-                snapshots
-                    .into_iter()
-                    .filter(|snapshot| !self.exclude_patterns.is_match(snapshot.key()))
-                    .collect()
-            })
+impl PathFilter {
+    pub fn new(include: &[String], exclude: &[String]) -> Result<Self> {
+        let globs = |globs: &[String]| {
+            let regexes = globs
+                .iter()
+                .map(|glob| glob_to_regex(glob))
+                .collect::<Result<Vec<_>>>()?;
+            RegexSet::new(regexes)
+                .map_err(|err| Error::ConfigureError(format!("invalid glob: {:?}", err)))
+        };
+        Ok(Self {
+            include: if include.is_empty() {
+                None
+            } else {
+                Some(globs(include)?)
+            },
+            exclude: globs(exclude)?,
+        })
     }
 
-    fn info(&self) -> String {
-        format!(
-            "Filter by exclude patterns {:?} <{}>",
-            self.exclude_patterns,
-            self.source.info()
-        )
+    pub fn is_empty(&self) -> bool {
+        self.include.is_none() && self.exclude.is_empty()
+    }
+
+    pub fn included(&self, key: &str) -> bool {
+        self.include
+            .as_ref()
+            .is_none_or(|include| include.is_match(key))
+    }
+
+    pub fn excluded(&self, key: &str) -> bool {
+        self.exclude.is_match(key)
+    }
+
+    pub fn matches(&self, key: &str) -> bool {
+        self.included(key) && !self.excluded(key)
     }
 }
 
-#[async_trait]
-impl<Snapshot, Source, SourceItem> SourceStorage<Snapshot, SourceItem> for FilterPipe<Source>
-where
-    Snapshot: Send + Sync + 'static,
-    Source: SourceStorage<Snapshot, SourceItem>,
-{
-    async fn get_object(&self, snapshot: &Snapshot, mission: &Mission) -> Result<SourceItem> {
-        self.source.get_object(snapshot, mission).await
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_path_filter() {
+        let filter = PathFilter::new(
+            &["numpy*".to_string(), "simple/**".to_string()],
+            &["*.whl".to_string(), "packages/[!a-m]?/**".to_string()],
+        )
+        .unwrap();
+        assert!(filter.matches("packages/ab/numpy-1.0.tar.gz"));
+        assert!(!filter.matches("packages/ab/numpy-1.0-cp39.whl"));
+        assert!(!filter.matches("packages/zz/numpy-1.0.tar.gz"));
+        assert!(!filter.matches("packages/ab/scipy-1.0.tar.gz"));
+        assert!(filter.matches("simple/numpy/index.html"));
+        assert!(!filter.matches("simple"));
+        assert!(PathFilter::new(&[], &[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_glob_syntax() {
+        let filter =
+            PathFilter::new(&["*.{tar.gz,zip}".to_string(), "[]x]\\*".to_string()], &[]).unwrap();
+        assert!(filter.matches("dist/foo-1.0.tar.gz"));
+        assert!(filter.matches("foo-1.0.zip"));
+        assert!(!filter.matches("foo-1.0.whl"));
+        assert!(filter.matches("a/]*"));
+        assert!(!filter.matches("a/]x"));
+        for glob in ["[a-z", "{a,b", "{a,{b}}", "a\\"] {
+            assert!(
+                PathFilter::new(&[glob.to_string()], &[]).is_err(),
+                "{}",
+                glob
+            );
+        }
     }
 }
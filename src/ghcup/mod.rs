@@ -22,7 +22,6 @@ use structopt::StructOpt;
 use crate::ghcup::packages::GhcupPackages;
 use crate::ghcup::script::GhcupScript;
 use crate::ghcup::yaml::GhcupYaml;
-use crate::utils::CommaSplitVecString;
 
 mod packages;
 mod parser;
@@ -44,11 +43,6 @@ pub struct Ghcup {
     pub retain_stack_versions: usize,
     #[structopt(long, help = "Hls versions to retain", default_value = "3")]
     pub retain_hls_versions: usize,
-    #[structopt(
-        long,
-        default_value = "ghcup-0.0.4.yaml,ghcup-0.0.5.yaml,ghcup-0.0.6.yaml"
-    )]
-    pub additional_yaml: CommaSplitVecString,
 }
 
 #[derive(Debug, Clone, StructOpt)]
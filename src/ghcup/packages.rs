@@ -7,7 +7,7 @@ use crate::metadata::SnapshotMeta;
 use crate::traits::{SnapshotStorage, SourceStorage};
 
 use super::parser::{GhcupYamlParser, EXPECTED_CONFIG_VERSION};
-use super::utils::{fetch_last_tag, filter_map_file_objs, list_files};
+use super::utils::{check_repo, fetch_last_tag, filter_map_file_objs, list_files};
 use super::GhcupRepoConfig;
 
 #[derive(Debug, Clone)]
@@ -74,6 +74,10 @@ impl SnapshotStorage<SnapshotMeta> for GhcupPackages {
         Ok(crate::utils::snapshot_string_to_meta(fetch_uris))
     }
 
+    async fn check(&mut self, mission: Mission, _config: &SnapshotConfig) -> Result<String> {
+        check_repo(&mission, &self.ghcup_repo_config).await
+    }
+
     fn info(&self) -> String {
         format!("ghcup_packages, {:?}", self)
     }
@@ -82,7 +86,7 @@ impl SnapshotStorage<SnapshotMeta> for GhcupPackages {
 #[async_trait]
 impl SourceStorage<SnapshotMeta, TransferURL> for GhcupPackages {
     async fn get_object(&self, snapshot: &SnapshotMeta, _mission: &Mission) -> Result<TransferURL> {
-        Ok(TransferURL(format!(
+        Ok(TransferURL::new(format!(
             "{}/{}",
             "https://downloads.haskell.org", snapshot.key
         )))
@@ -42,6 +42,6 @@ impl SourceStorage<SnapshotMeta, TransferURL> for GhcupScript {
         _snapshot: &SnapshotMeta,
         _mission: &Mission,
     ) -> Result<TransferURL> {
-        Ok(TransferURL(self.script_url.clone()))
+        Ok(TransferURL::new(self.script_url.clone()))
     }
 }
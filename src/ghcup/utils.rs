@@ -7,7 +7,9 @@ use lazy_static::lazy_static;
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
 
+use crate::common::Mission;
 use crate::error::{Error, Result};
+use crate::utils::check_url;
 
 use super::GhcupRepoConfig;
 
@@ -110,14 +112,24 @@ impl ObjectInfo {
     }
 }
 
+/// Check that ghcup repo is reachable on gitlab.
+pub async fn check_repo(mission: &Mission, config: &GhcupRepoConfig) -> Result<String> {
+    let url = format!(
+        "https://{}/api/v4/projects/{}",
+        config.host,
+        urlencoding::encode(&config.repo)
+    );
+    check_url(mission, &url).await
+}
+
 pub async fn fetch_last_tag(client: &Client, config: &GhcupRepoConfig) -> Result<String> {
     let req = client.get(format!(
         "https://{}/api/v4/projects/{}/repository/tags",
         config.host,
-        urlencoding::encode(&*config.repo)
+        urlencoding::encode(&config.repo)
     ));
 
-    let tags: Vec<TagInfo> = serde_json::from_slice(&*req.send().await?.bytes().await?)
+    let tags: Vec<TagInfo> = serde_json::from_slice(&req.send().await?.bytes().await?)
         .map_err(Error::JsonDecodeError)?;
 
     Ok(tags
@@ -138,11 +150,11 @@ pub async fn list_files(
             .get(format!(
                 "https://{}/api/v4/projects/{}/repository/tree",
                 config.host,
-                urlencoding::encode(&*config.repo)
+                urlencoding::encode(&config.repo)
             ))
             .query(&[("per_page", config.pagination), ("page", page)])
             .query(&[("ref", commit.clone())]);
-        let res: Vec<FileInfo> = serde_json::from_slice(&*req.send().await?.bytes().await?)
+        let res: Vec<FileInfo> = serde_json::from_slice(&req.send().await?.bytes().await?)
             .map_err(Error::JsonDecodeError)?;
 
         if !res.is_empty() {
@@ -158,7 +170,7 @@ pub fn filter_map_file_objs(
     files: impl IntoIterator<Item = FileInfo>,
 ) -> impl Iterator<Item = ObjectInfo> {
     files.into_iter().filter_map(|f| {
-        YAML_CONFIG_PATTERN.captures(&*f.name).and_then(|c| {
+        YAML_CONFIG_PATTERN.captures(&f.name).and_then(|c| {
             c.name("ver").and_then(|m| {
                 Some(ObjectInfo {
                     id: f.id.clone(),
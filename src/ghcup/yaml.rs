@@ -9,7 +9,7 @@ use crate::error::Result;
 use crate::metadata::SnapshotMeta;
 use crate::traits::{Key, SnapshotStorage, SourceStorage};
 
-use super::utils::{fetch_last_tag, filter_map_file_objs, list_files};
+use super::utils::{check_repo, fetch_last_tag, filter_map_file_objs, list_files};
 use super::GhcupRepoConfig;
 
 #[derive(Debug, Clone)]
@@ -67,6 +67,10 @@ impl SnapshotStorage<SnapshotMeta> for GhcupYaml {
             .collect())
     }
 
+    async fn check(&mut self, mission: Mission, _config: &SnapshotConfig) -> Result<String> {
+        check_repo(&mission, &self.ghcup_repo_config).await
+    }
+
     fn info(&self) -> String {
         format!("ghcup_config, {:?}", self)
     }
@@ -75,7 +79,7 @@ impl SnapshotStorage<SnapshotMeta> for GhcupYaml {
 #[async_trait]
 impl SourceStorage<SnapshotMeta, TransferURL> for GhcupYaml {
     async fn get_object(&self, snapshot: &SnapshotMeta, _mission: &Mission) -> Result<TransferURL> {
-        Ok(TransferURL(
+        Ok(TransferURL::new(
             self.snapmeta_to_remote
                 .get(snapshot.key())
                 .unwrap() // SAFETY `snapshot()` is called in prior to `get_object()`, thus the key must be present
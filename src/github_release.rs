@@ -6,8 +6,10 @@
 use crate::common::{Mission, SnapshotConfig, TransferURL};
 use crate::error::Result;
 use crate::metadata::SnapshotMeta;
+use crate::rate_limit::throttle;
 use crate::timeout::{TryTimeoutExt, TryTimeoutFutureExt};
 use crate::traits::{SnapshotStorage, SourceStorage};
+use crate::utils::check_url;
 
 use async_trait::async_trait;
 use chrono::{DateTime, Utc};
@@ -18,12 +20,7 @@ use structopt::StructOpt;
 
 #[derive(Deserialize, Debug)]
 pub struct GitHubReleaseAsset {
-    url: String,
-    id: u64,
-    name: String,
-    content_type: String,
     size: u64,
-    created_at: DateTime<Utc>,
     updated_at: DateTime<Utc>,
     browser_download_url: String,
 }
@@ -31,7 +28,6 @@ pub struct GitHubReleaseAsset {
 #[derive(Deserialize, Debug)]
 pub struct GitHubReleaseItem {
     tag_name: String,
-    name: String,
     assets: Vec<GitHubReleaseAsset>,
 }
 
@@ -62,10 +58,12 @@ impl SnapshotStorage<SnapshotMeta> for GitHubRelease {
         let logger = mission.logger;
         let progress = mission.progress;
         let client = mission.client;
+        let rate_limit = mission.rate_limit;
 
         info!(logger, "fetching GitHub json...");
+        throttle(&rate_limit).await;
         let data = client
-            .get(&format!(
+            .get(format!(
                 "https://api.github.com/repos/{}/releases",
                 self.repo
             ))
@@ -106,6 +104,14 @@ impl SnapshotStorage<SnapshotMeta> for GitHubRelease {
         Ok(snapshot)
     }
 
+    async fn check(&mut self, mission: Mission, _config: &SnapshotConfig) -> Result<String> {
+        check_url(
+            &mission,
+            &format!("https://api.github.com/repos/{}", self.repo),
+        )
+        .await
+    }
+
     fn info(&self) -> String {
         format!("github releases, {:?}", self)
     }
@@ -114,7 +120,7 @@ impl SnapshotStorage<SnapshotMeta> for GitHubRelease {
 #[async_trait]
 impl SourceStorage<SnapshotMeta, TransferURL> for GitHubRelease {
     async fn get_object(&self, snapshot: &SnapshotMeta, _mission: &Mission) -> Result<TransferURL> {
-        Ok(TransferURL(format!(
+        Ok(TransferURL::new(format!(
             "https://github.com/{}/{}",
             self.repo, snapshot.key
         )))
@@ -0,0 +1,264 @@
+//! Go module proxy source
+//!
+//! GoProxy mirrors a GOPROXY. Modules are read from a module list file, one
+//! per line, or taken from module index of proxy (e.g. index.golang.org)
+//! page by page. Versions of each module are listed with `@v/list`, and for
+//! each version `.info`, `.mod` and `.zip` are taken. `@v/list` itself is
+//! always transferred, so that new versions show up on mirror. Modules whose
+//! `@v/list` is gone are skipped, while other failures abort the snapshot.
+//!
+//! Keys are paths on proxy, in which uppercase letters of module paths and
+//! versions are case-encoded as `!` followed by the lowercase letter, e.g.
+//! `github.com/!azure/azure-sdk-for-go/@v/v1.0.0.zip`.
+//!
+//! GoProxy supports path snapshot, and TransferURL source object.
+
+use std::collections::BTreeSet;
+
+use crate::common::{Mission, SnapshotConfig, SnapshotPath, TransferURL};
+use crate::error::{Error, Result};
+use crate::rate_limit::throttle;
+use crate::traits::{SnapshotStorage, SourceStorage};
+use crate::utils::check_url;
+
+use async_trait::async_trait;
+use futures_util::{stream, StreamExt, TryStreamExt};
+use serde::Deserialize;
+use slog::{info, warn};
+use structopt::StructOpt;
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct GoProxy {
+    #[structopt(long, default_value = "https://proxy.golang.org")]
+    pub proxy_base: String,
+    #[structopt(
+        long,
+        help = "File of module paths to mirror, one per line. Modules are taken from module index if not given"
+    )]
+    pub module_list: Option<String>,
+    #[structopt(long, default_value = "https://index.golang.org/index")]
+    pub index_base: String,
+    #[structopt(
+        long,
+        help = "Only take modules from index since this time, in RFC 3339"
+    )]
+    pub index_since: Option<String>,
+    #[structopt(long)]
+    pub debug: bool,
+}
+
+/// Number of modules selected in debug mode
+const DEBUG_MODULES: usize = 10;
+/// Entries of module index in one page, as served by index.golang.org
+const INDEX_PAGE: usize = 2000;
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct IndexEntry {
+    path: String,
+    timestamp: String,
+}
+
+/// Case-encode module path or version for proxy, as uppercase letters may
+/// not be distinguished by file systems.
+fn encode_path(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len());
+    for ch in path.chars() {
+        if ch.is_ascii_uppercase() {
+            encoded.push('!');
+            encoded.push(ch.to_ascii_lowercase());
+        } else {
+            encoded.push(ch);
+        }
+    }
+    encoded
+}
+
+/// Parse module list, skipping empty lines and comments.
+fn parse_module_list(content: &str) -> Vec<String> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(ToString::to_string)
+        .collect()
+}
+
+/// Keys of all files of module versions in `@v/list`.
+fn version_keys(module: &str, list: &str) -> Vec<SnapshotPath> {
+    let module = encode_path(module);
+    let mut keys = vec![SnapshotPath::force(format!("{}/@v/list", module))];
+    for version in list.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        let version = encode_path(version);
+        for ext in ["info", "mod", "zip"] {
+            keys.push(SnapshotPath::new(format!(
+                "{}/@v/{}.{}",
+                module, version, ext
+            )));
+        }
+    }
+    keys
+}
+
+impl GoProxy {
+    /// Module paths in module index, fetched page by page from the last
+    /// timestamp of previous page.
+    async fn index_modules(&self, mission: &Mission) -> Result<Vec<String>> {
+        let mut modules = BTreeSet::new();
+        let mut since = self.index_since.clone();
+        loop {
+            let mut request = mission.client.get(&self.index_base);
+            if let Some(since) = &since {
+                request = request.query(&[("since", since)]);
+            }
+            throttle(&mission.rate_limit).await;
+            let content = request.send().await?.error_for_status()?.text().await?;
+            let entries = content
+                .lines()
+                .filter(|line| !line.trim().is_empty())
+                .map(serde_json::from_str)
+                .collect::<std::result::Result<Vec<IndexEntry>, _>>()?;
+            let count = entries.len();
+            let last = entries.last().map(|entry| entry.timestamp.clone());
+            modules.extend(entries.into_iter().map(|entry| entry.path));
+            mission.progress.set_message(&format!(
+                "fetching module index, total modules = {}",
+                modules.len()
+            ));
+            if count < INDEX_PAGE || last == since || self.debug {
+                break;
+            }
+            since = last;
+        }
+        Ok(modules.into_iter().collect())
+    }
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotPath> for GoProxy {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotPath>> {
+        let logger = mission.logger.clone();
+        let progress = mission.progress.clone();
+
+        let mut modules = match &self.module_list {
+            Some(module_list) => parse_module_list(&tokio::fs::read_to_string(module_list).await?),
+            None => {
+                info!(logger, "fetching module index...");
+                self.index_modules(&mission).await?
+            }
+        };
+        if self.debug {
+            modules.truncate(DEBUG_MODULES);
+        }
+
+        info!(logger, "{} modules, fetching versions...", modules.len());
+        progress.set_length(modules.len() as u64);
+
+        let proxy_base = &self.proxy_base;
+        let mission = &mission;
+        let snapshots: Vec<Vec<SnapshotPath>> =
+            stream::iter(modules.into_iter().map(|module| async move {
+                let func = async {
+                    throttle(&mission.rate_limit).await;
+                    let response = mission
+                        .client
+                        .get(format!("{}/{}/@v/list", proxy_base, encode_path(&module)))
+                        .send()
+                        .await?;
+                    if !response.status().is_success() {
+                        return Err(Error::HTTPError(response.status()));
+                    }
+                    let list = response.text().await?;
+                    Ok::<_, Error>(version_keys(&module, &list))
+                };
+                let result = func.await;
+                mission.progress.inc(1);
+                match result {
+                    Ok(keys) => Ok(keys),
+                    Err(err) if err.is_gone() => {
+                        warn!(
+                            mission.logger,
+                            "versions of {} unavailable, skipped", module;
+                            "path" => &module,
+                            "error" => format!("{:?}", err)
+                        );
+                        Ok(vec![])
+                    }
+                    Err(err) => {
+                        warn!(
+                            mission.logger,
+                            "failed to fetch versions of {}, aborting snapshot", module;
+                            "path" => &module,
+                            "error" => format!("{:?}", err)
+                        );
+                        Err(err)
+                    }
+                }
+            }))
+            .buffer_unordered(config.concurrent_resolve)
+            .try_collect()
+            .await?;
+
+        progress.finish_with_message("done");
+
+        Ok(snapshots.into_iter().flatten().collect())
+    }
+
+    async fn check(&mut self, mission: Mission, _config: &SnapshotConfig) -> Result<String> {
+        check_url(&mission, &self.proxy_base).await
+    }
+
+    fn info(&self) -> String {
+        format!("go proxy, {:?}", self)
+    }
+}
+
+#[async_trait]
+impl SourceStorage<SnapshotPath, TransferURL> for GoProxy {
+    async fn get_object(&self, snapshot: &SnapshotPath, _mission: &Mission) -> Result<TransferURL> {
+        Ok(TransferURL::new(format!(
+            "{}/{}",
+            self.proxy_base, snapshot.0
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_path() {
+        assert_eq!(
+            encode_path("github.com/Azure/azure-sdk-for-go"),
+            "github.com/!azure/azure-sdk-for-go"
+        );
+        assert_eq!(encode_path("v1.0.0-RC1"), "v1.0.0-!r!c1");
+        assert_eq!(encode_path("golang.org/x/mod"), "golang.org/x/mod");
+
+        let keys: Vec<_> = version_keys("github.com/BurntSushi/toml", "v0.3.1\nv1.0.0\n")
+            .into_iter()
+            .map(|key| key.0)
+            .collect();
+        assert_eq!(
+            keys,
+            vec![
+                "github.com/!burnt!sushi/toml/@v/list",
+                "github.com/!burnt!sushi/toml/@v/v0.3.1.info",
+                "github.com/!burnt!sushi/toml/@v/v0.3.1.mod",
+                "github.com/!burnt!sushi/toml/@v/v0.3.1.zip",
+                "github.com/!burnt!sushi/toml/@v/v1.0.0.info",
+                "github.com/!burnt!sushi/toml/@v/v1.0.0.mod",
+                "github.com/!burnt!sushi/toml/@v/v1.0.0.zip",
+            ]
+        );
+        assert_eq!(
+            parse_module_list("# comment\ngolang.org/x/mod\n\n"),
+            vec!["golang.org/x/mod"]
+        );
+    }
+}
@@ -1,8 +1,10 @@
 use crate::common::{Mission, SnapshotConfig, TransferURL};
 use crate::error::Result;
 use crate::metadata::SnapshotMeta;
+use crate::rate_limit::throttle;
 use crate::timeout::{TryTimeoutExt, TryTimeoutFutureExt};
 use crate::traits::{SnapshotStorage, SourceStorage};
+use crate::utils::check_url;
 use async_trait::async_trait;
 use serde_json::Value;
 use slog::info;
@@ -27,8 +29,10 @@ impl SnapshotStorage<SnapshotMeta> for Gradle {
         let logger = mission.logger;
         let progress = mission.progress;
         let client = mission.client;
+        let rate_limit = mission.rate_limit;
 
         info!(logger, "fetching API json...");
+        throttle(&rate_limit).await;
         let data = client
             .get(&self.api_base)
             .send()
@@ -80,6 +84,10 @@ impl SnapshotStorage<SnapshotMeta> for Gradle {
         Ok(snapshot)
     }
 
+    async fn check(&mut self, mission: Mission, _config: &SnapshotConfig) -> Result<String> {
+        check_url(&mission, &self.api_base).await
+    }
+
     fn info(&self) -> String {
         format!("gradle, {:?}", self)
     }
@@ -88,7 +96,7 @@ impl SnapshotStorage<SnapshotMeta> for Gradle {
 #[async_trait]
 impl SourceStorage<SnapshotMeta, TransferURL> for Gradle {
     async fn get_object(&self, snapshot: &SnapshotMeta, _mission: &Mission) -> Result<TransferURL> {
-        Ok(TransferURL(format!(
+        Ok(TransferURL::new(format!(
             "{}/{}",
             self.distribution_base, snapshot.key
         )))
@@ -1,15 +1,19 @@
 //! Homebrew source
 //!
 //! Homebrew source will use brew.sh API to fetch all available bottles.
-//! It will generate a list of URLs.
+//! It will generate a list of URLs. Bottles may be limited to some
+//! platforms. Bottles are OCI blobs on ghcr.io, which are resolved to their
+//! download URL with an anonymous bearer token when transferring.
 //!
 //! Reference: https://github.com/ustclug/ustcmirror-images/blob/master/homebrew-bottles/bottles-json/src/main.rs
 //! MIT License, Copyright (c) 2017 Jian Zeng
 
 use crate::common::{Mission, SnapshotConfig, SnapshotPath, TransferURL};
 use crate::error::{Error, Result};
+use crate::rate_limit::throttle;
 use crate::timeout::{TryTimeoutExt, TryTimeoutFutureExt};
 use crate::traits::{SnapshotStorage, SourceStorage};
+use crate::utils::check_url;
 
 use std::collections::{BTreeMap, HashMap};
 use std::time::Duration;
@@ -23,10 +27,23 @@ use structopt::StructOpt;
 pub struct HomebrewConfig {
     #[structopt(long, default_value = "https://formulae.brew.sh/api/formula.json")]
     pub api_base: String,
-    #[structopt(long, default_value = "all")]
+    #[structopt(
+        long,
+        help = "Platforms of bottles, comma separated, e.g. arm64_sonoma,ventura",
+        default_value = "all"
+    )]
     pub arch: String,
 }
 
+impl HomebrewConfig {
+    /// Whether bottles of platform should be mirrored
+    fn match_platform(&self, platform: &str) -> bool {
+        self.arch.is_empty()
+            || self.arch == "all"
+            || self.arch.split(',').any(|arch| arch.trim() == platform)
+    }
+}
+
 pub struct Homebrew {
     pub config: HomebrewConfig,
     url_mapping: BTreeMap<String, String>,
@@ -86,10 +103,12 @@ impl SnapshotStorage<SnapshotPath> for Homebrew {
         let logger = mission.logger;
         let progress = mission.progress;
         let client = mission.client;
+        let rate_limit = mission.rate_limit;
         let gen_map = crate::utils::generate_s3_url_reverse_encode_map();
 
         info!(logger, "fetching API json...");
         progress.set_message("fetching API json...");
+        throttle(&rate_limit).await;
         let data = client
             .get(&self.config.api_base)
             .send()
@@ -111,10 +130,7 @@ impl SnapshotStorage<SnapshotPath> for Homebrew {
                 if let Some(versions_stable) = f.versions.stable {
                     if let Some(bs) = f.bottle.stable {
                         for (platform, v) in bs.files {
-                            if self.config.arch.is_empty()
-                                || self.config.arch == "all"
-                                || platform == self.config.arch
-                            {
+                            if self.config.match_platform(&platform) {
                                 let key = format!(
                                     "{name}-{version}{revision}.{platform}.bottle{rebuild}.tar.gz",
                                     name = f.name,
@@ -146,9 +162,18 @@ impl SnapshotStorage<SnapshotPath> for Homebrew {
         Ok(snapshots)
     }
 
+    async fn check(&mut self, mission: Mission, _config: &SnapshotConfig) -> Result<String> {
+        check_url(&mission, &self.config.api_base).await
+    }
+
     fn info(&self) -> String {
         format!("homebrew, {:?}", self.config)
     }
+
+    // URL of bottles is taken while taking snapshot
+    fn cacheable(&self) -> bool {
+        false
+    }
 }
 
 #[async_trait]
@@ -157,7 +182,8 @@ impl SourceStorage<SnapshotPath, TransferURL> for Homebrew {
         let url = self
             .url_mapping
             .get(&snapshot.0)
-            .expect("no URL for bottle");
+            .ok_or_else(|| Error::ProcessError(format!("no URL for bottle {}", snapshot.0)))?;
+        mission.throttle().await;
         let resp = mission
             .client
             .get(url)
@@ -171,6 +197,24 @@ impl SourceStorage<SnapshotPath, TransferURL> for Homebrew {
         if !resp.status().is_success() {
             return Err(Error::HTTPError(resp.status()));
         }
-        Ok(TransferURL(resp.url().as_str().to_string()))
+        Ok(TransferURL::new(resp.url().as_str().to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_match_platform() {
+        let mut config = HomebrewConfig {
+            api_base: String::new(),
+            arch: "all".to_string(),
+        };
+        assert!(config.match_platform("ventura"));
+        config.arch = "arm64_sonoma, ventura".to_string();
+        assert!(config.match_platform("ventura"));
+        assert!(config.match_platform("arm64_sonoma"));
+        assert!(!config.match_platform("x86_64_linux"));
     }
 }
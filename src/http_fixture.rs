@@ -0,0 +1,251 @@
+//! HTTP fixtures
+//!
+//! `FixtureServer` is a local HTTP server for deterministic tests of sources.
+//! Point the base URL of a source at the server, and requests are served
+//! from fixtures on disk, keyed by path and query of URL. In record mode,
+//! requests are forwarded to upstream, and responses are saved as fixtures
+//! before being served, so that fixtures may be captured from a live run.
+//!
+//! Each fixture is a JSON file with status and headers, and a body file next
+//! to it. This is only built for tests with the `http-fixture` feature.
+
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server, StatusCode};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::oneshot;
+
+use crate::error::{Error, Result};
+
+/// Response headers saved in fixtures
+const FIXTURE_HEADERS: &[&str] = &["content-type", "last-modified"];
+
+#[derive(Debug, Clone)]
+pub enum Mode {
+    /// Forward requests to upstream base, and save responses
+    Record { upstream: String },
+    /// Serve saved responses, and 404 for unknown requests
+    Replay,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Fixture {
+    url: String,
+    status: u16,
+    headers: BTreeMap<String, String>,
+}
+
+fn fixture_path(dir: &Path, url: &str) -> PathBuf {
+    let mut hasher = Sha256::new();
+    hasher.update(url.as_bytes());
+    dir.join(format!("{:x}", hasher.finalize()))
+}
+
+/// Save a response as fixture. `url` is path and query of the request.
+pub fn save(
+    dir: &Path,
+    url: &str,
+    status: u16,
+    headers: BTreeMap<String, String>,
+    body: &[u8],
+) -> Result<()> {
+    let path = fixture_path(dir, url);
+    let fixture = Fixture {
+        url: url.to_string(),
+        status,
+        headers,
+    };
+    std::fs::create_dir_all(dir)?;
+    std::fs::write(
+        path.with_extension("json"),
+        serde_json::to_vec_pretty(&fixture)?,
+    )?;
+    std::fs::write(path.with_extension("body"), body)?;
+    Ok(())
+}
+
+fn load(dir: &Path, url: &str) -> Result<Option<(Fixture, Vec<u8>)>> {
+    let path = fixture_path(dir, url);
+    let fixture = match std::fs::read(path.with_extension("json")) {
+        Ok(fixture) => serde_json::from_slice(&fixture)?,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let body = std::fs::read(path.with_extension("body"))?;
+    Ok(Some((fixture, body)))
+}
+
+async fn record(
+    client: &reqwest::Client,
+    dir: &Path,
+    upstream: &str,
+    url: &str,
+) -> Result<(Fixture, Vec<u8>)> {
+    let response = client.get(format!("{}{}", upstream, url)).send().await?;
+    let status = response.status().as_u16();
+    let headers = FIXTURE_HEADERS
+        .iter()
+        .filter_map(|name| {
+            let value = response.headers().get(*name)?.to_str().ok()?;
+            Some((name.to_string(), value.to_string()))
+        })
+        .collect();
+    let body = response.bytes().await?;
+    save(dir, url, status, headers, &body)?;
+    load(dir, url)?.ok_or(Error::NoneError)
+}
+
+async fn serve(
+    request: Request<Body>,
+    dir: Arc<PathBuf>,
+    mode: Arc<Mode>,
+    client: reqwest::Client,
+) -> Result<Response<Body>> {
+    let url = request
+        .uri()
+        .path_and_query()
+        .map(|x| x.as_str().to_string())
+        .unwrap_or_else(|| String::from("/"));
+    let fixture = match &*mode {
+        Mode::Record { upstream } => Some(record(&client, &dir, upstream, &url).await?),
+        Mode::Replay => load(&dir, &url)?,
+    };
+    let (fixture, body) = match fixture {
+        Some(fixture) => fixture,
+        None => {
+            let mut response = Response::new(Body::from(format!("no fixture for {}", url)));
+            *response.status_mut() = StatusCode::NOT_FOUND;
+            return Ok(response);
+        }
+    };
+    let mut response = Response::builder().status(fixture.status);
+    for (name, value) in &fixture.headers {
+        response = response.header(name.as_str(), value.as_str());
+    }
+    response
+        .body(Body::from(body))
+        .map_err(|err| Error::ProcessError(format!("invalid fixture {}: {:?}", url, err)))
+}
+
+pub struct FixtureServer {
+    addr: SocketAddr,
+    shutdown: Option<oneshot::Sender<()>>,
+}
+
+impl FixtureServer {
+    /// Start serving fixtures in `dir` on a random local port.
+    pub fn start(dir: PathBuf, mode: Mode) -> Result<Self> {
+        let dir = Arc::new(dir);
+        let mode = Arc::new(mode);
+        let client = reqwest::Client::new();
+        let make_service = make_service_fn(move |_| {
+            let dir = dir.clone();
+            let mode = mode.clone();
+            let client = client.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    let dir = dir.clone();
+                    let mode = mode.clone();
+                    let client = client.clone();
+                    async move {
+                        Ok::<_, Infallible>(serve(request, dir, mode, client).await.unwrap_or_else(
+                            |err| {
+                                let mut response = Response::new(Body::from(format!("{:?}", err)));
+                                *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                                response
+                            },
+                        ))
+                    }
+                }))
+            }
+        });
+        let server = Server::try_bind(&SocketAddr::from(([127, 0, 0, 1], 0)))
+            .map_err(|err| Error::ProcessError(format!("failed to bind: {:?}", err)))?
+            .serve(make_service);
+        let addr = server.local_addr();
+        let (shutdown, rx) = oneshot::channel();
+        tokio::spawn(server.with_graceful_shutdown(async {
+            rx.await.ok();
+        }));
+        Ok(Self {
+            addr,
+            shutdown: Some(shutdown),
+        })
+    }
+
+    /// Base URL of the server, e.g. `http://127.0.0.1:1234`.
+    pub fn base(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+}
+
+impl Drop for FixtureServer {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            shutdown.send(()).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_replay() {
+        let dir = std::env::temp_dir().join(format!("mirror-clone-fixture-{}", std::process::id()));
+        let mut headers = BTreeMap::new();
+        headers.insert("content-type".to_string(), "text/plain".to_string());
+        save(&dir, "/foo?bar=1", 200, headers, b"hello").unwrap();
+
+        let server = FixtureServer::start(dir.clone(), Mode::Replay).unwrap();
+        let client = reqwest::Client::new();
+        let response = client
+            .get(format!("{}/foo?bar=1", server.base()))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.text().await.unwrap(), "hello");
+        let response = client
+            .get(format!("{}/missing", server.base()))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 404);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_record() {
+        let upstream_dir =
+            std::env::temp_dir().join(format!("mirror-clone-upstream-{}", std::process::id()));
+        let dir = std::env::temp_dir().join(format!("mirror-clone-record-{}", std::process::id()));
+        save(&upstream_dir, "/foo", 200, BTreeMap::new(), b"hello").unwrap();
+        let upstream = FixtureServer::start(upstream_dir.clone(), Mode::Replay).unwrap();
+
+        let server = FixtureServer::start(
+            dir.clone(),
+            Mode::Record {
+                upstream: upstream.base(),
+            },
+        )
+        .unwrap();
+        let response = reqwest::get(format!("{}/foo", server.base()))
+            .await
+            .unwrap();
+        assert_eq!(response.text().await.unwrap(), "hello");
+
+        let (fixture, body) = load(&dir, "/foo").unwrap().unwrap();
+        assert_eq!(fixture.status, 200);
+        assert_eq!(body, b"hello");
+        std::fs::remove_dir_all(&dir).unwrap();
+        std::fs::remove_dir_all(&upstream_dir).unwrap();
+    }
+}
@@ -0,0 +1,211 @@
+//! HTTP Listing Source
+//!
+//! HTTP listing source crawls directory listing pages, e.g. autoindex of
+//! Apache or nginx, from a base URL. Links ending with `/` are directories,
+//! and are followed up to max depth. Other links are files. Links which
+//! leave the current directory, or carry a query (e.g. sort links like
+//! `?C=N;O=D`), are not followed. Links may also be ignored by pattern.
+//!
+//! Keys are percent-decoded paths relative to base.
+
+use crate::common::{Mission, SnapshotConfig, SnapshotPath, TransferURL};
+use crate::error::{Error, Result};
+use crate::rate_limit::throttle;
+use crate::traits::{SnapshotStorage, SourceStorage};
+use crate::utils::check_url;
+
+use async_trait::async_trait;
+use futures_util::{stream, StreamExt, TryStreamExt};
+use regex::Regex;
+use slog::info;
+use std::collections::HashSet;
+use structopt::StructOpt;
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct HttpListing {
+    #[structopt(long, help = "URL of top directory listing")]
+    pub base: String,
+    #[structopt(long, default_value = "16")]
+    pub max_depth: usize,
+    #[structopt(
+        long,
+        number_of_values = 1,
+        help = "Ignore files and directories whose key matches this regex, may be specified multiple times"
+    )]
+    pub ignore: Vec<String>,
+    #[structopt(long)]
+    pub debug: bool,
+}
+
+/// Number of files after which crawling stops in debug mode
+const DEBUG_FILES: usize = 100;
+
+/// Relative links in a directory listing page. Links to parent, to other
+/// sites, to absolute paths, and links with query or fragment are skipped.
+pub fn parse_links(content: &str) -> Vec<String> {
+    lazy_static::lazy_static! {
+        static ref HREF: Regex = Regex::new(r#"(?i)href\s*=\s*"([^"]*)""#).unwrap();
+    }
+    HREF.captures_iter(content)
+        .filter_map(|cap| {
+            let link = html_escape::decode_html_entities(&cap[1]);
+            let link = link.strip_prefix("./").unwrap_or(&link);
+            let scheme = link.split('/').next().unwrap_or_default().contains(':');
+            if link.is_empty()
+                || scheme
+                || link.starts_with(['/', '.', '?', '#'])
+                || link.contains(['?', '#'])
+            {
+                None
+            } else {
+                Some(link.to_string())
+            }
+        })
+        .collect()
+}
+
+impl HttpListing {
+    fn base(&self) -> String {
+        format!("{}/", self.base.trim_end_matches('/'))
+    }
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotPath> for HttpListing {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotPath>> {
+        let logger = mission.logger;
+        let progress = mission.progress;
+        let client = mission.client;
+        let rate_limit = mission.rate_limit;
+
+        let ignore = self
+            .ignore
+            .iter()
+            .map(|pattern| Regex::new(pattern))
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|err| Error::ConfigureError(format!("invalid ignore pattern: {:?}", err)))?;
+        let base = self.base();
+
+        info!(logger, "crawling {}...", base);
+        let mut dirs = vec![String::new()];
+        let mut seen = HashSet::new();
+        let mut files = vec![];
+
+        for depth in 0..=self.max_depth {
+            if dirs.is_empty() || (self.debug && files.len() >= DEBUG_FILES) {
+                break;
+            }
+            progress.inc_length(dirs.len() as u64);
+            let pages = stream::iter(dirs.drain(..).map(|dir| {
+                let client = client.clone();
+                let progress = progress.clone();
+                let rate_limit = rate_limit.clone();
+                let url = format!("{}{}", base, dir);
+                async move {
+                    progress.set_message(&url);
+                    throttle(&rate_limit).await;
+                    let resp = client.get(&url).send().await?;
+                    if !resp.status().is_success() {
+                        return Err(Error::HTTPError(resp.status()));
+                    }
+                    let content = resp.text().await?;
+                    progress.inc(1);
+                    Ok::<_, Error>((dir, content))
+                }
+            }))
+            .buffer_unordered(config.concurrent_resolve)
+            .try_collect::<Vec<_>>()
+            .await?;
+
+            let mut next = vec![];
+            for (dir, content) in pages {
+                for link in parse_links(&content) {
+                    let path = format!("{}{}", dir, link);
+                    let key = urlencoding::decode(&path)
+                        .map(|key| key.into_owned())
+                        .unwrap_or_else(|_| path.clone());
+                    if ignore.iter().any(|pattern| pattern.is_match(&key)) {
+                        continue;
+                    }
+                    if link.ends_with('/') {
+                        if depth < self.max_depth && seen.insert(path.clone()) {
+                            next.push(path);
+                        }
+                    } else {
+                        files.push(key);
+                    }
+                }
+            }
+            dirs = next;
+        }
+
+        if self.debug {
+            files.truncate(DEBUG_FILES);
+        }
+
+        progress.finish_with_message("done");
+
+        Ok(crate::utils::snapshot_string_to_path(files))
+    }
+
+    async fn check(&mut self, mission: Mission, _config: &SnapshotConfig) -> Result<String> {
+        check_url(&mission, &self.base).await
+    }
+
+    fn info(&self) -> String {
+        format!("http listing, {:?}", self)
+    }
+}
+
+#[async_trait]
+impl SourceStorage<SnapshotPath, TransferURL> for HttpListing {
+    async fn get_object(&self, snapshot: &SnapshotPath, _mission: &Mission) -> Result<TransferURL> {
+        let path: Vec<_> = snapshot
+            .0
+            .split('/')
+            .map(|segment| urlencoding::encode(segment).into_owned())
+            .collect();
+        Ok(TransferURL::new(format!(
+            "{}{}",
+            self.base(),
+            path.join("/")
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_links() {
+        let links = parse_links(
+            r#"<a href="?C=N;O=D">Name</a> <a href="../">Parent Directory</a>
+<a href="/icons/">icons</a> <a href="https://example.com/">elsewhere</a>
+<a href="mailto:admin@example.com">admin</a>
+<a href="pool/">pool/</a> <a HREF="./a%20b.tar.gz">a b.tar.gz</a>
+<a href="c&amp;d.txt">c&amp;d.txt</a>"#,
+        );
+        assert_eq!(links, vec!["pool/", "a%20b.tar.gz", "c&d.txt"]);
+    }
+
+    #[tokio::test]
+    async fn test_get_object() {
+        let source = HttpListing {
+            base: "http://mirror.example.com/debian".to_string(),
+            max_depth: 16,
+            ignore: vec![],
+            debug: false,
+        };
+        let snapshot = SnapshotPath::new("pool/a b.tar.gz".to_string());
+        let url = source
+            .get_object(&snapshot, &Mission::for_test())
+            .await
+            .unwrap();
+        assert_eq!(url.0, "http://mirror.example.com/debian/pool/a%20b.tar.gz");
+    }
+}
@@ -1,6 +1,6 @@
 //! IndexPipe adds Index to every directory of source.
 
-use crate::common::{Mission, SnapshotConfig, SnapshotPath};
+use crate::common::{Mission, SnapshotConfig, SnapshotPath, TransferPath, TransferStream};
 use crate::error::Result;
 use crate::metadata::SnapshotMeta;
 use crate::stream_pipe::{ByteObject, ByteStream};
@@ -8,9 +8,10 @@ use crate::traits::{Key, SnapshotStorage, SourceStorage};
 use crate::utils::{hash_string, unix_time};
 
 use async_trait::async_trait;
+use futures_util::{future, stream, StreamExt};
 use itertools::Itertools;
 use std::collections::{BTreeMap, BTreeSet};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use tokio::io::{AsyncSeekExt, AsyncWriteExt, BufWriter};
 
 static LIST_URL: &str = "mirror_clone_list.html";
@@ -110,8 +111,8 @@ impl Index {
             data += &format!(r#"<tr><td><a href="../{}">..</a></td></tr>"#, list_key);
             data += &self
                 .prefixes
-                .iter()
-                .map(|(key, _)| {
+                .keys()
+                .map(|key| {
                     format!(
                         r#"<tr><td><a href="{}/{}">{}/</a></td></tr>"#,
                         urlencoding::encode(key),
@@ -198,6 +199,37 @@ impl<Source> IndexPipe<Source> {
         }
     }
 
+    fn index_content(&self, prefix: &str) -> Vec<u8> {
+        self.index
+            .index_for(prefix, &[&self.base_path], LIST_URL)
+            .into_bytes()
+    }
+
+    /// Write index page of directory `prefix` to a new file in buffer path.
+    async fn write_index(
+        &self,
+        prefix: &str,
+        key: &str,
+    ) -> Result<(tokio::fs::File, PathBuf, u64)> {
+        let content = self.index_content(prefix);
+        let pipe_file = format!("{}.{}.buffer", hash_string(key), unix_time());
+        let path = Path::new(&self.buffer_path).join(pipe_file);
+        let mut f = BufWriter::new(
+            tokio::fs::OpenOptions::default()
+                .create(true)
+                .truncate(true)
+                .write(true)
+                .read(true)
+                .open(&path)
+                .await?,
+        );
+        f.write_all(&content).await?;
+        f.flush().await?;
+        let mut f = f.into_inner();
+        f.seek(std::io::SeekFrom::Start(0)).await?;
+        Ok((f, path, content.len() as u64))
+    }
+
     fn snapshot_index_keys(&mut self, mut snapshot: Vec<String>) -> Vec<String> {
         snapshot.sort();
         // If duplicated keys are found, there should be a warning.
@@ -225,9 +257,18 @@ where
         Ok(snapshot)
     }
 
+    async fn check(&mut self, mission: Mission, config: &SnapshotConfig) -> Result<String> {
+        self.source.check(mission, config).await
+    }
+
     fn info(&self) -> String {
         format!("IndexPipe (path) <{}>", self.source.info())
     }
+
+    // index pages are generated from snapshot
+    fn cacheable(&self) -> bool {
+        false
+    }
 }
 
 #[async_trait]
@@ -247,9 +288,18 @@ where
         Ok(snapshot)
     }
 
+    async fn check(&mut self, mission: Mission, config: &SnapshotConfig) -> Result<String> {
+        self.source.check(mission, config).await
+    }
+
     fn info(&self) -> String {
         format!("IndexPipe (meta) <{}>", self.source.info())
     }
+
+    // index pages are generated from snapshot
+    fn cacheable(&self) -> bool {
+        false
+    }
 }
 
 #[async_trait]
@@ -261,38 +311,96 @@ where
     async fn get_object(&self, snapshot: &Snapshot, mission: &Mission) -> Result<ByteStream> {
         let key = snapshot.key();
         if let Some(prefix) = key.strip_suffix(LIST_URL) {
-            let content = self
-                .index
-                .index_for(prefix, &[&self.base_path], LIST_URL)
-                .into_bytes();
-            let pipe_file = format!("{}.{}.buffer", hash_string(key), unix_time());
-            let path = Path::new(&self.buffer_path).join(pipe_file);
-            let mut f = BufWriter::new(
-                tokio::fs::OpenOptions::default()
-                    .create(true)
-                    .truncate(true)
-                    .write(true)
-                    .read(true)
-                    .open(&path)
-                    .await?,
-            );
-            f.write_all(&content).await?;
-            f.flush().await?;
-            let mut f = f.into_inner();
-            f.seek(std::io::SeekFrom::Start(0)).await?;
+            let (f, path, length) = self.write_index(prefix, key).await?;
             Ok(ByteStream {
                 object: ByteObject::LocalFile {
                     file: Some(f),
                     path: Some(path),
                 },
-                length: content.len() as u64,
+                length,
                 modified_at: unix_time(),
                 content_type: None, // use `text/html` by default
+                headers: vec![],
             })
         } else {
             self.source.get_object(snapshot, mission).await
         }
     }
+
+    async fn get_size(&self, snapshot: &Snapshot, mission: &Mission) -> Result<Option<u64>>
+    where
+        Snapshot: Sync,
+    {
+        if snapshot.key().ends_with(LIST_URL) {
+            Ok(None)
+        } else {
+            self.source.get_size(snapshot, mission).await
+        }
+    }
+}
+
+/// Index pages are written to buffer path, and removed once transferred.
+#[async_trait]
+impl<Snapshot, Source> SourceStorage<Snapshot, TransferPath> for IndexPipe<Source>
+where
+    Snapshot: Key,
+    Source: SourceStorage<Snapshot, TransferPath>,
+{
+    async fn get_object(&self, snapshot: &Snapshot, mission: &Mission) -> Result<TransferPath> {
+        let key = snapshot.key();
+        if let Some(prefix) = key.strip_suffix(LIST_URL) {
+            let (_, path, _) = self.write_index(prefix, key).await?;
+            Ok(TransferPath::temporary(path))
+        } else {
+            self.source.get_object(snapshot, mission).await
+        }
+    }
+
+    async fn get_size(&self, snapshot: &Snapshot, mission: &Mission) -> Result<Option<u64>>
+    where
+        Snapshot: Sync,
+    {
+        if snapshot.key().ends_with(LIST_URL) {
+            Ok(None)
+        } else {
+            self.source.get_size(snapshot, mission).await
+        }
+    }
+}
+
+/// Index pages are streamed from memory, without touching buffer path.
+#[async_trait]
+impl<Snapshot, Source> SourceStorage<Snapshot, TransferStream> for IndexPipe<Source>
+where
+    Snapshot: Key,
+    Source: SourceStorage<Snapshot, TransferStream>,
+{
+    async fn get_object(&self, snapshot: &Snapshot, mission: &Mission) -> Result<TransferStream> {
+        let key = snapshot.key();
+        if let Some(prefix) = key.strip_suffix(LIST_URL) {
+            let content = bytes::Bytes::from(self.index_content(prefix));
+            Ok(TransferStream {
+                length: Some(content.len() as u64),
+                stream: stream::once(future::ready(Ok(content))).boxed(),
+                modified_at: unix_time(),
+                content_type: None, // use `text/html` by default
+                headers: vec![],
+            })
+        } else {
+            self.source.get_object(snapshot, mission).await
+        }
+    }
+
+    async fn get_size(&self, snapshot: &Snapshot, mission: &Mission) -> Result<Option<u64>>
+    where
+        Snapshot: Sync,
+    {
+        if snapshot.key().ends_with(LIST_URL) {
+            Ok(None)
+        } else {
+            self.source.get_size(snapshot, mission).await
+        }
+    }
 }
 
 #[cfg(test)]
@@ -357,4 +465,54 @@ mod tests {
             vec!["list.html", "c/list.html", "c/a/list.html"]
         );
     }
+
+    struct Paths(Vec<&'static str>);
+
+    #[async_trait]
+    impl SnapshotStorage<SnapshotPath> for Paths {
+        async fn snapshot(&mut self, _: Mission, _: &SnapshotConfig) -> Result<Vec<SnapshotPath>> {
+            Ok(self
+                .0
+                .iter()
+                .map(|x| SnapshotPath::new(x.to_string()))
+                .collect())
+        }
+
+        fn info(&self) -> String {
+            String::from("paths")
+        }
+    }
+
+    #[async_trait]
+    impl SourceStorage<SnapshotPath, TransferStream> for Paths {
+        async fn get_object(&self, _: &SnapshotPath, _: &Mission) -> Result<TransferStream> {
+            Err(crate::error::Error::PipeError(String::from(
+                "only index pages are fetched",
+            )))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stream_index() {
+        let mut pipe = IndexPipe::new(
+            Paths(vec!["a", "c/b"]),
+            String::new(),
+            String::from("Root"),
+            999,
+        );
+        let snapshot: Vec<SnapshotPath> = pipe
+            .snapshot(Mission::for_test(), &SnapshotConfig::for_test())
+            .await
+            .unwrap();
+        let index = snapshot
+            .iter()
+            .find(|x| x.key() == "c/mirror_clone_list.html")
+            .unwrap();
+
+        let item: TransferStream = pipe.get_object(index, &Mission::for_test()).await.unwrap();
+        let content: Vec<_> = item.stream.map(|x| x.unwrap()).collect().await;
+        let content = String::from_utf8(content.concat()).unwrap();
+        assert_eq!(item.length, Some(content.len() as u64));
+        assert!(content.contains(r#"<a href="b">b</a>"#));
+    }
 }
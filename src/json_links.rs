@@ -0,0 +1,192 @@
+//! JSON links source.
+//!
+//! JsonLinks is a source storage which follows download links in a JSON
+//! index, such as the Terraform provider registry or Helm chart repos. Items
+//! of the index are selected with a JSONPath expression, and the download URL
+//! of each item with another JSONPath expression evaluated on the item. If a
+//! next page expression is given, pages are fetched until it selects nothing.
+//!
+//! Relative URLs are resolved against the page they appear on. Only URLs
+//! under `base` are mirrored, and keys are relative to `base`.
+//!
+//! JsonLinks supports path snapshot, and TransferURL source object.
+
+use std::collections::HashSet;
+
+use crate::common::{Mission, SnapshotConfig, SnapshotPath, TransferURL};
+use crate::error::{Error, Result};
+use crate::traits::{SnapshotStorage, SourceStorage};
+use crate::utils::check_url;
+
+use async_trait::async_trait;
+use serde_json::Value;
+use slog::{info, warn};
+use structopt::StructOpt;
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct JsonLinks {
+    /// URL of first page of index
+    #[structopt(long, help = "URL of JSON index")]
+    pub index_url: String,
+    /// JSONPath of items, evaluated on each page
+    #[structopt(long, help = "JSONPath of items in index", default_value = "$[*]")]
+    pub items_path: String,
+    /// JSONPath of download URL, evaluated on each item
+    #[structopt(long, help = "JSONPath of download URL within each item")]
+    pub url_path: String,
+    /// JSONPath of next page URL, evaluated on each page
+    #[structopt(long, help = "JSONPath of next page URL in index")]
+    pub next_path: Option<String>,
+    /// Base of download URLs
+    #[structopt(long, help = "Base of download URLs, other URLs are skipped")]
+    pub base: String,
+    /// When debug mode is enabled, only the first page will be fetched.
+    #[structopt(long)]
+    pub debug: bool,
+}
+
+fn select<'a>(json: &'a Value, path: &str) -> Result<Vec<&'a Value>> {
+    jsonpath_lib::select(json, path)
+        .map_err(|err| Error::ConfigureError(format!("invalid JSONPath {}: {:?}", path, err)))
+}
+
+fn select_urls(json: &Value, path: &str, page: &url::Url) -> Result<Vec<String>> {
+    Ok(select(json, path)?
+        .into_iter()
+        .filter_map(|value| value.as_str())
+        .filter_map(|href| page.join(href).ok())
+        .map(|url| url.to_string())
+        .collect())
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotPath> for JsonLinks {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        _config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotPath>> {
+        let logger = mission.logger;
+        let progress = mission.progress;
+        let client = mission.client;
+        let rate_limit = mission.rate_limit;
+
+        let base = if self.base.ends_with('/') {
+            self.base.clone()
+        } else {
+            format!("{}/", self.base)
+        };
+
+        info!(logger, "fetching index...");
+
+        let mut next_url = Some(
+            url::Url::parse(&self.index_url)
+                .map_err(|err| Error::ConfigureError(format!("invalid index URL: {:?}", err)))?,
+        );
+        let mut visited = HashSet::new();
+        let mut snapshot = vec![];
+        let mut page: usize = 1;
+
+        while let Some(url) = next_url.take() {
+            if !visited.insert(url.clone()) {
+                warn!(logger, "next page loops back to {}", url);
+                break;
+            }
+            progress.set_message(&format!(
+                "fetching page {}, total objects = {}",
+                page,
+                snapshot.len()
+            ));
+
+            crate::rate_limit::throttle(&rate_limit).await;
+            let data: Value = client
+                .get(url.clone())
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+
+            for item in select(&data, &self.items_path)? {
+                for download_url in select_urls(item, &self.url_path, &url)? {
+                    if let Some(key) = download_url.strip_prefix(&base) {
+                        snapshot.push(key.to_string());
+                    } else {
+                        warn!(logger, "URL isn't stored on base: {:?}", download_url);
+                    }
+                }
+            }
+
+            if self.debug {
+                break;
+            }
+            if let Some(next_path) = &self.next_path {
+                next_url = select_urls(&data, next_path, &url)?
+                    .into_iter()
+                    .next()
+                    .and_then(|next| url::Url::parse(&next).ok());
+            }
+            page += 1;
+        }
+
+        progress.finish_with_message("done");
+
+        Ok(crate::utils::snapshot_string_to_path(snapshot))
+    }
+
+    async fn check(&mut self, mission: Mission, _config: &SnapshotConfig) -> Result<String> {
+        check_url(&mission, &self.index_url).await
+    }
+
+    fn info(&self) -> String {
+        format!("json links, {:?}", self)
+    }
+}
+
+#[async_trait]
+impl SourceStorage<SnapshotPath, TransferURL> for JsonLinks {
+    async fn get_object(&self, snapshot: &SnapshotPath, _mission: &Mission) -> Result<TransferURL> {
+        Ok(TransferURL::new(format!(
+            "{}/{}",
+            self.base.trim_end_matches('/'),
+            snapshot.0
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_select_urls() {
+        let page = url::Url::parse("http://registry.example.com/v1/providers?page=1").unwrap();
+        let json: Value = serde_json::from_str(
+            r#"{
+                "items": [
+                    {"download": "http://dl.example.com/a.zip"},
+                    {"download": "/files/b.zip"},
+                    {"name": "no download"}
+                ],
+                "meta": {"next": "?page=2"}
+            }"#,
+        )
+        .unwrap();
+        let items = select(&json, "$.items[*]").unwrap();
+        let urls: Vec<String> = items
+            .into_iter()
+            .flat_map(|item| select_urls(item, "$.download", &page).unwrap())
+            .collect();
+        assert_eq!(
+            urls,
+            vec![
+                "http://dl.example.com/a.zip",
+                "http://registry.example.com/files/b.zip"
+            ]
+        );
+        assert_eq!(
+            select_urls(&json, "$.meta.next", &page).unwrap(),
+            vec!["http://registry.example.com/v1/providers?page=2"]
+        );
+    }
+}
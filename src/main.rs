@@ -6,8 +6,9 @@ use std::path::Path;
 use lazy_static::lazy_static;
 use structopt::StructOpt;
 
+use cas_backend::CasBackend;
 use common::SnapshotConfig;
-use error::Result;
+use error::{Error, Result};
 use file_backend::FileBackend;
 use opts::{Source, Target};
 use s3::S3Backend;
@@ -16,36 +17,61 @@ use simple_diff_transfer::SimpleDiffTransfer;
 use crate::github_release::GitHubRelease;
 use crate::homebrew::Homebrew;
 
+mod cas_backend;
+mod checksum;
+mod circuit_breaker;
 mod common;
 mod conda;
+mod conditional;
+mod cran;
 mod crates_io;
+mod crates_io_index;
 mod dart;
+mod debian;
 mod error;
+mod external_sort;
 mod file_backend;
 mod filter_pipe;
 mod ghcup;
 mod github_release;
+mod go_proxy;
 mod gradle;
 mod homebrew;
-mod html_scanner;
+#[cfg(all(test, feature = "http-fixture"))]
+mod http_fixture;
+mod http_listing;
 mod index_pipe;
+mod json_links;
+mod maven;
 mod merge_pipe;
 mod metadata;
+mod metrics;
+mod npm;
+mod oci_registry;
 mod opts;
+mod path_list;
 mod pypi;
+mod rate_limit;
+mod rewrite;
 mod rewrite_pipe;
 mod rsync;
-mod rustup;
+mod rubygems;
 mod s3;
+mod sidecar_pipe;
 mod simple_diff_transfer;
+mod snapshot_cache;
+mod snapshot_file;
+mod snapshot_stats;
 mod stream_pipe;
 mod timeout;
+mod tombstone;
 mod traits;
 mod utils;
 
 macro_rules! index_bytes_pipe {
-    ($buffer_path: expr, $prefix: expr, $use_snapshot_last_modified: expr, $max_depth: expr) => {
+    ($buffer_path: expr, $prefix: expr, $use_snapshot_last_modified: expr, $max_depth: expr, $sidecar_suffix: expr) => {
         |source| {
+            let source = sidecar_pipe::SidecarPipe::new(source, $sidecar_suffix.clone());
             let source = stream_pipe::ByteStreamPipe::new(
                 source,
                 $buffer_path.clone().unwrap(),
@@ -61,33 +87,104 @@ macro_rules! index_bytes_pipe {
     };
 }
 
+/// Like `index_bytes_pipe`, but objects are streamed into target. Index
+/// pages are generated in memory, so buffer path is not used.
+macro_rules! index_stream_pipe {
+    ($prefix: expr, $use_snapshot_last_modified: expr, $max_depth: expr, $sidecar_suffix: expr) => {
+        |source| {
+            let source = sidecar_pipe::SidecarPipe::new(source, $sidecar_suffix.clone());
+            let source = stream_pipe::TransferStreamPipe::new(source, $use_snapshot_last_modified);
+            index_pipe::IndexPipe::new(source, String::new(), $prefix.clone().unwrap(), $max_depth)
+        }
+    };
+}
+
 macro_rules! id_pipe {
     () => {
         |src| src
     };
 }
 
+/// Transfer from source through pipes to the given target, and exit with
+/// error if the transfer fails.
+macro_rules! transfer_to {
+    ($opts: expr, $target: expr, $source: expr, $transfer_config: expr, $pipes: expr) => {{
+        let pipes = $pipes;
+        let source = pipes(snapshot_cache::CachedSource::new($source));
+        let transfer = unwrap_or_exit(SimpleDiffTransfer::new(source, $target, $transfer_config));
+        if $opts.check {
+            unwrap_or_exit(transfer.check().await);
+        } else {
+            unwrap_or_exit(transfer.transfer().await);
+        }
+    }};
+}
+
 macro_rules! transfer {
     ($opts: expr, $source: expr, $transfer_config: expr, $pipes: expr) => {
         match $opts.target_type {
             Target::S3 => {
                 let target: S3Backend = $opts.s3_config.clone().into();
-                let pipes = $pipes;
-                let source = pipes($source);
-                let transfer = SimpleDiffTransfer::new(source, target, $transfer_config);
-                transfer.transfer().await.unwrap();
+                transfer_to!($opts, target, $source, $transfer_config, $pipes)
             }
             Target::File => {
                 let target: FileBackend = $opts.file_config.clone().into();
-                let pipes = $pipes;
-                let source = pipes($source);
-                let transfer = SimpleDiffTransfer::new(source, target, $transfer_config);
-                transfer.transfer().await.unwrap();
+                transfer_to!($opts, target, $source, $transfer_config, $pipes)
             }
+            Target::Cas => {
+                let target: CasBackend = $opts.cas_config.clone().into();
+                transfer_to!($opts, target, $source, $transfer_config, $pipes)
+            }
+        }
+    };
+}
+
+/// Transfer from source of `TransferURL` with index pages, streaming objects
+/// into target if `--stream-objects` is given.
+macro_rules! transfer_indexed {
+    ($opts: expr, $source: expr, $transfer_config: expr, $buffer_path: expr, $prefix: expr, $use_snapshot_last_modified: expr, $max_depth: expr, $sidecar_suffix: expr) => {
+        if $opts.stream_objects {
+            transfer!(
+                $opts,
+                $source,
+                $transfer_config,
+                index_stream_pipe!(
+                    $prefix,
+                    $use_snapshot_last_modified,
+                    $max_depth,
+                    $sidecar_suffix
+                )
+            );
+        } else {
+            transfer!(
+                $opts,
+                $source,
+                $transfer_config,
+                index_bytes_pipe!(
+                    $buffer_path,
+                    $prefix,
+                    $use_snapshot_last_modified,
+                    $max_depth,
+                    $sidecar_suffix
+                )
+            );
         }
     };
 }
 
+/// Value of a successful result, or exit with the error. Summary of a run
+/// is already logged when it fails, and an interrupted run exits with 130.
+fn unwrap_or_exit<T>(result: Result<T>) -> T {
+    match result {
+        Ok(value) => value,
+        Err(Error::Interrupted) => std::process::exit(130),
+        Err(err) => {
+            eprintln!("error: {}", err);
+            std::process::exit(1)
+        }
+    }
+}
+
 lazy_static! {
     static ref HASKELL_PATTERN: regex::Regex =
         regex::Regex::new("https://downloads.haskell.org").unwrap();
@@ -97,7 +194,34 @@ const STACK_URL: &str = "https://github.com/commercialhaskell/stack";
 const HASKELL_URL: &str = "https://downloads.haskell.org";
 
 fn main() {
-    let opts: opts::Opts = opts::Opts::from_args();
+    let mut opts: opts::Opts = opts::Opts::from_args();
+    let source = unwrap_or_exit(match (opts.source.take(), &opts.source_type) {
+        (Some(source), None) => Ok(source),
+        (None, Some(name)) => Source::from_name(name, std::mem::take(&mut opts.source_arg)),
+        (Some(source), Some(name)) => Err(Error::ConfigureError(format!(
+            "source is given both as subcommand {} and --source-type {}",
+            source.name(),
+            name
+        ))),
+        (None, None) => Err(Error::ConfigureError(String::from(
+            "source should be given as subcommand or --source-type",
+        ))),
+    });
+    if opts.stream_objects {
+        // index pages are streamed, but these sources are rewritten or
+        // already on local disk
+        let unsupported = match &source {
+            Source::Ghcup(_) => true,
+            Source::Rsync(source) => source.local_base.is_some(),
+            _ => false,
+        };
+        if unsupported {
+            unwrap_or_exit::<()>(Err(Error::ConfigureError(format!(
+                "--stream-objects is not supported by source {}",
+                source.name()
+            ))));
+        }
+    }
 
     // create runtime
     let mut runtime = tokio::runtime::Builder::new_multi_thread();
@@ -111,14 +235,119 @@ fn main() {
     // parse config
     let snapshot_config = SnapshotConfig {
         concurrent_resolve: opts.concurrent_resolve,
+        snapshot_cache_dir: opts.snapshot_cache_dir.clone(),
+        snapshot_ttl: std::time::Duration::from_secs(opts.snapshot_ttl_secs),
+        debug_limit: opts.debug_limit,
     };
     let transfer_config = simple_diff_transfer::SimpleDiffTransferConfig {
         progress: opts.progress,
+        log_level: opts.log_level,
+        log_json: opts.log_json,
         concurrent_transfer: opts.transfer_config.concurrent_transfer,
+        debug_sample: opts.transfer_config.debug_sample,
+        debug_sample_seed: opts.transfer_config.debug_sample_seed,
         no_delete: opts.transfer_config.no_delete,
         print_plan: opts.transfer_config.print_plan,
         dry_run: opts.transfer_config.dry_run,
         force_all: opts.transfer_config.force_all,
+        min_free_space: opts.transfer_config.min_free_space,
+        case_insensitive: opts.transfer_config.case_insensitive,
+        status_file: opts.transfer_config.status_file.clone(),
+        tombstone_file: opts.transfer_config.tombstone_file.clone(),
+        tombstone_runs: opts.transfer_config.tombstone_runs,
+        tombstone_days: opts.transfer_config.tombstone_days,
+        plan_file: opts.transfer_config.plan_file.clone(),
+        plan_only: opts.transfer_config.plan_only,
+        confirm_plan: opts.transfer_config.confirm_plan,
+        execute_plan: opts.transfer_config.execute_plan.clone(),
+        max_updates: opts.transfer_config.max_updates,
+        max_duration: opts
+            .transfer_config
+            .max_duration_secs
+            .map(std::time::Duration::from_secs),
+        max_update_fraction: opts.transfer_config.max_update_fraction,
+        yes_full_sync: opts.transfer_config.yes_full_sync,
+        max_deletion_fraction: opts.transfer_config.max_deletion_fraction,
+        yes_mass_delete: opts.transfer_config.yes_mass_delete,
+        transfer_jitter_ms: opts.transfer_config.transfer_jitter_ms,
+        index_only: opts.transfer_config.index_only,
+        index_pattern: opts.transfer_config.index_pattern.clone(),
+        include: opts.transfer_config.include.clone(),
+        exclude: opts.transfer_config.exclude.clone(),
+        metrics_addr: opts.transfer_config.metrics_addr,
+        path_rewriter: if opts.transfer_config.rewrite_path.is_empty() {
+            None
+        } else {
+            Some(unwrap_or_exit(rewrite::PathRewriter::new(
+                &opts.transfer_config.rewrite_path,
+            )))
+        },
+        export_snapshot: opts.transfer_config.export_snapshot.clone(),
+        snapshot_stats: opts.transfer_config.snapshot_stats,
+        external_sort_threshold: opts.transfer_config.external_sort_threshold,
+        external_sort_dir: opts
+            .transfer_config
+            .external_sort_dir
+            .as_ref()
+            .map_or_else(std::env::temp_dir, Into::into),
+        concurrency_limit: opts
+            .transfer_config
+            .max_concurrent_requests
+            .map(simple_diff_transfer::ConcurrencyLimit::new),
+        head_sizes: opts.transfer_config.head_sizes,
+        max_deletes_per_run: opts.transfer_config.max_deletes_per_run,
+        limit_objects: opts.transfer_config.limit_objects,
+        max_failures: opts.transfer_config.max_failures,
+        max_failure_fraction: opts.transfer_config.max_failure_fraction,
+        failed_list: opts.transfer_config.failed_list.clone(),
+        report_json: opts.transfer_config.report_json.clone(),
+        checkpoint: opts.transfer_config.checkpoint.clone(),
+        user_agent: opts.transfer_config.user_agent.clone(),
+        headers: opts.transfer_config.headers.iter().cloned().collect(),
+        proxies: opts
+            .transfer_config
+            .http_proxy
+            .iter()
+            .chain(&opts.transfer_config.https_proxy)
+            .cloned()
+            .collect(),
+        pool_max_idle_per_host: opts.transfer_config.pool_max_idle_per_host,
+        pool_idle_timeout_secs: opts.transfer_config.pool_idle_timeout_secs,
+        connect_timeout_secs: opts.transfer_config.connect_timeout_secs,
+        request_timeout_secs: opts.transfer_config.request_timeout_secs,
+        circuit_breaker: opts.transfer_config.breaker_threshold.map(|threshold| {
+            circuit_breaker::CircuitBreakerConfig {
+                window: opts.transfer_config.breaker_window,
+                threshold,
+                cooldown: std::time::Duration::from_secs(
+                    opts.transfer_config.breaker_cooldown_secs,
+                ),
+                probes: opts.transfer_config.breaker_probes,
+                max_trips: opts.transfer_config.breaker_max_trips,
+            }
+        }),
+        transfer_timeout_base_secs: opts.transfer_config.transfer_timeout_base_secs,
+        transfer_timeout_unknown_size_secs: opts.transfer_config.transfer_timeout_unknown_size_secs,
+        min_throughput: opts.transfer_config.min_throughput,
+        get_timeout: std::time::Duration::from_secs(opts.transfer_config.get_timeout_secs),
+        put_timeout: std::time::Duration::from_secs(opts.transfer_config.put_timeout_secs),
+        delete_timeout: std::time::Duration::from_secs(opts.transfer_config.delete_timeout_secs),
+        max_retries: opts.transfer_config.max_retries,
+        retry_backoff: std::time::Duration::from_millis(opts.transfer_config.retry_backoff_ms),
+        max_retry_backoff: std::time::Duration::from_millis(
+            opts.transfer_config.max_retry_backoff_ms,
+        ),
+        verify_checksum: opts.transfer_config.verify_checksum,
+        conditional_requests: opts.transfer_config.conditional_requests,
+        transfer_order: opts.transfer_config.transfer_order,
+        rate_limit: opts
+            .transfer_config
+            .max_requests_per_sec
+            .map(rate_limit::RateLimit::new),
+        bandwidth_limit: opts
+            .transfer_config
+            .max_bandwidth_bytes_per_sec
+            .map(|rate| rate_limit::RateLimit::new(rate as f64)),
         snapshot_config,
     };
 
@@ -127,79 +356,288 @@ fn main() {
             .s3_config
             .s3_buffer_path
             .clone()
-            .or_else(|| opts.file_config.file_buffer_path.clone());
+            .or_else(|| opts.file_config.file_buffer_path.clone())
+            .or_else(|| opts.cas_config.cas_buffer_path.clone());
         let prefix = opts
             .s3_config
             .s3_prefix
             .clone()
             .or_else(|| Some(String::from("Root")));
-        match opts.source {
+        let sidecar_suffix = opts.sidecar_suffix.clone();
+        match source {
             Source::Pypi(source) => {
-                transfer!(
+                transfer_indexed!(
                     opts,
                     source,
                     transfer_config,
-                    index_bytes_pipe!(buffer_path, prefix, false, 2)
+                    buffer_path,
+                    prefix,
+                    false,
+                    2,
+                    sidecar_suffix
                 );
             }
             Source::Homebrew(config) => {
                 let source = Homebrew::new(config);
-                transfer!(
+                transfer_indexed!(
                     opts,
                     source,
                     transfer_config,
-                    index_bytes_pipe!(buffer_path, prefix, false, 999)
+                    buffer_path,
+                    prefix,
+                    false,
+                    999,
+                    sidecar_suffix
                 );
             }
             Source::CratesIo(source) => {
-                transfer!(
+                transfer_indexed!(
                     opts,
                     source,
                     transfer_config,
-                    index_bytes_pipe!(buffer_path, prefix, false, 999)
+                    buffer_path,
+                    prefix,
+                    false,
+                    999,
+                    sidecar_suffix
                 );
             }
+            Source::CratesIoIndex(source) => {
+                // cargo reads sparse index directly, without listing pages
+                if opts.stream_objects {
+                    transfer!(opts, source, transfer_config, |source| {
+                        stream_pipe::TransferStreamPipe::new(source, false)
+                    });
+                } else {
+                    transfer!(opts, source, transfer_config, |source| {
+                        stream_pipe::ByteStreamPipe::new(
+                            source,
+                            buffer_path.clone().unwrap(),
+                            false,
+                        )
+                    });
+                }
+            }
             Source::Conda(config) => {
                 let source = conda::Conda::new(config);
-                transfer!(
+                transfer_indexed!(
                     opts,
                     source,
                     transfer_config,
-                    index_bytes_pipe!(buffer_path, prefix, false, 999)
+                    buffer_path,
+                    prefix,
+                    false,
+                    999,
+                    sidecar_suffix
                 );
             }
+            Source::Rsync(source) if source.local_base.is_some() => {
+                // objects are on local disk, and are not fetched
+                let pipes = |source| {
+                    let source = sidecar_pipe::SidecarPipe::new(source, sidecar_suffix.clone());
+                    index_pipe::IndexPipe::new(
+                        source,
+                        buffer_path.clone().unwrap(),
+                        prefix.clone().unwrap(),
+                        999,
+                    )
+                };
+                transfer!(opts, source, transfer_config, pipes);
+            }
             Source::Rsync(source) => {
-                transfer!(
+                transfer_indexed!(
                     opts,
                     source,
                     transfer_config,
-                    index_bytes_pipe!(buffer_path, prefix, false, 999)
+                    buffer_path,
+                    prefix,
+                    false,
+                    999,
+                    sidecar_suffix
                 );
             }
             Source::GithubRelease(source) => {
-                transfer!(
+                transfer_indexed!(
                     opts,
                     source,
                     transfer_config,
-                    index_bytes_pipe!(buffer_path, prefix, true, 999)
+                    buffer_path,
+                    prefix,
+                    true,
+                    999,
+                    sidecar_suffix
                 );
             }
             Source::DartPub(source) => {
-                transfer!(
+                transfer_indexed!(
                     opts,
                     source,
                     transfer_config,
-                    index_bytes_pipe!(buffer_path, prefix, false, 999)
+                    buffer_path,
+                    prefix,
+                    false,
+                    999,
+                    sidecar_suffix
                 );
             }
             Source::Gradle(source) => {
-                transfer!(
+                transfer_indexed!(
+                    opts,
+                    source,
+                    transfer_config,
+                    buffer_path,
+                    prefix,
+                    false,
+                    999,
+                    sidecar_suffix
+                );
+            }
+            Source::JsonLinks(source) => {
+                transfer_indexed!(
+                    opts,
+                    source,
+                    transfer_config,
+                    buffer_path,
+                    prefix,
+                    false,
+                    999,
+                    sidecar_suffix
+                );
+            }
+            Source::Maven(source) => {
+                transfer_indexed!(
+                    opts,
+                    source,
+                    transfer_config,
+                    buffer_path,
+                    prefix,
+                    false,
+                    999,
+                    sidecar_suffix
+                );
+            }
+            Source::Npm(source) => {
+                transfer_indexed!(
+                    opts,
+                    source,
+                    transfer_config,
+                    buffer_path,
+                    prefix,
+                    false,
+                    999,
+                    sidecar_suffix
+                );
+            }
+            Source::GoProxy(source) => {
+                transfer_indexed!(
+                    opts,
+                    source,
+                    transfer_config,
+                    buffer_path,
+                    prefix,
+                    false,
+                    999,
+                    sidecar_suffix
+                );
+            }
+            Source::OciRegistry(mut source) => {
+                source.blob_client = Some(unwrap_or_exit(
+                    simple_diff_transfer::client_builder(&transfer_config)
+                        .redirect(reqwest::redirect::Policy::none())
+                        .build()
+                        .map_err(Into::into),
+                ));
+                transfer_indexed!(
                     opts,
                     source,
                     transfer_config,
-                    index_bytes_pipe!(buffer_path, prefix, false, 999)
+                    buffer_path,
+                    prefix,
+                    false,
+                    999,
+                    sidecar_suffix
                 );
             }
+            Source::Debian(source) => {
+                transfer_indexed!(
+                    opts,
+                    source,
+                    transfer_config,
+                    buffer_path,
+                    prefix,
+                    false,
+                    999,
+                    sidecar_suffix
+                );
+            }
+            Source::Rubygems(source) => {
+                transfer_indexed!(
+                    opts,
+                    source,
+                    transfer_config,
+                    buffer_path,
+                    prefix,
+                    false,
+                    999,
+                    sidecar_suffix
+                );
+            }
+            Source::Cran(source) => {
+                transfer_indexed!(
+                    opts,
+                    source,
+                    transfer_config,
+                    buffer_path,
+                    prefix,
+                    false,
+                    999,
+                    sidecar_suffix
+                );
+            }
+            Source::HttpListing(source) => {
+                transfer_indexed!(
+                    opts,
+                    source,
+                    transfer_config,
+                    buffer_path,
+                    prefix,
+                    false,
+                    999,
+                    sidecar_suffix
+                );
+            }
+            Source::SnapshotFile(source) => {
+                transfer_indexed!(
+                    opts,
+                    source,
+                    transfer_config,
+                    buffer_path,
+                    prefix,
+                    false,
+                    999,
+                    sidecar_suffix
+                );
+            }
+            Source::PathList(source) => {
+                // path list only covers part of source
+                let transfer_config = simple_diff_transfer::SimpleDiffTransferConfig {
+                    no_delete: true,
+                    ..transfer_config
+                };
+                if opts.stream_objects {
+                    transfer!(opts, source, transfer_config, |source| {
+                        stream_pipe::TransferStreamPipe::new(source, false)
+                    });
+                } else {
+                    transfer!(opts, source, transfer_config, |source| {
+                        stream_pipe::ByteStreamPipe::new(
+                            source,
+                            buffer_path.clone().unwrap(),
+                            false,
+                        )
+                    });
+                }
+            }
             Source::Ghcup(source) => {
                 let target_mirror = source.target_mirror.clone();
 
@@ -209,7 +647,6 @@ fn main() {
                         buffer_path.clone().unwrap(),
                         false,
                     ),
-                    buffer_path.clone().unwrap(),
                     utils::fn_regex_rewrite(
                         &HASKELL_PATTERN,
                         Path::new(&target_mirror)
@@ -242,7 +679,6 @@ fn main() {
                         buffer_path.clone().unwrap(),
                         false,
                     ),
-                    buffer_path.clone().unwrap(),
                     yaml_rewrite_fn,
                     999999,
                 );
@@ -292,7 +728,7 @@ fn main() {
                 );
 
                 let indexed = index_pipe::IndexPipe::new(
-                    unified,
+                    snapshot_cache::CachedSource::new(unified),
                     buffer_path.clone().unwrap(),
                     prefix.clone().unwrap(),
                     999,
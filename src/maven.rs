@@ -0,0 +1,336 @@
+//! Maven repository source
+//!
+//! Maven mirrors artifacts of a Maven repository, given their `group:artifact`
+//! coordinates on command line or in a file. Versions of each artifact are
+//! taken from its `maven-metadata.xml`, and for each version its `.jar` and
+//! `.pom` are taken, along with `.sha1` and `.md5` checksum sidecars.
+//! Artifacts without jar (e.g. of pom packaging) are reported as unavailable
+//! on source, and don't count as failures. Failing to fetch metadata of an
+//! artifact aborts the snapshot, unless the metadata is gone from repository.
+//!
+//! Files of a SNAPSHOT version are named after the timestamp of its latest
+//! build, e.g. `foo-1.0-20210101.000000-1.jar`, which is taken from the
+//! `maven-metadata.xml` of that version. That metadata is mirrored as well,
+//! so that clients may resolve the latest build.
+//!
+//! Maven supports path snapshot, and TransferURL source object.
+
+use crate::common::{Mission, SnapshotConfig, SnapshotPath, TransferURL};
+use crate::error::{Error, Result};
+use crate::rate_limit::throttle;
+use crate::traits::{SnapshotStorage, SourceStorage};
+use crate::utils::check_url;
+
+use async_trait::async_trait;
+use futures_util::{stream, StreamExt, TryStreamExt};
+use regex::Regex;
+use slog::{info, warn};
+use structopt::StructOpt;
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct Maven {
+    #[structopt(long, default_value = "https://repo1.maven.org/maven2")]
+    pub repo_base: String,
+    #[structopt(
+        long,
+        number_of_values = 1,
+        help = "Coordinates of artifact to mirror, e.g. org.slf4j:slf4j-api, may be specified multiple times"
+    )]
+    pub artifact: Vec<String>,
+    #[structopt(long, help = "File of artifact coordinates to mirror, one per line")]
+    pub artifact_list: Option<String>,
+    #[structopt(long)]
+    pub debug: bool,
+}
+
+/// Number of versions of each artifact selected in debug mode
+const DEBUG_VERSIONS: usize = 5;
+/// Checksum sidecars of every file
+const CHECKSUM_SUFFIXES: &[&str] = &[".sha1", ".md5"];
+
+lazy_static::lazy_static! {
+    static ref SNAPSHOT_VERSION: Regex =
+        Regex::new(r"(?s)<snapshotVersion>(.*?)</snapshotVersion>").unwrap();
+    static ref TEXT_ELEMENT: Regex = Regex::new(r"<(\w+)>\s*([^<]*?)\s*</(\w+)>").unwrap();
+}
+
+/// Text of all elements named `tag` in XML, e.g. `<version>1.0</version>`.
+fn tag_values<'a>(xml: &'a str, tag: &str) -> Vec<&'a str> {
+    TEXT_ELEMENT
+        .captures_iter(xml)
+        .filter(|cap| &cap[1] == tag && &cap[3] == tag)
+        .map(|cap| cap.get(2).unwrap().as_str())
+        .collect()
+}
+
+/// Path of artifact directory, e.g. `org/slf4j/slf4j-api` for
+/// `org.slf4j:slf4j-api`.
+fn artifact_path(coordinate: &str) -> Result<(String, &str)> {
+    match coordinate.split(':').collect::<Vec<_>>()[..] {
+        [group, artifact] if !group.is_empty() && !artifact.is_empty() => Ok((
+            format!("{}/{}", group.replace('.', "/"), artifact),
+            artifact,
+        )),
+        _ => Err(Error::ConfigureError(format!(
+            "artifact should be group:artifact: {}",
+            coordinate
+        ))),
+    }
+}
+
+/// File names of SNAPSHOT version, from `maven-metadata.xml` of it. Files
+/// listed in `snapshotVersions` are taken, or jar and pom of the latest
+/// build in older metadata without it.
+fn snapshot_files(artifact: &str, version: &str, metadata: &str) -> Vec<String> {
+    let files: Vec<_> = SNAPSHOT_VERSION
+        .captures_iter(metadata)
+        .filter_map(|cap| {
+            let block = cap.get(1).unwrap().as_str();
+            let extension = *tag_values(block, "extension").first()?;
+            let value = *tag_values(block, "value").first()?;
+            Some(match tag_values(block, "classifier").first() {
+                Some(classifier) => format!("{}-{}-{}.{}", artifact, value, classifier, extension),
+                None => format!("{}-{}.{}", artifact, value, extension),
+            })
+        })
+        .collect();
+    if !files.is_empty() {
+        return files;
+    }
+    let build = tag_values(metadata, "timestamp")
+        .first()
+        .zip(tag_values(metadata, "buildNumber").first())
+        .map(|(timestamp, build_number)| {
+            version.replace("SNAPSHOT", &format!("{}-{}", timestamp, build_number))
+        })
+        .unwrap_or_else(|| version.to_string());
+    vec![
+        format!("{}-{}.jar", artifact, build),
+        format!("{}-{}.pom", artifact, build),
+    ]
+}
+
+/// Keys of file and its checksum sidecars.
+fn with_checksums(key: String) -> impl Iterator<Item = String> {
+    let sidecars: Vec<_> = CHECKSUM_SUFFIXES
+        .iter()
+        .map(|suffix| format!("{}{}", key, suffix))
+        .collect();
+    std::iter::once(key).chain(sidecars)
+}
+
+impl Maven {
+    async fn get(&self, mission: &Mission, path: &str) -> Result<String> {
+        throttle(&mission.rate_limit).await;
+        let response = mission
+            .client
+            .get(format!("{}/{}", self.repo_base, path))
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(Error::HTTPError(response.status()));
+        }
+        Ok(response.text().await?)
+    }
+
+    /// Keys of all versions of artifact.
+    async fn artifact_keys(
+        &self,
+        mission: &Mission,
+        coordinate: &str,
+    ) -> Result<Vec<SnapshotPath>> {
+        let (path, artifact) = artifact_path(coordinate)?;
+        let metadata_key = format!("{}/maven-metadata.xml", path);
+        let metadata = self.get(mission, &metadata_key).await?;
+        let mut versions = tag_values(&metadata, "version");
+        // `<version>` of a plugin may appear outside of `<versions>`
+        versions.sort_unstable();
+        versions.dedup();
+        if self.debug {
+            versions.truncate(DEBUG_VERSIONS);
+        }
+
+        // metadata is updated on every release
+        let mut keys: Vec<_> = with_checksums(metadata_key)
+            .map(SnapshotPath::force)
+            .collect();
+        for version in versions {
+            let files = if version.ends_with("-SNAPSHOT") {
+                // metadata of SNAPSHOT version is updated on every build
+                let metadata_key = format!("{}/{}/maven-metadata.xml", path, version);
+                let metadata = self.get(mission, &metadata_key).await?;
+                keys.extend(with_checksums(metadata_key).map(SnapshotPath::force));
+                snapshot_files(artifact, version, &metadata)
+            } else {
+                vec![
+                    format!("{}-{}.jar", artifact, version),
+                    format!("{}-{}.pom", artifact, version),
+                ]
+            };
+            keys.extend(
+                files
+                    .into_iter()
+                    .flat_map(|file| with_checksums(format!("{}/{}/{}", path, version, file)))
+                    .map(SnapshotPath::new),
+            );
+        }
+        Ok(keys)
+    }
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotPath> for Maven {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotPath>> {
+        let logger = mission.logger.clone();
+        let progress = mission.progress.clone();
+
+        let mut coordinates = self.artifact.clone();
+        if let Some(artifact_list) = &self.artifact_list {
+            coordinates.extend(
+                tokio::fs::read_to_string(artifact_list)
+                    .await?
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                    .map(ToString::to_string),
+            );
+        }
+        for coordinate in &coordinates {
+            artifact_path(coordinate)?;
+        }
+
+        info!(
+            logger,
+            "{} artifacts, fetching metadata...",
+            coordinates.len()
+        );
+        progress.set_length(coordinates.len() as u64);
+
+        let this = &*self;
+        let mission = &mission;
+        let snapshots: Vec<Vec<SnapshotPath>> =
+            stream::iter(coordinates.into_iter().map(|coordinate| async move {
+                mission.progress.set_message(&coordinate);
+                let result = this.artifact_keys(mission, &coordinate).await;
+                mission.progress.inc(1);
+                match result {
+                    Ok(keys) => Ok(keys),
+                    Err(err) if err.is_gone() => {
+                        warn!(
+                            mission.logger,
+                            "metadata of {} unavailable, skipped", coordinate;
+                            "path" => &coordinate,
+                            "error" => format!("{:?}", err)
+                        );
+                        Ok(vec![])
+                    }
+                    Err(err) => {
+                        warn!(
+                            mission.logger,
+                            "failed to fetch metadata of {}, aborting snapshot", coordinate;
+                            "path" => &coordinate,
+                            "error" => format!("{:?}", err)
+                        );
+                        Err(err)
+                    }
+                }
+            }))
+            .buffer_unordered(config.concurrent_resolve)
+            .try_collect()
+            .await?;
+
+        progress.finish_with_message("done");
+
+        Ok(snapshots.into_iter().flatten().collect())
+    }
+
+    async fn check(&mut self, mission: Mission, _config: &SnapshotConfig) -> Result<String> {
+        check_url(&mission, &self.repo_base).await
+    }
+
+    fn info(&self) -> String {
+        format!("maven, {:?}", self)
+    }
+}
+
+#[async_trait]
+impl SourceStorage<SnapshotPath, TransferURL> for Maven {
+    async fn get_object(&self, snapshot: &SnapshotPath, _mission: &Mission) -> Result<TransferURL> {
+        Ok(TransferURL::new(format!(
+            "{}/{}",
+            self.repo_base, snapshot.0
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_metadata() {
+        let metadata = r#"<metadata>
+            <groupId>org.slf4j</groupId>
+            <artifactId>slf4j-api</artifactId>
+            <versioning>
+                <latest>2.0.0-SNAPSHOT</latest>
+                <versions>
+                    <version>1.7.30</version>
+                    <version> 2.0.0-SNAPSHOT </version>
+                </versions>
+            </versioning>
+        </metadata>"#;
+        assert_eq!(
+            tag_values(metadata, "version"),
+            vec!["1.7.30", "2.0.0-SNAPSHOT"]
+        );
+        assert_eq!(
+            artifact_path("org.slf4j:slf4j-api").unwrap(),
+            ("org/slf4j/slf4j-api".to_string(), "slf4j-api")
+        );
+        assert!(artifact_path("org.slf4j").is_err());
+
+        let snapshot = r#"<metadata>
+            <versioning>
+                <snapshot>
+                    <timestamp>20210101.000000</timestamp>
+                    <buildNumber>3</buildNumber>
+                </snapshot>
+                <snapshotVersions>
+                    <snapshotVersion>
+                        <extension>jar</extension>
+                        <value>2.0.0-20210101.000000-3</value>
+                    </snapshotVersion>
+                    <snapshotVersion>
+                        <classifier>sources</classifier>
+                        <extension>jar</extension>
+                        <value>2.0.0-20210101.000000-3</value>
+                    </snapshotVersion>
+                </snapshotVersions>
+            </versioning>
+        </metadata>"#;
+        assert_eq!(
+            snapshot_files("slf4j-api", "2.0.0-SNAPSHOT", snapshot),
+            vec![
+                "slf4j-api-2.0.0-20210101.000000-3.jar",
+                "slf4j-api-2.0.0-20210101.000000-3-sources.jar"
+            ]
+        );
+        let legacy = "<snapshot><timestamp>20210101.000000</timestamp><buildNumber>3</buildNumber></snapshot>";
+        assert_eq!(
+            snapshot_files("slf4j-api", "2.0.0-SNAPSHOT", legacy),
+            vec![
+                "slf4j-api-2.0.0-20210101.000000-3.jar",
+                "slf4j-api-2.0.0-20210101.000000-3.pom"
+            ]
+        );
+        assert_eq!(
+            with_checksums("a.jar".to_string()).collect::<Vec<_>>(),
+            vec!["a.jar", "a.jar.sha1", "a.jar.md5"]
+        );
+    }
+}
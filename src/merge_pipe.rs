@@ -82,9 +82,19 @@ where
         Ok(snapshot1)
     }
 
+    async fn check(&mut self, mission: Mission, config: &SnapshotConfig) -> Result<String> {
+        let s1 = self.s1.check(mission.clone(), config).await?;
+        let s2 = self.s2.check(mission, config).await?;
+        Ok(format!("{}; {}", s1, s2))
+    }
+
     fn info(&self) -> String {
         format!("MergePipe (<{}>, <{}>)", self.s1.info(), self.s2.info())
     }
+
+    fn cacheable(&self) -> bool {
+        self.s1.cacheable() && self.s2.cacheable()
+    }
 }
 
 #[async_trait]
@@ -1,16 +1,18 @@
-use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
 
-use crate::common::{Mission, SnapshotConfig, SnapshotPath};
-use crate::error::Result;
-use crate::traits::{Diff, Key, Metadata, SnapshotStorage};
+use crate::traits::{Diff, Key, Metadata};
 
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct SnapshotMetaFlag {
     pub force: bool,
     pub force_last: bool,
+    /// Transferred after all objects with `force_last`, e.g. files which
+    /// sign the indexes
+    #[serde(default)]
+    pub force_final: bool,
 }
 
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct SnapshotMeta {
     pub key: String,
     pub size: Option<u64>,
@@ -33,37 +35,13 @@ impl SnapshotMeta {
             flags: SnapshotMetaFlag {
                 force: true,
                 force_last: true,
+                force_final: false,
             },
             ..Default::default()
         }
     }
 }
 
-pub struct MetaAsPath<Source: SnapshotStorage<SnapshotMeta> + std::fmt::Debug + std::marker::Send> {
-    source: Source,
-}
-
-#[async_trait]
-impl<Source: SnapshotStorage<SnapshotMeta> + std::fmt::Debug + std::marker::Send>
-    SnapshotStorage<SnapshotPath> for MetaAsPath<Source>
-{
-    async fn snapshot(
-        &mut self,
-        mission: Mission,
-        config: &SnapshotConfig,
-    ) -> Result<Vec<SnapshotPath>> {
-        let snapshot = self.source.snapshot(mission, config).await?;
-        Ok(snapshot
-            .into_iter()
-            .map(|item| SnapshotPath::new(item.key))
-            .collect())
-    }
-
-    fn info(&self) -> String {
-        format!("as snapshot path, {:?}", self.source)
-    }
-}
-
 impl Key for SnapshotMeta {
     fn key(&self) -> &str {
         &self.key
@@ -81,6 +59,10 @@ fn compare_option<T: Eq>(a: &Option<T>, b: &Option<T>) -> bool {
     }
 }
 
+/// An object differs if size, modified time or checksum known on both sides
+/// differ, e.g. a file rebuilt upstream under the same name. Metadata known
+/// on one side only is not compared, so sources without sizes fall back to
+/// comparing paths.
 impl Diff for SnapshotMeta {
     fn diff(&self, other: &Self) -> bool {
         if !compare_option(&self.size, &other.size) {
@@ -104,7 +86,9 @@ impl Diff for SnapshotMeta {
 
 impl Metadata for SnapshotMeta {
     fn priority(&self) -> isize {
-        if self.flags.force_last {
+        if self.flags.force_final {
+            -2
+        } else if self.flags.force_last {
             -1
         } else {
             0
@@ -114,4 +98,40 @@ impl Metadata for SnapshotMeta {
     fn last_modified(&self) -> Option<u64> {
         self.last_modified
     }
+
+    fn size(&self) -> Option<u64> {
+        self.size
+    }
+
+    fn set_size(&mut self, size: u64) {
+        self.size = Some(size);
+    }
+
+    fn checksum(&self) -> Option<(&str, &str)> {
+        match (&self.checksum_method, &self.checksum) {
+            (Some(method), Some(checksum)) => Some((method, checksum)),
+            _ => None,
+        }
+    }
+
+    fn forced(&self) -> bool {
+        self.flags.force
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff() {
+        let meta = |size: Option<u64>| SnapshotMeta {
+            size,
+            ..SnapshotMeta::new("a.tar.gz".to_string())
+        };
+        assert!(meta(Some(1)).diff(&meta(Some(2))));
+        assert!(!meta(Some(1)).diff(&meta(Some(1))));
+        assert!(!meta(None).diff(&meta(Some(2))));
+        assert!(SnapshotMeta::force("Release".to_string()).diff(&meta(None)));
+    }
 }
@@ -0,0 +1,126 @@
+//! Metrics
+//!
+//! If a metrics address is configured, counters of a transfer are served in
+//! Prometheus text format on every path of it, so that long-running mirror
+//! jobs may be scraped. The server is a minimal HTTP/1.1 responder, and is
+//! only bound when an address is given.
+//!
+//! Bytes transferred are counted as they are downloaded from source, so that
+//! objects of unknown size count as well, and a large object counts while it
+//! is in progress. Bytes of attempts which fail later are counted too.
+
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Arc;
+
+use slog::{info, warn, Logger};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+use crate::error::Result;
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub transferred: AtomicU64,
+    pub failed: AtomicU64,
+    pub deleted: AtomicU64,
+    pub bytes_transferred: Arc<AtomicU64>,
+    pub in_progress: AtomicI64,
+}
+
+impl Metrics {
+    /// Metrics in Prometheus text format.
+    pub fn render(&self) -> String {
+        let counters = [
+            (
+                "mirror_clone_objects_transferred_total",
+                "Objects transferred to target",
+                &self.transferred,
+            ),
+            (
+                "mirror_clone_objects_failed_total",
+                "Objects failed to transfer or delete",
+                &self.failed,
+            ),
+            (
+                "mirror_clone_objects_deleted_total",
+                "Objects deleted from target",
+                &self.deleted,
+            ),
+            (
+                "mirror_clone_bytes_transferred_total",
+                "Bytes downloaded from source",
+                &self.bytes_transferred,
+            ),
+        ];
+        let mut content = String::new();
+        for (name, help, value) in counters {
+            content.push_str(&format!(
+                "# HELP {name} {help}\n# TYPE {name} counter\n{name} {}\n",
+                value.load(Ordering::Relaxed)
+            ));
+        }
+        let name = "mirror_clone_transfers_in_progress";
+        content.push_str(&format!(
+            "# HELP {name} Objects being transferred\n# TYPE {name} gauge\n{name} {}\n",
+            self.in_progress.load(Ordering::Relaxed)
+        ));
+        content
+    }
+}
+
+/// Serve metrics on `listener` in background, until the process exits.
+pub async fn serve(listener: TcpListener, metrics: Arc<Metrics>, logger: Logger) -> Result<()> {
+    info!(logger, "serving metrics on {}", listener.local_addr()?);
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = match listener.accept().await {
+                Ok(conn) => conn,
+                Err(err) => {
+                    warn!(logger, "failed to accept metrics connection: {:?}", err);
+                    continue;
+                }
+            };
+            let metrics = metrics.clone();
+            tokio::spawn(async move {
+                // request is not parsed, every path serves metrics
+                let mut request = [0; 1024];
+                let _ = stream.read(&mut request).await;
+                let body = metrics.render();
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+                let _ = stream.shutdown().await;
+            });
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_serve() {
+        let metrics = Arc::new(Metrics::default());
+        metrics.transferred.fetch_add(3, Ordering::Relaxed);
+        metrics.bytes_transferred.fetch_add(1024, Ordering::Relaxed);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let logger = Logger::root(slog::Discard, slog::o!());
+        serve(listener, metrics, logger).await.unwrap();
+
+        let content = reqwest::get(format!("http://{}/metrics", addr))
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert!(content.contains("\nmirror_clone_objects_transferred_total 3\n"));
+        assert!(content.contains("\nmirror_clone_bytes_transferred_total 1024\n"));
+        assert!(content.contains("\nmirror_clone_transfers_in_progress 0\n"));
+    }
+}
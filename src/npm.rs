@@ -0,0 +1,276 @@
+//! npm Source
+//!
+//! npm source lists all packages with `_all_docs` of the registry (or a
+//! replica of it), page by page. Then, it fetches abbreviated metadata of
+//! each package, and takes tarball of every version. Keys are tarball paths
+//! relative to registry base, e.g. `@babel/core/-/core-7.0.0.tgz`. Name of
+//! scoped package is escaped when fetching its metadata, as `@babel%2fcore`.
+//!
+//! SHA-1 checksum of each tarball is recorded in snapshot. A package whose
+//! metadata is gone (e.g. unpublished) is skipped, and other failures abort
+//! the snapshot, so that its tarballs are not deleted from mirror.
+
+use crate::common::{Mission, SnapshotConfig, TransferURL};
+use crate::error::{Error, Result};
+use crate::metadata::SnapshotMeta;
+use crate::rate_limit::throttle;
+use crate::traits::{SnapshotStorage, SourceStorage};
+use crate::utils::check_url;
+
+use async_trait::async_trait;
+use futures_util::{stream, StreamExt, TryStreamExt};
+use serde::Deserialize;
+use slog::{info, warn};
+use std::collections::BTreeMap;
+use structopt::StructOpt;
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct Npm {
+    #[structopt(long, default_value = "https://registry.npmjs.org")]
+    pub registry_base: String,
+    #[structopt(
+        long,
+        help = "Registry to list packages with _all_docs",
+        default_value = "https://replicate.npmjs.com"
+    )]
+    pub all_docs_base: String,
+    #[structopt(long)]
+    pub debug: bool,
+}
+
+/// Rows of `_all_docs` fetched in one page
+const ALL_DOCS_PAGE: usize = 10000;
+/// Number of packages selected in debug mode
+const DEBUG_PACKAGES: usize = 100;
+/// Abbreviated package metadata, which only contains fields for installing
+const NPM_ABBREVIATED_ACCEPT: &str = "application/vnd.npm.install-v1+json";
+
+#[derive(Deserialize, Debug)]
+struct AllDocs {
+    rows: Vec<AllDocsRow>,
+}
+
+#[derive(Deserialize, Debug)]
+struct AllDocsRow {
+    id: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct NpmPackage {
+    #[serde(default)]
+    versions: BTreeMap<String, NpmVersion>,
+}
+
+#[derive(Deserialize, Debug)]
+struct NpmVersion {
+    dist: NpmDist,
+}
+
+#[derive(Deserialize, Debug)]
+struct NpmDist {
+    tarball: String,
+    shasum: Option<String>,
+}
+
+/// URL of package metadata. The scope separator of scoped package is
+/// escaped, as required by registry.
+fn package_url(registry_base: &str, name: &str) -> String {
+    format!("{}/{}", registry_base, name.replacen('/', "%2f", 1))
+}
+
+/// Parse tarballs of all versions in package metadata. Tarballs not under
+/// registry base are skipped.
+fn parse_package(registry_base: &str, content: &str) -> Result<(Vec<SnapshotMeta>, usize)> {
+    let package: NpmPackage = serde_json::from_str(content)?;
+    let prefix = format!("{}/", registry_base);
+    let mut skipped = 0;
+    let tarballs = package
+        .versions
+        .into_values()
+        .filter_map(|version| match version.dist.tarball.strip_prefix(&prefix) {
+            Some(key) => Some(SnapshotMeta {
+                key: key.to_string(),
+                checksum_method: version.dist.shasum.as_ref().map(|_| "sha1".to_string()),
+                checksum: version.dist.shasum.map(|shasum| shasum.to_lowercase()),
+                ..Default::default()
+            }),
+            None => {
+                skipped += 1;
+                None
+            }
+        })
+        .collect();
+    Ok((tarballs, skipped))
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotMeta> for Npm {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotMeta>> {
+        let logger = mission.logger;
+        let progress = mission.progress;
+        let client = mission.client;
+        let rate_limit = mission.rate_limit;
+
+        info!(logger, "fetching packages...");
+        let all_docs = format!("{}/_all_docs", self.all_docs_base);
+        let mut packages: Vec<String> = vec![];
+        let mut page: usize = 1;
+
+        loop {
+            let mut request = client
+                .get(&all_docs)
+                .query(&[("limit", ALL_DOCS_PAGE.to_string())]);
+            if let Some(last) = packages.last() {
+                request = request.query(&[
+                    ("startkey", serde_json::to_string(last)?),
+                    ("skip", "1".to_string()),
+                ]);
+            }
+            throttle(&rate_limit).await;
+            let data: AllDocs = request.send().await?.error_for_status()?.json().await?;
+            let rows = data.rows.len();
+            packages.extend(data.rows.into_iter().map(|row| row.id));
+
+            progress.set_message(&format!(
+                "fetching page {}, total packages = {}",
+                page,
+                packages.len()
+            ));
+            page += 1;
+            if rows < ALL_DOCS_PAGE || (self.debug && packages.len() >= DEBUG_PACKAGES) {
+                break;
+            }
+        }
+
+        // design documents of CouchDB are not packages
+        packages.retain(|name| !name.starts_with("_design/"));
+        if self.debug {
+            packages.truncate(DEBUG_PACKAGES);
+        }
+
+        info!(logger, "{} packages, fetching metadata...", packages.len());
+        progress.inc_length(packages.len() as u64);
+
+        let registry_base = self.registry_base.clone();
+        let snapshots: Result<Vec<Vec<SnapshotMeta>>> =
+            stream::iter(packages.into_iter().map(|name| {
+                let client = client.clone();
+                let progress = progress.clone();
+                let logger = logger.clone();
+                let rate_limit = rate_limit.clone();
+                let registry_base = registry_base.clone();
+                let package_logger = logger.clone();
+
+                let func = async move {
+                    progress.set_message(&name);
+                    throttle(&rate_limit).await;
+                    let response = client
+                        .get(package_url(&registry_base, &name))
+                        .header(reqwest::header::ACCEPT, NPM_ABBREVIATED_ACCEPT)
+                        .send()
+                        .await?;
+                    if !response.status().is_success() {
+                        return Err(Error::HTTPError(response.status()));
+                    }
+                    let package = response.text().await?;
+                    let (tarballs, skipped) = parse_package(&registry_base, &package)?;
+                    if skipped != 0 {
+                        warn!(
+                            package_logger,
+                            "{} tarballs of {} are not under registry base", skipped, name
+                        );
+                    }
+                    progress.inc(1);
+                    Ok::<Vec<SnapshotMeta>, Error>(tarballs)
+                };
+                async move {
+                    match func.await {
+                        Ok(x) => Ok(x),
+                        Err(err) if err.is_gone() => {
+                            warn!(logger, "package meta unavailable, skipped {:?}", err);
+                            Ok(vec![])
+                        }
+                        Err(err) => {
+                            warn!(
+                                logger,
+                                "failed to fetch package meta, aborting snapshot {:?}", err
+                            );
+                            Err(err)
+                        }
+                    }
+                }
+            }))
+            .buffer_unordered(config.concurrent_resolve)
+            .try_collect()
+            .await;
+
+        let snapshot: Vec<_> = snapshots?.into_iter().flatten().collect();
+
+        progress.finish_with_message("done");
+
+        Ok(snapshot)
+    }
+
+    async fn check(&mut self, mission: Mission, _config: &SnapshotConfig) -> Result<String> {
+        check_url(&mission, &self.registry_base).await
+    }
+
+    fn info(&self) -> String {
+        format!("npm, {:?}", self)
+    }
+}
+
+#[async_trait]
+impl SourceStorage<SnapshotMeta, TransferURL> for Npm {
+    async fn get_object(&self, snapshot: &SnapshotMeta, _mission: &Mission) -> Result<TransferURL> {
+        Ok(TransferURL::new(format!(
+            "{}/{}",
+            self.registry_base, snapshot.key
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_package() {
+        let base = "https://registry.npmjs.org";
+        assert_eq!(
+            package_url(base, "@babel/core"),
+            "https://registry.npmjs.org/@babel%2fcore"
+        );
+        assert_eq!(
+            package_url(base, "left-pad"),
+            "https://registry.npmjs.org/left-pad"
+        );
+
+        let content = r#"{
+            "name": "@babel/core",
+            "versions": {
+                "7.0.0": {
+                    "dist": {
+                        "tarball": "https://registry.npmjs.org/@babel/core/-/core-7.0.0.tgz",
+                        "shasum": "ABCDEF"
+                    }
+                },
+                "7.0.1": {
+                    "dist": {
+                        "tarball": "https://other.example.com/core-7.0.1.tgz"
+                    }
+                }
+            }
+        }"#;
+        let (tarballs, skipped) = parse_package(base, content).unwrap();
+        assert_eq!(skipped, 1);
+        assert_eq!(tarballs.len(), 1);
+        assert_eq!(tarballs[0].key, "@babel/core/-/core-7.0.0.tgz");
+        assert_eq!(tarballs[0].checksum_method.as_deref(), Some("sha1"));
+        assert_eq!(tarballs[0].checksum.as_deref(), Some("abcdef"));
+    }
+}
@@ -0,0 +1,489 @@
+//! OCI registry source
+//!
+//! OciRegistry mirrors blobs of repositories in a docker / OCI registry.
+//! Repositories are given on command line, or listed from `_catalog` of
+//! registry. For each tag of a repository, its manifest is fetched, and if
+//! it's a manifest list (or image index), manifest of every platform is
+//! fetched as well. Keys are digest-based blob paths, e.g.
+//! `library/ubuntu/blobs/sha256/<hex>`, which cover manifests, configs and
+//! layers. A layer shared by several tags is only one snapshot entry. A tag
+//! removed while taking snapshot is skipped, and other failures of fetching
+//! manifests abort the snapshot.
+//!
+//! Registries authorize pulls with bearer tokens. A request challenged with
+//! `WWW-Authenticate` takes a token from the given realm, and tokens are
+//! cached per repository until they are rejected.
+//!
+//! Source objects are pull URLs of blobs. Most registries redirect blob
+//! pulls to a storage URL, which is taken as the source object, so that
+//! blobs may be downloaded without token. Registries serving blobs directly
+//! must allow anonymous pulls.
+
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+
+use crate::checksum::Checksum;
+use crate::common::{Mission, SnapshotConfig, SnapshotPath, TransferURL};
+use crate::error::{Error, Result};
+use crate::rate_limit::throttle;
+use crate::traits::{SnapshotStorage, SourceStorage};
+
+use async_trait::async_trait;
+use futures_util::{stream, StreamExt, TryStreamExt};
+use reqwest::{Client, Response, StatusCode};
+use serde::Deserialize;
+use slog::{info, warn};
+use structopt::StructOpt;
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct OciRegistry {
+    #[structopt(long, default_value = "https://registry-1.docker.io")]
+    pub registry_base: String,
+    #[structopt(
+        long,
+        number_of_values = 1,
+        help = "Repository to mirror, e.g. library/ubuntu, may be specified multiple times. All repositories in catalog are mirrored if none is given"
+    )]
+    pub repository: Vec<String>,
+    #[structopt(long)]
+    pub debug: bool,
+    /// Bearer tokens by repository, or by empty string for catalog
+    #[structopt(skip)]
+    tokens: Arc<Mutex<HashMap<String, String>>>,
+    /// Client which doesn't follow redirects of blob pulls, built from
+    /// transfer config by main
+    #[structopt(skip)]
+    pub blob_client: Option<Client>,
+}
+
+/// Number of tags of each repository selected in debug mode
+const DEBUG_TAGS: usize = 5;
+/// Repositories listed from catalog in one page
+const CATALOG_PAGE: usize = 1000;
+/// Manifest types accepted, including manifest lists and image indexes
+const MANIFEST_ACCEPT: &str = "application/vnd.docker.distribution.manifest.list.v2+json, \
+    application/vnd.oci.image.index.v1+json, \
+    application/vnd.docker.distribution.manifest.v2+json, \
+    application/vnd.oci.image.manifest.v1+json";
+
+#[derive(Deserialize, Debug)]
+struct Catalog {
+    #[serde(default)]
+    repositories: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct TagList {
+    #[serde(default)]
+    tags: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Token {
+    token: Option<String>,
+    access_token: Option<String>,
+}
+
+/// Image manifest, or manifest list if `manifests` is not empty.
+#[derive(Deserialize, Debug)]
+struct Manifest {
+    #[serde(default)]
+    manifests: Vec<Descriptor>,
+    config: Option<Descriptor>,
+    #[serde(default)]
+    layers: Vec<Descriptor>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Descriptor {
+    digest: String,
+}
+
+/// Key of blob with `digest` in repository, e.g. `sha256:abc` is stored at
+/// `library/ubuntu/blobs/sha256/abc`.
+fn blob_key(repository: &str, digest: &str) -> String {
+    format!("{}/blobs/{}", repository, digest.replacen(':', "/", 1))
+}
+
+/// Repository and digest of blob key, inverse of `blob_key`.
+fn parse_blob_key(key: &str) -> Option<(&str, String)> {
+    let (repository, digest) = key.rsplit_once("/blobs/")?;
+    let (algorithm, hex) = digest.split_once('/')?;
+    Some((repository, format!("{}:{}", algorithm, hex)))
+}
+
+/// Parse a bearer challenge, e.g. `Bearer realm="https://auth.docker.io/token",
+/// service="registry.docker.io",scope="repository:library/ubuntu:pull"`, into
+/// realm and other parameters.
+fn parse_challenge(header: &str) -> Option<(String, Vec<(String, String)>)> {
+    let params = header.strip_prefix("Bearer ")?;
+    let mut realm = None;
+    let mut query = vec![];
+    let mut rest = params.trim();
+    while !rest.is_empty() {
+        let (name, value) = rest.split_once('=')?;
+        let value = value.strip_prefix('"')?;
+        let (value, next) = value.split_once('"')?;
+        let name = name.trim().to_string();
+        if name == "realm" {
+            realm = Some(value.to_string());
+        } else {
+            query.push((name, value.to_string()));
+        }
+        rest = next.trim_start_matches(',').trim();
+    }
+    Some((realm?, query))
+}
+
+/// URL of next page in `Link` header, e.g. `</v2/_catalog?last=a&n=1000>;
+/// rel="next"`.
+fn next_link(registry_base: &str, response: &Response) -> Option<String> {
+    let link = response
+        .headers()
+        .get(reqwest::header::LINK)?
+        .to_str()
+        .ok()?;
+    let (target, rel) = link.split_once(';')?;
+    if !rel.contains("rel=\"next\"") {
+        return None;
+    }
+    let target = target.trim().strip_prefix('<')?.strip_suffix('>')?;
+    if target.starts_with('/') {
+        Some(format!("{}{}", registry_base, target))
+    } else {
+        Some(target.to_string())
+    }
+}
+
+/// Digests referenced by manifest, including child manifests of a manifest
+/// list.
+fn parse_manifest(content: &[u8]) -> Result<(Vec<String>, Vec<String>)> {
+    let manifest: Manifest = serde_json::from_slice(content)?;
+    let children = manifest
+        .manifests
+        .into_iter()
+        .map(|descriptor| descriptor.digest)
+        .collect();
+    let blobs = manifest
+        .config
+        .into_iter()
+        .chain(manifest.layers)
+        .map(|descriptor| descriptor.digest)
+        .collect();
+    Ok((children, blobs))
+}
+
+impl OciRegistry {
+    /// Send a GET request authorized for `repository`. If registry challenges
+    /// it, a token is taken from realm, and the request is sent again.
+    async fn get(
+        &self,
+        client: &Client,
+        repository: &str,
+        url: &str,
+        accept: Option<&str>,
+    ) -> Result<Response> {
+        let send = |token: Option<String>| {
+            let mut request = client.get(url);
+            if let Some(accept) = accept {
+                request = request.header(reqwest::header::ACCEPT, accept);
+            }
+            if let Some(token) = token {
+                request = request.bearer_auth(token);
+            }
+            request.send()
+        };
+        let token = self.tokens.lock().unwrap().get(repository).cloned();
+        let response = send(token).await?;
+        if response.status() != StatusCode::UNAUTHORIZED {
+            return Ok(response);
+        }
+        let (realm, query) = response
+            .headers()
+            .get(reqwest::header::WWW_AUTHENTICATE)
+            .and_then(|header| header.to_str().ok())
+            .and_then(parse_challenge)
+            .ok_or(Error::HTTPError(StatusCode::UNAUTHORIZED))?;
+        let token: Token = client
+            .get(&realm)
+            .query(&query)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let token = token
+            .token
+            .or(token.access_token)
+            .ok_or_else(|| Error::ProcessError(format!("no token from {}", realm)))?;
+        self.tokens
+            .lock()
+            .unwrap()
+            .insert(repository.to_string(), token.clone());
+        Ok(send(Some(token)).await?)
+    }
+
+    /// Get all pages of a paginated list, e.g. catalog or tags.
+    async fn get_pages<T: serde::de::DeserializeOwned>(
+        &self,
+        mission: &Mission,
+        repository: &str,
+        url: String,
+    ) -> Result<Vec<T>> {
+        let mut pages = vec![];
+        let mut url = Some(url);
+        while let Some(page_url) = url {
+            throttle(&mission.rate_limit).await;
+            let response = self
+                .get(&mission.client, repository, &page_url, None)
+                .await?
+                .error_for_status()?;
+            url = next_link(&self.registry_base, &response);
+            pages.push(response.json().await?);
+        }
+        Ok(pages)
+    }
+
+    /// Fetch manifest by tag or digest, and return its digest along with
+    /// digests it references.
+    async fn get_manifest(
+        &self,
+        mission: &Mission,
+        repository: &str,
+        reference: &str,
+    ) -> Result<(String, Vec<String>, Vec<String>)> {
+        throttle(&mission.rate_limit).await;
+        let url = format!(
+            "{}/v2/{}/manifests/{}",
+            self.registry_base, repository, reference
+        );
+        let response = self
+            .get(&mission.client, repository, &url, Some(MANIFEST_ACCEPT))
+            .await?;
+        if !response.status().is_success() {
+            return Err(Error::HTTPError(response.status()));
+        }
+        let digest = response
+            .headers()
+            .get("docker-content-digest")
+            .and_then(|digest| digest.to_str().ok())
+            .map(|digest| digest.to_string());
+        let content = response.bytes().await?;
+        let digest = digest.unwrap_or_else(|| {
+            let mut checksum = Checksum::new("sha256").unwrap();
+            checksum.update(&content);
+            format!("sha256:{}", checksum.finish())
+        });
+        let (children, blobs) = parse_manifest(&content)?;
+        Ok((digest, children, blobs))
+    }
+
+    /// Digests of manifests and blobs of a tag.
+    async fn tag_digests(
+        &self,
+        mission: &Mission,
+        repository: &str,
+        tag: &str,
+    ) -> Result<Vec<String>> {
+        let (digest, children, mut digests) = self.get_manifest(mission, repository, tag).await?;
+        digests.push(digest);
+        for child in children {
+            let (digest, _, blobs) = self.get_manifest(mission, repository, &child).await?;
+            digests.push(digest);
+            digests.extend(blobs);
+        }
+        Ok(digests)
+    }
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotPath> for OciRegistry {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotPath>> {
+        let logger = mission.logger.clone();
+        let progress = mission.progress.clone();
+
+        let mut repositories = self.repository.clone();
+        if repositories.is_empty() {
+            info!(logger, "fetching catalog...");
+            let url = format!("{}/v2/_catalog?n={}", self.registry_base, CATALOG_PAGE);
+            let pages: Vec<Catalog> = self.get_pages(&mission, "", url).await?;
+            repositories = pages
+                .into_iter()
+                .flat_map(|page| page.repositories)
+                .collect();
+        }
+        if self.debug {
+            repositories.truncate(1);
+        }
+
+        let mut tags = vec![];
+        for repository in &repositories {
+            progress.set_message(repository);
+            let url = format!("{}/v2/{}/tags/list", self.registry_base, repository);
+            let pages: Vec<TagList> = self.get_pages(&mission, repository, url).await?;
+            let mut repository_tags: Vec<_> = pages
+                .into_iter()
+                .flat_map(|page| page.tags.unwrap_or_default())
+                .collect();
+            if self.debug {
+                repository_tags.truncate(DEBUG_TAGS);
+            }
+            tags.extend(
+                repository_tags
+                    .into_iter()
+                    .map(|tag| (repository.clone(), tag)),
+            );
+        }
+
+        info!(
+            logger,
+            "{} tags in {} repositories, fetching manifests...",
+            tags.len(),
+            repositories.len()
+        );
+        progress.set_length(tags.len() as u64);
+
+        let this = &*self;
+        let mission = &mission;
+        let digests: Vec<(String, Vec<String>)> =
+            stream::iter(tags.into_iter().map(|(repository, tag)| async move {
+                let result = this.tag_digests(mission, &repository, &tag).await;
+                mission.progress.inc(1);
+                match result {
+                    Ok(digests) => Ok((repository, digests)),
+                    Err(err) if err.is_gone() => {
+                        warn!(
+                            mission.logger,
+                            "manifest of {}:{} unavailable, skipped", repository, tag;
+                            "error" => format!("{:?}", err)
+                        );
+                        Ok((repository, vec![]))
+                    }
+                    Err(err) => {
+                        warn!(
+                            mission.logger,
+                            "failed to fetch manifest of {}:{}, aborting snapshot", repository, tag;
+                            "error" => format!("{:?}", err)
+                        );
+                        Err(err)
+                    }
+                }
+            }))
+            .buffer_unordered(config.concurrent_resolve)
+            .try_collect()
+            .await?;
+
+        // blobs shared by tags are only taken once
+        let keys: BTreeSet<String> = digests
+            .iter()
+            .flat_map(|(repository, digests)| {
+                digests
+                    .iter()
+                    .map(move |digest| blob_key(repository, digest))
+            })
+            .collect();
+
+        progress.finish_with_message("done");
+
+        Ok(keys.into_iter().map(SnapshotPath::new).collect())
+    }
+
+    async fn check(&mut self, mission: Mission, _config: &SnapshotConfig) -> Result<String> {
+        throttle(&mission.rate_limit).await;
+        let url = format!("{}/v2/", self.registry_base);
+        let response = self.get(&mission.client, "", &url, None).await?;
+        if !response.status().is_success() {
+            return Err(Error::HTTPError(response.status()));
+        }
+        Ok(format!("{} {}", url, response.status()))
+    }
+
+    fn info(&self) -> String {
+        format!(
+            "oci registry, {} {:?}, debug: {}",
+            self.registry_base, self.repository, self.debug
+        )
+    }
+}
+
+#[async_trait]
+impl SourceStorage<SnapshotPath, TransferURL> for OciRegistry {
+    async fn get_object(&self, snapshot: &SnapshotPath, mission: &Mission) -> Result<TransferURL> {
+        let (repository, digest) = parse_blob_key(&snapshot.0)
+            .ok_or_else(|| Error::ProcessError(format!("not a blob key: {}", snapshot.0)))?;
+        let url = format!("{}/v2/{}/blobs/{}", self.registry_base, repository, digest);
+        // redirect is resolved here, as storage URL takes no token
+        let client = self
+            .blob_client
+            .as_ref()
+            .ok_or_else(|| Error::ProcessError("blob client not configured".to_string()))?;
+        throttle(&mission.rate_limit).await;
+        let response = self.get(client, repository, &url, None).await?;
+        let status = response.status();
+        if status.is_redirection() {
+            let location = response
+                .headers()
+                .get(reqwest::header::LOCATION)
+                .and_then(|location| location.to_str().ok())
+                .ok_or(Error::HTTPError(status))?;
+            let location = url::Url::parse(&url)
+                .and_then(|url| url.join(location))
+                .map_err(|err| Error::ProcessError(format!("invalid redirect: {:?}", err)))?;
+            Ok(TransferURL::new(location.to_string()))
+        } else if status.is_success() {
+            Ok(TransferURL::new(url))
+        } else {
+            Err(Error::HTTPError(status))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_registry() {
+        let key = blob_key("library/ubuntu", "sha256:abc");
+        assert_eq!(key, "library/ubuntu/blobs/sha256/abc");
+        assert_eq!(
+            parse_blob_key(&key),
+            Some(("library/ubuntu", "sha256:abc".to_string()))
+        );
+        assert_eq!(parse_blob_key("library/ubuntu/manifests/latest"), None);
+
+        let (realm, query) = parse_challenge(
+            r#"Bearer realm="https://auth.docker.io/token",service="registry.docker.io",scope="repository:library/ubuntu:pull""#,
+        )
+        .unwrap();
+        assert_eq!(realm, "https://auth.docker.io/token");
+        assert_eq!(
+            query,
+            vec![
+                ("service".to_string(), "registry.docker.io".to_string()),
+                (
+                    "scope".to_string(),
+                    "repository:library/ubuntu:pull".to_string()
+                )
+            ]
+        );
+        assert_eq!(parse_challenge("Basic realm=\"registry\""), None);
+
+        let (children, blobs) = parse_manifest(
+            br#"{"manifests": [{"digest": "sha256:amd64"}, {"digest": "sha256:arm64"}]}"#,
+        )
+        .unwrap();
+        assert_eq!(children, vec!["sha256:amd64", "sha256:arm64"]);
+        assert!(blobs.is_empty());
+        let (children, blobs) = parse_manifest(
+            br#"{"config": {"digest": "sha256:config"}, "layers": [{"digest": "sha256:layer"}]}"#,
+        )
+        .unwrap();
+        assert!(children.is_empty());
+        assert_eq!(blobs, vec!["sha256:config", "sha256:layer"]);
+    }
+}
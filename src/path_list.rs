@@ -0,0 +1,143 @@
+//! Path list source.
+//!
+//! PathList is a source storage which takes keys from a file, one per line,
+//! and yields TransferURL against a configurable base. If path list is `-`,
+//! keys are read from stdin. Together with `--failed-list -` of simple diff
+//! transfer, objects failed in a previous run may be re-attempted without a
+//! full snapshot, e.g. by looping until failed list is empty. With
+//! `--from-report`, path list is read as a `--report-json` report instead,
+//! and its failed paths are taken.
+//!
+//! As snapshot only covers listed keys, deletion is always disabled when
+//! transferring from path list. Index pages are not generated either.
+//!
+//! PathList supports path snapshot. With `--force`, listed objects are
+//! transferred even if they exist on target.
+
+use crate::common::{Mission, SnapshotConfig, SnapshotPath, TransferURL};
+use crate::error::{Error, Result};
+use crate::traits::{SnapshotStorage, SourceStorage};
+
+use async_trait::async_trait;
+use slog::info;
+use structopt::StructOpt;
+use tokio::io::AsyncReadExt;
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct PathList {
+    /// File of keys to transfer, one per line, or `-` for stdin
+    #[structopt(
+        long,
+        default_value = "-",
+        help = "File of keys to transfer, `-` for stdin"
+    )]
+    pub path_list: String,
+    /// Base of objects in path list
+    #[structopt(long, help = "Base URL of objects in path list")]
+    pub base: String,
+    /// Transfer listed objects even if they exist on target
+    #[structopt(long, help = "Transfer listed objects even if they exist on target")]
+    pub force: bool,
+    /// Read path list as JSON report of a previous run, taking failed paths
+    #[structopt(
+        long,
+        help = "Read path list as JSON report of a previous run, taking its failed paths"
+    )]
+    pub from_report: bool,
+}
+
+fn parse_path_list(content: &str, force: bool) -> Vec<SnapshotPath> {
+    to_snapshot(content.lines(), force)
+}
+
+/// Failed paths of a report written by `--report-json`.
+fn parse_report(content: &str, force: bool) -> Result<Vec<SnapshotPath>> {
+    let report: serde_json::Value = serde_json::from_str(content)?;
+    let paths = report["failed_paths"]
+        .as_array()
+        .ok_or_else(|| Error::ProcessError("no failed paths in report".to_string()))?
+        .iter()
+        .filter_map(|path| path.as_str());
+    Ok(to_snapshot(paths, force))
+}
+
+fn to_snapshot<'a>(paths: impl Iterator<Item = &'a str>, force: bool) -> Vec<SnapshotPath> {
+    paths
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let key = line.trim_start_matches('/').to_string();
+            if force {
+                SnapshotPath::force(key)
+            } else {
+                SnapshotPath::new(key)
+            }
+        })
+        .collect()
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotPath> for PathList {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        _config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotPath>> {
+        let logger = mission.logger;
+        let progress = mission.progress;
+
+        let content = if self.path_list == "-" {
+            info!(logger, "reading path list from stdin...");
+            let mut content = String::new();
+            tokio::io::stdin().read_to_string(&mut content).await?;
+            content
+        } else {
+            info!(logger, "reading path list from {}...", self.path_list);
+            tokio::fs::read_to_string(&self.path_list).await?
+        };
+
+        let snapshot = if self.from_report {
+            parse_report(&content, self.force)?
+        } else {
+            parse_path_list(&content, self.force)
+        };
+        info!(logger, "{} paths in list", snapshot.len());
+        progress.finish_with_message("done");
+
+        Ok(snapshot)
+    }
+
+    fn info(&self) -> String {
+        format!("path list, {:?}", self)
+    }
+}
+
+#[async_trait]
+impl SourceStorage<SnapshotPath, TransferURL> for PathList {
+    async fn get_object(&self, snapshot: &SnapshotPath, _mission: &Mission) -> Result<TransferURL> {
+        Ok(TransferURL::new(format!("{}/{}", self.base, snapshot.0)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_path_list() {
+        let snapshot = parse_path_list("a/b.tar.gz\n\n  /c.txt \n", false);
+        assert_eq!(snapshot.len(), 2);
+        assert_eq!(snapshot[0].0, "a/b.tar.gz");
+        assert!(!snapshot[0].1);
+        assert_eq!(snapshot[1].0, "c.txt");
+
+        let snapshot = parse_path_list("a\n", true);
+        assert!(snapshot[0].1);
+
+        let snapshot =
+            parse_report(r#"{"failed": 2, "failed_paths": ["a/b", "/c"]}"#, false).unwrap();
+        assert_eq!(snapshot.len(), 2);
+        assert_eq!(snapshot[1].0, "c");
+        assert!(parse_report("{}", false).is_err());
+    }
+}
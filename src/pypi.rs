@@ -3,19 +3,48 @@
 //! Pypi is a source storage which scans PyPI. The snapshot is generated by first
 //! scanning the package index, then scanning index of every package. This only takes
 //! about 5 minutes on SJTUG server, where we fetch data from TUNA mirrors.
-//! A PyPI link may contain checksum in its URL, and when taking snapshot, this source
-//! will remove checksums from URL.
+//! A PyPI link may contain checksum in its URL fragment, e.g. `#sha256=...` or
+//! `#md5=...`. When taking snapshot, this source will remove checksums from URL
+//! and record them in snapshot, so that downloads may be verified. Preferred
+//! checksum methods may be given with `--checksum-algo`; files without them
+//! are still verified with the checksum provided, and files without any
+//! checksum are counted in a warning.
 //!
-//! Pypi supports path snapshot, and TransferURL source object.
+//! With `--json-api` (or `--json-index`), the JSON simple index in PEP 691
+//! is requested instead, and checksums are taken from `hashes` of each file.
+//! Pages served as HTML regardless, by indexes without JSON support, are
+//! parsed as HTML.
+//!
+//! If an allow list is given, only listed packages are mirrored.
+//!
+//! Transient failures of fetching a package index are retried with backoff.
+//! If a package index still fails, snapshot is aborted, as a partial
+//! snapshot would delete files of that package from target. Package index
+//! which no longer exists (404 or 410) is skipped, while 403 (e.g. from a
+//! WAF of upstream) aborts snapshot as well.
+//!
+//! Package indexes are fetched with `--concurrent-resolve` concurrency, as
+//! they are many and small. Packages themselves are transferred with
+//! `--concurrent-transfer`, so that the two can be tuned independently.
+//!
+//! Pypi supports meta snapshot, and TransferURL source object. If fallback
+//! package bases are configured, they will be tried in order when downloading
+//! a package from package base fails.
 
-use crate::common::{Mission, SnapshotConfig, SnapshotPath, TransferURL};
+use crate::checksum::CHECKSUM_METHODS;
+use crate::common::{Mission, SnapshotConfig, TransferURL};
 use crate::error::{Error, Result};
+use crate::metadata::SnapshotMeta;
+use crate::rate_limit::throttle;
 use crate::traits::{SnapshotStorage, SourceStorage};
-use crate::utils::bar;
+use crate::utils::{bar, check_url};
+
+use std::collections::BTreeMap;
 
 use async_trait::async_trait;
-use futures_util::{stream, StreamExt, TryStreamExt};
+use futures_util::{future, stream, StreamExt, TryStreamExt};
 use regex::Regex;
+use serde::Deserialize;
 use slog::{info, warn};
 use structopt::StructOpt;
 
@@ -35,110 +64,498 @@ pub struct Pypi {
         help = "Base of package index"
     )]
     pub package_base: String,
-    /// When debug mode is enabled, only first 1000 packages will be selected.
+    /// Fallback bases of packages, which are tried in order when downloading
+    /// from package base fails.
+    #[structopt(
+        long,
+        number_of_values = 1,
+        help = "Fallback base of package index, may be specified multiple times"
+    )]
+    pub fallback_package_base: Vec<String>,
+    /// Preferred checksum methods in package URL. Files without a checksum
+    /// of these methods are verified with the one provided instead. If
+    /// empty, the strongest supported method is used.
+    #[structopt(
+        long,
+        number_of_values = 1,
+        possible_values = CHECKSUM_METHODS,
+        help = "Preferred checksum method, may be specified multiple times. Other methods are used if a file has none of preferred ones"
+    )]
+    pub checksum_algo: Vec<String>,
+    /// File of package names to mirror, one per line. Glob patterns with `*`
+    /// and `?` are supported. If only exact names are listed, the full index
+    /// is not downloaded.
+    #[structopt(long, help = "Only mirror packages in this allow list file")]
+    pub allow_list: Option<String>,
+    /// Use JSON simple index in PEP 691 instead of parsing HTML pages
+    #[structopt(
+        long,
+        alias = "json-index",
+        help = "Use JSON simple index (PEP 691), falling back to HTML if not served"
+    )]
+    pub json_api: bool,
+    /// When debug mode is enabled, only first few packages will be selected.
     /// Please add `--no-delete` parameter on simple diff transfer when enabling
     /// debug mode on a production endpoint.
     #[structopt(long)]
     pub debug: bool,
 }
 
+/// Package names to mirror. Names are normalized as in PEP 503. Entries with
+/// `*` or `?` are glob patterns, others are exact names.
+#[derive(Debug, Default)]
+struct AllowList {
+    names: Vec<String>,
+    patterns: Vec<Regex>,
+}
+
+fn normalize_name(name: &str) -> String {
+    lazy_static::lazy_static! {
+        static ref SEPARATOR: Regex = Regex::new(r"[-_.]+").unwrap();
+    }
+    SEPARATOR.replace_all(name, "-").to_lowercase()
+}
+
+impl AllowList {
+    /// Parse allow list, one entry per line. Empty lines and lines starting
+    /// with `#` are ignored.
+    fn parse(content: &str) -> Result<Self> {
+        let mut allow_list = Self::default();
+        for line in content.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let name = normalize_name(line);
+            if name.contains('*') || name.contains('?') {
+                let pattern = regex::escape(&name)
+                    .replace(r"\*", ".*")
+                    .replace(r"\?", ".");
+                allow_list.patterns.push(
+                    Regex::new(&format!("^{}$", pattern))
+                        .map_err(|err| Error::ConfigureError(format!("{:?}", err)))?,
+                );
+            } else {
+                allow_list.names.push(name);
+            }
+        }
+        Ok(allow_list)
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        let name = normalize_name(name);
+        self.names.contains(&name) || self.patterns.iter().any(|pattern| pattern.is_match(&name))
+    }
+}
+
+/// Media type of JSON simple index in PEP 691
+const PYPI_JSON_ACCEPT: &str = "application/vnd.pypi.simple.v1+json";
+/// Links in HTML simple index, with href and text
+const PACKAGE_LINK: &str = r#"<a.*href="(.*?)".*>(.*?)</a>"#;
+/// Number of projects selected from index in debug mode
+const DEBUG_PROJECTS: usize = 20;
+/// Times a package index is retried on transient errors
+const INDEX_RETRIES: u32 = 3;
+/// Backoff before the first retry of package index, doubled on each retry
+const INDEX_RETRY_BACKOFF: std::time::Duration = std::time::Duration::from_secs(1);
+
+#[derive(Deserialize, Debug)]
+struct JsonIndex {
+    projects: Vec<JsonProject>,
+}
+
+#[derive(Deserialize, Debug)]
+struct JsonProject {
+    name: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct JsonPackage {
+    files: Vec<JsonFile>,
+}
+
+#[derive(Deserialize, Debug)]
+struct JsonFile {
+    url: String,
+    #[serde(default)]
+    hashes: BTreeMap<String, String>,
+    /// Either a boolean, or a string of reason why the file is yanked
+    #[serde(default)]
+    yanked: serde_json::Value,
+    #[serde(default)]
+    size: Option<u64>,
+}
+
+/// A file listed in package index.
+#[derive(Debug)]
+struct PackageFile {
+    url: String,
+    checksum: Option<(String, String)>,
+    size: Option<u64>,
+    yanked: bool,
+}
+
+/// Whether response is JSON simple index, rather than HTML.
+fn is_json(response: &reqwest::Response) -> bool {
+    response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.contains("json"))
+}
+
+/// Fetch and parse package page, of JSON simple index or HTML.
+async fn fetch_package(
+    client: &reqwest::Client,
+    page_url: &str,
+    json_api: bool,
+    matcher: &Regex,
+    checksum_algo: &[String],
+    logger: &slog::Logger,
+) -> Result<Vec<PackageFile>> {
+    let mut request = client.get(page_url);
+    if json_api {
+        request = request.header(reqwest::header::ACCEPT, PYPI_JSON_ACCEPT);
+    }
+    let response = request.send().await?;
+    if !response.status().is_success() {
+        return Err(Error::HTTPError(response.status()));
+    }
+    let json = json_api && is_json(&response);
+    let package = response.text().await?;
+    if json {
+        parse_json_package(page_url, &package, checksum_algo)
+    } else {
+        Ok(parse_html_package(page_url, &package, matcher, logger))
+    }
+}
+
+/// Parse package page of HTML simple index. Links are resolved against
+/// the page, so that absolute, relative and protocol-relative links are
+/// all accepted. Links which aren't valid http(s) URLs, e.g. `mailto:`,
+/// are skipped with a warning.
+fn parse_html_package(
+    page_url: &str,
+    content: &str,
+    matcher: &Regex,
+    logger: &slog::Logger,
+) -> Vec<PackageFile> {
+    let page = match url::Url::parse(page_url) {
+        Ok(page) => page,
+        Err(err) => {
+            warn!(logger, "invalid package page url {}: {:?}", page_url, err);
+            return vec![];
+        }
+    };
+    matcher
+        .captures_iter(content)
+        .filter_map(|cap| {
+            let href = &cap[1];
+            let parsed = match page.join(href) {
+                Ok(parsed) if parsed.scheme() == "http" || parsed.scheme() == "https" => Ok(parsed),
+                Ok(parsed) => Err(format!("unsupported scheme {}", parsed.scheme())),
+                Err(err) => Err(format!("{:?}", err)),
+            };
+            let parsed = match parsed {
+                Ok(parsed) => parsed,
+                Err(err) => {
+                    warn!(
+                        logger,
+                        "invalid url {} in {}", href, page_url;
+                        "path" => page_url,
+                        "phase" => "index",
+                        "error" => err
+                    );
+                    return None;
+                }
+            };
+            let cleaned: &str = &parsed[..url::Position::AfterPath];
+            Some(PackageFile {
+                url: cleaned.to_string(),
+                checksum: parse_checksum(parsed.fragment()),
+                size: None,
+                yanked: false,
+            })
+        })
+        .collect()
+}
+
+/// Parse package page of JSON simple index. Relative URLs are resolved
+/// against the page. If a file has multiple hashes, the strongest preferred
+/// one is used, or the strongest supported one if none is preferred.
+fn parse_json_package(
+    page_url: &str,
+    content: &str,
+    checksum_algo: &[String],
+) -> Result<Vec<PackageFile>> {
+    let page_url = url::Url::parse(page_url)
+        .map_err(|err| Error::ConfigureError(format!("invalid simple base: {:?}", err)))?;
+    let package: JsonPackage = serde_json::from_str(content)?;
+    package
+        .files
+        .into_iter()
+        .map(|file| {
+            let mut url = page_url.join(&file.url).map_err(|err| {
+                Error::ProcessError(format!("invalid url {}: {:?}", file.url, err))
+            })?;
+            url.set_fragment(None);
+            let find_checksum = |preferred_only: bool| {
+                CHECKSUM_METHODS
+                    .iter()
+                    .rev()
+                    .filter(|method| {
+                        !preferred_only || checksum_algo.iter().any(|algo| algo == *method)
+                    })
+                    .find_map(|method| {
+                        file.hashes
+                            .get(*method)
+                            .filter(|checksum| !checksum.is_empty())
+                            .map(|checksum| (method.to_string(), checksum.to_lowercase()))
+                    })
+            };
+            let checksum =
+                find_checksum(!checksum_algo.is_empty()).or_else(|| find_checksum(false));
+            let yanked = match &file.yanked {
+                serde_json::Value::Bool(yanked) => *yanked,
+                serde_json::Value::String(_) => true,
+                _ => false,
+            };
+            Ok(PackageFile {
+                url: url.to_string(),
+                checksum,
+                size: file.size,
+                yanked,
+            })
+        })
+        .collect()
+}
+
+/// Parse checksum in URL fragment, e.g. `sha256=...`, into method and digest.
+fn parse_checksum(fragment: Option<&str>) -> Option<(String, String)> {
+    let (method, checksum) = fragment?.split_once('=')?;
+    if CHECKSUM_METHODS.contains(&method) && !checksum.is_empty() {
+        Some((method.to_string(), checksum.to_lowercase()))
+    } else {
+        None
+    }
+}
+
 #[async_trait]
-impl SnapshotStorage<SnapshotPath> for Pypi {
+impl SnapshotStorage<SnapshotMeta> for Pypi {
     async fn snapshot(
         &mut self,
         mission: Mission,
         config: &SnapshotConfig,
-    ) -> Result<Vec<SnapshotPath>> {
+    ) -> Result<Vec<SnapshotMeta>> {
         let logger = mission.logger;
         let progress = mission.progress;
         let client = mission.client;
+        let rate_limit = mission.rate_limit;
 
-        info!(logger, "downloading pypi index...");
-        let mut index = client
-            .get(&format!("{}/", self.simple_base))
-            .send()
-            .await?
-            .text()
-            .await?;
-        let matcher = Regex::new(r#"<a.*href="(.*?)".*>(.*?)</a>"#).unwrap();
+        let allow_list = match &self.allow_list {
+            Some(path) => Some(AllowList::parse(&tokio::fs::read_to_string(path).await?)?),
+            None => None,
+        };
+        let matcher = Regex::new(PACKAGE_LINK).unwrap();
 
-        info!(logger, "parsing index...");
-        if self.debug {
-            index = index[..1000].to_string();
-        }
-        let caps: Vec<(String, String)> = matcher
-            .captures_iter(&index)
-            .map(|cap| (cap[1].to_string(), cap[2].to_string()))
-            .collect();
+        let caps: Vec<(String, String)> = match &allow_list {
+            Some(allow_list) if allow_list.patterns.is_empty() => {
+                info!(
+                    logger,
+                    "using {} packages in allow list, skip downloading index",
+                    allow_list.names.len()
+                );
+                allow_list
+                    .names
+                    .iter()
+                    .map(|name| (format!("{}/", name), name.clone()))
+                    .collect()
+            }
+            _ => {
+                info!(logger, "downloading pypi index...");
+                throttle(&rate_limit).await;
+                let mut request = client.get(format!("{}/", self.simple_base));
+                if self.json_api {
+                    request = request.header(reqwest::header::ACCEPT, PYPI_JSON_ACCEPT);
+                }
+                let response = request.send().await?;
+                let json = self.json_api && is_json(&response);
+                if self.json_api && !json {
+                    warn!(logger, "JSON simple index not served, falling back to HTML");
+                }
+                let index = response.text().await?;
+
+                info!(logger, "parsing index...");
+                let mut projects: Vec<(String, String)> = if json {
+                    serde_json::from_str::<JsonIndex>(&index)?
+                        .projects
+                        .into_iter()
+                        .map(|project| {
+                            (format!("{}/", normalize_name(&project.name)), project.name)
+                        })
+                        .collect()
+                } else {
+                    matcher
+                        .captures_iter(&index)
+                        .map(|cap| (cap[1].to_string(), cap[2].to_string()))
+                        .collect()
+                };
+                if self.debug {
+                    projects.truncate(config.debug_limit.unwrap_or(DEBUG_PROJECTS));
+                }
+                projects
+                    .into_iter()
+                    .filter(|(_, name)| {
+                        allow_list
+                            .as_ref()
+                            .is_none_or(|allow_list| allow_list.matches(name))
+                    })
+                    .collect()
+            }
+        };
 
         info!(logger, "downloading package index...");
         progress.set_length(caps.len() as u64);
         progress.set_style(bar());
 
-        let packages: Result<Vec<Vec<(String, String)>>> =
+        let package_base = if self.package_base.ends_with('/') {
+            self.package_base.clone()
+        } else {
+            format!("{}/", self.package_base)
+        };
+
+        let mut fallback_checksums: usize = 0;
+        let mut unverified: usize = 0;
+        let mut yanked: usize = 0;
+        let mut to_meta = |file: PackageFile| {
+            if file.yanked {
+                yanked += 1;
+            }
+            if let Some(key) = file.url.strip_prefix(&package_base) {
+                match &file.checksum {
+                    None => unverified += 1,
+                    Some((method, _))
+                        if !self.checksum_algo.is_empty()
+                            && !self.checksum_algo.contains(method) =>
+                    {
+                        fallback_checksums += 1
+                    }
+                    Some(_) => {}
+                }
+                let (checksum_method, checksum) = file.checksum.unzip();
+                Some(SnapshotMeta {
+                    key: key.to_string(),
+                    size: file.size,
+                    checksum_method,
+                    checksum,
+                    ..Default::default()
+                })
+            } else {
+                warn!(logger, "PyPI package isn't stored on base: {:?}", file.url);
+                None
+            }
+        };
+
+        // files of each package are added to snapshot as soon as its index
+        // resolves, so that URLs of all packages are never held at once
+        let snapshot: Vec<SnapshotMeta> =
             stream::iter(caps.into_iter().map(|(url, name)| {
                 let client = client.clone();
                 let simple_base = self.simple_base.clone();
                 let progress = progress.clone();
                 let matcher = matcher.clone();
                 let logger = logger.clone();
+                let json_api = self.json_api;
+                let checksum_algo = self.checksum_algo.clone();
+                let rate_limit = rate_limit.clone();
+                let package_logger = logger.clone();
+                let path = url.clone();
 
-                let func = async move {
-                    progress.set_message(&name);
-                    let package = client
-                        .get(&format!("{}/{}", simple_base, url))
-                        .send()
-                        .await?
-                        .text()
-                        .await?;
-                    let caps: Vec<(String, String)> = matcher
-                        .captures_iter(&package)
-                        .map(|cap| {
-                            let url = format!("{}/{}{}", simple_base, url, &cap[1]);
-                            let parsed = url::Url::parse(&url).unwrap();
-                            let cleaned: &str = &parsed[..url::Position::AfterPath];
-                            (cleaned.to_string(), cap[2].to_string())
-                        })
-                        .collect();
-                    progress.inc(1);
-                    Ok::<Vec<(String, String)>, Error>(caps)
-                };
                 async move {
-                    match func.await {
-                        Ok(x) => Ok(x),
-                        Err(err) => {
-                            warn!(logger, "failed to fetch index {:?}", err);
-                            Ok(vec![])
+                    progress.set_message(&name);
+                    let page_url = format!("{}/{}", simple_base, url);
+                    let mut attempt = 0;
+                    let files = loop {
+                        throttle(&rate_limit).await;
+                        let result = fetch_package(
+                            &client,
+                            &page_url,
+                            json_api,
+                            &matcher,
+                            &checksum_algo,
+                            &package_logger,
+                        )
+                        .await;
+                        match result {
+                            Ok(files) => break files,
+                            Err(err) if err.is_gone() => {
+                                warn!(
+                                    logger,
+                                    "package index {} unavailable, skipped", path;
+                                    "path" => &path,
+                                    "phase" => "index",
+                                    "error" => format!("{:?}", err)
+                                );
+                                break vec![];
+                            }
+                            Err(err) if err.is_retryable() && attempt < INDEX_RETRIES => {
+                                let backoff = INDEX_RETRY_BACKOFF * 2u32.pow(attempt);
+                                attempt += 1;
+                                info!(
+                                    logger,
+                                    "failed to fetch index {}, retry {} in {:?}", path, attempt, backoff;
+                                    "path" => &path,
+                                    "phase" => "index",
+                                    "error" => format!("{:?}", err)
+                                );
+                                tokio::time::sleep(backoff).await;
+                            }
+                            Err(err) => {
+                                warn!(
+                                    logger,
+                                    "failed to fetch index {}, aborting snapshot", path;
+                                    "path" => &path,
+                                    "phase" => "index",
+                                    "error" => format!("{:?}", err)
+                                );
+                                return Err(err);
+                            }
                         }
-                    }
+                    };
+                    progress.inc(1);
+                    Ok::<_, Error>(files)
                 }
             }))
             .buffer_unordered(config.concurrent_resolve)
-            .try_collect()
-            .await;
-
-        let package_base = if self.package_base.ends_with('/') {
-            self.package_base.clone()
-        } else {
-            format!("{}/", self.package_base)
-        };
-
-        let snapshot = packages?
-            .into_iter()
-            .flatten()
-            .filter_map(|(url, _)| {
-                if url.starts_with(&package_base) {
-                    Some(url[package_base.len()..].to_string())
-                } else {
-                    warn!(logger, "PyPI package isn't stored on base: {:?}", url);
-                    None
-                }
+            .try_fold(Vec::new(), |mut snapshot, files| {
+                snapshot.extend(files.into_iter().filter_map(&mut to_meta));
+                future::ready(Ok(snapshot))
             })
-            .collect();
+            .await?;
+
+        if yanked != 0 {
+            info!(logger, "{} yanked files are kept in snapshot", yanked);
+        }
+        if fallback_checksums != 0 {
+            warn!(
+                logger,
+                "{} files have no checksum of preferred methods, verified with other methods",
+                fallback_checksums
+            );
+        }
+        if unverified != 0 {
+            warn!(
+                logger,
+                "{} files have no checksum and won't be verified", unverified
+            );
+        }
 
         progress.finish_with_message("done");
 
-        Ok(crate::utils::snapshot_string_to_path(snapshot))
+        Ok(snapshot)
+    }
+
+    async fn check(&mut self, mission: Mission, _config: &SnapshotConfig) -> Result<String> {
+        check_url(&mission, &self.simple_base).await
     }
 
     fn info(&self) -> String {
@@ -147,8 +564,372 @@ impl SnapshotStorage<SnapshotPath> for Pypi {
 }
 
 #[async_trait]
-impl SourceStorage<SnapshotPath, TransferURL> for Pypi {
-    async fn get_object(&self, snapshot: &SnapshotPath, _mission: &Mission) -> Result<TransferURL> {
-        Ok(TransferURL(format!("{}/{}", self.package_base, snapshot.0)))
+impl SourceStorage<SnapshotMeta, TransferURL> for Pypi {
+    async fn get_object(&self, snapshot: &SnapshotMeta, _mission: &Mission) -> Result<TransferURL> {
+        Ok(TransferURL::with_fallbacks(
+            format!("{}/{}", self.package_base, snapshot.key),
+            self.fallback_package_base
+                .iter()
+                .map(|base| format!("{}/{}", base, snapshot.key))
+                .collect(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_plain_http_base() {
+        let source = Pypi {
+            simple_base: "http://mirror.example.com/pypi/simple".to_string(),
+            package_base: "http://mirror.example.com/pypi/packages".to_string(),
+            fallback_package_base: vec!["http://fallback.example.com/packages".to_string()],
+            checksum_algo: vec![],
+            allow_list: None,
+            json_api: false,
+            debug: false,
+        };
+        let snapshot = SnapshotMeta::new("aa/bb/foo-1.0.tar.gz".to_string());
+        let url = source
+            .get_object(&snapshot, &Mission::for_test())
+            .await
+            .unwrap();
+        assert_eq!(
+            url.candidates().collect::<Vec<_>>(),
+            vec![
+                "http://mirror.example.com/pypi/packages/aa/bb/foo-1.0.tar.gz",
+                "http://fallback.example.com/packages/aa/bb/foo-1.0.tar.gz"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_checksum() {
+        assert_eq!(
+            parse_checksum(Some("sha256=ABCD")),
+            Some(("sha256".to_string(), "abcd".to_string()))
+        );
+        assert_eq!(
+            parse_checksum(Some("md5=abcd")),
+            Some(("md5".to_string(), "abcd".to_string()))
+        );
+        assert_eq!(parse_checksum(Some("blake3=abcd")), None);
+        assert_eq!(parse_checksum(Some("sha256=")), None);
+        assert_eq!(parse_checksum(None), None);
+    }
+
+    #[test]
+    fn test_parse_html_package_junk() {
+        let matcher = Regex::new(PACKAGE_LINK).unwrap();
+        let content = r#"<a href="mailto:admin@example.com">contact</a>
+            <a href="data:text/plain,foo">data</a>
+            <a href="http://[bad/foo-0.9.tar.gz">foo-0.9.tar.gz</a>
+            <a href="../../packages/aa/foo-1.0.tar.gz#sha256=abcd">foo-1.0.tar.gz</a>"#;
+        let files = parse_html_package(
+            "http://mirror.example.com/simple/foo/",
+            content,
+            &matcher,
+            &Mission::for_test().logger,
+        );
+        assert_eq!(files.len(), 1);
+        assert_eq!(
+            files[0].url,
+            "http://mirror.example.com/packages/aa/foo-1.0.tar.gz"
+        );
+        assert_eq!(
+            files[0].checksum,
+            Some(("sha256".to_string(), "abcd".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_parse_html_package_links() {
+        let matcher = Regex::new(PACKAGE_LINK).unwrap();
+        let content = r#"<a href="https://files.example.com/packages/aa/foo-1.0.tar.gz?x=1#md5=12">a</a>
+            <a href="//files.example.com/packages/bb/foo-1.1.tar.gz">b</a>
+            <a href="/packages/cc/foo-1.2.tar.gz">c</a>
+            <a href="foo-1.3.tar.gz">d</a>"#;
+        let urls: Vec<_> = parse_html_package(
+            "https://mirror.example.com/simple/foo/",
+            content,
+            &matcher,
+            &Mission::for_test().logger,
+        )
+        .into_iter()
+        .map(|file| file.url)
+        .collect();
+        assert_eq!(
+            urls,
+            vec![
+                "https://files.example.com/packages/aa/foo-1.0.tar.gz",
+                "https://files.example.com/packages/bb/foo-1.1.tar.gz",
+                "https://mirror.example.com/packages/cc/foo-1.2.tar.gz",
+                "https://mirror.example.com/simple/foo/foo-1.3.tar.gz",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_json_package() {
+        let content = r#"{
+            "meta": {"api-version": "1.0"},
+            "name": "foo",
+            "files": [
+                {
+                    "filename": "foo-1.0.tar.gz",
+                    "url": "../../packages/aa/bb/foo-1.0.tar.gz#sha256=abcd",
+                    "hashes": {"md5": "1234", "sha256": "ABCD"},
+                    "requires-python": ">=3.6",
+                    "yanked": "broken"
+                },
+                {
+                    "filename": "foo-1.1.tar.gz",
+                    "url": "https://files.example.com/packages/cc/foo-1.1.tar.gz",
+                    "hashes": {},
+                    "size": 42
+                }
+            ]
+        }"#;
+        let files =
+            parse_json_package("http://mirror.example.com/simple/foo/", content, &[]).unwrap();
+        assert_eq!(files.len(), 2);
+        assert_eq!(
+            files[0].url,
+            "http://mirror.example.com/packages/aa/bb/foo-1.0.tar.gz"
+        );
+        assert_eq!(
+            files[0].checksum,
+            Some(("sha256".to_string(), "abcd".to_string()))
+        );
+        assert!(files[0].yanked);
+        assert_eq!(files[1].checksum, None);
+        assert_eq!(files[1].size, Some(42));
+        assert!(!files[1].yanked);
+
+        let files = parse_json_package(
+            "http://mirror.example.com/simple/foo/",
+            content,
+            &["md5".to_string()],
+        )
+        .unwrap();
+        assert_eq!(
+            files[0].checksum,
+            Some(("md5".to_string(), "1234".to_string()))
+        );
+
+        let files = parse_json_package(
+            "http://mirror.example.com/simple/foo/",
+            content,
+            &["sha512".to_string()],
+        )
+        .unwrap();
+        assert_eq!(
+            files[0].checksum,
+            Some(("sha256".to_string(), "abcd".to_string()))
+        );
+    }
+
+    #[cfg(feature = "http-fixture")]
+    #[tokio::test]
+    async fn test_snapshot_replay() {
+        use crate::http_fixture::{save, FixtureServer, Mode};
+        use std::collections::BTreeMap;
+
+        let dir =
+            std::env::temp_dir().join(format!("mirror-clone-pypi-fixture-{}", std::process::id()));
+        save(
+            &dir,
+            "/simple/",
+            200,
+            BTreeMap::new(),
+            br#"<a href="foo/">foo</a>"#,
+        )
+        .unwrap();
+        save(
+            &dir,
+            "/simple/foo/",
+            200,
+            BTreeMap::new(),
+            br#"<a href="../../packages/aa/foo-1.0.tar.gz#sha256=abcd">foo-1.0.tar.gz</a>"#,
+        )
+        .unwrap();
+
+        let server = FixtureServer::start(dir.clone(), Mode::Replay).unwrap();
+        let mut source = Pypi {
+            simple_base: format!("{}/simple", server.base()),
+            package_base: format!("{}/packages", server.base()),
+            fallback_package_base: vec![],
+            checksum_algo: vec![],
+            allow_list: None,
+            json_api: false,
+            debug: false,
+        };
+        let config = SnapshotConfig::for_test();
+        let snapshot = source.snapshot(Mission::for_test(), &config).await.unwrap();
+        // index without JSON support falls back to HTML
+        source.json_api = true;
+        let fallback = source.snapshot(Mission::for_test(), &config).await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(snapshot.len(), 1);
+        assert_eq!(snapshot[0].key, "aa/foo-1.0.tar.gz");
+        assert_eq!(snapshot[0].checksum.as_deref(), Some("abcd"));
+        assert_eq!(fallback.len(), 1);
+        assert_eq!(fallback[0].key, snapshot[0].key);
+    }
+
+    #[cfg(feature = "http-fixture")]
+    #[tokio::test]
+    async fn test_snapshot_forbidden_index() {
+        use crate::http_fixture::{save, FixtureServer, Mode};
+        use std::collections::BTreeMap;
+
+        let dir = std::env::temp_dir().join(format!(
+            "mirror-clone-pypi-forbidden-{}",
+            std::process::id()
+        ));
+        save(
+            &dir,
+            "/simple/",
+            200,
+            BTreeMap::new(),
+            b"<a href=\"foo/\">foo</a>\n<a href=\"bar/\">bar</a>\n",
+        )
+        .unwrap();
+        save(
+            &dir,
+            "/simple/foo/",
+            200,
+            BTreeMap::new(),
+            br#"<a href="../../packages/aa/foo-1.0.tar.gz#sha256=abcd">foo-1.0.tar.gz</a>"#,
+        )
+        .unwrap();
+        save(&dir, "/simple/bar/", 404, BTreeMap::new(), b"").unwrap();
+
+        let server = FixtureServer::start(dir.clone(), Mode::Replay).unwrap();
+        let mut source = Pypi {
+            simple_base: format!("{}/simple", server.base()),
+            package_base: format!("{}/packages", server.base()),
+            fallback_package_base: vec![],
+            checksum_algo: vec![],
+            allow_list: None,
+            json_api: false,
+            debug: false,
+        };
+        let config = SnapshotConfig::for_test();
+        // package gone from index is skipped
+        let gone = source.snapshot(Mission::for_test(), &config).await;
+        // while a forbidden package aborts snapshot, instead of being
+        // deleted from target
+        save(&dir, "/simple/bar/", 403, BTreeMap::new(), b"").unwrap();
+        let forbidden = source.snapshot(Mission::for_test(), &config).await;
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let gone = gone.unwrap();
+        assert_eq!(gone.len(), 1);
+        assert_eq!(gone[0].key, "aa/foo-1.0.tar.gz");
+        assert!(matches!(
+            forbidden,
+            Err(Error::HTTPError(reqwest::StatusCode::FORBIDDEN))
+        ));
+    }
+
+    /// Peak memory of taking snapshot of an index of 3000 packages with 60
+    /// files each, which is printed so that revisions can be compared. Run
+    /// with `cargo test --release --all-features test_snapshot_peak_memory
+    /// -- --ignored --nocapture`. Linux only, as it reads VmHWM of /proc.
+    #[cfg(feature = "http-fixture")]
+    #[tokio::test]
+    #[ignore]
+    async fn test_snapshot_peak_memory() {
+        use crate::http_fixture::{save, FixtureServer, Mode};
+        use std::collections::BTreeMap;
+
+        const PACKAGES: usize = 3000;
+        const FILES: usize = 60;
+
+        fn vm_hwm_kb() -> u64 {
+            std::fs::read_to_string("/proc/self/status")
+                .unwrap()
+                .lines()
+                .find_map(|line| line.strip_prefix("VmHWM:"))
+                .and_then(|value| value.trim().trim_end_matches("kB").trim().parse().ok())
+                .unwrap()
+        }
+
+        let dir = std::env::temp_dir().join(format!(
+            "mirror-clone-pypi-peak-memory-{}",
+            std::process::id()
+        ));
+        let index: String = (0..PACKAGES)
+            .map(|package| format!("<a href=\"pkg{}/\">pkg{}</a>\n", package, package))
+            .collect();
+        save(&dir, "/simple/", 200, BTreeMap::new(), index.as_bytes()).unwrap();
+        for package in 0..PACKAGES {
+            let links: String = (0..FILES)
+                .map(|file| {
+                    format!(
+                        "<a href=\"../../packages/{:02x}/{:02x}/pkg{}-1.{}.tar.gz#sha256={:064x}\">pkg{}-1.{}.tar.gz</a>\n",
+                        package % 256,
+                        file % 256,
+                        package,
+                        file,
+                        package * FILES + file,
+                        package,
+                        file
+                    )
+                })
+                .collect();
+            save(
+                &dir,
+                &format!("/simple/pkg{}/", package),
+                200,
+                BTreeMap::new(),
+                links.as_bytes(),
+            )
+            .unwrap();
+        }
+
+        let server = FixtureServer::start(dir.clone(), Mode::Replay).unwrap();
+        let mut source = Pypi {
+            simple_base: format!("{}/simple", server.base()),
+            package_base: format!("{}/packages", server.base()),
+            fallback_package_base: vec![],
+            checksum_algo: vec![],
+            allow_list: None,
+            json_api: false,
+            debug: false,
+        };
+        let config = SnapshotConfig {
+            concurrent_resolve: 64,
+            ..SnapshotConfig::for_test()
+        };
+        // peak of generating fixtures is not counted
+        std::fs::write("/proc/self/clear_refs", "5").unwrap();
+        let before = vm_hwm_kb();
+        let snapshot = source.snapshot(Mission::for_test(), &config).await.unwrap();
+        let peak = vm_hwm_kb();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(snapshot.len(), PACKAGES * FILES);
+        println!(
+            "{} objects, VmHWM {} kB before snapshot, {} kB peak",
+            snapshot.len(),
+            before,
+            peak
+        );
+    }
+
+    #[test]
+    fn test_allow_list() {
+        let allow_list = AllowList::parse("# comment\nNumPy\n\nzope.*\ndjango-?est\n").unwrap();
+        assert_eq!(allow_list.names, vec!["numpy"]);
+        assert!(allow_list.matches("numpy"));
+        assert!(allow_list.matches("Zope.Interface"));
+        assert!(allow_list.matches("django_test"));
+        assert!(!allow_list.matches("django-tests"));
+        assert!(!allow_list.matches("scipy"));
     }
 }
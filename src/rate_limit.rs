@@ -0,0 +1,123 @@
+//! Request rate limit
+//!
+//! Some upstreams cap requests per second regardless of bytes transferred.
+//! `RateLimit` is a token bucket shared by all requests of a transfer, which
+//! is carried by `Mission`. Storages should call `Mission::throttle` before
+//! each request to source, e.g. when fanning out to package indexes.
+//!
+//! The same bucket also caps bandwidth, in which case each token is a byte,
+//! and downloads consume tokens as chunks arrive.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+#[derive(Debug, Clone)]
+pub struct RateLimit {
+    bucket: Arc<Mutex<Bucket>>,
+    /// Requests per second
+    rate: f64,
+    /// Max requests in a burst
+    burst: f64,
+}
+
+impl RateLimit {
+    /// Allow `rate` requests per second. Up to one second of requests may be
+    /// issued in a burst.
+    pub fn new(rate: f64) -> Self {
+        let burst = rate.max(1.0);
+        Self {
+            bucket: Arc::new(Mutex::new(Bucket {
+                tokens: burst,
+                last_refill: Instant::now(),
+            })),
+            rate,
+            burst,
+        }
+    }
+
+    /// Wait until a request may be issued.
+    pub async fn wait(&self) {
+        loop {
+            let wait = {
+                let mut bucket = self.bucket.lock().await;
+                let now = Instant::now();
+                let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+                bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+                bucket.last_refill = now;
+                if bucket.tokens >= 1.0 {
+                    bucket.tokens -= 1.0;
+                    return;
+                }
+                Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate)
+            };
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+impl RateLimit {
+    /// Take `amount` tokens, e.g. bytes of a downloaded chunk, and wait
+    /// until the bucket is no longer in debt. A chunk larger than burst is
+    /// allowed, and delays later consumers instead.
+    pub async fn consume(&self, amount: f64) {
+        let wait = {
+            let mut bucket = self.bucket.lock().await;
+            let now = Instant::now();
+            let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst) - amount;
+            bucket.last_refill = now;
+            if bucket.tokens >= 0.0 {
+                return;
+            }
+            Duration::from_secs_f64(-bucket.tokens / self.rate)
+        };
+        tokio::time::sleep(wait).await;
+    }
+}
+
+/// Wait for rate limit, if any.
+pub async fn throttle(rate_limit: &Option<RateLimit>) {
+    if let Some(rate_limit) = rate_limit {
+        rate_limit.wait().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        let rate_limit = RateLimit::new(20.0);
+        let start = Instant::now();
+        // burst of 20 requests, then 10 requests at 20 per second
+        for _ in 0..30 {
+            rate_limit.wait().await;
+        }
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(450), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(2), "{:?}", elapsed);
+    }
+
+    #[tokio::test]
+    async fn test_consume() {
+        let bandwidth = RateLimit::new(1000.0);
+        let start = Instant::now();
+        // burst of 1000 bytes, then 500 bytes at 1000 per second
+        for _ in 0..3 {
+            bandwidth.consume(500.0).await;
+        }
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(450), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(2), "{:?}", elapsed);
+    }
+}
@@ -0,0 +1,104 @@
+//! Path rewrite
+//!
+//! Some mirrors store objects under a different prefix than upstream, e.g.
+//! `pub/pypi/` of rsync source is `pypi/` on target. `PathRewriter` rewrites
+//! keys of source snapshot with regex rules, so that they are diffed against
+//! and written to target under rewritten keys. Original keys are kept, and
+//! objects are still fetched from source by them.
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+
+use regex::Regex;
+
+use crate::error::{Error, Result};
+use crate::traits::Key;
+
+#[derive(Debug, Clone)]
+pub struct PathRewriter {
+    rules: Vec<(Regex, String)>,
+}
+
+impl PathRewriter {
+    /// Parse rules in `regex=replacement` form, e.g. `^pub/pypi/=pypi/`.
+    /// Replacement may refer to capture groups, e.g. `$1`.
+    pub fn new(rules: &[String]) -> Result<Self> {
+        let rules = rules
+            .iter()
+            .map(|rule| {
+                let (pattern, replacement) = rule.rsplit_once('=').ok_or_else(|| {
+                    Error::ConfigureError(format!(
+                        "rewrite rule should be regex=replacement: {}",
+                        rule
+                    ))
+                })?;
+                let pattern = Regex::new(pattern).map_err(|err| {
+                    Error::ConfigureError(format!("invalid rewrite pattern {}: {}", pattern, err))
+                })?;
+                Ok((pattern, replacement.to_string()))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { rules })
+    }
+
+    /// Apply all rules in order.
+    pub fn rewrite(&self, key: &str) -> String {
+        let mut key = key.to_string();
+        for (pattern, replacement) in &self.rules {
+            key = pattern.replace(&key, replacement.as_str()).into_owned();
+        }
+        key
+    }
+
+    /// Rewrite keys of snapshot in place. Returns original keys of rewritten
+    /// objects, and number of objects rewritten onto a key taken by another.
+    pub fn rewrite_snapshot<Snapshot: Key>(
+        &self,
+        snapshot: &mut [Snapshot],
+    ) -> (HashMap<String, String>, usize) {
+        let mut original_keys = HashMap::new();
+        let mut collisions = 0;
+        for item in snapshot.iter_mut() {
+            let rewritten = self.rewrite(item.key());
+            if rewritten != item.key() {
+                let original = std::mem::replace(item.key_mut(), rewritten.clone());
+                match original_keys.entry(rewritten) {
+                    Entry::Occupied(_) => collisions += 1,
+                    Entry::Vacant(entry) => {
+                        entry.insert(original);
+                    }
+                }
+            }
+        }
+        (original_keys, collisions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::SnapshotPath;
+
+    #[test]
+    fn test_rewrite_snapshot() {
+        let rewriter = PathRewriter::new(&[
+            "^pub/pypi/=pypi/".to_string(),
+            r"\.tgz$=.tar.gz".to_string(),
+        ])
+        .unwrap();
+        let mut snapshot = vec![
+            SnapshotPath::new("pub/pypi/simple/index.html".to_string()),
+            SnapshotPath::new("pub/pypi/a.tgz".to_string()),
+            SnapshotPath::new("pub/pypi/a.tar.gz".to_string()),
+            SnapshotPath::new("other".to_string()),
+        ];
+        let (original_keys, collisions) = rewriter.rewrite_snapshot(&mut snapshot);
+        assert_eq!(snapshot[0].0, "pypi/simple/index.html");
+        assert_eq!(snapshot[1].0, "pypi/a.tar.gz");
+        assert_eq!(snapshot[3].0, "other");
+        assert_eq!(original_keys["pypi/a.tar.gz"], "pub/pypi/a.tgz");
+        assert_eq!(collisions, 1);
+        assert!(!original_keys.contains_key("other"));
+        assert!(PathRewriter::new(&["no replacement".to_string()]).is_err());
+    }
+}
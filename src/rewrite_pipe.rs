@@ -24,7 +24,6 @@ where
     F: Fn(RewriteItem) -> Result<RewriteItem> + Send + Sync,
 {
     pub source: Source,
-    pub buffer_path: String,
     pub rewrite_fn: F,
     pub max_length: u64,
     _phantom: std::marker::PhantomData<RewriteItem>,
@@ -34,10 +33,9 @@ impl<Source, RewriteItem, F> RewritePipe<Source, RewriteItem, F>
 where
     F: Fn(RewriteItem) -> Result<RewriteItem> + Send + Sync,
 {
-    pub fn new(source: Source, buffer_path: String, rewrite_fn: F, max_length: u64) -> Self {
+    pub fn new(source: Source, rewrite_fn: F, max_length: u64) -> Self {
         Self {
             source,
-            buffer_path,
             rewrite_fn,
             max_length,
             _phantom: Default::default(),
@@ -62,9 +60,17 @@ where
         self.source.snapshot(mission, config).await
     }
 
+    async fn check(&mut self, mission: Mission, config: &SnapshotConfig) -> Result<String> {
+        self.source.check(mission, config).await
+    }
+
     fn info(&self) -> String {
         format!("rewrite <{}>", self.source.info())
     }
+
+    fn cacheable(&self) -> bool {
+        self.source.cacheable()
+    }
 }
 
 // TODO support rewrite functions with `RewriteItem` other than String (eg. Vec<u8>)
@@ -110,6 +116,8 @@ where
                                     file.seek(std::io::SeekFrom::Start(0)).await?;
 
                                     byte_stream.length = content_length;
+                                    // etag of source no longer matches content
+                                    byte_stream.headers.retain(|(name, _)| name != "etag");
                                     Ok(byte_stream)
                                 }
                             }
@@ -5,7 +5,11 @@
 //! downlaod them over HTTP. Currently, symbolic links are not supported.
 //!
 //! Rsync snapshot provides a snapshot with metadata, which includes path, size,
-//! and file modified time.
+//! and file modified time. The file list may also be read from output of
+//! `rsync -r` captured before, e.g. where rsync can't reach upstream. Sizes
+//! grouped by locale are accepted, while human-readable sizes (`1.23M`) are
+//! approximate, and not recorded in snapshot. Files readable by neither
+//! owner nor others are skipped with a warning, as upstream can't serve them.
 //!
 //! Note that we do not ensure consistency between Rsync snapshot and HTTP downloads.
 //! Some servers serve different files under Rsync and HTTP. For example, mirrors.tuna
@@ -14,26 +18,28 @@
 
 use crate::error::Result;
 use crate::traits::{SnapshotStorage, SourceStorage};
+use crate::utils::check_url;
 
-use crate::common::{Mission, SnapshotConfig, TransferURL};
+use crate::common::{Mission, SnapshotConfig, TransferPath, TransferURL};
 use crate::error::Error;
 use crate::metadata::SnapshotMeta;
 
 use async_trait::async_trait;
 use chrono::TimeZone;
 use slog::{info, warn};
+use std::path::PathBuf;
 use std::process::Stdio;
 use structopt::StructOpt;
-use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, BufReader};
 use tokio::process::Command;
 
 #[derive(Debug, Clone, StructOpt)]
 pub struct Rsync {
     /// Rsync endpoint
-    #[structopt(long, help = "Base of Rsync")]
+    #[structopt(long, help = "Base of Rsync", default_value = "")]
     pub rsync_base: String,
     /// Corresponding HTTP endpoint
-    #[structopt(long, help = "Base of HTTP")]
+    #[structopt(long, help = "Base of HTTP, either http:// or https://")]
     pub http_base: String,
     /// When debug mode is enabled, we only scan first 1000 objects. Be sure
     /// to add `--no-delete` parameter to simple diff transfer when this is enabled.
@@ -42,17 +48,102 @@ pub struct Rsync {
     /// Prefix to ignore. If this is an empty string, all objects are transferred.
     #[structopt(long, help = "Prefix to ignore", default_value = "")]
     pub ignore_prefix: String,
+    /// Output of `rsync -r` captured before. If set, rsync is not run, and
+    /// rsync base is not required.
+    #[structopt(
+        long,
+        help = "Read rsync listing from this file instead of running rsync"
+    )]
+    pub from_file: Option<PathBuf>,
+    /// Local copy of rsync tree. If set, objects are linked or copied from
+    /// it to file target, or uploaded from it to S3, instead of being
+    /// fetched from HTTP.
+    #[structopt(
+        long,
+        help = "Local copy of rsync tree, to transfer objects from without HTTP"
+    )]
+    pub local_base: Option<PathBuf>,
 }
 
-fn parse_rsync_output(line: &str) -> Result<(&str, &str, &str, &str, &str)> {
-    let (permission, rest) = line.split_once(' ').ok_or(Error::NoneError)?;
-    let rest = rest.trim_start();
-    let (size, rest) = rest.split_once(' ').ok_or(Error::NoneError)?;
-    let rest = rest.trim_start();
-    let (date, rest) = rest.split_once(' ').ok_or(Error::NoneError)?;
-    let rest = rest.trim_start();
-    let (time, file) = rest.split_once(' ').ok_or(Error::NoneError)?;
-    Ok((permission, size, date, time, file))
+/// Lines of rsync listing taken in debug mode
+const DEBUG_LINES: usize = 1000;
+
+/// Parse size column of rsync listing into bytes, and whether it's exact.
+/// Digits may be grouped with `,` or `.` depending on locale. With
+/// `--human-readable`, size may carry a unit suffix, e.g. `1.23M`, which is
+/// taken in units of 1000 and is not exact.
+fn parse_rsync_size(size: &str) -> Option<(u64, bool)> {
+    const UNITS: &[char] = &['K', 'M', 'G', 'T', 'P'];
+    match size.find(|c: char| UNITS.contains(&c.to_ascii_uppercase())) {
+        Some(pos) if pos + 1 == size.len() => {
+            let unit = UNITS
+                .iter()
+                .position(|unit| size[pos..].eq_ignore_ascii_case(&unit.to_string()))?;
+            let number: f64 = size[..pos].replace(',', ".").parse().ok()?;
+            let bytes = number * 1000f64.powi(unit as i32 + 1);
+            Some((bytes.round() as u64, false))
+        }
+        Some(_) => None,
+        None => {
+            let digits = size.replace([',', '.'], "");
+            Some((digits.parse().ok()?, true))
+        }
+    }
+}
+
+/// An entry of rsync listing
+#[derive(Debug)]
+struct RsyncEntry<'a> {
+    permission: &'a str,
+    size: u64,
+    /// Whether size is exact, instead of human-readable
+    exact_size: bool,
+    date: &'a str,
+    time: &'a str,
+    file: &'a str,
+}
+
+/// Split an entry of rsync listing into permission, size, date, time and
+/// file. Leading columns are padded with spaces, while file follows time
+/// after exactly one space, and is the rest of line, spaces included.
+fn parse_rsync_output(line: &str) -> Result<RsyncEntry<'_>> {
+    let mut rest = line.trim_start();
+    let mut columns = [""; 4];
+    for (idx, column) in columns.iter_mut().enumerate() {
+        let (value, remaining) = rest.split_once(' ').ok_or(Error::NoneError)?;
+        *column = value;
+        rest = if idx < 3 {
+            remaining.trim_start()
+        } else {
+            remaining
+        };
+    }
+    let [permission, size, date, time] = columns;
+    if rest.is_empty() {
+        return Err(Error::NoneError);
+    }
+    let (size, exact_size) = parse_rsync_size(size).ok_or(Error::NoneError)?;
+    Ok(RsyncEntry {
+        permission,
+        size,
+        exact_size,
+        date,
+        time,
+        file: rest,
+    })
+}
+
+/// Whether an entry of rsync listing is a regular file, i.e. not a
+/// directory, link or special file.
+fn is_regular_file(permission: &str) -> bool {
+    permission.starts_with('-')
+}
+
+/// Whether an entry of rsync listing may be read by daemon, which needs
+/// read bit of owner or others, e.g. read-only `-r--r--r--`.
+fn is_readable(permission: &str) -> bool {
+    let bits = permission.as_bytes();
+    bits.get(1) == Some(&b'r') || bits.get(7) == Some(&b'r')
 }
 
 #[async_trait]
@@ -60,75 +151,110 @@ impl SnapshotStorage<SnapshotMeta> for Rsync {
     async fn snapshot(
         &mut self,
         mission: Mission,
-        _config: &SnapshotConfig,
+        config: &SnapshotConfig,
     ) -> Result<Vec<SnapshotMeta>> {
         let logger = mission.logger;
         let progress = mission.progress;
-        let _client = mission.client;
 
-        info!(logger, "running rsync...");
+        let (reader, child): (Box<dyn AsyncBufRead + Unpin + Send>, _) = match &self.from_file {
+            Some(path) => {
+                info!(logger, "reading rsync listing from {:?}...", path);
+                let file = tokio::fs::File::open(path).await?;
+                (Box::new(BufReader::new(file)), None)
+            }
+            None => {
+                if self.rsync_base.is_empty() {
+                    return Err(Error::ConfigureError(String::from(
+                        "either rsync base or listing file should be given",
+                    )));
+                }
+
+                info!(logger, "running rsync...");
 
-        let mut cmd = Command::new("rsync");
-        cmd.kill_on_drop(true);
-        cmd.arg("-r").arg(self.rsync_base.clone()).arg("--no-motd");
-        cmd.stdout(Stdio::piped());
+                let mut cmd = Command::new("rsync");
+                cmd.kill_on_drop(true);
+                cmd.arg("-r").arg(self.rsync_base.clone()).arg("--no-motd");
+                cmd.stdout(Stdio::piped());
 
-        let mut child = cmd.spawn().expect("failed to spawn command");
+                let mut child = cmd.spawn().map_err(|err| {
+                    if err.kind() == std::io::ErrorKind::NotFound {
+                        Error::ProcessError(String::from("rsync binary not found in PATH"))
+                    } else {
+                        Error::ProcessError(format!("failed to spawn rsync: {:?}", err))
+                    }
+                })?;
 
-        let stdout = child
-            .stdout
-            .take()
-            .expect("child did not have a handle to stdout");
+                let stdout = child.stdout.take().ok_or_else(|| {
+                    Error::ProcessError(String::from("rsync did not have a handle to stdout"))
+                })?;
 
-        let mut reader = BufReader::new(stdout).lines();
+                let result = tokio::spawn(async move {
+                    let status = child.wait().await.map_err(|err| {
+                        Error::ProcessError(format!(
+                            "child process encountered an error: {:?}",
+                            err
+                        ))
+                    })?;
+                    Ok::<_, Error>(status)
+                });
 
-        let result = tokio::spawn(async move {
-            let status = child.wait().await.map_err(|err| {
-                Error::ProcessError(format!("child process encountered an error: {:?}", err))
-            })?;
-            Ok::<_, Error>(status)
-        });
+                (Box::new(BufReader::new(stdout)), Some(result))
+            }
+        };
 
+        let mut reader = reader.lines();
         let mut snapshot = vec![];
         let mut idx: usize = 0;
+        let debug_limit = config.debug_limit.unwrap_or(DEBUG_LINES);
 
         let timezone = chrono::Local::now().timezone();
 
         while let Some(line) = reader.next_line().await? {
             progress.inc(1);
             idx += 1;
-            if self.debug && idx > 1000 {
+            if self.debug && idx > debug_limit {
                 continue;
             }
 
-            if let Ok((permission, size, date, time, file)) = parse_rsync_output(&line) {
+            if let Ok(entry) = parse_rsync_output(&line) {
+                let file = entry.file;
                 progress.set_message(file);
                 if !self.ignore_prefix.is_empty() && file.starts_with(&self.ignore_prefix) {
                     continue;
                 }
-                if permission.starts_with("-r") {
-                    let datetime = timezone
-                        .datetime_from_str(&format!("{} {}", date, time), "%Y/%m/%d %H:%M:%S")?;
-                    let size = size.replace(",", "");
+                if is_regular_file(entry.permission) {
+                    if !is_readable(entry.permission) {
+                        warn!(logger, "unreadable file skipped: {}", file);
+                        continue;
+                    }
+                    let datetime = timezone.datetime_from_str(
+                        &format!("{} {}", entry.date, entry.time),
+                        "%Y/%m/%d %H:%M:%S",
+                    )?;
+                    // approximate size would never match target
                     let meta = SnapshotMeta {
                         key: file.to_string(),
-                        size: Some(size.parse().unwrap()),
+                        size: entry.exact_size.then_some(entry.size),
                         last_modified: Some(datetime.timestamp() as u64),
                         ..Default::default()
                     };
                     snapshot.push(meta);
                 }
-                if permission.starts_with('l') {
+                if entry.permission.starts_with('l') {
                     warn!(logger, "symbolic link is not supported: {}", file);
                 }
             }
         }
 
-        progress.set_message("waiting for rsync to exit");
+        if let Some(result) = child {
+            progress.set_message("waiting for rsync to exit");
 
-        let status = result.await.unwrap()?;
-        if !status.success() {
-            return Err(Error::ProcessError(format!("exit code: {:?}", status)));
+            let status = result
+                .await
+                .map_err(|err| Error::ProcessError(format!("{:?}", err)))??;
+            if !status.success() {
+                return Err(Error::ProcessError(format!("exit code: {:?}", status)));
+            }
         }
 
         progress.finish_with_message("done");
@@ -136,6 +262,58 @@ impl SnapshotStorage<SnapshotMeta> for Rsync {
         Ok(snapshot)
     }
 
+    /// Listing of rsync base is checked without recursion, along with where
+    /// objects are taken from.
+    async fn check(&mut self, mission: Mission, _config: &SnapshotConfig) -> Result<String> {
+        let listing = match &self.from_file {
+            Some(path) => {
+                tokio::fs::metadata(path).await?;
+                format!("{:?}", path)
+            }
+            None => {
+                if self.rsync_base.is_empty() {
+                    return Err(Error::ConfigureError(String::from(
+                        "either rsync base or listing file should be given",
+                    )));
+                }
+                let output = Command::new("rsync")
+                    .arg(self.rsync_base.clone())
+                    .arg("--no-motd")
+                    .output()
+                    .await
+                    .map_err(|err| {
+                        Error::ProcessError(format!("failed to run rsync: {:?}", err))
+                    })?;
+                if !output.status.success() {
+                    return Err(Error::ProcessError(format!(
+                        "rsync exited with {}: {}",
+                        output.status,
+                        String::from_utf8_lossy(&output.stderr).trim()
+                    )));
+                }
+                let entries = output
+                    .stdout
+                    .split(|c| *c == b'\n')
+                    .filter(|line| !line.is_empty())
+                    .count();
+                format!("{} {} entries", self.rsync_base, entries)
+            }
+        };
+        let objects = match &self.local_base {
+            Some(local_base) => {
+                if !tokio::fs::metadata(local_base).await?.is_dir() {
+                    return Err(Error::ConfigureError(format!(
+                        "local base {:?} is not a directory",
+                        local_base
+                    )));
+                }
+                format!("{:?}", local_base)
+            }
+            None => check_url(&mission, &self.http_base).await?,
+        };
+        Ok(format!("{}; {}", listing, objects))
+    }
+
     fn info(&self) -> String {
         format!("rsync, {:?}", self)
     }
@@ -144,6 +322,137 @@ impl SnapshotStorage<SnapshotMeta> for Rsync {
 #[async_trait]
 impl SourceStorage<SnapshotMeta, TransferURL> for Rsync {
     async fn get_object(&self, snapshot: &SnapshotMeta, _mission: &Mission) -> Result<TransferURL> {
-        Ok(TransferURL(format!("{}/{}", self.http_base, snapshot.key)))
+        Ok(TransferURL::new(format!(
+            "{}/{}",
+            self.http_base, snapshot.key
+        )))
+    }
+}
+
+#[async_trait]
+impl SourceStorage<SnapshotMeta, TransferPath> for Rsync {
+    async fn get_object(
+        &self,
+        snapshot: &SnapshotMeta,
+        _mission: &Mission,
+    ) -> Result<TransferPath> {
+        let local_base = self
+            .local_base
+            .as_ref()
+            .ok_or_else(|| Error::ConfigureError("local base is not set".to_string()))?;
+        Ok(TransferPath::new(local_base.join(&snapshot.key)))
+    }
+
+    async fn get_size(&self, snapshot: &SnapshotMeta, mission: &Mission) -> Result<Option<u64>> {
+        let path = <Self as SourceStorage<SnapshotMeta, TransferPath>>::get_object(
+            self, snapshot, mission,
+        )
+        .await?;
+        Ok(Some(tokio::fs::metadata(&path.0).await?.len()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_with_spaces() {
+        let entry =
+            parse_rsync_output("-rw-r--r--      1,234,567 2021/01/01 00:00:00 My File.tar.gz")
+                .unwrap();
+        assert_eq!((entry.size, entry.exact_size), (1234567, true));
+        assert_eq!((entry.date, entry.time), ("2021/01/01", "00:00:00"));
+        assert_eq!(entry.file, "My File.tar.gz");
+
+        let entry =
+            parse_rsync_output("-rw-r--r--              6 2021/01/01 00:00:00 a  b   c").unwrap();
+        assert_eq!(entry.file, "a  b   c");
+
+        let entry =
+            parse_rsync_output("  -rw-r--r--       6 2021/01/01 00:00:00  leading and trailing ")
+                .unwrap();
+        assert_eq!(entry.file, " leading and trailing ");
+
+        assert!(parse_rsync_output("-rw-r--r--       6 2021/01/01 00:00:00 ").is_err());
+        assert!(parse_rsync_output("-rw-r--r--       6 2021/01/01").is_err());
+    }
+
+    #[test]
+    fn test_parse_rsync_size() {
+        assert_eq!(parse_rsync_size("1.234.567"), Some((1234567, true)));
+        assert_eq!(parse_rsync_size("1.23M"), Some((1230000, false)));
+        assert_eq!(parse_rsync_size("4,5K"), Some((4500, false)));
+        assert_eq!(parse_rsync_size("2G"), Some((2000000000, false)));
+        assert_eq!(parse_rsync_size("1.2X"), None);
+        assert_eq!(parse_rsync_size("1M2"), None);
+    }
+
+    #[test]
+    fn test_zero_byte_and_read_only() {
+        let entry =
+            parse_rsync_output("-r--r--r--              0 2021/01/01 00:00:00 pkg/__init__.py")
+                .unwrap();
+        assert!(is_regular_file(entry.permission));
+        assert_eq!(entry.size, 0);
+        assert_eq!(entry.file, "pkg/__init__.py");
+
+        let entry =
+            parse_rsync_output("----------          1,024 2021/01/01 00:00:00 private").unwrap();
+        assert!(is_regular_file(entry.permission));
+        assert!(!is_readable(entry.permission));
+        assert_eq!(entry.size, 1024);
+        assert!(is_readable("-r--------"));
+        assert!(is_readable("-------r--"));
+
+        assert!(!is_regular_file("drwxr-xr-x"));
+        assert!(!is_regular_file("lrwxrwxrwx"));
+    }
+
+    #[tokio::test]
+    async fn test_from_file() {
+        let path = std::env::temp_dir().join(format!("mirror-clone-rsync-{}", std::process::id()));
+        std::fs::write(
+            &path,
+            "drwxr-xr-x          4,096 2021/01/01 00:00:00 dists\n\
+             -rw-r--r--          1,024 2021/01/01 00:00:00 dists/Release\n\
+             lrwxrwxrwx              7 2021/01/01 00:00:00 latest\n",
+        )
+        .unwrap();
+        let mut source = Rsync {
+            rsync_base: "".to_string(),
+            http_base: "http://mirror.example.com/debian".to_string(),
+            debug: false,
+            ignore_prefix: "".to_string(),
+            from_file: Some(path.clone()),
+            local_base: None,
+        };
+        let config = SnapshotConfig::for_test();
+        let snapshot = source.snapshot(Mission::for_test(), &config).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(snapshot.len(), 1);
+        assert_eq!(snapshot[0].key, "dists/Release");
+        assert_eq!(snapshot[0].size, Some(1024));
+    }
+
+    #[tokio::test]
+    async fn test_plain_http_base() {
+        let source = Rsync {
+            rsync_base: "rsync://mirror.example.com/debian/".to_string(),
+            http_base: "http://mirror.example.com/debian".to_string(),
+            debug: false,
+            ignore_prefix: "".to_string(),
+            from_file: None,
+            local_base: None,
+        };
+        let snapshot = SnapshotMeta::new("dists/stable/Release".to_string());
+        let url: TransferURL = source
+            .get_object(&snapshot, &Mission::for_test())
+            .await
+            .unwrap();
+        assert_eq!(
+            url.0,
+            "http://mirror.example.com/debian/dists/stable/Release"
+        );
     }
 }
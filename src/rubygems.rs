@@ -0,0 +1,236 @@
+//! RubyGems Source
+//!
+//! RubyGems source reads `/versions` of compact index, which lists all
+//! versions of every gem. Each version may carry a platform, e.g.
+//! `1.15.0-x86_64-linux`, and is a distinct gem file. Versions prefixed with
+//! `-` in later lines are yanked, and removed from snapshot. Keys are gem
+//! files, e.g. `gems/nokogiri-1.15.0-x86_64-linux.gem`.
+//!
+//! If `--fetch-info` is set, `/info/<gem>` of each gem is also fetched,
+//! and the sha256 of each gem file is recorded in snapshot.
+
+use crate::common::{Mission, SnapshotConfig, TransferURL};
+use crate::error::{Error, Result};
+use crate::metadata::SnapshotMeta;
+use crate::rate_limit::throttle;
+use crate::traits::{SnapshotStorage, SourceStorage};
+use crate::utils::check_url;
+
+use async_trait::async_trait;
+use futures_util::{stream, StreamExt, TryStreamExt};
+use slog::{info, warn};
+use std::collections::{BTreeMap, HashMap};
+use structopt::StructOpt;
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct RubyGems {
+    #[structopt(
+        long,
+        help = "Base of compact index",
+        default_value = "https://rubygems.org"
+    )]
+    pub index_base: String,
+    #[structopt(
+        long,
+        help = "Base of gem files",
+        default_value = "https://rubygems.org"
+    )]
+    pub gems_base: String,
+    #[structopt(long, help = "Fetch info of each gem for checksums")]
+    pub fetch_info: bool,
+    #[structopt(long)]
+    pub debug: bool,
+}
+
+/// Number of gems selected in debug mode
+const DEBUG_GEMS: usize = 100;
+
+/// Parse `/versions` of compact index into versions of each gem, with
+/// yanked versions removed.
+fn parse_versions(content: &str) -> BTreeMap<String, Vec<String>> {
+    let mut gems: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    let body = content
+        .split_once("\n---\n")
+        .map_or(content, |(_, body)| body);
+    for line in body.lines() {
+        let mut parts = line.split_whitespace();
+        let (name, versions) = match (parts.next(), parts.next()) {
+            (Some(name), Some(versions)) => (name, versions),
+            _ => continue,
+        };
+        let gem = gems.entry(name.to_string()).or_default();
+        for version in versions.split(',') {
+            match version.strip_prefix('-') {
+                Some(yanked) => gem.retain(|version| version != yanked),
+                None => gem.push(version.to_string()),
+            }
+        }
+    }
+    gems.retain(|_, versions| !versions.is_empty());
+    gems
+}
+
+/// Parse sha256 of each version in `/info/<gem>`.
+fn parse_info(content: &str) -> HashMap<String, String> {
+    let body = content
+        .split_once("---\n")
+        .map_or(content, |(_, body)| body);
+    body.lines()
+        .filter_map(|line| {
+            let (version, rest) = line.split_once(' ')?;
+            let (_, requirements) = rest.split_once('|')?;
+            let checksum = requirements
+                .split(',')
+                .find_map(|requirement| requirement.strip_prefix("checksum:"))?;
+            Some((version.to_string(), checksum.to_lowercase()))
+        })
+        .collect()
+}
+
+fn gem_key(name: &str, version: &str) -> String {
+    format!("gems/{}-{}.gem", name, version)
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotMeta> for RubyGems {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotMeta>> {
+        let logger = mission.logger;
+        let progress = mission.progress;
+        let client = mission.client;
+        let rate_limit = mission.rate_limit;
+
+        info!(logger, "fetching versions...");
+        progress.set_message("fetching versions...");
+        throttle(&rate_limit).await;
+        let versions = client
+            .get(format!("{}/versions", self.index_base))
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        let mut gems = parse_versions(&versions);
+        if self.debug {
+            gems = gems.into_iter().take(DEBUG_GEMS).collect();
+        }
+        info!(logger, "{} gems", gems.len());
+
+        if !self.fetch_info {
+            progress.finish_with_message("done");
+            return Ok(gems
+                .iter()
+                .flat_map(|(name, versions)| {
+                    versions
+                        .iter()
+                        .map(move |version| SnapshotMeta::new(gem_key(name, version)))
+                })
+                .collect());
+        }
+
+        progress.inc_length(gems.len() as u64);
+        let index_base = self.index_base.clone();
+        let snapshots: Result<Vec<Vec<SnapshotMeta>>> =
+            stream::iter(gems.into_iter().map(|(name, versions)| {
+                let client = client.clone();
+                let progress = progress.clone();
+                let logger = logger.clone();
+                let rate_limit = rate_limit.clone();
+                let index_base = index_base.clone();
+
+                async move {
+                    progress.set_message(&name);
+                    throttle(&rate_limit).await;
+                    let result = async {
+                        let info = client
+                            .get(format!("{}/info/{}", index_base, name))
+                            .send()
+                            .await?
+                            .error_for_status()?
+                            .text()
+                            .await?;
+                        Ok::<_, Error>(parse_info(&info))
+                    }
+                    .await;
+                    let checksums = result.unwrap_or_else(|err| {
+                        warn!(logger, "failed to fetch info of {}: {:?}", name, err);
+                        HashMap::new()
+                    });
+                    progress.inc(1);
+                    Ok(versions
+                        .iter()
+                        .map(|version| {
+                            let checksum = checksums.get(version);
+                            SnapshotMeta {
+                                key: gem_key(&name, version),
+                                checksum_method: checksum.map(|_| "sha256".to_string()),
+                                checksum: checksum.cloned(),
+                                ..Default::default()
+                            }
+                        })
+                        .collect())
+                }
+            }))
+            .buffer_unordered(config.concurrent_resolve)
+            .try_collect()
+            .await;
+
+        let snapshot: Vec<_> = snapshots?.into_iter().flatten().collect();
+
+        progress.finish_with_message("done");
+
+        Ok(snapshot)
+    }
+
+    async fn check(&mut self, mission: Mission, _config: &SnapshotConfig) -> Result<String> {
+        check_url(&mission, &self.index_base).await
+    }
+
+    fn info(&self) -> String {
+        format!("rubygems, {:?}", self)
+    }
+}
+
+#[async_trait]
+impl SourceStorage<SnapshotMeta, TransferURL> for RubyGems {
+    async fn get_object(&self, snapshot: &SnapshotMeta, _mission: &Mission) -> Result<TransferURL> {
+        Ok(TransferURL::new(format!(
+            "{}/{}",
+            self.gems_base, snapshot.key
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_versions() {
+        let gems = parse_versions(
+            "created_at: 2024-01-01T00:00:00Z\n\
+             ---\n\
+             nokogiri 1.15.0,1.15.0-x86_64-linux,1.15.1 0123\n\
+             rake 13.0.0 4567\n\
+             nokogiri -1.15.1 89ab\n\
+             gone 0.1.0,-0.1.0 cdef\n",
+        );
+        assert_eq!(gems.len(), 2);
+        assert_eq!(gems["nokogiri"], vec!["1.15.0", "1.15.0-x86_64-linux"]);
+        assert_eq!(
+            gem_key("nokogiri", &gems["nokogiri"][1]),
+            "gems/nokogiri-1.15.0-x86_64-linux.gem"
+        );
+
+        let checksums = parse_info(
+            "---\n\
+             1.15.0 racc:~> 1.4|checksum:ABCD,ruby:>= 2.7\n\
+             1.15.0-x86_64-linux racc:~> 1.4|checksum:ef01\n",
+        );
+        assert_eq!(checksums["1.15.0"], "abcd");
+        assert_eq!(checksums["1.15.0-x86_64-linux"], "ef01");
+    }
+}
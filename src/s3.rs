@@ -3,7 +3,12 @@
 //! S3 backend is a target storage, which enables taking snapshot of an S3
 //! storage, and uploading objects to it. For snapshot, this storage by default
 //! only has size and path. We could enable modify time and other metadata
-//! in snapshot later. This storage only accepts `ByteStream`.
+//! in snapshot later. This storage accepts `ByteStream`, `TransferStream`
+//! and `TransferPath`, whose file is streamed from local disk.
+//!
+//! If `store_headers` is set, stored response headers of source objects are
+//! mapped to the corresponding S3 fields (e.g. `Cache-Control`), or to object
+//! metadata prefixed with `origin-` if S3 has no such field.
 //!
 //! This backend has only been tested with SJTU S3 service, which is
 //! (possibly) set up with Ceph. Unlike official S3 protocol, SJTU
@@ -13,23 +18,38 @@
 //! `go@...` or `go%40...` on HTTP.
 //!
 //! This backend will automatically add a MIME type for object, based on
-//! suffix.
+//! suffix. Objects larger than multipart threshold are uploaded in parts.
+//! Credentials are taken from environment or AWS profile, as with other
+//! AWS tools.
 
-use std::{collections::HashMap, sync::atomic::AtomicU64};
+use std::collections::HashMap;
 
-use crate::common::{Mission, SnapshotConfig, SnapshotPath};
+use crate::checksum::checksum_reader;
+use crate::common::{Mission, SnapshotConfig, SnapshotPath, TransferPath, TransferStream};
 use crate::error::{Error, Result};
 use crate::metadata::SnapshotMeta;
+use crate::rate_limit::throttle;
 use crate::stream_pipe::ByteStream;
 use crate::traits::{Key, SnapshotStorage, TargetStorage};
 
 use async_trait::async_trait;
-use futures_util::{stream, StreamExt};
-use rusoto_core::Region;
+use filetime::FileTime;
+use futures_core::Stream;
+use futures_util::{
+    stream::{self, BoxStream},
+    StreamExt, TryStreamExt,
+};
+use indicatif::ProgressBar;
+use rusoto_core::{Region, RusotoError};
 use rusoto_s3::{
-    DeleteObjectRequest, HeadObjectRequest, ListObjectsV2Request, PutObjectRequest, S3Client, S3,
+    AbortMultipartUploadRequest, CompleteMultipartUploadRequest, CompletedMultipartUpload,
+    CompletedPart, CreateMultipartUploadRequest, DeleteObjectRequest, GetObjectError,
+    GetObjectRequest, HeadObjectRequest, ListObjectsV2Request, PutObjectRequest, S3Client,
+    UploadPartRequest, S3,
 };
 use slog::{debug, info, warn};
+use tokio::io::AsyncReadExt;
+use tokio_util::codec;
 
 #[derive(Debug)]
 pub struct S3Config {
@@ -39,6 +59,12 @@ pub struct S3Config {
     pub prefix_hint_mode: Option<String>,
     pub scan_metadata: bool,
     pub max_keys: u64,
+    pub store_headers: bool,
+    /// Region name used for signing requests
+    pub region: String,
+    /// Objects larger than this are uploaded in parts
+    pub multipart_threshold: u64,
+    pub part_size: u64,
 }
 
 impl S3Config {
@@ -50,10 +76,17 @@ impl S3Config {
             max_keys: 1000,
             prefix_hint_mode: None,
             scan_metadata,
+            store_headers: false,
+            region: "jCloud S3".to_string(),
+            multipart_threshold: 1 << 30,
+            part_size: 64 << 20,
         }
     }
 }
 
+/// Minimum size of parts except the last one, required by S3
+const MIN_PART_SIZE: u64 = 5 << 20;
+
 pub struct S3Backend {
     config: S3Config,
     client: S3Client,
@@ -69,7 +102,7 @@ fn get_s3_client(name: String, endpoint: String) -> S3Client {
 
 impl S3Backend {
     pub fn new(config: S3Config) -> Self {
-        let client = get_s3_client("jCloud S3".to_string(), config.endpoint.clone());
+        let client = get_s3_client(config.region.clone(), config.endpoint.clone());
         Self { config, client }
     }
 
@@ -80,22 +113,145 @@ impl S3Backend {
     }
 }
 
-#[async_trait]
-impl SnapshotStorage<SnapshotMeta> for S3Backend {
-    async fn snapshot(
-        &mut self,
-        mission: Mission,
-        _config: &SnapshotConfig,
-    ) -> Result<Vec<SnapshotMeta>> {
-        let logger = mission.logger;
-        let progress = mission.progress;
+impl S3Backend {
+    /// Upload object in parts of `part_size`, so that objects larger than
+    /// the limit of a single put may be uploaded. Body is streamed, and each
+    /// part is uploaded as soon as it's read, so that at most one part is
+    /// held in memory. The upload is aborted on failure, so that no parts
+    /// are left on S3.
+    async fn put_multipart(
+        &self,
+        req: PutObjectRequest,
+        mut body: impl Stream<Item = std::io::Result<bytes::Bytes>> + Send + Unpin,
+        mission: &Mission,
+    ) -> Result<()> {
+        let create = CreateMultipartUploadRequest {
+            bucket: req.bucket,
+            key: req.key,
+            metadata: req.metadata,
+            content_type: req.content_type,
+            cache_control: req.cache_control,
+            content_disposition: req.content_disposition,
+            content_encoding: req.content_encoding,
+            content_language: req.content_language,
+            ..Default::default()
+        };
+        let (bucket, key) = (create.bucket.clone(), create.key.clone());
+        let upload_id = self
+            .client
+            .create_multipart_upload(create)
+            .await?
+            .upload_id
+            .ok_or_else(|| Error::StorageError(format!("no upload id for {}", key)))?;
+
+        let part_size = self.config.part_size.max(MIN_PART_SIZE) as usize;
+        let upload = async {
+            let mut buf = vec![];
+            let mut parts = vec![];
+            let mut exhausted = false;
+            loop {
+                while !exhausted && buf.len() < part_size {
+                    match body.next().await {
+                        Some(chunk) => buf.extend_from_slice(&chunk?),
+                        None => exhausted = true,
+                    }
+                }
+                if buf.is_empty() {
+                    break;
+                }
+                let rest = buf.split_off(part_size.min(buf.len()));
+                let part = std::mem::replace(&mut buf, rest);
+                let part_number = parts.len() as i64 + 1;
+                let resp = self
+                    .client
+                    .upload_part(UploadPartRequest {
+                        bucket: bucket.clone(),
+                        key: key.clone(),
+                        upload_id: upload_id.clone(),
+                        part_number,
+                        content_length: Some(part.len() as i64),
+                        body: Some(part.into()),
+                        ..Default::default()
+                    })
+                    .await?;
+                parts.push(CompletedPart {
+                    e_tag: resp.e_tag,
+                    part_number: Some(part_number),
+                });
+            }
+            debug!(mission.logger, "upload {} in {} parts", key, parts.len());
+            self.client
+                .complete_multipart_upload(CompleteMultipartUploadRequest {
+                    bucket: bucket.clone(),
+                    key: key.clone(),
+                    upload_id: upload_id.clone(),
+                    multipart_upload: Some(CompletedMultipartUpload { parts: Some(parts) }),
+                    ..Default::default()
+                })
+                .await?;
+            Ok::<_, Error>(())
+        };
 
-        info!(logger, "fetching data from S3 storage...");
+        let result = upload.await;
+        if result.is_err() {
+            let abort = AbortMultipartUploadRequest {
+                bucket,
+                key: key.clone(),
+                upload_id,
+                ..Default::default()
+            };
+            if let Err(err) = self.client.abort_multipart_upload(abort).await {
+                warn!(
+                    mission.logger,
+                    "failed to abort upload of {}: {:?}", key, err
+                );
+            }
+        }
+        result
+    }
 
-        let s3_prefix_base = format!("{}/", self.config.prefix);
-        let total_size = std::sync::Arc::new(AtomicU64::new(0));
+    /// Request to put object, without body and its length.
+    fn put_request<Snapshot: Key + S3Metadata>(
+        &self,
+        snapshot: &Snapshot,
+        modified_at: u64,
+        content_type: Option<String>,
+        headers: Vec<(String, String)>,
+    ) -> PutObjectRequest {
+        let mut metadata = self.gen_metadata();
+        metadata.insert("clone-last-modified".to_string(), modified_at.to_string());
+        metadata.extend(snapshot.s3_meta());
 
-        let prefix = match self.config.prefix_hint_mode.as_deref() {
+        let mut req = PutObjectRequest {
+            bucket: self.config.bucket.clone(),
+            key: format!("{}/{}", self.config.prefix, snapshot.key()),
+            metadata: Some(metadata),
+            content_type: content_type.or_else(|| get_mime(snapshot.key())),
+            ..Default::default()
+        };
+
+        if self.config.store_headers {
+            for (name, value) in headers {
+                match name.as_str() {
+                    "cache-control" => req.cache_control = Some(value),
+                    "content-disposition" => req.content_disposition = Some(value),
+                    "content-encoding" => req.content_encoding = Some(value),
+                    "content-language" => req.content_language = Some(value),
+                    _ => {
+                        req.metadata
+                            .as_mut()
+                            .unwrap()
+                            .insert(format!("origin-{}", name), value);
+                    }
+                }
+            }
+        }
+
+        req
+    }
+
+    fn list_prefixes(&self) -> Vec<String> {
+        match self.config.prefix_hint_mode.as_deref() {
             Some("pypi") => {
                 let mut prefix = vec![];
                 for i in 0..256 {
@@ -107,127 +263,139 @@ impl SnapshotStorage<SnapshotMeta> for S3Backend {
             Some(other) => {
                 panic!("unsupported prefix hint mode {}", other);
             }
-        };
-
-        // List bucket
-        let mut futures = stream::iter(prefix)
-            .map(|additional_prefix| {
-                let bucket = self.config.bucket.clone();
-                let prefix = Some(format!("{}{}", self.config.prefix, additional_prefix));
-                let client = self.client.clone();
-                let total_size = total_size.clone();
-                let progress = progress.clone();
-                let logger = logger.clone();
-                let s3_prefix_base = s3_prefix_base.clone();
-                let max_keys = self.config.max_keys;
+        }
+    }
 
-                async move {
-                    let mut snapshot = vec![];
-                    let mut continuation_token = None;
-
-                    loop {
-                        let req = ListObjectsV2Request {
-                            bucket: bucket.clone(),
-                            prefix: prefix.clone(),
-                            max_keys: Some(max_keys as i64),
-                            continuation_token,
-                            ..Default::default()
-                        };
-
-                        let resp = client.list_objects_v2(req).await?;
-
-                        let mut first_key = true;
-
-                        if let Some(contents) = resp.contents {
-                            for item in contents {
-                                if let Some(size) = item.size {
-                                    total_size.fetch_add(
-                                        size as u64,
-                                        std::sync::atomic::Ordering::SeqCst,
-                                    );
-                                }
-                                let key = item.key.unwrap();
-                                if key.starts_with(&s3_prefix_base) {
-                                    let key = key[s3_prefix_base.len()..].to_string();
-                                    // let key = crate::utils::rewrite_url_string(&gen_map, &key);
-                                    if first_key {
-                                        first_key = false;
-                                        progress.set_message(&key);
-                                    }
-                                    snapshot.push(SnapshotMeta {
-                                        key,
-                                        size: item.size.map(|x| x as u64),
-                                        ..Default::default()
-                                    });
-                                } else {
-                                    warn!(logger, "prefix not match {}", key);
-                                }
+    /// List all objects under `prefix`, yielding one page per `ListObjectsV2` request.
+    fn list_prefix(
+        &self,
+        prefix: String,
+        mission: &Mission,
+    ) -> impl Stream<Item = Result<Vec<SnapshotMeta>>> {
+        let bucket = self.config.bucket.clone();
+        let client = self.client.clone();
+        let progress = mission.progress.clone();
+        let logger = mission.logger.clone();
+        let rate_limit = mission.rate_limit.clone();
+        let s3_prefix_base = format!("{}/", self.config.prefix);
+        let max_keys = self.config.max_keys;
+
+        // `None` marks the end of listing, `Some(None)` is the first request.
+        stream::try_unfold(Some(None), move |continuation_token| {
+            let bucket = bucket.clone();
+            let prefix = prefix.clone();
+            let client = client.clone();
+            let progress = progress.clone();
+            let logger = logger.clone();
+            let rate_limit = rate_limit.clone();
+            let s3_prefix_base = s3_prefix_base.clone();
+
+            async move {
+                let continuation_token = match continuation_token {
+                    Some(continuation_token) => continuation_token,
+                    None => return Ok(None),
+                };
+
+                let req = ListObjectsV2Request {
+                    bucket,
+                    prefix: Some(prefix),
+                    max_keys: Some(max_keys as i64),
+                    continuation_token,
+                    ..Default::default()
+                };
+
+                throttle(&rate_limit).await;
+                let resp = client.list_objects_v2(req).await?;
+
+                let mut snapshot = vec![];
+                let mut first_key = true;
+
+                if let Some(contents) = resp.contents {
+                    for item in contents {
+                        let key = item.key.unwrap();
+                        if key.starts_with(&s3_prefix_base) {
+                            let key = key[s3_prefix_base.len()..].to_string();
+                            if first_key {
+                                first_key = false;
+                                progress.set_message(&key);
                             }
-                        }
-
-                        if let Some(next_continuation_token) = resp.next_continuation_token {
-                            continuation_token = Some(next_continuation_token);
+                            snapshot.push(SnapshotMeta {
+                                key,
+                                size: item.size.map(|x| x as u64),
+                                ..Default::default()
+                            });
                         } else {
-                            break;
+                            warn!(logger, "prefix not match {}", key);
                         }
                     }
-                    Ok::<_, Error>(snapshot)
                 }
-            })
-            .buffer_unordered(256);
-
-        let mut snapshots = vec![];
 
-        while let Some(snapshot) = futures.next().await {
-            snapshots.append(&mut snapshot?);
-        }
+                Ok::<_, Error>(Some((snapshot, resp.next_continuation_token.map(Some))))
+            }
+        })
+    }
 
-        // Get metadata
-        let snapshots = if self.config.scan_metadata {
-            let mut futures = stream::iter(snapshots)
-                .map(|snapshot| {
-                    let bucket = self.config.bucket.clone();
-                    let client = self.client.clone();
-                    let progress = progress.clone();
-                    let prefix = self.config.prefix.clone();
-
-                    async move {
-                        progress.set_message(&snapshot.key);
-                        let req = HeadObjectRequest {
-                            bucket,
-                            key: format!("{}/{}", prefix, snapshot.key),
-                            ..Default::default()
-                        };
-                        let resp = client.head_object(req).await?;
-                        let last_modified = if let Some(metadata) = resp.metadata {
-                            metadata
-                                .get("clone-last-modified")
-                                .and_then(|x| x.parse::<u64>().ok())
-                        } else {
-                            None
-                        };
-                        Ok::<_, Error>(SnapshotMeta {
-                            last_modified,
-                            ..snapshot
-                        })
-                    }
-                })
-                .buffer_unordered(64);
+    /// Fill in metadata of a page of snapshot with `HeadObject` requests.
+    async fn scan_metadata(
+        &self,
+        snapshot: Vec<SnapshotMeta>,
+        progress: ProgressBar,
+        concurrent_resolve: usize,
+    ) -> Result<Vec<SnapshotMeta>> {
+        stream::iter(snapshot)
+            .map(|snapshot| {
+                let bucket = self.config.bucket.clone();
+                let client = self.client.clone();
+                let progress = progress.clone();
+                let prefix = self.config.prefix.clone();
 
-            let mut snapshots = vec![];
+                async move {
+                    progress.set_message(&snapshot.key);
+                    let req = HeadObjectRequest {
+                        bucket,
+                        key: format!("{}/{}", prefix, snapshot.key),
+                        ..Default::default()
+                    };
+                    let resp = client.head_object(req).await?;
+                    let last_modified = if let Some(metadata) = resp.metadata {
+                        metadata
+                            .get("clone-last-modified")
+                            .and_then(|x| x.parse::<u64>().ok())
+                    } else {
+                        None
+                    };
+                    Ok::<_, Error>(SnapshotMeta {
+                        last_modified,
+                        ..snapshot
+                    })
+                }
+            })
+            .buffer_unordered(concurrent_resolve)
+            .try_collect()
+            .await
+    }
+}
 
-            while let Some(snapshot) = futures.next().await {
-                snapshots.push(snapshot?);
-            }
+#[async_trait]
+impl SnapshotStorage<SnapshotMeta> for S3Backend {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotMeta>> {
+        let logger = mission.logger.clone();
+        let progress = mission.progress.clone();
 
-            snapshots
-        } else {
-            snapshots
-        };
+        let mut snapshots = vec![];
+        let mut pages =
+            <Self as SnapshotStorage<SnapshotMeta>>::snapshot_stream(self, mission, config);
+        while let Some(page) = pages.next().await {
+            snapshots.append(&mut page?);
+        }
 
         progress.finish_with_message("done");
 
-        let total_size = total_size.load(std::sync::atomic::Ordering::SeqCst);
+        let total_size: u64 = snapshots.iter().filter_map(|x| x.size).sum();
         info!(
             logger,
             "total size: {}B or {}G",
@@ -238,6 +406,37 @@ impl SnapshotStorage<SnapshotMeta> for S3Backend {
         Ok(snapshots)
     }
 
+    fn snapshot_stream<'a>(
+        &'a mut self,
+        mission: Mission,
+        config: &'a SnapshotConfig,
+    ) -> BoxStream<'a, Result<Vec<SnapshotMeta>>> {
+        info!(mission.logger, "fetching data from S3 storage...");
+
+        let this = &*self;
+        let progress = mission.progress.clone();
+
+        // List bucket, all prefixes are listed concurrently
+        let pages = stream::select_all(this.list_prefixes().into_iter().map(|additional_prefix| {
+            this.list_prefix(
+                format!("{}{}", this.config.prefix, additional_prefix),
+                &mission,
+            )
+            .boxed()
+        }));
+
+        // Get metadata
+        if this.config.scan_metadata {
+            pages
+                .and_then(move |page| {
+                    this.scan_metadata(page, progress.clone(), config.concurrent_resolve)
+                })
+                .boxed()
+        } else {
+            pages.boxed()
+        }
+    }
+
     fn info(&self) -> String {
         format!("s3 (meta), {:?}", self.config)
     }
@@ -259,6 +458,16 @@ impl SnapshotStorage<SnapshotPath> for S3Backend {
         )
     }
 
+    fn snapshot_stream<'a>(
+        &'a mut self,
+        mission: Mission,
+        config: &'a SnapshotConfig,
+    ) -> BoxStream<'a, Result<Vec<SnapshotPath>>> {
+        <Self as SnapshotStorage<SnapshotMeta>>::snapshot_stream(self, mission, config)
+            .map_ok(|page| page.into_iter().map(|x| SnapshotPath::new(x.key)).collect())
+            .boxed()
+    }
+
     fn info(&self) -> String {
         format!("s3 (path), {:?}", self.config)
     }
@@ -294,6 +503,8 @@ fn get_mime(key: &str) -> Option<String> {
     // TODO: the correct way is to mirror content-type from remote as-is, or to read MIME type
     if key.ends_with(".htm") || key.ends_with(".html") || key.ends_with(".shtml") {
         Some("text/html; charset=utf-8".to_string())
+    } else if key.ends_with(".json") {
+        Some("application/json".to_string())
     } else {
         None
     }
@@ -318,36 +529,471 @@ where
             length,
             modified_at,
             content_type,
+            headers,
         } = byte_stream;
 
-        let body = object.as_stream();
+        let mut req = self.put_request(snapshot, modified_at, content_type, headers);
 
-        let mut metadata = self.gen_metadata();
-        metadata.insert("clone-last-modified".to_string(), modified_at.to_string());
-        metadata.extend(snapshot.s3_meta());
+        if length > self.config.multipart_threshold {
+            return self
+                .put_multipart(req, object.as_stream().boxed(), mission)
+                .await;
+        }
 
-        let req = PutObjectRequest {
+        req.content_length = Some(length as i64);
+        req.body = Some(rusoto_s3::StreamingBody::new(object.as_stream()));
+        self.client.put_object(req).await?;
+
+        Ok(())
+    }
+
+    async fn delete_object(&self, snapshot: &Snapshot, _mission: &Mission) -> Result<()> {
+        let req = DeleteObjectRequest {
             bucket: self.config.bucket.clone(),
             key: format!("{}/{}", self.config.prefix, snapshot.key()),
-            body: Some(rusoto_s3::StreamingBody::new(body)),
-            metadata: Some(metadata),
-            content_length: Some(length as i64),
-            content_type: content_type.or_else(|| get_mime(snapshot.key())),
             ..Default::default()
         };
+        self.client.delete_object(req).await?;
+        Ok(())
+    }
 
-        self.client.put_object(req).await?;
+    fn can_checksum(&self) -> bool {
+        true
+    }
+
+    async fn checksum_object(&self, snapshot: &Snapshot, method: &str) -> Result<Option<String>> {
+        let req = GetObjectRequest {
+            bucket: self.config.bucket.clone(),
+            key: format!("{}/{}", self.config.prefix, snapshot.key()),
+            ..Default::default()
+        };
+        let resp = match self.client.get_object(req).await {
+            Ok(resp) => resp,
+            Err(RusotoError::Service(GetObjectError::NoSuchKey(_))) => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        match resp.body {
+            Some(body) => checksum_reader(method, body.into_async_read()).await,
+            None => checksum_reader(method, tokio::io::empty()).await,
+        }
+    }
+
+    async fn get_raw_object(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let req = GetObjectRequest {
+            bucket: self.config.bucket.clone(),
+            key: format!("{}/{}", self.config.prefix, key),
+            ..Default::default()
+        };
+        let resp = match self.client.get_object(req).await {
+            Ok(resp) => resp,
+            Err(RusotoError::Service(GetObjectError::NoSuchKey(_))) => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let mut content = vec![];
+        if let Some(body) = resp.body {
+            body.into_async_read().read_to_end(&mut content).await?;
+        }
+        Ok(Some(content))
+    }
 
+    async fn put_raw_object(&self, key: &str, content: Vec<u8>) -> Result<()> {
+        let req = PutObjectRequest {
+            bucket: self.config.bucket.clone(),
+            key: format!("{}/{}", self.config.prefix, key),
+            content_length: Some(content.len() as i64),
+            body: Some(content.into()),
+            metadata: Some(self.gen_metadata()),
+            content_type: get_mime(key),
+            ..Default::default()
+        };
+        self.client.put_object(req).await?;
         Ok(())
     }
 
-    async fn delete_object(&self, snapshot: &Snapshot, _mission: &Mission) -> Result<()> {
+    async fn delete_raw_object(&self, key: &str) -> Result<()> {
         let req = DeleteObjectRequest {
             bucket: self.config.bucket.clone(),
-            key: format!("{}/{}", self.config.prefix, snapshot.key()),
+            key: format!("{}/{}", self.config.prefix, key),
             ..Default::default()
         };
         self.client.delete_object(req).await?;
         Ok(())
     }
 }
+
+/// Streamed objects of known length up to multipart threshold are put in one
+/// request. Others are uploaded in parts, which also covers objects whose
+/// length is unknown.
+#[async_trait]
+impl<Snapshot> TargetStorage<Snapshot, TransferStream> for S3Backend
+where
+    Snapshot: Key + S3Metadata,
+{
+    async fn put_object(
+        &self,
+        snapshot: &Snapshot,
+        item: TransferStream,
+        mission: &Mission,
+    ) -> Result<()> {
+        debug!(mission.logger, "upload: {}", snapshot.key());
+
+        let mut req = self.put_request(snapshot, item.modified_at, item.content_type, item.headers);
+
+        match item.length {
+            Some(length) if length <= self.config.multipart_threshold => {
+                req.content_length = Some(length as i64);
+                req.body = Some(rusoto_s3::StreamingBody::new(item.stream));
+                self.client.put_object(req).await?;
+                Ok(())
+            }
+            _ => self.put_multipart(req, item.stream, mission).await,
+        }
+    }
+
+    async fn delete_object(&self, snapshot: &Snapshot, mission: &Mission) -> Result<()> {
+        <Self as TargetStorage<Snapshot, ByteStream>>::delete_object(self, snapshot, mission).await
+    }
+
+    fn can_checksum(&self) -> bool {
+        true
+    }
+
+    async fn checksum_object(&self, snapshot: &Snapshot, method: &str) -> Result<Option<String>> {
+        <Self as TargetStorage<Snapshot, ByteStream>>::checksum_object(self, snapshot, method).await
+    }
+
+    async fn get_raw_object(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        <Self as TargetStorage<Snapshot, ByteStream>>::get_raw_object(self, key).await
+    }
+
+    async fn put_raw_object(&self, key: &str, content: Vec<u8>) -> Result<()> {
+        <Self as TargetStorage<Snapshot, ByteStream>>::put_raw_object(self, key, content).await
+    }
+
+    async fn delete_raw_object(&self, key: &str) -> Result<()> {
+        <Self as TargetStorage<Snapshot, ByteStream>>::delete_raw_object(self, key).await
+    }
+}
+
+/// Objects already on local disk are streamed from their file, in one
+/// request up to multipart threshold or in parts otherwise. Modification
+/// time is taken from the file.
+#[async_trait]
+impl<Snapshot> TargetStorage<Snapshot, TransferPath> for S3Backend
+where
+    Snapshot: Key + S3Metadata,
+{
+    async fn put_object(
+        &self,
+        snapshot: &Snapshot,
+        item: TransferPath,
+        mission: &Mission,
+    ) -> Result<()> {
+        debug!(mission.logger, "upload: {}", snapshot.key());
+
+        let file = tokio::fs::File::open(&item.0).await?;
+        let metadata = file.metadata().await?;
+        let modified_at = FileTime::from_last_modification_time(&metadata).unix_seconds() as u64;
+        let length = metadata.len();
+        let body = codec::FramedRead::new(file, codec::BytesCodec::new())
+            .map_ok(|bytes| bytes.freeze())
+            .boxed();
+
+        let mut req = self.put_request(snapshot, modified_at, None, vec![]);
+
+        // temporary file of item is removed once it is dropped after upload
+        if length > self.config.multipart_threshold {
+            return self.put_multipart(req, body, mission).await;
+        }
+
+        req.content_length = Some(length as i64);
+        req.body = Some(rusoto_s3::StreamingBody::new(body));
+        self.client.put_object(req).await?;
+        Ok(())
+    }
+
+    async fn delete_object(&self, snapshot: &Snapshot, mission: &Mission) -> Result<()> {
+        <Self as TargetStorage<Snapshot, ByteStream>>::delete_object(self, snapshot, mission).await
+    }
+
+    fn can_checksum(&self) -> bool {
+        true
+    }
+
+    async fn checksum_object(&self, snapshot: &Snapshot, method: &str) -> Result<Option<String>> {
+        <Self as TargetStorage<Snapshot, ByteStream>>::checksum_object(self, snapshot, method).await
+    }
+
+    async fn get_raw_object(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        <Self as TargetStorage<Snapshot, ByteStream>>::get_raw_object(self, key).await
+    }
+
+    async fn put_raw_object(&self, key: &str, content: Vec<u8>) -> Result<()> {
+        <Self as TargetStorage<Snapshot, ByteStream>>::put_raw_object(self, key, content).await
+    }
+
+    async fn delete_raw_object(&self, key: &str) -> Result<()> {
+        <Self as TargetStorage<Snapshot, ByteStream>>::delete_raw_object(self, key).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::TransferURL;
+    use crate::stream_pipe::TransferStreamPipe;
+    use crate::traits::SourceStorage;
+    use rusoto_core::credential::StaticProvider;
+    use rusoto_core::HttpClient;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+
+    struct StaticURL(String);
+
+    #[async_trait]
+    impl SourceStorage<SnapshotMeta, TransferURL> for StaticURL {
+        async fn get_object(
+            &self,
+            _snapshot: &SnapshotMeta,
+            _mission: &Mission,
+        ) -> Result<TransferURL> {
+            Ok(TransferURL::new(self.0.clone()))
+        }
+    }
+
+    /// Backend on a fake S3 endpoint, with parts of minimum size.
+    fn test_backend(addr: std::net::SocketAddr) -> S3Backend {
+        let mut config = S3Config::new_jcloud("prefix".to_string(), false);
+        config.endpoint = format!("http://{}", addr);
+        config.part_size = MIN_PART_SIZE;
+        let client = S3Client::new_with(
+            HttpClient::new().unwrap(),
+            StaticProvider::new_minimal("key".to_string(), "secret".to_string()),
+            jcloud_region(config.region.clone(), config.endpoint.clone()),
+        );
+        S3Backend { config, client }
+    }
+
+    /// Read one request of a connection, returning its request line and
+    /// body, or `None` once the connection is closed.
+    async fn read_request(
+        socket: &mut tokio::net::TcpStream,
+        buffered: &mut Vec<u8>,
+    ) -> Option<(String, Vec<u8>)> {
+        let mut buf = vec![0; 64 << 10];
+        loop {
+            if let Some(end) = buffered.windows(4).position(|w| w == b"\r\n\r\n") {
+                let head = String::from_utf8_lossy(&buffered[..end]).to_string();
+                let length: usize = head
+                    .lines()
+                    .find_map(|line| {
+                        let (name, value) = line.split_once(':')?;
+                        name.eq_ignore_ascii_case("content-length")
+                            .then(|| value.trim().parse().unwrap())
+                    })
+                    .unwrap_or(0);
+                if buffered.len() >= end + 4 + length {
+                    let body = buffered[end + 4..end + 4 + length].to_vec();
+                    buffered.drain(..end + 4 + length);
+                    return Some((head.lines().next().unwrap().to_string(), body));
+                }
+            }
+            let n = socket.read(&mut buf).await.unwrap_or(0);
+            if n == 0 {
+                return None;
+            }
+            buffered.extend_from_slice(&buf[..n]);
+        }
+    }
+
+    /// Serve multipart uploads, recording each request handled.
+    async fn serve_multipart(listener: TcpListener, events: Arc<Mutex<Vec<String>>>) {
+        loop {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let events = events.clone();
+            tokio::spawn(async move {
+                let mut buffered = vec![];
+                while let Some((line, body)) = read_request(&mut socket, &mut buffered).await {
+                    let (event, response) = if line.starts_with("POST") && line.contains("?uploads")
+                    {
+                        (
+                            "create".to_string(),
+                            "<InitiateMultipartUploadResult><UploadId>id</UploadId></InitiateMultipartUploadResult>",
+                        )
+                    } else if line.starts_with("PUT") && line.contains("partNumber=") {
+                        (format!("part {}", body.len()), "")
+                    } else if line.starts_with("POST") && line.contains("uploadId=") {
+                        (
+                            "complete".to_string(),
+                            "<CompleteMultipartUploadResult></CompleteMultipartUploadResult>",
+                        )
+                    } else {
+                        (line, "")
+                    };
+                    events.lock().unwrap().push(event);
+                    let response = format!(
+                        "HTTP/1.1 200 OK\r\nETag: \"etag\"\r\nContent-Length: {}\r\n\r\n{}",
+                        response.len(),
+                        response
+                    );
+                    socket.write_all(response.as_bytes()).await.unwrap();
+                }
+            });
+        }
+    }
+
+    /// Serve one PUT, and respond only if the whole body is received.
+    async fn serve_put(listener: TcpListener, committed: Arc<AtomicBool>) {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0; 1024];
+        loop {
+            let n = socket.read(&mut buf).await.unwrap_or(0);
+            if n == 0 {
+                return;
+            }
+            request.extend_from_slice(&buf[..n]);
+            let request = String::from_utf8_lossy(&request).to_string();
+            if let Some((head, body)) = request.split_once("\r\n\r\n") {
+                let length: usize = head
+                    .lines()
+                    .find_map(|line| {
+                        let (name, value) = line.split_once(':')?;
+                        name.eq_ignore_ascii_case("content-length")
+                            .then(|| value.trim().parse().unwrap())
+                    })
+                    .unwrap();
+                if body.len() >= length {
+                    break;
+                }
+            }
+        }
+        committed.store(true, Ordering::SeqCst);
+        socket
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_put_mismatched_checksum() {
+        let source = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let source_addr = source.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = source.accept().await.unwrap();
+            let mut request = [0; 1024];
+            let _ = socket.read(&mut request).await.unwrap();
+            socket
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nLast-Modified: Fri, 01 Jan 2021 00:00:00 GMT\r\n\r\nhello")
+                .await
+                .unwrap();
+        });
+
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        let committed = Arc::new(AtomicBool::new(false));
+        tokio::spawn(serve_put(target, committed.clone()));
+
+        let backend = test_backend(target_addr);
+
+        let source =
+            TransferStreamPipe::new(StaticURL(format!("http://{}/object", source_addr)), false);
+        let snapshot = SnapshotMeta {
+            checksum_method: Some("sha256".to_string()),
+            checksum: Some("0".repeat(64)),
+            ..SnapshotMeta::new("object".to_string())
+        };
+        let mission = Mission::for_test();
+        let item = source.get_object(&snapshot, &mission).await.unwrap();
+        assert_eq!(item.length, Some(5));
+        assert!(backend.put_object(&snapshot, item, &mission).await.is_err());
+        assert!(!committed.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_put_multipart_streaming() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        let events = Arc::new(Mutex::new(vec![]));
+        tokio::spawn(serve_multipart(target, events.clone()));
+        let backend = test_backend(target_addr);
+
+        // stream of unknown length, which is fed only after its first part
+        // is uploaded
+        let (sender, receiver) = tokio::sync::mpsc::channel::<bytes::Bytes>(1);
+        let stream = stream::unfold(receiver, |mut receiver| async move {
+            let chunk = receiver.recv().await?;
+            Some((Ok(chunk), receiver))
+        });
+        let item = TransferStream {
+            stream: stream.boxed(),
+            length: None,
+            modified_at: 0,
+            content_type: None,
+            headers: vec![],
+        };
+        let snapshot = SnapshotMeta::new("object".to_string());
+        let mission = Mission::for_test();
+        let feed = async {
+            for _ in 0..5 {
+                sender.send(vec![0; 1 << 20].into()).await.unwrap();
+            }
+            let uploaded = async {
+                while !events.lock().unwrap().iter().any(|e| e.starts_with("part")) {
+                    tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+                }
+            };
+            tokio::time::timeout(std::time::Duration::from_secs(10), uploaded)
+                .await
+                .expect("first part isn't uploaded before stream ends");
+            sender.send(vec![0; 100].into()).await.unwrap();
+            drop(sender);
+        };
+        let (result, ()) = tokio::join!(backend.put_object(&snapshot, item, &mission), feed);
+        result.unwrap();
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec!["create", "part 5242880", "part 100", "complete"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_put_path() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        let events = Arc::new(Mutex::new(vec![]));
+        tokio::spawn(serve_multipart(target, events.clone()));
+        let mut backend = test_backend(target_addr);
+        backend.config.multipart_threshold = MIN_PART_SIZE;
+
+        let dir = std::env::temp_dir().join(format!("mirror-clone-s3-path-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let small = dir.join("small");
+        let large = dir.join("large");
+        std::fs::write(&small, b"hello").unwrap();
+        std::fs::write(&large, vec![0; MIN_PART_SIZE as usize + 100]).unwrap();
+
+        let snapshot = SnapshotMeta::new("object".to_string());
+        let mission = Mission::for_test();
+        backend
+            .put_object(&snapshot, TransferPath::new(small.clone()), &mission)
+            .await
+            .unwrap();
+        backend
+            .put_object(&snapshot, TransferPath::new(large), &mission)
+            .await
+            .unwrap();
+        // files of source are kept
+        assert!(small.exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let events = events.lock().unwrap();
+        assert!(events[0].starts_with("PUT /"), "{:?}", events);
+        assert_eq!(
+            events[1..],
+            ["create", "part 5242880", "part 100", "complete"]
+        );
+    }
+}
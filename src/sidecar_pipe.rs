@@ -0,0 +1,166 @@
+//! SidecarPipe mirrors detached signature and checksum files.
+//!
+//! Artifacts are often shipped with sidecars next to them, e.g. `.asc`,
+//! `.sig` or `.sha256`, which are not always listed in index. For each
+//! object in snapshot, SidecarPipe probes its sidecar variants on source with
+//! `HEAD` requests, and adds those present to snapshot. Sidecars already
+//! listed by source are kept as is, and are not probed.
+//!
+//! Sidecars are resolved with the URL of source, as if they were listed by
+//! source. If no suffix is configured, this pipe does nothing.
+
+use std::collections::HashSet;
+
+use async_trait::async_trait;
+use futures_util::{stream, StreamExt};
+use slog::{info, warn};
+
+use crate::common::{Mission, SnapshotConfig, TransferPath, TransferURL};
+use crate::error::Result;
+use crate::traits::{Key, SnapshotStorage, SourceStorage};
+
+pub struct SidecarPipe<Source> {
+    pub source: Source,
+    pub suffixes: Vec<String>,
+}
+
+impl<Source> SidecarPipe<Source> {
+    pub fn new(source: Source, suffixes: Vec<String>) -> Self {
+        Self { source, suffixes }
+    }
+}
+
+/// Sidecar keys to probe, which are not in snapshot yet.
+fn sidecar_keys<'a>(keys: &[&'a str], suffixes: &'a [String]) -> Vec<String> {
+    let listed: HashSet<&str> = keys.iter().copied().collect();
+    keys.iter()
+        .filter(|key| !suffixes.iter().any(|suffix| key.ends_with(suffix.as_str())))
+        .flat_map(|key| {
+            suffixes
+                .iter()
+                .map(move |suffix| format!("{}{}", key, suffix))
+        })
+        .filter(|key| !listed.contains(key.as_str()))
+        .collect()
+}
+
+#[async_trait]
+impl<Snapshot, Source> SnapshotStorage<Snapshot> for SidecarPipe<Source>
+where
+    Snapshot: Key + Default + 'static,
+    Source: SnapshotStorage<Snapshot> + SourceStorage<Snapshot, TransferURL>,
+{
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        config: &SnapshotConfig,
+    ) -> Result<Vec<Snapshot>> {
+        let mut snapshot = self.source.snapshot(mission.clone(), config).await?;
+        if self.suffixes.is_empty() {
+            return Ok(snapshot);
+        }
+
+        let logger = &mission.logger;
+        let keys: Vec<&str> = snapshot.iter().map(|item| item.key()).collect();
+        let candidates = sidecar_keys(&keys, &self.suffixes);
+        info!(logger, "probing {} sidecars...", candidates.len());
+
+        let source = &self.source;
+        let found: Vec<Snapshot> = stream::iter(candidates)
+            .map(|key| {
+                let mission = &mission;
+                async move {
+                    let mut sidecar = Snapshot::default();
+                    *sidecar.key_mut() = key;
+                    let url = match source.get_object(&sidecar, mission).await {
+                        Ok(url) => url,
+                        Err(err) => {
+                            warn!(
+                                mission.logger,
+                                "failed to resolve {}: {:?}",
+                                sidecar.key(),
+                                err
+                            );
+                            return None;
+                        }
+                    };
+                    mission.throttle().await;
+                    match mission.client.head(&url.0).send().await {
+                        Ok(response) if response.status().is_success() => Some(sidecar),
+                        Ok(_) => None,
+                        Err(err) => {
+                            warn!(mission.logger, "failed to probe {}: {:?}", url.0, err);
+                            None
+                        }
+                    }
+                }
+            })
+            .buffer_unordered(config.concurrent_resolve)
+            .filter_map(|sidecar| async move { sidecar })
+            .collect()
+            .await;
+
+        info!(logger, "found {} sidecars", found.len());
+        snapshot.extend(found);
+        Ok(snapshot)
+    }
+
+    async fn check(&mut self, mission: Mission, config: &SnapshotConfig) -> Result<String> {
+        self.source.check(mission, config).await
+    }
+
+    fn info(&self) -> String {
+        format!("Sidecars {:?} <{}>", self.suffixes, self.source.info())
+    }
+
+    fn cacheable(&self) -> bool {
+        self.source.cacheable()
+    }
+}
+
+#[async_trait]
+impl<Snapshot, Source> SourceStorage<Snapshot, TransferURL> for SidecarPipe<Source>
+where
+    Snapshot: Send + Sync + 'static,
+    Source: SourceStorage<Snapshot, TransferURL>,
+{
+    async fn get_object(&self, snapshot: &Snapshot, mission: &Mission) -> Result<TransferURL> {
+        self.source.get_object(snapshot, mission).await
+    }
+
+    async fn get_size(&self, snapshot: &Snapshot, mission: &Mission) -> Result<Option<u64>> {
+        self.source.get_size(snapshot, mission).await
+    }
+}
+
+/// Objects on local disk are taken from source as well, while sidecars are
+/// still probed with its URL.
+#[async_trait]
+impl<Snapshot, Source> SourceStorage<Snapshot, TransferPath> for SidecarPipe<Source>
+where
+    Snapshot: Send + Sync + 'static,
+    Source: SourceStorage<Snapshot, TransferPath>,
+{
+    async fn get_object(&self, snapshot: &Snapshot, mission: &Mission) -> Result<TransferPath> {
+        self.source.get_object(snapshot, mission).await
+    }
+
+    async fn get_size(&self, snapshot: &Snapshot, mission: &Mission) -> Result<Option<u64>> {
+        self.source.get_size(snapshot, mission).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sidecar_keys() {
+        let suffixes = vec![".asc".to_string(), ".sha256".to_string()];
+        let keys = vec!["a.tar.gz", "a.tar.gz.asc", "b.whl"];
+        assert_eq!(
+            sidecar_keys(&keys, &suffixes),
+            vec!["a.tar.gz.sha256", "b.whl.asc", "b.whl.sha256"]
+        );
+    }
+}
@@ -8,44 +8,469 @@
 //! 2. Snapshot object not in target but in source, add
 //! 3. Snapshot object in both source and target but different, update
 //!
+//! Source snapshot may contain the same key more than once, e.g. a pool file
+//! shared by several suites of a distro mirror. Such keys are deduplicated,
+//! and transferred only once.
+//!
+//! The target snapshot is consumed page by page with `snapshot_stream`,
+//! and diffed against the sorted source snapshot as pages arrive. This
+//! avoids holding and sorting the full key set of a large target.
+//!
 //! Then, it will concurrently transfer the objects between two endpoints.
 //! The snapshot object should support `Metadata` trait, and simple diff
 //! transfer will transfer them from highest priority to lowest priority.
+//! Within a priority, objects are transferred in key order by default, or
+//! in source listing order, or newest first, as configured.
+//!
+//! If transfer of an object fails with a transient error (e.g. timeout or
+//! 5xx), it is retried with exponential backoff up to a configured maximum,
+//! and otherwise ignored. Objects permanently unavailable on source (e.g.
+//! 404) are not retried. If circuit breaker is enabled, requests to source
+//! are paused when too many of them fail. Keys which failed to transfer may
+//! be written to a failed list, and re-attempted with `path-list` source.
+//! The run ends with an error if more objects fail than a configured count
+//! or fraction of objects to transfer and delete, so that a broken source
+//! is noticed by automation. If checksum verification is enabled, objects
+//! with checksum in snapshot are read back from target after being written,
+//! and a mismatch is retried as well. Objects without checksum on target
+//! are reported as unverified, and targets which can't compute checksums
+//! are rejected.
+//!
+//! Keys of source snapshot may be rewritten before diffing, e.g. to strip a
+//! prefix of upstream. See `rewrite`.
+//!
+//! Include and exclude globs select paths to transfer, see `PathFilter`.
+//! Objects on target outside of them are neither updated nor deleted.
+//!
+//! In index only mode, only objects whose key matches index pattern (e.g.
+//! `Packages`, `repomd.xml`, simple index HTML) are updated or deleted, so
+//! that indexes may be kept fresh between full syncs.
+//!
+//! Transfer refuses to start if it would update too many source objects, or
+//! delete too many target objects, unless overridden, so that a wrong
+//! target or an upstream outage doesn't wipe the mirror.
+//!
+//! If a max duration is configured, no more objects are scheduled once it
+//! has passed since start. Objects in flight finish or time out, deletion is
+//! skipped, and the rest is left to the next run.
+//!
+//! On SIGINT or SIGTERM during transfer, no more objects are scheduled, and
+//! objects in flight finish or time out. Deletion is skipped, and the run
+//! ends with an error after writing its summary. A second signal exits
+//! immediately.
+//!
+//! If a checkpoint file is configured, keys of transferred objects are
+//! appended to it along with their size, modified time and checksum, and
+//! skipped when a crashed or interrupted run is restarted, unless they are
+//! changed since or forced. The checkpoint is removed once transfer
+//! completes.
+//!
+//! If a limit of objects is configured, only that number of pending
+//! updates are transferred in a run, after ordering, so that a huge initial
+//! sync may be spread over many short runs. As with an interrupted run,
+//! deletion is skipped and checkpoint is kept in a limited run.
+//!
+//! The transfer plan may be written to a file for review, and a plan saved
+//! by a previous run may be executed, in which case only objects in both the
+//! saved plan and the current plan are transferred.
 //!
-//! If transfer of an object fails, it will be simply ignored. We could
-//! later implement some kind of retry logic.
+//! If a report file is configured, a JSON summary of the run is written to
+//! it when transfer completes or fails, for automation to alert on. The
+//! report and failed list are written on every exit, including errors
+//! before any object is transferred, in which case they list no paths.
+//!
+//! If a snapshot cache directory is configured, source snapshot may be
+//! reused from a previous run, if source is wrapped by `CachedSource`.
+//! See `snapshot_cache`.
+//!
+//! If conditional requests are enabled, validators of objects are saved
+//! along with snapshot cache, and objects not modified on source since
+//! previous run are skipped. See `conditional`.
+//!
+//! If a tombstone file is configured, objects missing from source are only
+//! deleted after they have been missing for a grace period. See `tombstone`.
+//!
+//! If a metrics address is configured, progress of transfer is served for
+//! Prometheus to scrape. See `metrics`.
+//!
+//! If a status file is configured, a JSON summary of the run is written to
+//! target at the end, so that mirror front-ends may show mirror health.
 
 use futures_util::{stream, StreamExt};
 use indicatif::{MultiProgress, ProgressBar};
-use reqwest::ClientBuilder;
+use reqwest::{Client, ClientBuilder};
 
+use crate::circuit_breaker::{CircuitBreaker, CircuitBreakerConfig};
 use crate::common::{Mission, SnapshotConfig};
+use crate::conditional::Validators;
 use crate::error::{Error, Result};
+use crate::filter_pipe::PathFilter;
+use crate::metrics::Metrics;
+use crate::rate_limit::RateLimit;
+use crate::rewrite::PathRewriter;
 use crate::timeout::{TryTimeoutExt, TryTimeoutFutureExt};
+use crate::tombstone::Tombstones;
 use crate::traits::{Diff, Key, Metadata, SnapshotStorage, SourceStorage, TargetStorage};
-use crate::utils::{create_logger, spinner};
+use crate::utils::{create_logger, spinner, unix_time};
 
-use iter_set::{classify_by, Inclusion};
 use rand::prelude::*;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
 use slog::{debug, info, o, warn};
 
-use std::sync::Arc;
-use std::time::Duration;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+
+/// Cap on concurrent requests. It may be shared by several transfers in one
+/// process, so that their combined concurrency is bounded.
+#[derive(Debug, Clone)]
+pub struct ConcurrencyLimit {
+    semaphore: Arc<Semaphore>,
+    permits: usize,
+}
+
+impl ConcurrencyLimit {
+    pub fn new(permits: usize) -> Self {
+        Self {
+            semaphore: Arc::new(Semaphore::new(permits)),
+            permits,
+        }
+    }
+}
+
+/// Order in which updates are transferred. Diff is always done on sorted
+/// snapshots, and this only affects the transfer schedule.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferOrder {
+    /// Sorted by key
+    Sorted,
+    /// As listed by source
+    Source,
+    /// Most recently modified first, objects without modified time last
+    Newest,
+    /// Shuffled, so that large objects under one directory are spread over
+    /// the run
+    Random,
+    /// Smallest first, so that most objects are available sooner. Objects
+    /// without size are last, sorted by key
+    Smallest,
+    /// Largest first, objects without size last, sorted by key
+    Largest,
+}
+
+impl TransferOrder {
+    pub const NAMES: &'static [&'static str] = &[
+        "sorted", "source", "newest", "random", "smallest", "largest",
+    ];
+}
+
+impl std::str::FromStr for TransferOrder {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "sorted" => Ok(Self::Sorted),
+            "source" => Ok(Self::Source),
+            "newest" => Ok(Self::Newest),
+            "random" => Ok(Self::Random),
+            "smallest" => Ok(Self::Smallest),
+            "largest" => Ok(Self::Largest),
+            _ => Err(Error::ConfigureError(format!(
+                "unsupported transfer order {}, expected one of {:?}",
+                s,
+                Self::NAMES
+            ))),
+        }
+    }
+}
 
 enum PlanType {
     Update,
     Delete,
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone)]
 pub struct SimpleDiffTransferConfig {
     pub progress: bool,
+    pub log_level: Option<slog::Level>,
+    pub log_json: bool,
+    /// Concurrent object transfers. Concurrency of snapshot phase is
+    /// `concurrent_resolve` of `SnapshotConfig`.
     pub concurrent_transfer: usize,
+    pub debug_sample: usize,
+    pub debug_sample_seed: Option<u64>,
     pub no_delete: bool,
     pub dry_run: bool,
     pub snapshot_config: SnapshotConfig,
     pub print_plan: usize,
     pub force_all: bool,
+    pub min_free_space: u64,
+    pub case_insensitive: bool,
+    pub status_file: Option<String>,
+    pub tombstone_file: Option<String>,
+    pub tombstone_runs: u64,
+    pub tombstone_days: u64,
+    pub plan_file: Option<String>,
+    pub plan_only: bool,
+    pub confirm_plan: bool,
+    pub execute_plan: Option<String>,
+    pub max_updates: Option<usize>,
+    pub max_duration: Option<Duration>,
+    pub max_update_fraction: f64,
+    pub yes_full_sync: bool,
+    pub max_deletion_fraction: f64,
+    pub yes_mass_delete: bool,
+    pub transfer_jitter_ms: u64,
+    pub index_only: bool,
+    pub index_pattern: String,
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    pub path_rewriter: Option<PathRewriter>,
+    pub metrics_addr: Option<std::net::SocketAddr>,
+    pub export_snapshot: Option<String>,
+    pub snapshot_stats: bool,
+    /// Source snapshots with more objects are sorted in runs of this many
+    /// objects on disk. See `external_sort`.
+    pub external_sort_threshold: Option<usize>,
+    pub external_sort_dir: std::path::PathBuf,
+    pub concurrency_limit: Option<ConcurrencyLimit>,
+    pub head_sizes: bool,
+    pub max_deletes_per_run: Option<usize>,
+    pub limit_objects: Option<usize>,
+    pub max_failures: Option<usize>,
+    pub max_failure_fraction: Option<f64>,
+    pub failed_list: Option<String>,
+    pub report_json: Option<String>,
+    pub checkpoint: Option<String>,
+    /// User agent of requests to source, built from `MIRROR_CLONE_SITE` by
+    /// default
+    pub user_agent: Option<String>,
+    /// Extra headers sent with every request to source, e.g. a token of
+    /// private index. Values are sensitive, and not logged.
+    pub headers: reqwest::header::HeaderMap,
+    /// Proxies of requests to source. If any is given, proxies in environment
+    /// (e.g. `HTTPS_PROXY`) are ignored.
+    pub proxies: Vec<reqwest::Proxy>,
+    pub pool_max_idle_per_host: Option<usize>,
+    pub pool_idle_timeout_secs: u64,
+    /// Timeout of connecting to source, 0 for no timeout
+    pub connect_timeout_secs: u64,
+    /// Timeout of each request to source including reading its body, 0 for
+    /// no timeout
+    pub request_timeout_secs: u64,
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+    pub transfer_timeout_base_secs: u64,
+    pub transfer_timeout_unknown_size_secs: u64,
+    pub min_throughput: Option<u64>,
+    pub get_timeout: Duration,
+    pub put_timeout: Duration,
+    pub delete_timeout: Duration,
+    pub max_retries: usize,
+    pub retry_backoff: Duration,
+    pub max_retry_backoff: Duration,
+    pub verify_checksum: bool,
+    pub conditional_requests: bool,
+    pub transfer_order: TransferOrder,
+    pub rate_limit: Option<RateLimit>,
+    pub bandwidth_limit: Option<RateLimit>,
+}
+
+/// Records of transfer plan printed in dry run, unless `print_plan` is set.
+const DRY_RUN_PRINT_PLAN: usize = 100;
+
+/// Keys to update and delete, which may be saved and executed later.
+#[derive(Serialize, Deserialize, Debug)]
+struct TransferPlan {
+    update: Vec<String>,
+    delete: Vec<String>,
+}
+
+/// Object recorded in checkpoint once transferred. On resume, an object is
+/// skipped only if it is unchanged on source since.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Hash)]
+struct CheckpointEntry {
+    key: String,
+    size: Option<u64>,
+    last_modified: Option<u64>,
+    checksum: Option<(String, String)>,
+}
+
+impl CheckpointEntry {
+    fn new<Snapshot: Key + Metadata>(snapshot: &Snapshot) -> Self {
+        Self {
+            key: snapshot.key().to_string(),
+            size: snapshot.size(),
+            last_modified: snapshot.last_modified(),
+            checksum: snapshot
+                .checksum()
+                .map(|(method, checksum)| (method.to_string(), checksum.to_string())),
+        }
+    }
+}
+
+/// Status of mirror, written to target after each run.
+#[derive(Serialize, Deserialize, Debug)]
+struct MirrorStatus {
+    source: String,
+    total_objects: usize,
+    updated_objects: usize,
+    deleted_objects: usize,
+    failed_objects: usize,
+    /// Objects permanently unavailable on source (403, 404 or 410), which
+    /// are not counted as failures
+    #[serde(default)]
+    unavailable_objects: usize,
+    last_sync: u64,
+    last_successful_sync: Option<u64>,
+}
+
+/// Summary of a run, written to report file when it ends.
+#[derive(Serialize, Debug, Default)]
+struct TransferReport {
+    source: String,
+    target: String,
+    source_objects: usize,
+    target_objects: usize,
+    /// Objects planned to be added or changed
+    added: usize,
+    changed: usize,
+    /// Objects skipped as not modified on source, see `conditional`
+    not_modified: usize,
+    /// Objects transferred without checksum on target to verify against,
+    /// see `verify_checksum`
+    unverified: usize,
+    transferred: u64,
+    deleted: u64,
+    failed: u64,
+    failed_paths: Vec<String>,
+    /// Updates failed, which are written to failed list
+    #[serde(skip)]
+    failed_updates: Vec<String>,
+    bytes_transferred: u64,
+    duration_secs: f64,
+    /// Error which stopped the run, if any
+    error: Option<String>,
+}
+
+/// Timeout of getting or putting an object, which is proportional to its
+/// expected transfer time at minimum throughput. Objects of unknown size
+/// time out after `unknown_size_secs`. There is no timeout without minimum
+/// throughput configured.
+fn object_timeout(
+    size: Option<u64>,
+    base_secs: u64,
+    unknown_size_secs: u64,
+    min_throughput: Option<u64>,
+) -> Option<Duration> {
+    let min_throughput = min_throughput.filter(|throughput| *throughput != 0)?;
+    Some(Duration::from_secs(match size {
+        Some(size) => base_secs + size / min_throughput,
+        None => unknown_size_secs,
+    }))
+}
+
+/// Decrements gauge of objects in progress when an object is done, however
+/// its transfer returns.
+struct InProgress<'a>(&'a std::sync::atomic::AtomicI64);
+
+impl Drop for InProgress<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Snapshot of object with its key on source, if the key is rewritten.
+fn source_key<'a, Snapshot: Key + Clone>(
+    snapshot: &'a Snapshot,
+    original_keys: &HashMap<String, String>,
+) -> std::borrow::Cow<'a, Snapshot> {
+    match original_keys.get(snapshot.key()) {
+        Some(original) => {
+            let mut snapshot = snapshot.clone();
+            *snapshot.key_mut() = original.clone();
+            std::borrow::Cow::Owned(snapshot)
+        }
+        None => std::borrow::Cow::Borrowed(snapshot),
+    }
+}
+
+/// Wait for SIGINT, or SIGTERM on unix.
+async fn shutdown_signal() -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        let mut terminate =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result,
+            _ = terminate.recv() => Ok(()),
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await
+}
+
+/// Timeout of one phase of transfer, the shorter of fixed timeout of the
+/// phase and timeout of the object. Zero fixed timeout means unlimited.
+fn phase_timeout(fixed: Duration, object: Option<Duration>) -> Option<Duration> {
+    let fixed = Some(fixed).filter(|fixed| !fixed.is_zero());
+    fixed.into_iter().chain(object).min()
+}
+
+/// Backoff before retry after `attempt` retries, doubled on each retry up
+/// to `max`.
+fn backoff(base: Duration, max: Duration, attempt: usize) -> Duration {
+    u32::try_from(attempt)
+        .ok()
+        .and_then(|attempt| 2u32.checked_pow(attempt))
+        .and_then(|factor| base.checked_mul(factor))
+        .map_or(max, |backoff| backoff.min(max))
+}
+
+/// Builder of clients to source with proxies, timeouts, user agent and
+/// headers of config, for sources which need a client of their own.
+pub fn client_builder(config: &SimpleDiffTransferConfig) -> ClientBuilder {
+    let mut builder = ClientBuilder::new();
+    for proxy in &config.proxies {
+        builder = builder.proxy(proxy.clone());
+    }
+    if config.connect_timeout_secs != 0 {
+        builder = builder.connect_timeout(Duration::from_secs(config.connect_timeout_secs));
+    }
+    if config.request_timeout_secs != 0 {
+        builder = builder.timeout(Duration::from_secs(config.request_timeout_secs));
+    }
+    if let Some(max_idle) = config.pool_max_idle_per_host {
+        builder = builder.pool_max_idle_per_host(max_idle);
+    }
+    builder
+        .user_agent(
+            config
+                .user_agent
+                .clone()
+                .unwrap_or_else(crate::utils::user_agent),
+        )
+        .default_headers(config.headers.clone())
+        .pool_idle_timeout(match config.pool_idle_timeout_secs {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        })
+}
+
+/// Compare keys ignoring case.
+fn cmp_ignore_case(a: &str, b: &str) -> std::cmp::Ordering {
+    a.chars()
+        .flat_map(char::to_lowercase)
+        .cmp(b.chars().flat_map(char::to_lowercase))
+}
+
+/// Order of keys in sorted snapshot. Keys which only differ in case are
+/// adjacent, so that case collisions can be found in one pass.
+fn cmp_key(a: &str, b: &str) -> std::cmp::Ordering {
+    cmp_ignore_case(a, b).then_with(|| a.cmp(b))
 }
 
 pub struct SimpleDiffTransfer<Snapshot, Source, Target, Item>
@@ -62,45 +487,239 @@ where
 
 impl<Snapshot, Source, Target, Item> SimpleDiffTransfer<Snapshot, Source, Target, Item>
 where
-    Snapshot: Diff + Key + Metadata,
+    Snapshot: Diff + Key + Metadata + Clone + Serialize + DeserializeOwned,
     Source: SourceStorage<Snapshot, Item> + SnapshotStorage<Snapshot>,
     Target: TargetStorage<Snapshot, Item> + SnapshotStorage<Snapshot>,
 {
-    pub fn new(source: Source, target: Target, config: SimpleDiffTransferConfig) -> Self {
-        Self {
+    pub fn new(source: Source, target: Target, config: SimpleDiffTransferConfig) -> Result<Self> {
+        if config.concurrent_transfer == 0 {
+            return Err(Error::ConfigureError(
+                "concurrent transfer should be at least 1".to_string(),
+            ));
+        }
+        if config.conditional_requests && config.snapshot_config.snapshot_cache_dir.is_none() {
+            return Err(Error::ConfigureError(
+                "conditional requests need snapshot cache dir to save validators".to_string(),
+            ));
+        }
+        if config.verify_checksum && !target.can_checksum() {
+            return Err(Error::ConfigureError(
+                "checksum verification is not supported by target".to_string(),
+            ));
+        }
+        Ok(Self {
             source,
             target,
             config,
             _phantom: std::marker::PhantomData,
-        }
+        })
     }
 
-    fn debug_snapshot(logger: slog::Logger, snapshot: &[Snapshot]) {
-        let mut selected: Vec<_> = snapshot
-            .choose_multiple(&mut rand::thread_rng(), 50)
-            .collect();
+    /// Log a sample of keys in snapshot for debugging, all of them if there
+    /// are fewer than the sample size. With a seed, the same snapshot always
+    /// yields the same sample.
+    fn debug_snapshot(
+        config: &SimpleDiffTransferConfig,
+        logger: slog::Logger,
+        snapshot: &[Snapshot],
+    ) {
+        let count = config.debug_sample;
+        if count == 0 {
+            return;
+        }
+        let mut selected: Vec<_> = match config.debug_sample_seed {
+            Some(seed) => snapshot
+                .choose_multiple(&mut StdRng::seed_from_u64(seed), count)
+                .collect(),
+            None => snapshot
+                .choose_multiple(&mut rand::thread_rng(), count)
+                .collect(),
+        };
         selected.sort_by(|a, b| a.key().cmp(b.key()));
         for item in selected {
             debug!(logger, "{}", item.key());
         }
     }
 
-    pub async fn transfer(mut self) -> Result<()> {
-        let logger = create_logger();
-        let client = ClientBuilder::new()
-            .user_agent(crate::utils::user_agent())
-            .connect_timeout(Duration::from_secs(10))
-            .build()?;
+    fn build_client(&self) -> Result<Client> {
+        Ok(client_builder(&self.config).build()?)
+    }
+
+    /// Check connectivity of source and target without transferring. Source
+    /// is checked with `SnapshotStorage::check`, which is usually a request
+    /// to its base, the first page of target snapshot is taken, and a probe
+    /// object is written, read back and deleted on target. Each step is
+    /// reported, and an error listing failed steps is returned if any.
+    pub async fn check(mut self) -> Result<()> {
+        let logger = create_logger(self.config.log_level, self.config.log_json);
+        let client = self.build_client()?;
+        info!(logger, "begin check"; "source" => self.source.info(), "target" => self.target.info());
+
+        let rate_limit = self.config.rate_limit.clone();
+        let mission = |task: &'static str| Mission {
+            client: client.clone(),
+            progress: ProgressBar::hidden(),
+            logger: logger.new(o!("task" => task)),
+            rate_limit: rate_limit.clone(),
+            bandwidth_limit: None,
+            bytes_received: None,
+            validators: None,
+        };
+        let snapshot_config = self.config.snapshot_config.clone();
+        let mut failed = vec![];
+        let mut report = |step: &'static str, result: Result<String>| match result {
+            Ok(message) => info!(logger, "[pass] {}", step; "result" => message),
+            Err(err) => {
+                warn!(logger, "[fail] {}", step; "error" => format!("{:?}", err));
+                failed.push(step);
+            }
+        };
+
+        let result = self
+            .source
+            .check(mission("check.source"), &snapshot_config)
+            .await;
+        report("source", result);
+
+        let target_sample = self
+            .target
+            .snapshot_stream(mission("check.target"), &snapshot_config)
+            .next()
+            .await
+            .unwrap_or_else(|| Ok(vec![]))
+            .map(|sample| format!("{} objects", sample.len()));
+        report("target snapshot", target_sample);
+
+        let probe_key = format!(".mirror-clone-check-{}", unix_time());
+        let probe_content = probe_key.clone().into_bytes();
+        let result = self
+            .target
+            .put_raw_object(&probe_key, probe_content.clone())
+            .await
+            .map(|_| probe_key.clone());
+        let written = result.is_ok();
+        report("target write", result);
+        if written {
+            let result = match self.target.get_raw_object(&probe_key).await {
+                Ok(Some(content)) if content == probe_content => Ok(probe_key.clone()),
+                Ok(Some(_)) => Err(Error::StorageError(String::from(
+                    "content of probe object mismatched",
+                ))),
+                Ok(None) => Err(Error::StorageError(String::from(
+                    "probe object not found after write",
+                ))),
+                Err(err) => Err(err),
+            };
+            report("target read", result);
+            let result = self
+                .target
+                .delete_raw_object(&probe_key)
+                .await
+                .map(|_| probe_key.clone());
+            report("target delete", result);
+        }
+
+        if failed.is_empty() {
+            info!(logger, "check passed");
+            Ok(())
+        } else {
+            Err(Error::ConfigureError(format!(
+                "check failed: {}",
+                failed.join(", ")
+            )))
+        }
+    }
+
+    pub async fn transfer(self) -> Result<()> {
+        let logger = create_logger(self.config.log_level, self.config.log_json);
+        let start = Instant::now();
+        let report_json = self.config.report_json.clone();
+        let failed_list = self.config.failed_list.clone();
+        let mut report = TransferReport {
+            source: self.source.info(),
+            target: self.target.info(),
+            ..Default::default()
+        };
+        let metrics = Arc::new(Metrics::default());
+
+        let result = self
+            .run_transfer(logger.clone(), metrics.clone(), &mut report)
+            .await;
+
+        if let Some(report_json) = report_json {
+            report.transferred = metrics.transferred.load(Ordering::Relaxed);
+            report.deleted = metrics.deleted.load(Ordering::Relaxed);
+            report.failed = metrics.failed.load(Ordering::Relaxed);
+            report.bytes_transferred = metrics.bytes_transferred.load(Ordering::Relaxed);
+            report.duration_secs = start.elapsed().as_secs_f64();
+            report.error = result.as_ref().err().map(|err| format!("{:?}", err));
+            let result = async {
+                let content = serde_json::to_vec_pretty(&report)?;
+                if report_json == "-" {
+                    use std::io::Write;
+                    std::io::stdout().write_all(&content)?;
+                } else {
+                    tokio::fs::write(&report_json, content).await?;
+                }
+                Ok::<_, Error>(())
+            };
+            if let Err(err) = result.await {
+                warn!(logger, "failed to write report: {:?}", err);
+            }
+        }
+
+        // failed list is written even if run stops early, so that a list of
+        // a previous run isn't taken for this one
+        if let Some(failed_list) = failed_list {
+            let content: String = report
+                .failed_updates
+                .iter()
+                .map(|key| format!("{}\n", key))
+                .collect();
+            let result = if failed_list == "-" {
+                use std::io::Write;
+                std::io::stdout().write_all(content.as_bytes())
+            } else {
+                tokio::fs::write(&failed_list, content).await
+            };
+            if let Err(err) = result {
+                warn!(logger, "failed to write failed list: {:?}", err);
+            }
+        }
+
+        result
+    }
+
+    async fn run_transfer(
+        mut self,
+        logger: slog::Logger,
+        metrics: Arc<Metrics>,
+        report: &mut TransferReport,
+    ) -> Result<()> {
+        let start = Instant::now();
+        let client = self.build_client()?;
         info!(logger, "using simple diff transfer"; "config" => format!("{:?}", self.config));
         info!(logger, "begin transfer"; "source" => self.source.info(), "target" => self.target.info());
 
+        if let Some(addr) = self.config.metrics_addr {
+            let listener = tokio::net::TcpListener::bind(addr).await?;
+            crate::metrics::serve(listener, metrics.clone(), logger.clone()).await?;
+        }
+
         info!(logger, "taking snapshot...");
 
-        let all_progress = MultiProgress::new();
-        let source_progress = all_progress.add(ProgressBar::new(0));
+        // Without progress bar, snapshot progress bars are hidden, instead of
+        // being drawn to a `MultiProgress` nobody joins.
+        let (source_progress, target_progress, all_progress) = if self.config.progress {
+            let all_progress = MultiProgress::new();
+            let source_progress = all_progress.add(ProgressBar::new(0));
+            let target_progress = all_progress.add(ProgressBar::new(0));
+            (source_progress, target_progress, Some(all_progress))
+        } else {
+            (ProgressBar::hidden(), ProgressBar::hidden(), None)
+        };
         source_progress.set_style(spinner());
         source_progress.set_prefix("[source]");
-        let target_progress = all_progress.add(ProgressBar::new(0));
         target_progress.set_style(spinner());
         target_progress.set_prefix("[target]");
 
@@ -108,36 +727,291 @@ where
             client: client.clone(),
             progress: source_progress,
             logger: logger.new(o!("task" => "snapshot.source")),
+            rate_limit: self.config.rate_limit.clone(),
+            bandwidth_limit: None,
+            bytes_received: None,
+            validators: None,
         };
 
         let target_mission = Mission {
             client: client.clone(),
-            progress: target_progress,
+            progress: target_progress.clone(),
             logger: logger.new(o!("task" => "snapshot.target")),
+            rate_limit: None,
+            bandwidth_limit: None,
+            bytes_received: None,
+            validators: None,
         };
 
-        let config_progress = self.config.progress;
+        let handle = all_progress
+            .map(|all_progress| tokio::task::spawn_blocking(move || all_progress.join().unwrap()));
 
-        let handle = tokio::task::spawn_blocking(move || {
-            if config_progress {
-                all_progress.join().unwrap()
+        // Snapshot phase holds as many permits as its concurrency, which is
+        // capped by concurrency limit.
+        let mut snapshot_config = self.config.snapshot_config.clone();
+        let snapshot_permits = match &self.config.concurrency_limit {
+            Some(limit) => {
+                snapshot_config.concurrent_resolve =
+                    snapshot_config.concurrent_resolve.min(limit.permits);
+                Some(
+                    limit
+                        .semaphore
+                        .acquire_many(snapshot_config.concurrent_resolve as u32)
+                        .await
+                        .map_err(|err| Error::ProcessError(format!("{:?}", err)))?,
+                )
             }
-        });
+            None => None,
+        };
 
-        let source_snapshot = self
+        let mut source_snapshot = self
             .source
-            .snapshot(source_mission, &self.config.snapshot_config)
+            .snapshot(source_mission, &snapshot_config)
             .await?;
+        let original_keys = match &self.config.path_rewriter {
+            Some(rewriter) => {
+                let (original_keys, collisions) = rewriter.rewrite_snapshot(&mut source_snapshot);
+                info!(logger, "{} source objects rewritten", original_keys.len());
+                if collisions != 0 {
+                    warn!(
+                        logger,
+                        "{} source objects rewritten onto the key of another", collisions
+                    );
+                }
+                original_keys
+            }
+            None => HashMap::new(),
+        };
 
-        let target_snapshot = self
-            .target
-            .snapshot(target_mission, &self.config.snapshot_config)
-            .await?;
+        let path_filter = PathFilter::new(&self.config.include, &self.config.exclude)?;
+        let source_snapshot = if path_filter.is_empty() {
+            source_snapshot
+        } else {
+            let total = source_snapshot.len();
+            let (mut not_included, mut excluded) = (0, 0);
+            let snapshot: Vec<_> = source_snapshot
+                .into_iter()
+                .filter(|snapshot| {
+                    if !path_filter.included(snapshot.key()) {
+                        not_included += 1;
+                        false
+                    } else if path_filter.excluded(snapshot.key()) {
+                        excluded += 1;
+                        false
+                    } else {
+                        true
+                    }
+                })
+                .collect();
+            info!(
+                logger,
+                "{} of {} source objects not included, {} excluded", not_included, total, excluded
+            );
+            snapshot
+        };
 
-        handle.await.ok();
+        Self::debug_snapshot(&self.config, logger.clone(), &source_snapshot);
 
-        Self::debug_snapshot(logger.clone(), &source_snapshot);
-        Self::debug_snapshot(logger.clone(), &target_snapshot);
+        let case_insensitive = self.config.case_insensitive;
+        let cmp_diff_key = move |a: &str, b: &str| {
+            if case_insensitive {
+                cmp_ignore_case(a, b)
+            } else {
+                cmp_key(a, b)
+            }
+        };
+
+        let source_count = source_snapshot.len();
+
+        let export_snapshot = self.config.export_snapshot.clone();
+        let transfer_order = self.config.transfer_order;
+        let external_sort_threshold = self.config.external_sort_threshold;
+        let external_sort_dir = self.config.external_sort_dir.clone();
+        let sort_logger = logger.clone();
+        let (source_snapshot, source_duplicated, collisions, listing_order) =
+            tokio::task::spawn_blocking(move || {
+                let mut source_snapshot: Vec<Snapshot> = source_snapshot;
+                // position of each key in source listing, before sorting
+                let mut listing_order = HashMap::new();
+                if transfer_order == TransferOrder::Source {
+                    for (idx, item) in source_snapshot.iter().enumerate() {
+                        listing_order.entry(item.key().to_string()).or_insert(idx);
+                    }
+                }
+                match external_sort_threshold {
+                    Some(threshold) if source_snapshot.len() > threshold => {
+                        info!(
+                            sort_logger,
+                            "sorting source snapshot in runs of {} objects", threshold
+                        );
+                        source_snapshot = crate::external_sort::sort_dedup(
+                            source_snapshot,
+                            threshold,
+                            &external_sort_dir,
+                            |a: &Snapshot, b: &Snapshot| cmp_key(a.key(), b.key()),
+                        )?;
+                    }
+                    _ => {
+                        source_snapshot.sort_by(|a, b| cmp_key(a.key(), b.key()));
+                        source_snapshot.dedup_by(|a, b| a.key().eq(b.key()));
+                    }
+                }
+                let source_duplicated = source_count - source_snapshot.len();
+                let collisions: Vec<(String, String)> = source_snapshot
+                    .windows(2)
+                    .filter(|pair| cmp_ignore_case(pair[0].key(), pair[1].key()).is_eq())
+                    .map(|pair| (pair[0].key().to_string(), pair[1].key().to_string()))
+                    .collect();
+                if case_insensitive {
+                    source_snapshot.dedup_by(|a, b| cmp_ignore_case(a.key(), b.key()).is_eq());
+                }
+                if let Some(export_snapshot) = export_snapshot {
+                    crate::snapshot_file::export(&export_snapshot, &source_snapshot)?;
+                }
+                Ok::<_, Error>((
+                    source_snapshot,
+                    source_duplicated,
+                    collisions,
+                    listing_order,
+                ))
+            })
+            .await
+            .map_err(|err| Error::ProcessError(format!("error while sorting: {:?}", err)))??;
+
+        if self.config.snapshot_stats {
+            crate::snapshot_stats::log_snapshot_stats(&logger, &source_snapshot);
+        }
+
+        if source_duplicated != 0 {
+            info!(
+                logger,
+                "source: {} duplicated items, each key is transferred once", source_duplicated
+            );
+        }
+
+        if !collisions.is_empty() {
+            warn!(
+                logger,
+                "source: {} items only differ in case with others, they will collide on case-insensitive targets",
+                collisions.len()
+            );
+            for (a, b) in &collisions {
+                warn!(logger, "case collision: {} <-> {}", a, b);
+            }
+            if case_insensitive {
+                warn!(
+                    logger,
+                    "only the first item of each case collision will be transferred"
+                );
+            }
+        }
+
+        // Target snapshot is consumed page by page, and diffed against sorted
+        // source snapshot on the fly. Objects existing in both source and target
+        // are only recorded by their index in source snapshot.
+        let mut in_target = vec![false; source_snapshot.len()];
+        let mut changed = vec![false; source_snapshot.len()];
+        // size on target of objects to be replaced, which is freed by update
+        let mut replaced_sizes: HashMap<String, u64> = HashMap::new();
+        let mut deletions = vec![];
+        let mut target_count: usize = 0;
+        let mut target_duplicated: usize = 0;
+
+        if self.config.force_all {
+            info!(logger, "force transfer all objects");
+            target_progress.finish_with_message("skipped");
+        } else {
+            let mut pages = self
+                .target
+                .snapshot_stream(target_mission, &snapshot_config);
+            let mut is_first_page = true;
+            while let Some(page) = pages.next().await {
+                let page = page?;
+                if is_first_page {
+                    Self::debug_snapshot(&self.config, logger.clone(), &page);
+                    is_first_page = false;
+                }
+                for target in page {
+                    if Some(target.key()) == self.config.status_file.as_deref()
+                        || Some(target.key()) == self.config.tombstone_file.as_deref()
+                        || !path_filter.matches(target.key())
+                    {
+                        continue;
+                    }
+                    target_count += 1;
+                    match source_snapshot
+                        .binary_search_by(|source| cmp_diff_key(source.key(), target.key()))
+                    {
+                        Ok(idx) => {
+                            if in_target[idx] {
+                                target_duplicated += 1;
+                            } else {
+                                in_target[idx] = true;
+                                changed[idx] = source_snapshot[idx].diff(&target);
+                                if let (true, Some(size)) = (changed[idx], target.size()) {
+                                    replaced_sizes
+                                        .insert(source_snapshot[idx].key().to_string(), size);
+                                }
+                            }
+                        }
+                        Err(_) => deletions.push(target),
+                    }
+                }
+            }
+            target_progress.finish_with_message("done");
+        }
+
+        if let Some(handle) = handle {
+            handle.await.ok();
+        }
+
+        deletions.sort_by(|a, b| cmp_key(a.key(), b.key()));
+        let deletions_count = deletions.len();
+        deletions.dedup_by(|a, b| cmp_diff_key(a.key(), b.key()).is_eq());
+        target_duplicated += deletions_count - deletions.len();
+
+        if target_duplicated != 0 {
+            warn!(logger, "target: {} duplicated items", target_duplicated);
+        }
+
+        // Without target snapshot, we know nothing about missing objects, and
+        // previous tombstones are left untouched.
+        let tombstones = match &self.config.tombstone_file {
+            Some(tombstone_file) if !self.config.force_all => {
+                let previous = match self.target.get_raw_object(tombstone_file).await? {
+                    Some(data) => serde_json::from_slice(&data).unwrap_or_else(|err| {
+                        warn!(
+                            logger,
+                            "failed to parse tombstones, starting over: {:?}", err
+                        );
+                        Tombstones::default()
+                    }),
+                    None => Tombstones::default(),
+                };
+                let now = unix_time();
+                let tombstones = previous.update(deletions.iter().map(|target| target.key()), now);
+                let missing = deletions.len();
+                deletions.retain(|target| {
+                    tombstones.expired(
+                        target.key(),
+                        self.config.tombstone_runs,
+                        self.config.tombstone_days,
+                        now,
+                    )
+                });
+                let expired = deletions.len();
+                deletions.retain(|target| !tombstones.backing_off(target.key()));
+                info!(
+                    logger,
+                    "{} objects missing from source, {} of them passed grace period, {} of them backed off after failed deletion",
+                    missing,
+                    expired,
+                    expired - deletions.len()
+                );
+                Some((tombstone_file, tombstones))
+            }
+            _ => None,
+        };
 
         info!(logger, "mirror in progress...");
 
@@ -149,111 +1023,303 @@ where
         progress.set_style(crate::utils::bar());
         progress.set_prefix("mirror");
 
+        let validators = match &self.config.snapshot_config.snapshot_cache_dir {
+            Some(dir) if self.config.conditional_requests => {
+                let path = Validators::path(dir, &self.source.info());
+                let validators = Validators::load(&path);
+                if !self.config.force_all {
+                    // other objects are updated because they differ from target
+                    let on_target: HashSet<&str> = source_snapshot
+                        .iter()
+                        .zip(&in_target)
+                        .filter(|(source, in_target)| **in_target && source.forced())
+                        .map(|(source, _)| {
+                            original_keys
+                                .get(source.key())
+                                .map_or(source.key(), String::as_str)
+                        })
+                        .collect();
+                    validators.retain(|key| on_target.contains(key));
+                }
+                info!(
+                    logger,
+                    "{} objects on target have validators for conditional requests",
+                    validators.len()
+                );
+                Some((path, Arc::new(validators)))
+            }
+            _ => None,
+        };
+
         let source_mission = Arc::new(Mission {
             client: client.clone(),
             progress: ProgressBar::hidden(),
             logger: logger.new(o!("task" => "mirror.source")),
+            rate_limit: self.config.rate_limit.clone(),
+            bandwidth_limit: self.config.bandwidth_limit.clone(),
+            bytes_received: Some(metrics.bytes_transferred.clone()),
+            validators: validators
+                .as_ref()
+                .map(|(_, validators)| validators.clone()),
         });
 
         let target_mission = Arc::new(Mission {
             client: client.clone(),
             progress: ProgressBar::hidden(),
             logger: logger.new(o!("task" => "mirror.target")),
+            rate_limit: None,
+            bandwidth_limit: None,
+            bytes_received: None,
+            validators: None,
         });
 
         info!(logger, "generating transfer plan...");
 
-        let source_count = source_snapshot.len();
-
-        let source_sort = tokio::task::spawn_blocking(move || {
-            let mut source_snapshot: Vec<Snapshot> = source_snapshot;
-            source_snapshot.sort_by(|a, b| a.key().cmp(b.key()));
-            source_snapshot.dedup_by(|a, b| a.key().eq(b.key()));
-            source_snapshot
-        });
-
-        let target_count = target_snapshot.len();
-
-        let target_sort = tokio::task::spawn_blocking(move || {
-            let mut target_snapshot: Vec<Snapshot> = target_snapshot;
-            target_snapshot.sort_by(|a, b| a.key().cmp(b.key()));
-            target_snapshot.dedup_by(|a, b| a.key().eq(b.key()));
-            target_snapshot
-        });
+        info!(
+            logger,
+            "source {} objects -> target {} objects",
+            source_snapshot.len(),
+            target_count - target_duplicated
+        );
+        report.source_objects = source_snapshot.len();
+        report.target_objects = target_count - target_duplicated;
 
-        let (source_snapshot, target_snapshot) = tokio::join!(source_sort, target_sort);
+        let total_objects = source_snapshot.len();
+        let mut updates = vec![];
 
-        let source_snapshot = source_snapshot
-            .map_err(|err| Error::ProcessError(format!("error while sorting: {:?}", err)))?;
-        let mut target_snapshot = target_snapshot
-            .map_err(|err| Error::ProcessError(format!("error while sorting: {:?}", err)))?;
+        let print_plan = if self.config.dry_run && self.config.print_plan == 0 {
+            DRY_RUN_PRINT_PLAN
+        } else {
+            self.config.print_plan
+        };
+        let mut max_info = 0;
+        let (mut added, mut modified) = (0, 0);
+        for (idx, source) in source_snapshot.into_iter().enumerate() {
+            if !in_target[idx] {
+                if max_info < print_plan {
+                    info!(logger, "+ {:?}", source.key());
+                    max_info += 1;
+                }
+                added += 1;
+                updates.push(source);
+            } else if changed[idx] {
+                if max_info < print_plan {
+                    info!(logger, "= {:?}", source.key());
+                    max_info += 1;
+                }
+                modified += 1;
+                updates.push(source);
+            }
+        }
+        info!(
+            logger,
+            "{} objects added, {} changed, {} up to date and skipped",
+            added,
+            modified,
+            total_objects - added - modified
+        );
+        report.added = added;
+        report.changed = modified;
+        for target in &deletions {
+            if max_info < print_plan {
+                info!(logger, "- {:?}", target.key());
+                max_info += 1;
+            }
+        }
 
-        if source_count != source_snapshot.len() {
-            warn!(
+        if self.config.index_only {
+            let index_pattern = regex::Regex::new(&self.config.index_pattern).map_err(|err| {
+                Error::ConfigureError(format!("invalid index pattern: {:?}", err))
+            })?;
+            let planned = (updates.len(), deletions.len());
+            updates.retain(|snapshot| index_pattern.is_match(snapshot.key()));
+            deletions.retain(|snapshot| index_pattern.is_match(snapshot.key()));
+            info!(
                 logger,
-                "source: {} duplicated items",
-                source_count - source_snapshot.len()
+                "index only, {} of {} updates and {} of {} deletions are indexes",
+                updates.len(),
+                planned.0,
+                deletions.len(),
+                planned.1
             );
         }
 
-        if target_count != target_snapshot.len() {
-            warn!(
+        if let Some(execute_plan) = &self.config.execute_plan {
+            let saved: TransferPlan =
+                serde_json::from_slice(&tokio::fs::read(execute_plan).await?)?;
+            let saved_update: std::collections::HashSet<String> =
+                saved.update.into_iter().collect();
+            let saved_delete: std::collections::HashSet<String> =
+                saved.delete.into_iter().collect();
+            let planned = (saved_update.len(), saved_delete.len());
+            updates.retain(|snapshot| saved_update.contains(snapshot.key()));
+            deletions.retain(|snapshot| saved_delete.contains(snapshot.key()));
+            info!(
                 logger,
-                "target: {} duplicated items",
-                target_count - target_snapshot.len()
+                "executing saved plan {}, {} of {} updates and {} of {} deletions still apply",
+                execute_plan,
+                updates.len(),
+                planned.0,
+                deletions.len(),
+                planned.1
             );
         }
 
-        if self.config.force_all {
-            info!(logger, "force transfer all objects");
-            target_snapshot = vec![];
+        if let Some(checkpoint) = &self.config.checkpoint {
+            let transferred: HashSet<CheckpointEntry> =
+                match tokio::fs::read_to_string(checkpoint).await {
+                    Ok(content) => content
+                        .lines()
+                        .filter_map(|line| serde_json::from_str(line).ok())
+                        .collect(),
+                    Err(err) if err.kind() == std::io::ErrorKind::NotFound => Default::default(),
+                    Err(err) => return Err(err.into()),
+                };
+            if !transferred.is_empty() {
+                let planned = updates.len();
+                updates.retain(|snapshot| {
+                    snapshot.forced() || !transferred.contains(&CheckpointEntry::new(snapshot))
+                });
+                info!(
+                    logger,
+                    "resuming from checkpoint {}, {} of {} updates already transferred",
+                    checkpoint,
+                    planned - updates.len(),
+                    planned
+                );
+            }
         }
 
-        info!(
-            logger,
-            "source {} objects -> target {} objects",
-            source_snapshot.len(),
-            target_snapshot.len()
-        );
-
-        let mut updates = vec![];
-        let mut deletions = vec![];
-
-        let mut max_info = 0;
-        for result in classify_by(source_snapshot, target_snapshot, |a, b| {
-            a.key().cmp(b.key())
-        }) {
-            match result {
-                Inclusion::Left(source) => {
-                    if max_info < self.config.print_plan {
-                        info!(logger, "+ {:?}", source.key());
-                        max_info += 1;
-                    }
-                    updates.push(source);
-                }
-                Inclusion::Both(l, r) => {
-                    if l.diff(&r) {
-                        if max_info < self.config.print_plan {
-                            info!(logger, "= {:?}", l.key());
-                            max_info += 1;
+        // Optionally fill in unknown sizes with `HEAD` requests to source,
+        // within concurrency of snapshot phase. Sizes found are written back
+        // to snapshot, so that ordering, timeouts and metrics use them.
+        let filled_sizes: HashMap<String, u64> = if self.config.head_sizes {
+            let unknown = updates.iter().filter(|x| x.size().is_none()).count();
+            info!(logger, "fetching size of {} objects from source", unknown);
+            let source = &self.source;
+            let original_keys = &original_keys;
+            let head_mission = &Mission {
+                client: client.clone(),
+                progress: ProgressBar::hidden(),
+                logger: logger.new(o!("task" => "head.source")),
+                rate_limit: self.config.rate_limit.clone(),
+                bandwidth_limit: None,
+                bytes_received: None,
+                validators: None,
+            };
+            stream::iter(updates.iter().filter(|snapshot| snapshot.size().is_none()))
+                .map(|snapshot| async move {
+                    let key = snapshot.key().to_string();
+                    let snapshot = source_key(snapshot, original_keys);
+                    match source.get_size(&snapshot, head_mission).await {
+                        Ok(size) => size.map(|size| (key, size)),
+                        Err(err) => {
+                            debug!(
+                                head_mission.logger,
+                                "failed to get size of {}: {:?}",
+                                snapshot.key(),
+                                err
+                            );
+                            None
                         }
-                        updates.push(l);
-                    }
-                }
-                Inclusion::Right(target) => {
-                    if max_info < self.config.print_plan {
-                        info!(logger, "- {:?}", target.key());
-                        max_info += 1;
                     }
-                    deletions.push(target);
-                }
+                })
+                .buffered(snapshot_config.concurrent_resolve)
+                .filter_map(std::future::ready)
+                .collect()
+                .await
+        } else {
+            HashMap::new()
+        };
+        drop(snapshot_permits);
+        // sizes filled in aren't recorded in checkpoint, as snapshot of the
+        // next run doesn't have them before comparing with checkpoint. They
+        // are kept by key for snapshots which don't carry size.
+        for snapshot in updates.iter_mut() {
+            if let Some(size) = filled_sizes.get(snapshot.key()) {
+                snapshot.set_size(*size);
+            }
+        }
+
+        match self.config.transfer_order {
+            TransferOrder::Sorted => {}
+            TransferOrder::Source => {
+                updates.sort_by_key(|snapshot| listing_order.get(snapshot.key()).copied())
+            }
+            TransferOrder::Newest => {
+                updates.sort_by_key(|snapshot| std::cmp::Reverse(snapshot.last_modified()))
+            }
+            TransferOrder::Random => updates.shuffle(&mut rand::thread_rng()),
+            // updates are sorted by key, which is kept among objects without
+            // size by stable sort
+            TransferOrder::Smallest => {
+                updates.sort_by_key(|snapshot| (snapshot.size().is_none(), snapshot.size()))
             }
+            TransferOrder::Largest => updates.sort_by_key(|snapshot| {
+                (
+                    snapshot.size().is_none(),
+                    std::cmp::Reverse(snapshot.size()),
+                )
+            }),
         }
+        drop(listing_order);
 
-        // sort plan by priority
+        // sort plan by priority, keeping transfer order within a priority
         updates.sort_by_key(|snapshot| -snapshot.priority());
         deletions.sort_by_key(|snapshot| -snapshot.priority());
 
+        let mut limited = false;
+        if let Some(limit) = self.config.limit_objects {
+            if updates.len() > limit {
+                info!(
+                    logger,
+                    "limited to {} of {} pending objects",
+                    limit,
+                    updates.len()
+                );
+                updates.truncate(limit);
+                limited = true;
+            }
+        }
+        if limited && !deletions.is_empty() {
+            info!(
+                logger,
+                "skip deleting {} objects in a limited run, they will be deleted by a later run",
+                deletions.len()
+            );
+            deletions.clear();
+        }
+
+        if let Some(max_deletes) = self.config.max_deletes_per_run {
+            if deletions.len() > max_deletes {
+                let deferred = deletions.split_off(max_deletes);
+                info!(
+                    logger,
+                    "delete at most {} objects in this run, {} objects deferred to later runs",
+                    max_deletes,
+                    deferred.len()
+                );
+                for target in &deferred {
+                    debug!(logger, "deferred - {:?}", target.key());
+                }
+            }
+        }
+
+        if let Some(plan_file) = &self.config.plan_file {
+            let plan = TransferPlan {
+                update: updates.iter().map(|x| x.key().to_string()).collect(),
+                delete: deletions.iter().map(|x| x.key().to_string()).collect(),
+            };
+            let content = serde_json::to_vec_pretty(&plan)?;
+            if plan_file == "-" {
+                use std::io::Write;
+                std::io::stdout().write_all(&content)?;
+            } else {
+                tokio::fs::write(plan_file, content).await?;
+                info!(logger, "transfer plan written to {}", plan_file);
+            }
+        }
+
         info!(
             logger,
             "update {} objects, delete {} objects",
@@ -261,18 +1327,181 @@ where
             deletions.len()
         );
 
+        // dry run writes nothing, so space is not checked
+        let free_space = if self.config.dry_run {
+            None
+        } else {
+            self.target.free_space().await?
+        };
+        if let Some(free_space) = free_space {
+            let sizes: Vec<Option<u64>> = updates
+                .iter()
+                .map(|snapshot| {
+                    snapshot
+                        .size()
+                        .or_else(|| filled_sizes.get(snapshot.key()).copied())
+                })
+                .collect();
+            // objects of unknown size don't count, so neither does the size
+            // they replace
+            let replaced: u64 = updates
+                .iter()
+                .zip(&sizes)
+                .filter(|(_, size)| size.is_some())
+                .filter_map(|(snapshot, _)| replaced_sizes.get(snapshot.key()))
+                .sum();
+            let required = sizes.iter().flatten().sum::<u64>().saturating_sub(replaced);
+            let unknown = sizes.iter().filter(|size| size.is_none()).count();
+            info!(
+                logger,
+                "{}B to transfer ({} objects of unknown size), {}B available on target",
+                required,
+                unknown,
+                free_space
+            );
+            if required + self.config.min_free_space > free_space {
+                return Err(Error::StorageError(format!(
+                    "insufficient space on target: {}B required, {}B reserved, {}B available",
+                    required, self.config.min_free_space, free_space
+                )));
+            }
+        }
+
         if self.config.dry_run {
+            info!(
+                logger,
+                "dry run, would update {} objects and delete {} objects",
+                updates.len(),
+                if self.config.no_delete {
+                    0
+                } else {
+                    deletions.len()
+                }
+            );
+            return Ok(());
+        }
+
+        if self.config.plan_only {
             return Ok(());
         }
 
+        // Guard against transferring (almost) everything by accident, e.g.
+        // running against a wrong or empty target. Forced objects are updated
+        // in every run, and are not counted.
+        let unforced_updates = updates.iter().filter(|x| !x.forced()).count();
+        let exceeds_count = self
+            .config
+            .max_updates
+            .is_some_and(|max_updates| unforced_updates > max_updates);
+        let exceeds_fraction =
+            unforced_updates as f64 > self.config.max_update_fraction * total_objects as f64;
+        if (exceeds_count || exceeds_fraction)
+            && !self.config.yes_full_sync
+            && !self.config.force_all
+        {
+            let message = format!(
+                "{} of {} objects to update exceeds the limit, pass --yes-full-sync to proceed",
+                unforced_updates, total_objects
+            );
+            if self.config.confirm_plan {
+                warn!(logger, "{}", message);
+            } else {
+                return Err(Error::ConfigureError(message));
+            }
+        }
+
+        // Guard against wiping target when source snapshot comes back (almost)
+        // empty, e.g. during an upstream outage.
+        let target_objects = report.target_objects;
+        if !self.config.no_delete
+            && !self.config.yes_mass_delete
+            && deletions.len() as f64 > self.config.max_deletion_fraction * target_objects as f64
+        {
+            let message = format!(
+                "{} of {} objects on target to delete exceeds the limit, pass --yes-mass-delete to proceed",
+                deletions.len(),
+                target_objects
+            );
+            if self.config.confirm_plan {
+                warn!(logger, "{}", message);
+            } else {
+                return Err(Error::ConfigureError(message));
+            }
+        }
+
+        if self.config.confirm_plan {
+            eprint!(
+                "update {} objects, delete {} objects, continue? [y/N] ",
+                updates.len(),
+                if self.config.no_delete {
+                    0
+                } else {
+                    deletions.len()
+                }
+            );
+            let answer = tokio::task::spawn_blocking(|| {
+                let mut answer = String::new();
+                std::io::stdin().read_line(&mut answer).map(|_| answer)
+            })
+            .await
+            .map_err(|err| Error::ProcessError(format!("error while reading: {:?}", err)))??;
+            if !matches!(answer.trim(), "y" | "Y" | "yes") {
+                info!(logger, "transfer plan not confirmed, exiting");
+                return Ok(());
+            }
+        }
+
         info!(logger, "updating objects");
 
+        let checkpoint = match &self.config.checkpoint {
+            Some(path) => Some(Mutex::new(
+                std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)?,
+            )),
+            None => None,
+        };
+
+        let source_info = self.source.info();
+        let updated_objects = updates.len();
+        let deleted_objects = if self.config.no_delete {
+            0
+        } else {
+            deletions.len()
+        };
+
         let source = Arc::new(self.source);
         let target = Arc::new(self.target);
 
         progress.set_length(updates.len() as u64);
         progress.set_position(0);
 
+        let failed = AtomicUsize::new(0);
+        let unavailable = AtomicUsize::new(0);
+        let not_modified = AtomicUsize::new(0);
+        let unverified = AtomicUsize::new(0);
+        let failed_deletes = Mutex::new(vec![]);
+        let failed_updates = Mutex::new(vec![]);
+
+        let transfer_jitter_ms = self.config.transfer_jitter_ms;
+        let timeout_base_secs = self.config.transfer_timeout_base_secs;
+        let timeout_unknown_size_secs = self.config.transfer_timeout_unknown_size_secs;
+        let min_throughput = self.config.min_throughput;
+        let get_timeout = self.config.get_timeout;
+        let put_timeout = self.config.put_timeout;
+        let delete_timeout = self.config.delete_timeout;
+        let max_retries = self.config.max_retries;
+        let retry_backoff = self.config.retry_backoff;
+        let max_retry_backoff = self.config.max_retry_backoff;
+        let verify_checksum = self.config.verify_checksum;
+        let circuit_breaker = self
+            .config
+            .circuit_breaker
+            .clone()
+            .map(|config| Arc::new(CircuitBreaker::new(config)));
+        let concurrency_limit = self.config.concurrency_limit.clone();
+
         let map_snapshot = |snapshot: Snapshot, plan: PlanType| {
             progress.set_message(snapshot.key());
             let source = source.clone();
@@ -280,45 +1509,232 @@ where
             let source_mission = source_mission.clone();
             let target_mission = target_mission.clone();
             let logger = logger.clone();
+            let failed = &failed;
+            let unavailable = &unavailable;
+            let not_modified = &not_modified;
+            let unverified = &unverified;
+            let failed_deletes = &failed_deletes;
+            let failed_updates = &failed_updates;
+            let checkpoint = &checkpoint;
+            let filled_sizes = &filled_sizes;
+            let original_keys = &original_keys;
+            let metrics = &metrics;
+            let concurrency_limit = concurrency_limit.clone();
+            let circuit_breaker = circuit_breaker.clone();
 
             let func = async move {
+                let _permit = match &concurrency_limit {
+                    Some(limit) => Some(
+                        limit
+                            .semaphore
+                            .acquire()
+                            .await
+                            .map_err(|err| Error::ProcessError(format!("{:?}", err)))?,
+                    ),
+                    None => None,
+                };
                 match plan {
-                    PlanType::Update => match source.get_object(&snapshot, &source_mission).await {
-                        Ok(source_object) => {
-                            if let Err(err) = target
-                                .put_object(&snapshot, source_object, &target_mission)
-                                .await
-                            {
-                                warn!(
+                    PlanType::Update => {
+                        metrics.in_progress.fetch_add(1, Ordering::Relaxed);
+                        let _in_progress = InProgress(&metrics.in_progress);
+                        if transfer_jitter_ms != 0 {
+                            let jitter = rand::thread_rng().gen_range(0..=transfer_jitter_ms);
+                            tokio::time::sleep(Duration::from_millis(jitter)).await;
+                        }
+                        let deadline = object_timeout(
+                            snapshot.size(),
+                            timeout_base_secs,
+                            timeout_unknown_size_secs,
+                            min_throughput,
+                        );
+                        let mut attempt = 0;
+                        loop {
+                            if let Some(breaker) = &circuit_breaker {
+                                if !breaker.wait().await {
+                                    failed.fetch_add(1, Ordering::SeqCst);
+                                    metrics.failed.fetch_add(1, Ordering::Relaxed);
+                                    failed_updates
+                                        .lock()
+                                        .unwrap()
+                                        .push(snapshot.key().to_string());
+                                    return Ok(());
+                                }
+                            }
+                            let get_start = Instant::now();
+                            let source_snapshot = source_key(&snapshot, original_keys);
+                            let result = match phase_timeout(get_timeout, deadline) {
+                                Some(deadline) => source
+                                    .get_object(&source_snapshot, &source_mission)
+                                    .timeout(deadline)
+                                    .await
+                                    .into_result(),
+                                None => source.get_object(&source_snapshot, &source_mission).await,
+                            };
+                            if let Some(breaker) = &circuit_breaker {
+                                let success = match &result {
+                                    Ok(_) => true,
+                                    Err(err) => {
+                                        err.is_permanent() || matches!(err, Error::NotModified)
+                                    }
+                                };
+                                if breaker.record(success) {
+                                    warn!(
+                                        target_mission.logger,
+                                        "too many failures on source, circuit breaker opened"
+                                    );
+                                }
+                            }
+                            let (phase, err) = match result {
+                                Ok(source_object) => {
+                                    let get_elapsed = get_start.elapsed();
+                                    let put_start = Instant::now();
+                                    let put = target.put_object(
+                                        &snapshot,
+                                        source_object,
+                                        &target_mission,
+                                    );
+                                    let mut result = match phase_timeout(put_timeout, deadline) {
+                                        Some(deadline) => put.timeout(deadline).await.into_result(),
+                                        None => put.await,
+                                    };
+                                    if let (true, Ok(()), Some((method, expected))) =
+                                        (verify_checksum, &result, snapshot.checksum())
+                                    {
+                                        result =
+                                            match target.checksum_object(&snapshot, method).await {
+                                                Ok(Some(actual))
+                                                    if !actual.eq_ignore_ascii_case(expected) =>
+                                                {
+                                                    Err(Error::ChecksumMismatch(
+                                                        actual,
+                                                        expected.to_string(),
+                                                    ))
+                                                }
+                                                Ok(Some(_)) => Ok(()),
+                                                Ok(None) => {
+                                                    debug!(
+                                                        target_mission.logger,
+                                                        "no checksum {} on target for {}",
+                                                        method,
+                                                        snapshot.key()
+                                                    );
+                                                    unverified.fetch_add(1, Ordering::SeqCst);
+                                                    Ok(())
+                                                }
+                                                Err(err) => Err(err),
+                                            };
+                                    }
+                                    match result {
+                                        Ok(()) => {
+                                            debug!(
+                                                target_mission.logger,
+                                                "transferred {}", snapshot.key();
+                                                "size" => snapshot.size(),
+                                                "get_ms" => get_elapsed.as_millis() as u64,
+                                                "put_ms" => put_start.elapsed().as_millis() as u64
+                                            );
+                                            if let Some(validators) = &source_mission.validators {
+                                                validators.commit(source_snapshot.key());
+                                            }
+                                            metrics.transferred.fetch_add(1, Ordering::Relaxed);
+                                            if let Some(checkpoint) = checkpoint {
+                                                use std::io::Write;
+                                                let mut entry = CheckpointEntry::new(&snapshot);
+                                                if filled_sizes.contains_key(snapshot.key()) {
+                                                    entry.size = None;
+                                                }
+                                                let entry = serde_json::to_string(&entry)?;
+                                                let mut checkpoint = checkpoint.lock().unwrap();
+                                                if let Err(err) = writeln!(checkpoint, "{}", entry)
+                                                {
+                                                    warn!(
+                                                        target_mission.logger,
+                                                        "failed to write checkpoint: {:?}", err
+                                                    );
+                                                }
+                                            }
+                                            break;
+                                        }
+                                        Err(err) => ("put", err),
+                                    }
+                                }
+                                Err(Error::NotModified) => {
+                                    debug!(
+                                        target_mission.logger,
+                                        "not modified on source {}", snapshot.key();
+                                        "path" => snapshot.key()
+                                    );
+                                    not_modified.fetch_add(1, Ordering::SeqCst);
+                                    break;
+                                }
+                                Err(err) if err.is_permanent() => {
+                                    info!(
+                                        target_mission.logger,
+                                        "unavailable on source {}", snapshot.key();
+                                        "path" => snapshot.key(),
+                                        "phase" => "get",
+                                        "error" => format!("{:?}", err)
+                                    );
+                                    unavailable.fetch_add(1, Ordering::SeqCst);
+                                    break;
+                                }
+                                Err(err) => ("get", err),
+                            };
+                            if attempt < max_retries && err.is_retryable() {
+                                let backoff = backoff(retry_backoff, max_retry_backoff, attempt);
+                                attempt += 1;
+                                info!(
                                     target_mission.logger,
-                                    "error while put {}: {:?}",
+                                    "error while {} {}, retry {} in {:?}",
+                                    phase,
                                     snapshot.key(),
-                                    err
+                                    attempt,
+                                    backoff;
+                                    "path" => snapshot.key(),
+                                    "phase" => phase,
+                                    "error" => format!("{:?}", err)
                                 );
+                                tokio::time::sleep(backoff).await;
+                                continue;
                             }
-                        }
-                        Err(err) => {
                             warn!(
                                 target_mission.logger,
-                                "error while get {}: {:?}",
-                                snapshot.key(),
-                                err
+                                "error while {} {}", phase, snapshot.key();
+                                "path" => snapshot.key(),
+                                "phase" => phase,
+                                "error" => format!("{:?}", err)
                             );
+                            failed.fetch_add(1, Ordering::SeqCst);
+                            metrics.failed.fetch_add(1, Ordering::Relaxed);
+                            failed_updates
+                                .lock()
+                                .unwrap()
+                                .push(snapshot.key().to_string());
+                            break;
                         }
-                    },
+                    }
                     PlanType::Delete => {
-                        if let Err(err) = target
-                            .delete_object(&snapshot, &target_mission)
-                            .timeout(Duration::from_secs(60))
-                            .await
-                            .into_result()
-                        {
+                        let delete = target.delete_object(&snapshot, &target_mission);
+                        let result = match phase_timeout(delete_timeout, None) {
+                            Some(timeout) => delete.timeout(timeout).await.into_result(),
+                            None => delete.await,
+                        };
+                        if let Err(err) = result {
                             warn!(
                                 target_mission.logger,
-                                "error while delete {}: {:?}",
-                                snapshot.key(),
-                                err
+                                "error while delete {}", snapshot.key();
+                                "path" => snapshot.key(),
+                                "phase" => "delete",
+                                "error" => format!("{:?}", err)
                             );
+                            failed.fetch_add(1, Ordering::SeqCst);
+                            metrics.failed.fetch_add(1, Ordering::Relaxed);
+                            failed_deletes
+                                .lock()
+                                .unwrap()
+                                .push(snapshot.key().to_string());
+                        } else {
+                            metrics.deleted.fetch_add(1, Ordering::Relaxed);
                         }
                     }
                 }
@@ -333,18 +1749,126 @@ where
             }
         };
 
-        let mut results = stream::iter(
-            updates
-                .into_iter()
-                .map(|plan| map_snapshot(plan, PlanType::Update)),
-        )
-        .buffer_unordered(self.config.concurrent_transfer);
+        let min_free_space = self.config.min_free_space;
+        let out_of_space = AtomicBool::new(false);
+        let deadline = self.config.max_duration.map(|duration| start + duration);
+        let out_of_time = AtomicBool::new(false);
+        let scheduled = AtomicUsize::new(0);
+
+        let interrupted = Arc::new(AtomicBool::new(false));
+        {
+            let interrupted = interrupted.clone();
+            let logger = logger.clone();
+            tokio::spawn(async move {
+                if shutdown_signal().await.is_err() {
+                    return;
+                }
+                warn!(
+                    logger,
+                    "interrupted, waiting for objects in flight, interrupt again to exit immediately"
+                );
+                interrupted.store(true, Ordering::SeqCst);
+                if shutdown_signal().await.is_ok() {
+                    warn!(logger, "interrupted again, exiting");
+                    std::process::exit(130);
+                }
+            });
+        }
+
+        let results = stream::iter(updates)
+            .take_while(|_| {
+                let target = target.clone();
+                let out_of_space = &out_of_space;
+                let out_of_time = &out_of_time;
+                let scheduled = &scheduled;
+                let logger = &logger;
+                let interrupted = &interrupted;
+                async move {
+                    if interrupted.load(Ordering::SeqCst) {
+                        return false;
+                    }
+                    if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                        warn!(logger, "max duration exceeded, stop transferring");
+                        out_of_time.store(true, Ordering::SeqCst);
+                        return false;
+                    }
+                    scheduled.fetch_add(1, Ordering::SeqCst);
+                    if min_free_space == 0 {
+                        return true;
+                    }
+                    match target.free_space().await {
+                        Ok(Some(free_space)) if free_space < min_free_space => {
+                            warn!(
+                                logger,
+                                "free space on target {}B is below {}B, stop transferring",
+                                free_space,
+                                min_free_space
+                            );
+                            out_of_space.store(true, Ordering::SeqCst);
+                            scheduled.fetch_sub(1, Ordering::SeqCst);
+                            false
+                        }
+                        _ => true,
+                    }
+                }
+            })
+            .map(|plan| map_snapshot(plan, PlanType::Update))
+            .buffer_unordered(self.config.concurrent_transfer);
+        tokio::pin!(results);
 
         while let Some(_x) = results.next().await {
             progress.inc(1);
         }
 
-        if !self.config.no_delete {
+        let out_of_space = out_of_space.load(Ordering::SeqCst);
+        let out_of_time = out_of_time.load(Ordering::SeqCst);
+        let interrupted = interrupted.load(Ordering::SeqCst);
+
+        report.not_modified = not_modified.load(Ordering::SeqCst);
+        report.unverified = unverified.load(Ordering::SeqCst);
+        if report.unverified != 0 {
+            warn!(
+                logger,
+                "{} objects could not be verified, target has no checksum of them",
+                report.unverified
+            );
+        }
+        if let Some((path, validators)) = &validators {
+            info!(
+                logger,
+                "{} objects not modified on source, skipped", report.not_modified
+            );
+            if let Err(err) = validators.store(path) {
+                warn!(logger, "failed to save validators: {:?}", err);
+            }
+        }
+
+        if out_of_space {
+            warn!(
+                logger,
+                "skip deleting objects due to insufficient space on target"
+            );
+        } else if interrupted {
+            warn!(
+                logger,
+                "{} objects left untransferred after interrupt, skip deleting objects",
+                updated_objects - scheduled.load(Ordering::SeqCst)
+            );
+        } else if out_of_time {
+            warn!(
+                logger,
+                "{} objects left untransferred after max duration, skip deleting objects, they will be synced by next run",
+                updated_objects - scheduled.load(Ordering::SeqCst)
+            );
+        } else if self.config.no_delete {
+            if !deletions.is_empty() {
+                info!(
+                    logger,
+                    "{} objects missing from source are kept on target",
+                    deletions.len()
+                );
+            }
+        } else {
             info!(logger, "deleting objects");
 
             progress.set_length(deletions.len() as u64);
@@ -362,6 +1886,117 @@ where
             }
         }
 
+        report.failed_updates = std::mem::take(&mut *failed_updates.lock().unwrap());
+        report.failed_updates.sort();
+        report.failed_paths = report.failed_updates.clone();
+        report
+            .failed_paths
+            .extend(failed_deletes.lock().unwrap().iter().cloned());
+        report.failed_paths.sort();
+
+        // interrupted or limited run is to be resumed from checkpoint
+        if let (Some(path), false) = (&self.config.checkpoint, interrupted || limited) {
+            if let Err(err) = tokio::fs::remove_file(path).await {
+                warn!(logger, "failed to remove checkpoint: {:?}", err);
+            }
+        }
+
+        let unavailable = unavailable.load(Ordering::SeqCst);
+        if unavailable != 0 {
+            warn!(
+                logger,
+                "{} objects are permanently unavailable on source, {} objects failed",
+                unavailable,
+                failed.load(Ordering::SeqCst)
+            );
+        }
+
+        if let Some((tombstone_file, mut tombstones)) = tombstones {
+            for key in std::mem::take(&mut *failed_deletes.lock().unwrap()) {
+                tombstones.record_delete_failure(&key);
+            }
+            let persistent_delete_failures = tombstones.persistent_delete_failures();
+            if persistent_delete_failures != 0 {
+                warn!(
+                    logger,
+                    "{} objects repeatedly failed to delete, they will be retried with backoff",
+                    persistent_delete_failures
+                );
+            }
+            let result = async {
+                target
+                    .put_raw_object(tombstone_file, serde_json::to_vec(&tombstones)?)
+                    .await
+            };
+            if let Err(err) = result.await {
+                warn!(logger, "failed to write tombstones: {:?}", err);
+            }
+        }
+
+        if let Some(status_file) = &self.config.status_file {
+            let mut status = MirrorStatus {
+                source: source_info,
+                total_objects,
+                updated_objects,
+                deleted_objects,
+                failed_objects: failed.load(Ordering::SeqCst),
+                unavailable_objects: unavailable,
+                last_sync: unix_time(),
+                last_successful_sync: None,
+            };
+            let result = async {
+                if status.failed_objects == 0 && !out_of_space && !out_of_time && !interrupted {
+                    status.last_successful_sync = Some(status.last_sync);
+                } else if let Some(previous) = target.get_raw_object(status_file).await? {
+                    status.last_successful_sync = serde_json::from_slice::<MirrorStatus>(&previous)
+                        .ok()
+                        .and_then(|previous| previous.last_successful_sync);
+                }
+                target
+                    .put_raw_object(status_file, serde_json::to_vec_pretty(&status)?)
+                    .await
+            };
+            if let Err(err) = result.await {
+                warn!(logger, "failed to write status file: {:?}", err);
+            }
+        }
+
+        if out_of_space {
+            return Err(Error::StorageError(String::from(
+                "transfer stopped due to insufficient space on target",
+            )));
+        }
+
+        if interrupted {
+            return Err(Error::Interrupted);
+        }
+
+        if circuit_breaker
+            .as_ref()
+            .is_some_and(|breaker| breaker.given_up())
+        {
+            return Err(Error::ProcessError(String::from(
+                "source unavailable, circuit breaker gave up",
+            )));
+        }
+
+        let failed = failed.load(Ordering::SeqCst);
+        let attempted = updated_objects + deleted_objects;
+        let too_many_failures = self
+            .config
+            .max_failures
+            .is_some_and(|max_failures| failed > max_failures)
+            || self
+                .config
+                .max_failure_fraction
+                .is_some_and(|fraction| failed as f64 > fraction * attempted as f64);
+        if too_many_failures {
+            return Err(Error::ProcessError(format!(
+                "{} of {} objects failed, exceeding failure limit",
+                failed, attempted
+            )));
+        }
+
         info!(logger, "transfer complete");
 
         Ok(())
@@ -0,0 +1,233 @@
+//! Snapshot cache
+//!
+//! Taking snapshot of some sources, e.g. PyPI, takes minutes. If a snapshot
+//! cache directory is configured, source snapshot is saved there after it is
+//! taken, and reused by later runs until it is older than TTL. Cache files
+//! are named after a digest of `info()` of source, so that sources with
+//! different configuration never share a cache.
+//!
+//! Only source snapshot is cached. Target is always scanned, as it changes
+//! during every transfer.
+//!
+//! `CachedSource` wraps a source beneath all pipes, so that the cached
+//! snapshot is replayed through pipes which build state from it, e.g. index
+//! pages of `IndexPipe`. Sources which keep state of their own snapshot
+//! (e.g. URL of each bottle of Homebrew) are never cached.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::checksum::Checksum;
+use crate::common::{Mission, SnapshotConfig};
+use crate::error::Result;
+use crate::traits::{SnapshotStorage, SourceStorage};
+use crate::utils::unix_time;
+
+use async_trait::async_trait;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use slog::{info, warn};
+
+#[derive(Serialize, Deserialize)]
+struct CachedSnapshot<Snapshot> {
+    /// Unix time when the snapshot was taken
+    taken_at: u64,
+    snapshot: Snapshot,
+}
+
+/// Path of cache file for source with `info`.
+pub fn cache_path(dir: &Path, info: &str) -> PathBuf {
+    let mut checksum = Checksum::new("sha256").unwrap();
+    checksum.update(info.as_bytes());
+    dir.join(format!("{}.json", checksum.finish()))
+}
+
+/// Load cached snapshot, if it exists and is younger than `ttl`. A cache
+/// which can't be read is ignored.
+pub fn load<Snapshot: DeserializeOwned>(path: &Path, ttl: Duration) -> Option<Vec<Snapshot>> {
+    let file = std::fs::File::open(path).ok()?;
+    let cached: CachedSnapshot<Vec<Snapshot>> =
+        serde_json::from_reader(std::io::BufReader::new(file)).ok()?;
+    if unix_time().saturating_sub(cached.taken_at) < ttl.as_secs() {
+        Some(cached.snapshot)
+    } else {
+        None
+    }
+}
+
+/// Save snapshot to cache, replacing the previous one atomically.
+pub fn store<Snapshot: Serialize>(path: &Path, snapshot: &[Snapshot]) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let temp = path.with_extension("json.tmp");
+    let mut writer = std::io::BufWriter::new(std::fs::File::create(&temp)?);
+    serde_json::to_writer(
+        &mut writer,
+        &CachedSnapshot {
+            taken_at: unix_time(),
+            snapshot,
+        },
+    )?;
+    writer.flush()?;
+    std::fs::rename(&temp, path)?;
+    Ok(())
+}
+
+/// Source whose snapshot is loaded from cache if present, or saved to cache
+/// once taken. It does nothing if no cache directory is configured.
+pub struct CachedSource<Source> {
+    pub source: Source,
+}
+
+impl<Source> CachedSource<Source> {
+    pub fn new(source: Source) -> Self {
+        Self { source }
+    }
+}
+
+#[async_trait]
+impl<Snapshot, Source> SnapshotStorage<Snapshot> for CachedSource<Source>
+where
+    Snapshot: Serialize + DeserializeOwned + Send + Sync + 'static,
+    Source: SnapshotStorage<Snapshot>,
+{
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        config: &SnapshotConfig,
+    ) -> Result<Vec<Snapshot>> {
+        let path = match &config.snapshot_cache_dir {
+            Some(dir) if self.source.cacheable() => cache_path(dir, &self.source.info()),
+            _ => return self.source.snapshot(mission, config).await,
+        };
+        if let Some(snapshot) = load(&path, config.snapshot_ttl) {
+            info!(mission.logger, "using cached source snapshot");
+            return Ok(snapshot);
+        }
+        let logger = mission.logger.clone();
+        let snapshot = self.source.snapshot(mission, config).await?;
+        if let Err(err) = store(&path, &snapshot) {
+            warn!(logger, "failed to cache source snapshot: {:?}", err);
+        }
+        Ok(snapshot)
+    }
+
+    async fn check(&mut self, mission: Mission, config: &SnapshotConfig) -> Result<String> {
+        self.source.check(mission, config).await
+    }
+
+    fn info(&self) -> String {
+        self.source.info()
+    }
+
+    fn cacheable(&self) -> bool {
+        self.source.cacheable()
+    }
+}
+
+#[async_trait]
+impl<Snapshot, Source, SourceItem> SourceStorage<Snapshot, SourceItem> for CachedSource<Source>
+where
+    Snapshot: Send + Sync + 'static,
+    Source: SourceStorage<Snapshot, SourceItem>,
+{
+    async fn get_object(&self, snapshot: &Snapshot, mission: &Mission) -> Result<SourceItem> {
+        self.source.get_object(snapshot, mission).await
+    }
+
+    async fn get_size(&self, snapshot: &Snapshot, mission: &Mission) -> Result<Option<u64>> {
+        self.source.get_size(snapshot, mission).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::SnapshotPath;
+
+    #[test]
+    fn test_load_store() {
+        let dir = std::env::temp_dir().join(format!("snapshot-cache-{}", std::process::id()));
+        let path = cache_path(&dir, "pypi, Pypi { .. }");
+        let snapshot = vec![SnapshotPath::new("a".to_string())];
+        store(&path, &snapshot).unwrap();
+        let loaded: Option<Vec<SnapshotPath>> = load(&path, Duration::from_secs(60));
+        let expired: Option<Vec<SnapshotPath>> = load(&path, Duration::from_secs(0));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(loaded, Some(snapshot));
+        assert_eq!(expired, None);
+        assert_ne!(path, cache_path(&dir, "pypi, Pypi { debug }"));
+    }
+
+    struct Listed {
+        keys: Vec<&'static str>,
+        cacheable: bool,
+    }
+
+    #[async_trait]
+    impl SnapshotStorage<SnapshotPath> for Listed {
+        async fn snapshot(
+            &mut self,
+            _mission: Mission,
+            _config: &SnapshotConfig,
+        ) -> Result<Vec<SnapshotPath>> {
+            Ok(self
+                .keys
+                .iter()
+                .map(|key| SnapshotPath::new(key.to_string()))
+                .collect())
+        }
+
+        fn info(&self) -> String {
+            "listed".to_string()
+        }
+
+        fn cacheable(&self) -> bool {
+            self.cacheable
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replay_through_pipes() {
+        let dir = std::env::temp_dir().join(format!("snapshot-replay-{}", std::process::id()));
+        let config = SnapshotConfig {
+            snapshot_cache_dir: Some(dir.clone()),
+            snapshot_ttl: Duration::from_secs(60),
+            ..SnapshotConfig::for_test()
+        };
+        let snapshot = |keys, cacheable| {
+            let mut source = crate::index_pipe::IndexPipe::new(
+                CachedSource::new(Listed { keys, cacheable }),
+                String::new(),
+                String::new(),
+                999,
+            );
+            let config = &config;
+            async move {
+                let snapshot = source.snapshot(Mission::for_test(), config).await.unwrap();
+                snapshot.into_iter().map(|x| x.0).collect::<Vec<_>>()
+            }
+        };
+
+        snapshot(vec!["a", "c/b"], true).await;
+        // index pages are generated again from cached snapshot
+        assert_eq!(
+            snapshot(vec![], true).await,
+            vec![
+                "a",
+                "c/b",
+                "mirror_clone_list.html",
+                "c/mirror_clone_list.html"
+            ]
+        );
+        snapshot(vec!["a"], false).await;
+        assert_eq!(
+            snapshot(vec![], false).await,
+            vec!["mirror_clone_list.html"]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
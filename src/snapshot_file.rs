@@ -0,0 +1,160 @@
+//! Snapshot file source.
+//!
+//! Simple diff transfer may export source snapshot to a file with
+//! `--export-snapshot`, one JSON object per line. SnapshotFile is a source
+//! storage which loads snapshot from such a file, and yields TransferURL
+//! against a configurable base. This makes it possible to re-run a transfer
+//! against a frozen source state.
+//!
+//! SnapshotFile supports meta snapshot, so that size, modified time and
+//! checksum in exported snapshot are kept.
+
+use std::io::{BufRead, Write};
+
+use crate::common::{Mission, SnapshotConfig, TransferURL};
+use crate::error::{Error, Result};
+use crate::metadata::SnapshotMeta;
+use crate::traits::{Key, Metadata, SnapshotStorage, SourceStorage};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use slog::info;
+use structopt::StructOpt;
+
+/// A line in snapshot file.
+#[derive(Serialize, Deserialize, Debug)]
+struct SnapshotRecord {
+    key: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_modified: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    checksum_method: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    checksum: Option<String>,
+}
+
+/// Write snapshot to file, one JSON object per line.
+pub fn export<Snapshot: Key + Metadata>(path: &str, snapshot: &[Snapshot]) -> Result<()> {
+    let mut writer = std::io::BufWriter::new(std::fs::File::create(path)?);
+    for item in snapshot {
+        let (checksum_method, checksum) = item
+            .checksum()
+            .map(|(method, checksum)| (method.to_string(), checksum.to_string()))
+            .unzip();
+        let record = SnapshotRecord {
+            key: item.key().to_string(),
+            size: item.size(),
+            last_modified: item.last_modified(),
+            checksum_method,
+            checksum,
+        };
+        serde_json::to_writer(&mut writer, &record)?;
+        writer.write_all(b"\n")?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct SnapshotFile {
+    /// Snapshot exported with `--export-snapshot`
+    #[structopt(long, help = "Snapshot file exported by a previous transfer")]
+    pub snapshot_file: String,
+    /// Base of objects in snapshot
+    #[structopt(long, help = "Base URL of objects in snapshot")]
+    pub base: String,
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotMeta> for SnapshotFile {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        _config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotMeta>> {
+        let logger = mission.logger;
+        let progress = mission.progress;
+
+        info!(logger, "loading snapshot from {}...", self.snapshot_file);
+
+        let path = self.snapshot_file.clone();
+        let snapshot = tokio::task::spawn_blocking(move || {
+            let reader = std::io::BufReader::new(std::fs::File::open(path)?);
+            let mut snapshot = vec![];
+            for line in reader.lines() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let record: SnapshotRecord = serde_json::from_str(&line)?;
+                progress.inc(1);
+                snapshot.push(SnapshotMeta {
+                    key: record.key,
+                    size: record.size,
+                    last_modified: record.last_modified,
+                    checksum_method: record.checksum_method,
+                    checksum: record.checksum,
+                    ..Default::default()
+                });
+            }
+            progress.finish_with_message("done");
+            Ok::<_, Error>(snapshot)
+        })
+        .await
+        .map_err(|err| Error::ProcessError(format!("error while loading: {:?}", err)))??;
+
+        Ok(snapshot)
+    }
+
+    fn info(&self) -> String {
+        format!("snapshot file, {:?}", self)
+    }
+}
+
+#[async_trait]
+impl SourceStorage<SnapshotMeta, TransferURL> for SnapshotFile {
+    async fn get_object(&self, snapshot: &SnapshotMeta, _mission: &Mission) -> Result<TransferURL> {
+        Ok(TransferURL::new(format!("{}/{}", self.base, snapshot.key)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_export_and_load() {
+        let path =
+            std::env::temp_dir().join(format!("mirror-clone-snapshot-{}", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let exported = vec![
+            SnapshotMeta::new("a".to_string()),
+            SnapshotMeta {
+                key: "b/c".to_string(),
+                size: Some(42),
+                last_modified: Some(1000),
+                checksum_method: Some("sha256".to_string()),
+                checksum: Some("abcd".to_string()),
+                ..Default::default()
+            },
+        ];
+        export(&path, &exported).unwrap();
+
+        let mut source = SnapshotFile {
+            snapshot_file: path.clone(),
+            base: "http://mirror.example.com".to_string(),
+        };
+        let config = SnapshotConfig::for_test();
+        let loaded = source.snapshot(Mission::for_test(), &config).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded[0].key, "a");
+        assert_eq!(loaded[0].size, None);
+        assert_eq!(loaded[1].key, "b/c");
+        assert_eq!(loaded[1].size, Some(42));
+        assert_eq!(loaded[1].checksum(), Some(("sha256", "abcd")));
+    }
+}
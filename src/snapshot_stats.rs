@@ -0,0 +1,99 @@
+//! Snapshot statistics
+//!
+//! Summary of a snapshot, including object count by file extension, size
+//! distribution and largest objects. Size related statistics are only
+//! available when snapshot carries size.
+
+use std::collections::HashMap;
+
+use slog::{info, Logger};
+
+use crate::traits::{Key, Metadata};
+use crate::utils::human_size;
+
+const TOP_N: usize = 10;
+
+/// Upper bounds of size buckets.
+const SIZE_BUCKETS: &[u64] = &[1 << 10, 1 << 20, 10 << 20, 100 << 20, 1 << 30, u64::MAX];
+
+fn extension(key: &str) -> &str {
+    let file_name = key.rsplit('/').next().unwrap_or(key);
+    match file_name.rsplit_once('.') {
+        Some((name, extension)) if !name.is_empty() => extension,
+        _ => "(none)",
+    }
+}
+
+pub fn log_snapshot_stats<Snapshot: Key + Metadata>(logger: &Logger, snapshot: &[Snapshot]) {
+    let mut extensions: HashMap<&str, usize> = HashMap::new();
+    let mut buckets = vec![0usize; SIZE_BUCKETS.len()];
+    let mut unknown_size: usize = 0;
+    let mut total_size: u64 = 0;
+    let mut largest: Vec<(u64, &str)> = vec![];
+
+    for item in snapshot {
+        *extensions.entry(extension(item.key())).or_default() += 1;
+        match item.size() {
+            Some(size) => {
+                total_size += size;
+                let bucket = SIZE_BUCKETS
+                    .iter()
+                    .position(|bound| size < *bound)
+                    .unwrap_or(SIZE_BUCKETS.len() - 1);
+                buckets[bucket] += 1;
+                largest.push((size, item.key()));
+            }
+            None => unknown_size += 1,
+        }
+    }
+
+    info!(
+        logger,
+        "snapshot stats: {} objects, {} in total, {} of unknown size",
+        snapshot.len(),
+        human_size(total_size),
+        unknown_size
+    );
+
+    let mut extensions: Vec<_> = extensions.into_iter().collect();
+    extensions.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+    for (extension, count) in extensions.iter().take(TOP_N) {
+        info!(logger, "  .{:<16} {:>10} objects", extension, count);
+    }
+    if extensions.len() > TOP_N {
+        info!(logger, "  ({} more extensions)", extensions.len() - TOP_N);
+    }
+
+    if unknown_size == snapshot.len() {
+        return;
+    }
+
+    let mut lower = 0;
+    for (bound, count) in SIZE_BUCKETS.iter().zip(buckets) {
+        let range = if *bound == u64::MAX {
+            format!(">= {}", human_size(lower))
+        } else {
+            format!("{} - {}", human_size(lower), human_size(*bound))
+        };
+        info!(logger, "  {:<24} {:>10} objects", range, count);
+        lower = *bound;
+    }
+
+    largest.sort_unstable_by(|a, b| b.cmp(a));
+    for (size, key) in largest.iter().take(TOP_N) {
+        info!(logger, "  {:>12} {}", human_size(*size), key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extension() {
+        assert_eq!(extension("a/b/c.tar.gz"), "gz");
+        assert_eq!(extension("a/b.c/d"), "(none)");
+        assert_eq!(extension(".bashrc"), "(none)");
+        assert_eq!(extension("Packages"), "(none)");
+    }
+}
@@ -7,17 +7,41 @@
 //! Currently, this is done by downloading files to local file system,
 //! provide it to target storage, and delete it on dropping file object.
 //! We may later refactor it to use in-memory stream or direct reqwest stream.
+//!
+//! If snapshot carries a checksum, it is verified against the downloaded
+//! content, and object with mismatched checksum is not transferred. A
+//! download with mismatched checksum, or shorter than its `Content-Length`,
+//! is treated as failed, and retried along with other transient errors.
+//!
+//! If a bandwidth limit is set on mission, downloads of all concurrent
+//! transfers are throttled together.
+//!
+//! `TransferStreamPipe` instead yields `TransferStream`, which streams
+//! response body into target without buffering it on local disk. Checksum
+//! and length are verified at the end of stream before the last chunk is
+//! yielded, and target discards the object if they mismatch.
+//!
+//! Some response headers, e.g. `Cache-Control`, are kept along with the
+//! content, and targets may store them if configured to.
+//!
+//! If mission carries validators, requests are conditional, and objects not
+//! modified on source yield `NotModified`. See `conditional`.
 
 use async_trait::async_trait;
 use chrono::DateTime;
 
-use crate::common::{Mission, SnapshotConfig, TransferURL};
+use crate::checksum::Checksum;
+use crate::common::{Mission, SnapshotConfig, TransferStream, TransferURL};
 use crate::error::{Error, Result};
+use crate::rate_limit::RateLimit;
 use crate::traits::{Key, Metadata, SnapshotStorage, SourceStorage};
 use crate::utils::{hash_string, unix_time};
 use futures_core::Stream;
+use futures_util::stream::{self, BoxStream};
 use futures_util::{StreamExt, TryStreamExt};
 use slog::{debug, warn};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use tokio::fs::OpenOptions;
 use tokio::io::{AsyncSeekExt, AsyncWriteExt, BufReader, BufWriter};
 use tokio_util::codec;
@@ -71,8 +95,22 @@ pub struct ByteStream {
     pub length: u64,
     pub modified_at: u64,
     pub content_type: Option<String>,
+    /// Response headers of source object which targets may store, in
+    /// lowercase names. See `STORED_HEADERS`.
+    pub headers: Vec<(String, String)>,
 }
 
+/// Response headers kept in `ByteStream`, so that targets may serve objects
+/// with the same headers as source.
+pub const STORED_HEADERS: &[&str] = &[
+    "cache-control",
+    "content-disposition",
+    "content-encoding",
+    "content-language",
+    "etag",
+    "last-modified",
+];
+
 pub struct ByteStreamPipe<Source> {
     pub source: Source,
     pub buffer_path: String,
@@ -103,6 +141,10 @@ where
         self.source.snapshot(mission, config).await
     }
 
+    async fn check(&mut self, mission: Mission, config: &SnapshotConfig) -> Result<String> {
+        self.source.check(mission, config).await
+    }
+
     fn info(&self) -> String {
         format!(
             "StreamPipe buffered to {} <{}>",
@@ -110,23 +152,133 @@ where
             self.source.info()
         )
     }
+
+    fn cacheable(&self) -> bool {
+        self.source.cacheable()
+    }
 }
 
-#[async_trait]
-impl<Snapshot, Source> SourceStorage<Snapshot, ByteStream> for ByteStreamPipe<Source>
-where
-    Snapshot: Key + Metadata,
-    Source: SourceStorage<Snapshot, TransferURL>,
-{
-    async fn get_object(&self, snapshot: &Snapshot, mission: &Mission) -> Result<ByteStream> {
-        let transfer_url = self.source.get_object(snapshot, mission).await?;
+/// Modified time, content type and stored headers of response of an object.
+#[allow(clippy::type_complexity)]
+fn response_metadata<Snapshot: Metadata>(
+    response: &reqwest::Response,
+    snapshot: &Snapshot,
+    use_snapshot_last_modified: bool,
+    mission: &Mission,
+) -> Result<(u64, Option<String>, Vec<(String, String)>)> {
+    let snapshot_modified_at = snapshot.last_modified();
+    let http_modified_at = response
+        .headers()
+        .get(reqwest::header::LAST_MODIFIED)
+        .map(|x| x.as_bytes())
+        .and_then(|x| std::str::from_utf8(x).ok())
+        .and_then(|header| DateTime::parse_from_rfc2822(header).ok())
+        .map(|x| x.timestamp() as u64);
 
-        let path = format!(
-            "{}/{}.{}.buffer",
-            self.buffer_path,
-            hash_string(&transfer_url.0),
-            unix_time()
-        );
+    let modified_at = if use_snapshot_last_modified {
+        snapshot_modified_at
+    } else {
+        http_modified_at
+    };
+
+    let modified_at =
+        modified_at.ok_or_else(|| Error::PipeError("no modified time".to_string()))?;
+
+    if let Some(snapshot_modified_at) = snapshot_modified_at {
+        if let Some(http_modified_at) = http_modified_at {
+            if snapshot_modified_at != http_modified_at {
+                warn!(
+                    mission.logger,
+                    "mismatch modified time: http={}, snapshot={}",
+                    http_modified_at,
+                    snapshot_modified_at
+                );
+            }
+        }
+    }
+
+    let content_type = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .map(|x| x.as_bytes())
+        .and_then(|x| std::str::from_utf8(x).ok())
+        .map(|x| x.to_string());
+
+    let headers = STORED_HEADERS
+        .iter()
+        .filter_map(|name| {
+            let value = response.headers().get(*name)?.to_str().ok()?;
+            Some((name.to_string(), value.to_string()))
+        })
+        .collect();
+
+    Ok((modified_at, content_type, headers))
+}
+
+/// Hasher for checksum in snapshot, along with the expected digest.
+fn snapshot_checksum<'a, Snapshot: Metadata>(
+    snapshot: &'a Snapshot,
+    url: &str,
+    logger: &slog::Logger,
+) -> Option<(Checksum, &'a str)> {
+    snapshot.checksum().and_then(|(method, expected)| {
+        let checksum = Checksum::new(method);
+        if checksum.is_none() {
+            warn!(logger, "unsupported checksum method {}: {}", method, url);
+        }
+        checksum.map(|checksum| (checksum, expected))
+    })
+}
+
+/// Successful response of GET request, conditional if there are
+/// validators of the object. Validators of response are recorded, and
+/// `NotModified` is returned on 304.
+async fn conditional_get<Snapshot: Key>(
+    url: &str,
+    snapshot: &Snapshot,
+    mission: &Mission,
+) -> Result<reqwest::Response> {
+    let mut request = mission.client.get(url);
+    if let Some(validators) = &mission.validators {
+        request = validators.apply(snapshot.key(), request);
+    }
+    mission.throttle().await;
+    let response = request.send().await?;
+    let status = response.status();
+    if status == reqwest::StatusCode::NOT_MODIFIED {
+        return Err(Error::NotModified);
+    }
+    if !status.is_success() {
+        return Err(Error::HTTPError(status));
+    }
+    if let Some(validators) = &mission.validators {
+        validators.record(snapshot.key(), response.headers());
+    }
+    Ok(response)
+}
+
+/// Size of object in `Content-Length` of a HEAD request.
+async fn head_size(url: &str, mission: &Mission) -> Result<Option<u64>> {
+    mission.throttle().await;
+    let response = mission.client.head(url).send().await?;
+    if !response.status().is_success() {
+        return Err(Error::HTTPError(response.status()));
+    }
+    Ok(response
+        .headers()
+        .get(reqwest::header::CONTENT_LENGTH)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.parse().ok()))
+}
+
+impl<Source> ByteStreamPipe<Source> {
+    async fn download<Snapshot: Key + Metadata>(
+        &self,
+        url: &str,
+        path: &str,
+        snapshot: &Snapshot,
+        mission: &Mission,
+    ) -> Result<ByteStream> {
         let logger = &mission.logger;
         let mut f = BufWriter::new(
             OpenOptions::default()
@@ -134,71 +286,51 @@ where
                 .truncate(true)
                 .write(true)
                 .read(true)
-                .open(&path)
+                .open(path)
                 .await?,
         );
 
-        let response = mission.client.get(&transfer_url.0).send().await?;
-        let status = response.status();
-        if !status.is_success() {
-            return Err(Error::HTTPError(status));
-        }
+        let response = conditional_get(url, snapshot, mission).await?;
 
         let mut total_bytes: u64 = 0;
         let content_length = response.content_length();
-        let snapshot_modified_at = snapshot.last_modified();
-        let http_modified_at = response
-            .headers()
-            .get(reqwest::header::LAST_MODIFIED)
-            .map(|x| x.as_bytes())
-            .and_then(|x| std::str::from_utf8(x).ok())
-            .and_then(|header| DateTime::parse_from_rfc2822(header).ok())
-            .map(|x| x.timestamp() as u64);
-
-        let modified_at = if self.use_snapshot_last_modified {
-            snapshot_modified_at
-        } else {
-            http_modified_at
-        };
-
-        let modified_at =
-            modified_at.ok_or_else(|| Error::PipeError("no modified time".to_string()))?;
-
-        if let Some(snapshot_modified_at) = snapshot_modified_at {
-            if let Some(http_modified_at) = http_modified_at {
-                if snapshot_modified_at != http_modified_at {
-                    warn!(
-                        mission.logger,
-                        "mismatch modified time: http={}, snapshot={}",
-                        http_modified_at,
-                        snapshot_modified_at
-                    );
-                }
-            }
-        }
+        let (modified_at, content_type, headers) = response_metadata(
+            &response,
+            snapshot,
+            self.use_snapshot_last_modified,
+            mission,
+        )?;
 
-        let content_type = response
-            .headers()
-            .get(reqwest::header::CONTENT_TYPE)
-            .map(|x| x.as_bytes())
-            .and_then(|x| std::str::from_utf8(x).ok())
-            .map(|x| x.to_string());
+        debug!(logger, "download: {} {:?}", url, content_length);
 
-        debug!(logger, "download: {} {:?}", transfer_url.0, content_length);
+        let mut checksum = snapshot_checksum(snapshot, url, logger);
 
         let mut stream = response.bytes_stream();
         while let Some(content) = stream.next().await {
             let content = content?;
+            if let Some((checksum, _)) = &mut checksum {
+                checksum.update(&content);
+            }
             f.write_all(&content).await?;
             total_bytes += content.len() as u64;
+            if let Some(bytes_received) = &mission.bytes_received {
+                bytes_received.fetch_add(content.len() as u64, Ordering::Relaxed);
+            }
+            if let Some(bandwidth_limit) = &mission.bandwidth_limit {
+                bandwidth_limit.consume(content.len() as f64).await;
+            }
         }
 
         if let Some(content_length) = content_length {
             if total_bytes != content_length {
-                return Err(Error::PipeError(format!(
-                    "content length mismatch: {}/{}",
-                    total_bytes, content_length
-                )));
+                return Err(Error::TruncatedDownload(total_bytes, content_length));
+            }
+        }
+
+        if let Some((checksum, expected)) = checksum {
+            let actual = checksum.finish();
+            if !actual.eq_ignore_ascii_case(expected) {
+                return Err(Error::ChecksumMismatch(actual, expected.to_string()));
             }
         }
 
@@ -216,6 +348,376 @@ where
             length: total_bytes,
             modified_at,
             content_type,
+            headers,
+        })
+    }
+}
+
+#[async_trait]
+impl<Snapshot, Source> SourceStorage<Snapshot, ByteStream> for ByteStreamPipe<Source>
+where
+    Snapshot: Key + Metadata,
+    Source: SourceStorage<Snapshot, TransferURL>,
+{
+    async fn get_object(&self, snapshot: &Snapshot, mission: &Mission) -> Result<ByteStream> {
+        let transfer_url = self.source.get_object(snapshot, mission).await?;
+
+        let path = format!(
+            "{}/{}.{}.buffer",
+            self.buffer_path,
+            hash_string(&transfer_url.0),
+            unix_time()
+        );
+
+        // try fallback URLs in order, transient errors are retried by transfer
+        let mut candidates = transfer_url.candidates().peekable();
+        while let Some(url) = candidates.next() {
+            let err = match self.download(url, &path, snapshot, mission).await {
+                Ok(byte_stream) => return Ok(byte_stream),
+                Err(err) => err,
+            };
+            if candidates.peek().is_none() || matches!(err, Error::NotModified) {
+                tokio::fs::remove_file(&path).await.ok();
+                return Err(err);
+            }
+            warn!(
+                mission.logger,
+                "failed to download from {}, trying next mirror: {:?}", url, err
+            );
+        }
+
+        unreachable!()
+    }
+
+    async fn get_size(&self, snapshot: &Snapshot, mission: &Mission) -> Result<Option<u64>>
+    where
+        Snapshot: Sync,
+    {
+        let transfer_url = self.source.get_object(snapshot, mission).await?;
+        head_size(&transfer_url.0, mission).await
+    }
+}
+
+/// A `TransferStreamPipe` pipes `TransferURL` to `TransferStream`, so that
+/// response body flows into target as it is received, instead of being
+/// buffered on local disk.
+pub struct TransferStreamPipe<Source> {
+    pub source: Source,
+    pub use_snapshot_last_modified: bool,
+}
+
+impl<Source> TransferStreamPipe<Source> {
+    pub fn new(source: Source, use_snapshot_last_modified: bool) -> Self {
+        Self {
+            source,
+            use_snapshot_last_modified,
+        }
+    }
+
+    async fn open<Snapshot: Key + Metadata>(
+        &self,
+        url: &str,
+        snapshot: &Snapshot,
+        mission: &Mission,
+    ) -> Result<TransferStream> {
+        let response = conditional_get(url, snapshot, mission).await?;
+        let length = response.content_length();
+        let (modified_at, content_type, headers) = response_metadata(
+            &response,
+            snapshot,
+            self.use_snapshot_last_modified,
+            mission,
+        )?;
+        debug!(mission.logger, "stream: {} {:?}", url, length);
+
+        let checksum = snapshot_checksum(snapshot, url, &mission.logger)
+            .map(|(checksum, expected)| (checksum, expected.to_string()));
+        let body = VerifiedBody {
+            body: response.bytes_stream().boxed(),
+            length,
+            received: 0,
+            checksum,
+            bandwidth_limit: mission.bandwidth_limit.clone(),
+            bytes_received: mission.bytes_received.clone(),
+            pending: None,
+            done: false,
+        };
+        Ok(TransferStream {
+            stream: body.into_stream(),
+            length,
+            modified_at,
+            content_type,
+            headers,
+        })
+    }
+}
+
+/// Response body being streamed. Bytes are throttled by bandwidth limit,
+/// and a body shorter than `Content-Length` or with mismatched checksum
+/// ends with an error, so that target discards what it has written.
+///
+/// The last chunk is held back until body is verified, as targets which
+/// know the length (e.g. a single S3 PUT) may commit the object as soon as
+/// all bytes are received, without polling the stream to its end.
+struct VerifiedBody {
+    body: BoxStream<'static, reqwest::Result<bytes::Bytes>>,
+    length: Option<u64>,
+    received: u64,
+    checksum: Option<(Checksum, String)>,
+    bandwidth_limit: Option<RateLimit>,
+    bytes_received: Option<Arc<AtomicU64>>,
+    pending: Option<bytes::Bytes>,
+    done: bool,
+}
+
+impl VerifiedBody {
+    fn into_stream(self) -> BoxStream<'static, std::io::Result<bytes::Bytes>> {
+        stream::unfold(self, |mut body| async move {
+            if body.done {
+                return None;
+            }
+            let result = loop {
+                match body.body.next().await {
+                    Some(Ok(content)) => {
+                        if content.is_empty() {
+                            continue;
+                        }
+                        if let Some((checksum, _)) = &mut body.checksum {
+                            checksum.update(&content);
+                        }
+                        body.received += content.len() as u64;
+                        if let Some(bytes_received) = &body.bytes_received {
+                            bytes_received.fetch_add(content.len() as u64, Ordering::Relaxed);
+                        }
+                        if let Some(bandwidth_limit) = &body.bandwidth_limit {
+                            bandwidth_limit.consume(content.len() as f64).await;
+                        }
+                        if let Some(pending) = body.pending.replace(content) {
+                            return Some((Ok(pending), body));
+                        }
+                    }
+                    Some(Err(err)) => break Err(Error::Reqwest(err)),
+                    None => break body.finish(),
+                }
+            };
+            body.done = true;
+            match result {
+                Ok(()) => body.pending.take().map(|pending| (Ok(pending), body)),
+                Err(err) => Some((Err(std::io::Error::other(err)), body)),
+            }
         })
+        .boxed()
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        if let Some(length) = self.length {
+            if self.received != length {
+                return Err(Error::TruncatedDownload(self.received, length));
+            }
+        }
+        if let Some((checksum, expected)) = self.checksum.take() {
+            let actual = checksum.finish();
+            if !actual.eq_ignore_ascii_case(&expected) {
+                return Err(Error::ChecksumMismatch(actual, expected));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<Snapshot, Source> SnapshotStorage<Snapshot> for TransferStreamPipe<Source>
+where
+    Snapshot: Send + 'static,
+    Source: SnapshotStorage<Snapshot> + Send,
+{
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        config: &SnapshotConfig,
+    ) -> Result<Vec<Snapshot>> {
+        self.source.snapshot(mission, config).await
+    }
+
+    async fn check(&mut self, mission: Mission, config: &SnapshotConfig) -> Result<String> {
+        self.source.check(mission, config).await
+    }
+
+    fn info(&self) -> String {
+        format!("TransferStreamPipe <{}>", self.source.info())
+    }
+
+    fn cacheable(&self) -> bool {
+        self.source.cacheable()
+    }
+}
+
+#[async_trait]
+impl<Snapshot, Source> SourceStorage<Snapshot, TransferStream> for TransferStreamPipe<Source>
+where
+    Snapshot: Key + Metadata,
+    Source: SourceStorage<Snapshot, TransferURL>,
+{
+    async fn get_object(&self, snapshot: &Snapshot, mission: &Mission) -> Result<TransferStream> {
+        let transfer_url = self.source.get_object(snapshot, mission).await?;
+
+        // body can't be replayed once target starts consuming it, so only
+        // failed requests fall back to the next mirror, and truncated body
+        // is retried by transfer
+        let mut candidates = transfer_url.candidates().peekable();
+        while let Some(url) = candidates.next() {
+            match self.open(url, snapshot, mission).await {
+                Ok(transfer_stream) => return Ok(transfer_stream),
+                Err(err) if candidates.peek().is_some() && !matches!(err, Error::NotModified) => {
+                    warn!(
+                        mission.logger,
+                        "failed to request {}, trying next mirror: {:?}", url, err
+                    );
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        unreachable!()
+    }
+
+    async fn get_size(&self, snapshot: &Snapshot, mission: &Mission) -> Result<Option<u64>>
+    where
+        Snapshot: Sync,
+    {
+        let transfer_url = self.source.get_object(snapshot, mission).await?;
+        head_size(&transfer_url.0, mission).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::SnapshotPath;
+    use tokio::io::AsyncReadExt;
+
+    struct StaticURL(String);
+
+    #[async_trait]
+    impl SourceStorage<SnapshotPath, TransferURL> for StaticURL {
+        async fn get_object(
+            &self,
+            _snapshot: &SnapshotPath,
+            _mission: &Mission,
+        ) -> Result<TransferURL> {
+            Ok(TransferURL::new(self.0.clone()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_zero_byte_object() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = [0; 1024];
+            let _ = socket.read(&mut request).await.unwrap();
+            socket
+                .write_all(
+                    b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nCache-Control: max-age=60\r\nLast-Modified: Fri, 01 Jan 2021 00:00:00 GMT\r\n\r\n",
+                )
+                .await
+                .unwrap();
+        });
+
+        let buffer_path = std::env::temp_dir().to_str().unwrap().to_string();
+        let source = ByteStreamPipe::new(
+            StaticURL(format!("http://{}/pkg/__init__.py", addr)),
+            buffer_path,
+            false,
+        );
+        let snapshot = SnapshotPath::new("pkg/__init__.py".to_string());
+        let byte_stream = source
+            .get_object(&snapshot, &Mission::for_test())
+            .await
+            .unwrap();
+        assert_eq!(byte_stream.length, 0);
+        assert_eq!(byte_stream.modified_at, 1609459200);
+        assert_eq!(
+            byte_stream.headers,
+            vec![
+                ("cache-control".to_string(), "max-age=60".to_string()),
+                (
+                    "last-modified".to_string(),
+                    "Fri, 01 Jan 2021 00:00:00 GMT".to_string()
+                )
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_truncated_download() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = [0; 1024];
+            let _ = socket.read(&mut request).await.unwrap();
+            socket
+                .write_all(
+                    b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\nLast-Modified: Fri, 01 Jan 2021 00:00:00 GMT\r\n\r\nshort",
+                )
+                .await
+                .unwrap();
+        });
+
+        let buffer_path = std::env::temp_dir().to_str().unwrap().to_string();
+        let source = ByteStreamPipe::new(
+            StaticURL(format!("http://{}/truncated", addr)),
+            buffer_path,
+            false,
+        );
+        let snapshot = SnapshotPath::new("truncated".to_string());
+        let err = source
+            .get_object(&snapshot, &Mission::for_test())
+            .await
+            .err()
+            .unwrap();
+        assert!(err.is_retryable());
+    }
+
+    #[tokio::test]
+    async fn test_transfer_stream() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            for response in [
+                &b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nLast-Modified: Fri, 01 Jan 2021 00:00:00 GMT\r\n\r\nhello"[..],
+                &b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\nLast-Modified: Fri, 01 Jan 2021 00:00:00 GMT\r\n\r\nshort"[..],
+            ] {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = [0; 1024];
+                let _ = socket.read(&mut request).await.unwrap();
+                socket.write_all(response).await.unwrap();
+            }
+        });
+
+        let source = TransferStreamPipe::new(StaticURL(format!("http://{}/object", addr)), false);
+        let snapshot = SnapshotPath::new("object".to_string());
+        let item = source
+            .get_object(&snapshot, &Mission::for_test())
+            .await
+            .unwrap();
+        assert_eq!(item.length, Some(5));
+        let content: Vec<_> = item.stream.try_collect().await.unwrap();
+        assert_eq!(content.concat(), b"hello");
+
+        let item = source
+            .get_object(&snapshot, &Mission::for_test())
+            .await
+            .unwrap();
+        let err: Error = item
+            .stream
+            .try_collect::<Vec<_>>()
+            .await
+            .err()
+            .unwrap()
+            .into();
+        assert!(err.is_retryable());
     }
 }
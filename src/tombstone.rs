@@ -0,0 +1,140 @@
+//! Tombstones
+//!
+//! Objects missing from source are not deleted from target at once. Instead,
+//! a tombstone recording when an object was first found missing is persisted
+//! on target, and the object is only deleted after it has been missing for
+//! enough consecutive runs and days. If an object reappears in source, its
+//! tombstone is dropped. This protects target from mass deletion caused by
+//! a transient glitch of upstream.
+//!
+//! Tombstones also record objects which target failed to delete. Deletion
+//! of such objects is retried with exponential backoff in number of runs,
+//! instead of on every run.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+const MAX_RETRY_BACKOFF_RUNS: u64 = 64;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Tombstone {
+    /// Unix time when the object was first found missing from source
+    pub first_missing: u64,
+    /// Number of consecutive runs in which the object was missing
+    pub missing_runs: u64,
+    /// Number of times target failed to delete the object
+    #[serde(default)]
+    pub delete_failures: u64,
+    /// Deletion is not retried before the object is missing for this many runs
+    #[serde(default)]
+    pub retry_run: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Tombstones {
+    pub objects: BTreeMap<String, Tombstone>,
+}
+
+impl Tombstones {
+    /// Record keys missing from source in this run. Tombstones of keys which
+    /// are no longer missing are dropped.
+    pub fn update<'a>(&self, missing: impl Iterator<Item = &'a str>, now: u64) -> Self {
+        let objects = missing
+            .map(|key| {
+                let tombstone = match self.objects.get(key) {
+                    Some(previous) => Tombstone {
+                        missing_runs: previous.missing_runs + 1,
+                        ..previous.clone()
+                    },
+                    None => Tombstone {
+                        first_missing: now,
+                        missing_runs: 1,
+                        delete_failures: 0,
+                        retry_run: 0,
+                    },
+                };
+                (key.to_string(), tombstone)
+            })
+            .collect();
+        Self { objects }
+    }
+
+    /// Whether an object has been missing long enough to be deleted.
+    pub fn expired(&self, key: &str, min_runs: u64, min_days: u64, now: u64) -> bool {
+        match self.objects.get(key) {
+            Some(tombstone) => {
+                tombstone.missing_runs >= min_runs
+                    && now.saturating_sub(tombstone.first_missing) >= min_days * SECONDS_PER_DAY
+            }
+            None => false,
+        }
+    }
+
+    /// Whether deletion of an object is being backed off after failures.
+    pub fn backing_off(&self, key: &str) -> bool {
+        match self.objects.get(key) {
+            Some(tombstone) => tombstone.missing_runs < tombstone.retry_run,
+            None => false,
+        }
+    }
+
+    /// Record that target failed to delete an object in this run.
+    pub fn record_delete_failure(&mut self, key: &str) {
+        if let Some(tombstone) = self.objects.get_mut(key) {
+            tombstone.delete_failures += 1;
+            let backoff = 1u64
+                .checked_shl((tombstone.delete_failures - 1) as u32)
+                .unwrap_or(MAX_RETRY_BACKOFF_RUNS)
+                .min(MAX_RETRY_BACKOFF_RUNS);
+            tombstone.retry_run = tombstone.missing_runs + backoff;
+        }
+    }
+
+    /// Number of objects which target failed to delete more than once.
+    pub fn persistent_delete_failures(&self) -> usize {
+        self.objects
+            .values()
+            .filter(|tombstone| tombstone.delete_failures > 1)
+            .count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expire() {
+        let now = 100 * SECONDS_PER_DAY;
+        let first = Tombstones::default().update(["a", "b"].iter().copied(), now);
+        assert!(!first.expired("a", 2, 0, now));
+        assert!(first.expired("a", 1, 0, now));
+
+        let second = first.update(["a", "c"].iter().copied(), now + SECONDS_PER_DAY);
+        assert!(!second.objects.contains_key("b"));
+        assert_eq!(second.objects["a"].missing_runs, 2);
+        assert_eq!(second.objects["c"].missing_runs, 1);
+        assert!(second.expired("a", 2, 1, now + SECONDS_PER_DAY));
+        assert!(!second.expired("a", 2, 2, now + SECONDS_PER_DAY));
+        assert!(!second.expired("c", 2, 0, now + SECONDS_PER_DAY));
+    }
+
+    #[test]
+    fn test_delete_backoff() {
+        let mut tombstones = Tombstones::default().update(["a"].iter().copied(), 0);
+        assert!(!tombstones.backing_off("a"));
+        tombstones.record_delete_failure("a");
+        // retry on next run after first failure
+        tombstones = tombstones.update(["a"].iter().copied(), 0);
+        assert!(!tombstones.backing_off("a"));
+        tombstones.record_delete_failure("a");
+        // skip one run after second failure
+        tombstones = tombstones.update(["a"].iter().copied(), 0);
+        assert!(tombstones.backing_off("a"));
+        tombstones = tombstones.update(["a"].iter().copied(), 0);
+        assert!(!tombstones.backing_off("a"));
+        assert_eq!(tombstones.persistent_delete_failures(), 1);
+    }
+}
@@ -1,20 +1,68 @@
 use crate::common::{Mission, SnapshotConfig, SnapshotPath};
-use crate::error::Result;
+use crate::error::{Error, Result};
 use async_trait::async_trait;
+use futures_util::stream::{self, BoxStream, StreamExt};
 
+/// Storage which can be snapshotted. A snapshot need not be sorted or free
+/// of duplicates. Sources referencing the same object from several indexes
+/// (e.g. pool of a multi-suite distro mirror) may simply yield it more than
+/// once, and it will be transferred once.
 #[async_trait]
-pub trait SnapshotStorage<SnapshotItem>: Send + Sync + 'static {
+pub trait SnapshotStorage<SnapshotItem: Send + 'static>: Send + Sync + 'static {
     async fn snapshot(
         &mut self,
         mission: Mission,
         config: &SnapshotConfig,
     ) -> Result<Vec<SnapshotItem>>;
+
+    /// Take a snapshot page by page. By default, the whole snapshot is
+    /// yielded as a single page. Storages which list objects with pagination
+    /// (e.g. S3) should override this, so that callers may consume the
+    /// listing incrementally instead of waiting for all keys.
+    fn snapshot_stream<'a>(
+        &'a mut self,
+        mission: Mission,
+        config: &'a SnapshotConfig,
+    ) -> BoxStream<'a, Result<Vec<SnapshotItem>>> {
+        stream::once(self.snapshot(mission, config)).boxed()
+    }
+
     fn info(&self) -> String;
+
+    /// Check that storage is reachable, without taking a full snapshot, and
+    /// describe what was checked. By default, the first page of snapshot is
+    /// taken, which is cheap for paginated or local storages. Sources taking
+    /// snapshot from remote indexes should rather request their base, and
+    /// pipes should forward to their source.
+    async fn check(&mut self, mission: Mission, config: &SnapshotConfig) -> Result<String> {
+        let page = self
+            .snapshot_stream(mission, config)
+            .next()
+            .await
+            .unwrap_or_else(|| Ok(vec![]))?;
+        Ok(format!("{} objects in first page", page.len()))
+    }
+
+    /// Whether snapshot may be replaced by one cached by a previous run.
+    /// Storages which build state needed by `get_object` while taking
+    /// snapshot (e.g. URL of each object, or index pages) should return
+    /// false, and pipes should return that of their source.
+    fn cacheable(&self) -> bool {
+        true
+    }
 }
 
 #[async_trait]
 pub trait SourceStorage<SnapshotItem, SourceItem>: Send + Sync + 'static {
     async fn get_object(&self, snapshot: &SnapshotItem, mission: &Mission) -> Result<SourceItem>;
+
+    /// Size of object on source, without fetching it, or `None` if unknown.
+    async fn get_size(&self, _snapshot: &SnapshotItem, _mission: &Mission) -> Result<Option<u64>>
+    where
+        SnapshotItem: Sync,
+    {
+        Ok(None)
+    }
 }
 
 #[async_trait]
@@ -26,6 +74,54 @@ pub trait TargetStorage<SnapshotItem, TargetItem>: Send + Sync + 'static {
         mission: &Mission,
     ) -> Result<()>;
     async fn delete_object(&self, snapshot: &SnapshotItem, mission: &Mission) -> Result<()>;
+
+    /// Available space on target in bytes, or `None` if it's unknown or unlimited.
+    async fn free_space(&self) -> Result<Option<u64>> {
+        Ok(None)
+    }
+
+    /// Whether `checksum_object` is implemented, so that objects may be
+    /// verified after transfer.
+    fn can_checksum(&self) -> bool {
+        false
+    }
+
+    /// Read back an object on target and compute its checksum with `method`.
+    /// Returns `None` if the object doesn't exist or method is not supported.
+    async fn checksum_object(
+        &self,
+        _snapshot: &SnapshotItem,
+        _method: &str,
+    ) -> Result<Option<String>>
+    where
+        SnapshotItem: Sync,
+    {
+        Err(Error::StorageError(String::from(
+            "checksum is not supported on this target",
+        )))
+    }
+
+    /// Read a small object generated by mirror-clone itself (e.g. status file).
+    /// Returns `None` if the object doesn't exist.
+    async fn get_raw_object(&self, _key: &str) -> Result<Option<Vec<u8>>> {
+        Err(Error::StorageError(String::from(
+            "raw object is not supported on this target",
+        )))
+    }
+
+    /// Atomically write a small object generated by mirror-clone itself.
+    async fn put_raw_object(&self, _key: &str, _content: Vec<u8>) -> Result<()> {
+        Err(Error::StorageError(String::from(
+            "raw object is not supported on this target",
+        )))
+    }
+
+    /// Delete a small object generated by mirror-clone itself.
+    async fn delete_raw_object(&self, _key: &str) -> Result<()> {
+        Err(Error::StorageError(String::from(
+            "raw object is not supported on this target",
+        )))
+    }
 }
 
 pub trait Key: Send + Sync + 'static {
@@ -42,6 +138,23 @@ pub trait Metadata {
     fn last_modified(&self) -> Option<u64> {
         None
     }
+
+    fn size(&self) -> Option<u64> {
+        None
+    }
+
+    /// Record size of object found out after snapshot is taken, e.g. by a
+    /// `HEAD` request. Ignored by snapshots which don't carry size.
+    fn set_size(&mut self, _size: u64) {}
+
+    /// Checksum method and lowercase hex digest of object, if known.
+    fn checksum(&self) -> Option<(&str, &str)> {
+        None
+    }
+    /// Whether object is always transferred, regardless of target.
+    fn forced(&self) -> bool {
+        false
+    }
 }
 
 pub trait Diff {
@@ -64,4 +177,8 @@ impl Diff for SnapshotPath {
     }
 }
 
-impl Metadata for SnapshotPath {}
+impl Metadata for SnapshotPath {
+    fn forced(&self) -> bool {
+        self.1
+    }
+}
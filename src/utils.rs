@@ -1,45 +1,137 @@
-use std::convert::Infallible;
+use std::io::Write;
 use std::str::FromStr;
+use std::sync::Mutex;
 
 use indicatif::ProgressStyle;
 use regex::Regex;
-use slog::{o, Drain};
+use reqwest::header::{HeaderName, HeaderValue};
+use slog::{o, Drain, Level, OwnedKVList, Record, KV};
 
-use crate::common::SnapshotPath;
-use crate::error::Result;
+use crate::common::{Mission, SnapshotPath};
+use crate::error::{Error, Result};
 use crate::metadata::SnapshotMeta;
 
-#[derive(Debug, Clone, Default)]
-pub struct CommaSplitVecString(Vec<String>);
+/// Log levels accepted on command line.
+pub const LOG_LEVELS: &[&str] = &["trace", "debug", "info", "warn", "error"];
 
-impl FromStr for CommaSplitVecString {
-    type Err = Infallible;
+/// Level of logs when none is configured.
+#[cfg(debug_assertions)]
+const DEFAULT_LOG_LEVEL: Level = Level::Debug;
+#[cfg(not(debug_assertions))]
+const DEFAULT_LOG_LEVEL: Level = Level::Info;
 
-    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
-        Ok(Self(
-            s.split(',')
-                .map(str::trim)
-                .map(ToString::to_string)
-                .collect(),
+pub fn parse_log_level(level: &str) -> Result<Level> {
+    Level::from_str(level).map_err(|_| {
+        Error::ConfigureError(format!(
+            "unsupported log level {}, expected one of {:?}",
+            level, LOG_LEVELS
         ))
+    })
+}
+
+/// Check that `url` is reachable with a GET request, without reading the
+/// body of response.
+pub async fn check_url(mission: &Mission, url: &str) -> Result<String> {
+    mission.throttle().await;
+    let response = mission.client.get(url).send().await?;
+    if !response.status().is_success() {
+        return Err(Error::HTTPError(response.status()));
     }
+    Ok(format!("{} {}", url, response.status()))
 }
 
-impl From<CommaSplitVecString> for Vec<String> {
-    fn from(v: CommaSplitVecString) -> Self {
-        v.0
+/// Create a logger with records below `level` filtered out. Logs are written
+/// to terminal in human-readable form, or as one JSON object per line if
+/// `json` is set. `trace` records are compiled into both debug and release
+/// builds, with `max_level_trace` and `release_max_level_trace` of slog.
+pub fn create_logger(level: Option<Level>, json: bool) -> slog::Logger {
+    let level = level.unwrap_or(DEFAULT_LOG_LEVEL);
+    if json {
+        async_logger(JsonDrain::new(std::io::stderr()).fuse(), level)
+    } else {
+        let decorator = slog_term::TermDecorator::new().build();
+        async_logger(slog_term::FullFormat::new(decorator).build().fuse(), level)
     }
 }
 
-pub fn create_logger() -> slog::Logger {
-    let decorator = slog_term::TermDecorator::new().build();
-    let drain = slog_term::FullFormat::new(decorator).build().fuse();
-    #[cfg(not(debug_assertions))]
-    let drain = slog_envlogger::new(drain);
+fn async_logger<D>(drain: D, level: Level) -> slog::Logger
+where
+    D: Drain<Ok = (), Err = slog::Never> + Send + 'static,
+{
+    let drain = drain.filter_level(level).fuse();
     let drain = slog_async::Async::new(drain).chan_size(1024).build().fuse();
     slog::Logger::root(drain, o!())
 }
 
+/// Drain writing each record as a JSON object on its own line, with message,
+/// level, unix time and key-value pairs of record.
+pub struct JsonDrain<W: Write> {
+    writer: Mutex<W>,
+}
+
+impl<W: Write> JsonDrain<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer: Mutex::new(writer),
+        }
+    }
+}
+
+impl<W: Write> Drain for JsonDrain<W> {
+    type Ok = ();
+    type Err = std::io::Error;
+
+    fn log(&self, record: &Record, values: &OwnedKVList) -> std::io::Result<()> {
+        let mut serializer = JsonSerializer(serde_json::Map::new());
+        values
+            .serialize(record, &mut serializer)
+            .and_then(|_| record.kv().serialize(record, &mut serializer))
+            .map_err(|err| std::io::Error::other(format!("{:?}", err)))?;
+        let mut object = serializer.0;
+        object.insert("ts".to_string(), unix_time().into());
+        object.insert("level".to_string(), record.level().as_str().into());
+        object.insert("msg".to_string(), record.msg().to_string().into());
+        let mut writer = self.writer.lock().unwrap();
+        serde_json::to_writer(&mut *writer, &object)?;
+        writeln!(writer)
+    }
+}
+
+struct JsonSerializer(serde_json::Map<String, serde_json::Value>);
+
+impl JsonSerializer {
+    fn insert(&mut self, key: slog::Key, value: impl Into<serde_json::Value>) -> slog::Result {
+        self.0.insert(key.to_string(), value.into());
+        Ok(())
+    }
+}
+
+impl slog::Serializer for JsonSerializer {
+    fn emit_arguments(&mut self, key: slog::Key, val: &std::fmt::Arguments) -> slog::Result {
+        self.insert(key, val.to_string())
+    }
+
+    fn emit_u64(&mut self, key: slog::Key, val: u64) -> slog::Result {
+        self.insert(key, val)
+    }
+
+    fn emit_i64(&mut self, key: slog::Key, val: i64) -> slog::Result {
+        self.insert(key, val)
+    }
+
+    fn emit_f64(&mut self, key: slog::Key, val: f64) -> slog::Result {
+        self.insert(key, val)
+    }
+
+    fn emit_bool(&mut self, key: slog::Key, val: bool) -> slog::Result {
+        self.insert(key, val)
+    }
+
+    fn emit_none(&mut self, key: slog::Key) -> slog::Result {
+        self.insert(key, serde_json::Value::Null)
+    }
+}
+
 pub fn spinner() -> ProgressStyle {
     ProgressStyle::default_spinner()
         .template("{prefix:.bold.dim} {spinner} {msg}")
@@ -70,6 +162,18 @@ pub fn user_agent() -> String {
     )
 }
 
+/// Parse an extra request header, e.g. `Authorization: Bearer xxx`. The
+/// value is marked sensitive, so that it's never logged.
+pub fn parse_header(header: &str) -> Result<(HeaderName, HeaderValue)> {
+    let invalid =
+        || Error::ConfigureError(format!("invalid header {:?}, expected Name: value", header));
+    let (name, value) = header.split_once(':').ok_or_else(invalid)?;
+    let name = HeaderName::from_bytes(name.trim().as_bytes()).map_err(|_| invalid())?;
+    let mut value = HeaderValue::from_str(value.trim()).map_err(|_| invalid())?;
+    value.set_sensitive(true);
+    Ok((name, value))
+}
+
 pub fn generate_s3_url_encode_map() -> Vec<(&'static str, &'static str)> {
     // reference: https://github.com/GeorgePhillips/node-s3-url-encode/blob/master/index.js
     vec![
@@ -134,3 +238,68 @@ pub fn unix_time() -> u64 {
         .expect("Time went backwards")
         .as_secs()
 }
+
+/// Format bytes in binary units, e.g. `1.5 GiB`.
+pub fn human_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+/// Split a paragraph of Debian control file (also used by CRAN) into field
+/// names and values. Values of multi-line fields are joined by newline.
+pub fn parse_control_fields(paragraph: &str) -> Vec<(&str, String)> {
+    let mut fields: Vec<(&str, String)> = vec![];
+    for line in paragraph.lines() {
+        if line.starts_with(' ') || line.starts_with('\t') {
+            if let Some((_, value)) = fields.last_mut() {
+                value.push('\n');
+                value.push_str(line.trim());
+            }
+        } else if let Some((name, value)) = line.split_once(':') {
+            fields.push((name, value.trim().to_string()));
+        }
+    }
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_trace_emitted() {
+        let buffer = Buffer::default();
+        let drain = JsonDrain::new(buffer.clone())
+            .fuse()
+            .filter_level(Level::Trace)
+            .fuse();
+        let logger = slog::Logger::root(drain, o!());
+        slog::trace!(logger, "traced"; "key" => "value");
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        assert!(output.contains("traced"), "{}", output);
+    }
+}